@@ -0,0 +1,393 @@
+//
+// BIOS services
+//
+use std::io::{self, stdout, ErrorKind, Write};
+use std::sync::mpsc::Receiver;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::emulator::{Emulator, GPR, GPR8};
+use crate::emulator::error::EmuError;
+use crate::emulator::interrupt::InterruptHandler;
+use crate::emulator::segment::SREG;
+
+use self::disk::Disk;
+
+pub mod disk;
+pub mod video;
+
+pub const VIDEO_VECTOR: u8 = 0x10;
+pub const EQUIPMENT_VECTOR: u8 = 0x11;
+pub const MEMORY_SIZE_VECTOR: u8 = 0x12;
+pub const DISK_VECTOR: u8 = 0x13;
+pub const SYSTEM_VECTOR: u8 = 0x15;
+pub const KEYBOARD_VECTOR: u8 = 0x16;
+pub const TIME_VECTOR: u8 = 0x1a;
+
+// BIOS data area, the services keep their state there where guests expect it
+const EQUIPMENT: u32 = 0x410;
+const MEMORY_SIZE: u32 = 0x413;
+const VIDEO_MODE: u32 = 0x449;
+const COLUMNS: u32 = 0x44a;
+const CURSOR: u32 = 0x450;
+const CURSOR_SHAPE: u32 = 0x460;
+const ACTIVE_PAGE: u32 = 0x462;
+const HARD_DISKS: u32 = 0x475;
+const ROWS: u32 = 0x484;
+
+// 639 KiB of conventional memory, the last KiB is the extended BIOS data area
+const CONVENTIONAL_MEMORY: u32 = 0x9fc00;
+const EXTENDED_MEMORY: u32 = 0x100000;
+const BIOS_ROM: u32 = 0xf0000;
+
+const SMAP: u32 = 0x534d4150;
+const E820_USABLE: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+// the boot sector is loaded at 0000:7C00 and ends with 55 AA
+pub const BOOT_ADDRESS: u32 = 0x7c00;
+const BOOT_SIGNATURE: u16 = 0xaa55;
+
+const UNSUPPORTED: u8 = 0x86;
+
+// scan codes of the keys on the main rows of the keyboard, starting from the first one
+const SCAN_ROWS: [(u8, &[u8]); 4] = [
+    (0x02, b"1234567890-="),
+    (0x10, b"qwertyuiop[]"),
+    (0x1e, b"asdfghjkl;'`"),
+    (0x2c, b"zxcvbnm,./"),
+];
+
+// high level BIOS: video, keyboard, disk, memory and time services run on the host
+pub struct Bios {
+    keyboard: Receiver<u8>,
+    key: Option<u8>,
+    disk: Option<Disk>,
+    vga: bool,
+}
+
+impl Bios {
+    // fill in the BIOS data area for an 80x25 color display and the disk, if any
+    pub fn new(emu: &mut Emulator, keyboard: Receiver<u8>, disk: Option<Disk>) -> Result<Bios, EmuError> {
+        let floppy = disk.as_ref().is_some_and(|disk| disk.is_floppy());
+        emu.set_memory16(EQUIPMENT, 0x20 | floppy as u32)?;
+        emu.set_memory16(MEMORY_SIZE, CONVENTIONAL_MEMORY >> 10)?;
+        emu.set_memory8(HARD_DISKS, (disk.is_some() && !floppy) as u32)?;
+        emu.set_memory8(VIDEO_MODE, 0x03)?;
+        emu.set_memory16(COLUMNS, 80)?;
+        emu.set_memory8(ROWS, 24)?;
+        emu.set_memory16(CURSOR_SHAPE, 0x0607)?;
+        Ok(Bios { keyboard, key: None, disk, vga: false })
+    }
+
+    // with a VGA adapter the video services draw into its text buffer instead of printing to stdout
+    pub fn set_vga(&mut self, vga: bool) {
+        self.vga = vga;
+    }
+
+    fn keyboard(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        match emu.get_gpr8_value(&GPR8::AH) {
+            0x00 | 0x10 => {
+                let key = match self.key.take() {
+                    Some(key) => key,
+                    None => self.keyboard.recv().map_err(|_| EmuError::Console(ErrorKind::UnexpectedEof))?,
+                };
+                emu.set_gpr16(&GPR::EAX, keystroke(key));
+            },
+            0x01 | 0x11 => {
+                // the key stays in the buffer, ZF is set when there is none
+                if self.key.is_none() {
+                    self.key = self.keyboard.try_recv().ok();
+                }
+                if let Some(key) = self.key {
+                    emu.set_gpr16(&GPR::EAX, keystroke(key));
+                }
+                emu.set_zero(self.key.is_none());
+            },
+            // no shift keys are ever held
+            0x02 | 0x12 => emu.set_gpr8(&GPR8::AL, 0x0),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn system(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        let supported = match emu.get_gpr16_value(&GPR::EAX) {
+            0xe820 => memory_map(emu)?,
+            function @ (0x2400 | 0x2401) => {
+                emu.set_a20(function == 0x2401);
+                emu.set_gpr8(&GPR8::AH, 0x0);
+                true
+            },
+            0x2402 => {
+                emu.set_gpr16(&GPR::EAX, emu.is_a20_enabled() as u16);
+                true
+            },
+            0x2403 => {
+                // the gate is available through the keyboard controller and port 0x92
+                emu.set_gpr16(&GPR::EAX, 0x0);
+                emu.set_gpr16(&GPR::EBX, 0x3);
+                true
+            },
+            function => match function >> 8 {
+                0x86 => true,
+                0x88 => {
+                    let size = emu.get_memory_size().saturating_sub(EXTENDED_MEMORY) >> 10;
+                    emu.set_gpr16(&GPR::EAX, size.min(0xffff) as u16);
+                    true
+                },
+                _ => false,
+            },
+        };
+        if !supported {
+            emu.set_gpr8(&GPR8::AH, UNSUPPORTED);
+        }
+        emu.set_carry(!supported as u64);
+        Ok(())
+    }
+
+    fn time(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        // the real time clock keeps UTC
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (days, millis) = (now.as_secs() / 86400, (now.as_millis() % 86_400_000) as u64);
+        match emu.get_gpr8_value(&GPR8::AH) {
+            0x00 => {
+                // the timer ticks at 1193182 / 65536 Hz since midnight
+                let ticks = (millis * 1193182 / 65536 / 1000) as u32;
+                emu.set_gpr16(&GPR::ECX, (ticks >> 16) as u16);
+                emu.set_gpr16(&GPR::EDX, ticks as u16);
+                emu.set_gpr8(&GPR8::AL, 0x0);
+            },
+            0x02 => {
+                let seconds = (millis / 1000) as u32;
+                emu.set_gpr8(&GPR8::CH, bcd(seconds / 3600));
+                emu.set_gpr8(&GPR8::CL, bcd(seconds / 60 % 60));
+                emu.set_gpr8(&GPR8::DH, bcd(seconds % 60));
+                emu.set_gpr8(&GPR8::DL, 0x0);
+                emu.set_carry(0);
+            },
+            0x04 => {
+                let (year, month, day) = civil_date(days);
+                emu.set_gpr8(&GPR8::CH, bcd(year / 100));
+                emu.set_gpr8(&GPR8::CL, bcd(year % 100));
+                emu.set_gpr8(&GPR8::DH, bcd(month));
+                emu.set_gpr8(&GPR8::DL, bcd(day));
+                emu.set_carry(0);
+            },
+            _ => emu.set_carry(1),
+        }
+        Ok(())
+    }
+}
+
+impl InterruptHandler for Bios {
+    fn interrupt(&mut self, emu: &mut Emulator, vector: u8) -> Result<bool, EmuError> {
+        match vector {
+            VIDEO_VECTOR => self.video(emu)?,
+            EQUIPMENT_VECTOR => emu.set_gpr16(&GPR::EAX, emu.get_memory16(EQUIPMENT)?),
+            MEMORY_SIZE_VECTOR => emu.set_gpr16(&GPR::EAX, emu.get_memory16(MEMORY_SIZE)?),
+            DISK_VECTOR => self.disk(emu)?,
+            SYSTEM_VECTOR => self.system(emu)?,
+            KEYBOARD_VECTOR => self.keyboard(emu)?,
+            TIME_VECTOR => self.time(emu)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+// load the boot sector of a disk and set the CPU up the way the BIOS hands it over:
+// real mode at 0000:7C00 with the boot drive in DL, the stack right below the boot sector
+pub fn boot(emu: &mut Emulator, disk: &mut Disk) -> Result<(), EmuError> {
+    let sector = disk.read_sectors(0, 1).map_err(|err| EmuError::InvalidImage(err.to_string()))?;
+    if u16::from_le_bytes([sector[510], sector[511]]) != BOOT_SIGNATURE {
+        return Err(EmuError::InvalidImage("no boot signature in the first sector".to_string()));
+    }
+
+    emu.set_real_mode();
+    emu.load_bin(sector, BOOT_ADDRESS)?;
+    for reg in [GPR::EAX, GPR::EBX, GPR::ECX, GPR::EDX, GPR::ESI, GPR::EDI, GPR::EBP] {
+        emu.set_gpr(&reg, 0x0);
+    }
+    emu.set_gpr8(&GPR8::DL, disk.get_drive());
+    emu.set_gpr(&GPR::ESP, BOOT_ADDRESS);
+    // only the reserved bit is set
+    emu.set_eflags(0x2);
+    emu.set_eip(BOOT_ADDRESS);
+    Ok(())
+}
+
+// E820: the entry EBX points at goes to ES:DI, EBX becomes the next one or 0 after the last
+fn memory_map(emu: &mut Emulator) -> Result<bool, EmuError> {
+    let mut map = vec![
+        (0x0, CONVENTIONAL_MEMORY, E820_USABLE),
+        (CONVENTIONAL_MEMORY, 0xa0000 - CONVENTIONAL_MEMORY, E820_RESERVED),
+        (BIOS_ROM, EXTENDED_MEMORY - BIOS_ROM, E820_RESERVED),
+    ];
+    if emu.get_memory_size() > EXTENDED_MEMORY {
+        map.push((EXTENDED_MEMORY, emu.get_memory_size() - EXTENDED_MEMORY, E820_USABLE));
+    }
+
+    let (index, size) = (emu.get_gpr_value(&GPR::EBX) as usize, emu.get_gpr_value(&GPR::ECX));
+    let entry = map.get(index);
+    if emu.get_gpr_value(&GPR::EDX) != SMAP || size < 20 || entry.is_none() {
+        return Ok(false);
+    }
+    let (base, length, kind) = *entry.unwrap();
+    let buffer = emu.get_linear_address(&SREG::ES, emu.get_gpr16_value(&GPR::EDI) as u32);
+    for (i, value) in [base, 0x0, length, 0x0, kind].into_iter().enumerate() {
+        emu.set_memory32(buffer + i as u32 * 4, value)?;
+    }
+    // ACPI 3.0 callers also get the extended attributes, the entry is enabled
+    let size = if size >= 24 {
+        emu.set_memory32(buffer + 20, 0x1)?;
+        24
+    } else {
+        20
+    };
+
+    emu.set_gpr(&GPR::EAX, SMAP);
+    emu.set_gpr(&GPR::ECX, size);
+    emu.set_gpr(&GPR::EBX, if index + 1 < map.len() { index as u32 + 1 } else { 0 });
+    Ok(true)
+}
+
+// scan code in the high byte and ASCII in the low one, a newline is the Enter key
+fn keystroke(key: u8) -> u16 {
+    let (scan, ascii) = match key {
+        b'\n' | b'\r' => (0x1c, b'\r'),
+        0x1b => (0x01, 0x1b),
+        0x08 | 0x7f => (0x0e, 0x08),
+        b'\t' => (0x0f, b'\t'),
+        b' ' => (0x39, b' '),
+        _ => {
+            let lower = key.to_ascii_lowercase();
+            let scan = SCAN_ROWS.iter()
+                .find_map(|(first, row)| row.iter().position(|char| *char == lower).map(|i| first + i as u8))
+                .unwrap_or(0);
+            (scan, key)
+        },
+    };
+    (scan as u16) << 8 | ascii as u16
+}
+
+fn bcd(value: u32) -> u8 {
+    (((value / 10 % 10) << 4) | (value % 10)) as u8
+}
+
+// year, month and day of a day count since 1970-01-01
+fn civil_date(days: u64) -> (u32, u32, u32) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year as u32, month as u32, day as u32)
+}
+
+fn putchar(char: u8) -> io::Result<()> {
+    let mut stdout = stdout();
+    stdout.write_all(&[char])?;
+    stdout.flush()
+}
+
+fn io_error(err: io::Error) -> EmuError {
+    EmuError::Console(err.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn bios_test() {
+        let mut emu = Emulator::new(0x200000, 0x0, 0x0);
+        emu.set_real_mode();
+        let (sender, receiver) = mpsc::channel();
+        let bios = Bios::new(&mut emu, receiver, None).unwrap();
+        emu.attach_interrupt_handler(VIDEO_VECTOR..=TIME_VECTOR, Box::new(bios));
+
+        // teletype output moves the cursor and wraps at the end of the row
+        emu.set_gpr16(&GPR::EAX, 0x0e41);
+        emu.set_gpr16(&GPR::EBX, 0x0);
+        emu.software_interrupt(VIDEO_VECTOR).unwrap();
+        assert_eq!(emu.get_memory16(CURSOR), Ok(0x0001));
+        emu.set_gpr16(&GPR::EAX, 0x0200);
+        emu.set_gpr16(&GPR::EDX, 0x184f);
+        emu.software_interrupt(VIDEO_VECTOR).unwrap();
+        emu.set_gpr16(&GPR::EAX, 0x0e42);
+        emu.software_interrupt(VIDEO_VECTOR).unwrap();
+        emu.set_gpr16(&GPR::EAX, 0x0300);
+        emu.software_interrupt(VIDEO_VECTOR).unwrap();
+        assert_eq!(emu.get_gpr16_value(&GPR::EDX), 0x1800);
+        assert_eq!(emu.get_gpr16_value(&GPR::ECX), 0x0607);
+
+        // keyboard status leaves the key to be read
+        emu.set_gpr16(&GPR::EAX, 0x0100);
+        emu.software_interrupt(KEYBOARD_VECTOR).unwrap();
+        assert!(emu.is_zero());
+        sender.send(b'\n').unwrap();
+        emu.software_interrupt(KEYBOARD_VECTOR).unwrap();
+        assert!(!emu.is_zero());
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0x1c0d);
+        emu.set_gpr16(&GPR::EAX, 0x0000);
+        emu.software_interrupt(KEYBOARD_VECTOR).unwrap();
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0x1c0d);
+        drop(sender);
+        emu.set_gpr16(&GPR::EAX, 0x0000);
+        assert_eq!(emu.software_interrupt(KEYBOARD_VECTOR), Err(EmuError::Console(ErrorKind::UnexpectedEof)));
+        assert_eq!(keystroke(b'Q'), 0x1051);
+
+        // E820 walks the map through EBX
+        let mut entries = Vec::new();
+        emu.set_gpr(&GPR::EBX, 0);
+        loop {
+            emu.set_gpr(&GPR::EAX, 0xe820);
+            emu.set_gpr(&GPR::ECX, 24);
+            emu.set_gpr(&GPR::EDX, SMAP);
+            emu.set_gpr16(&GPR::EDI, 0x500);
+            emu.software_interrupt(SYSTEM_VECTOR).unwrap();
+            assert!(!emu.is_carry());
+            assert_eq!(emu.get_gpr_value(&GPR::EAX), SMAP);
+            entries.push((emu.get_memory32(0x500).unwrap(), emu.get_memory32(0x508).unwrap(), emu.get_memory32(0x510).unwrap()));
+            if emu.get_gpr_value(&GPR::EBX) == 0 {
+                break;
+            }
+        }
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0], (0x0, 0x9fc00, E820_USABLE));
+        assert_eq!(entries[3], (0x100000, 0x100000, E820_USABLE));
+        emu.set_gpr16(&GPR::EAX, 0xc000);
+        emu.software_interrupt(SYSTEM_VECTOR).unwrap();
+        assert!(emu.is_carry());
+        assert_eq!(emu.get_gpr8_value(&GPR8::AH), UNSUPPORTED);
+
+        emu.software_interrupt(MEMORY_SIZE_VECTOR).unwrap();
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 639);
+        // vectors without a service go to the guest
+        emu.set_ivt_entry(0x14, 0x1234, 0x5678).unwrap();
+        emu.software_interrupt(0x14).unwrap();
+        assert_eq!(emu.get_linear_eip(), 0x179b8);
+
+        // the boot sector needs its signature
+        let mut image = vec![0x90; 0x200 * 4];
+        image[0x1fe..0x200].copy_from_slice(&[0x55, 0xaa]);
+        emu.set_gpr(&GPR::ECX, 0x1234);
+        boot(&mut emu, &mut Disk::new(Box::new(io::Cursor::new(image.clone()))).unwrap()).unwrap();
+        assert_eq!(emu.get_linear_eip(), 0x7c00);
+        assert_eq!(emu.get_memory16(0x7dfe), Ok(0xaa55));
+        assert_eq!(emu.get_gpr_value(&GPR::ECX), 0);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), 0x00);
+        assert_eq!(emu.get_sp(), 0x7c00);
+        image[0x1ff] = 0x0;
+        assert!(boot(&mut emu, &mut Disk::new(Box::new(io::Cursor::new(image))).unwrap()).is_err());
+
+        assert_eq!(bcd(59), 0x59);
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(19783), (2024, 3, 1));
+    }
+}
@@ -0,0 +1,263 @@
+//
+// INT 13h disk services on a host image
+//
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::Bios;
+use crate::emulator::{Emulator, GPR, GPR8};
+use crate::emulator::error::EmuError;
+use crate::emulator::segment::SREG;
+
+pub const SECTOR_SIZE: u32 = 512;
+
+// image sizes of the standard floppy formats with their cylinders, heads and sectors per track
+const FLOPPIES: [(u64, u32, u32, u32); 5] = [
+    (368640, 40, 2, 9),
+    (737280, 80, 2, 9),
+    (1228800, 80, 2, 15),
+    (1474560, 80, 2, 18),
+    (2949120, 80, 2, 36),
+];
+const FLOPPY_1440K: u64 = 1474560;
+
+const STATUS_INVALID_COMMAND: u8 = 0x01;
+const STATUS_WRITE_PROTECTED: u8 = 0x03;
+const STATUS_SECTOR_NOT_FOUND: u8 = 0x04;
+const STATUS_BOUNDARY_ERROR: u8 = 0x09;
+const STATUS_READ_ERROR: u8 = 0x10;
+const STATUS_NOT_READY: u8 = 0x80;
+
+// AH on success, or the status of the failure
+type DiskResult = Result<u8, u8>;
+
+pub trait Image: Read + Seek {}
+
+impl<T: Read + Seek> Image for T {}
+
+// a read-only drive backed by an image, with the geometry the BIOS reports for it
+pub struct Disk {
+    image: Box<dyn Image>,
+    drive: u8,
+    cylinders: u32,
+    heads: u32,
+    sectors: u32,
+    total: u64,
+}
+
+impl Disk {
+    // images in a floppy format or smaller than a 1.44M floppy are floppies, anything else a hard disk
+    pub fn new(mut image: Box<dyn Image>) -> io::Result<Disk> {
+        let size = image.seek(SeekFrom::End(0))?;
+        let floppy = FLOPPIES.iter().find(|floppy| floppy.0 == size);
+        let (drive, cylinders, heads, sectors) = match floppy {
+            Some(&(_, cylinders, heads, sectors)) => (0x00, cylinders, heads, sectors),
+            None if size < FLOPPY_1440K => (0x00, 80, 2, 18),
+            None => {
+                let cylinders = size.div_ceil(SECTOR_SIZE as u64 * 16 * 63).min(1024);
+                (0x80, cylinders as u32, 16, 63)
+            },
+        };
+        // a hard disk is as large as its image, LBA reaches beyond the CHS geometry
+        let total = match drive {
+            0x00 => (cylinders * heads * sectors) as u64,
+            _ => size.div_ceil(SECTOR_SIZE as u64),
+        };
+        Ok(Disk { image, drive, cylinders, heads, sectors, total })
+    }
+
+    pub fn open(path: &Path) -> io::Result<Disk> {
+        Disk::new(Box::new(File::open(path)?))
+    }
+
+    pub fn get_drive(&self) -> u8 {
+        self.drive
+    }
+
+    pub fn is_floppy(&self) -> bool {
+        self.drive < 0x80
+    }
+
+    // sectors past the end of the image read as zeros
+    pub fn read_sectors(&mut self, lba: u64, count: u32) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; (count * SECTOR_SIZE) as usize];
+        self.image.seek(SeekFrom::Start(lba.saturating_mul(SECTOR_SIZE as u64)))?;
+        let mut filled = 0;
+        while filled < data.len() {
+            match self.image.read(&mut data[filled..])? {
+                0 => break,
+                length => filled += length,
+            }
+        }
+        Ok(data)
+    }
+
+    fn lba(&self, cylinder: u32, head: u32, sector: u32) -> Option<u64> {
+        if sector == 0 || sector > self.sectors || head >= self.heads || cylinder >= self.cylinders {
+            return None;
+        }
+        Some(((cylinder * self.heads + head) * self.sectors + sector - 1) as u64)
+    }
+
+    // count sectors from lba to segment:offset
+    fn transfer(&mut self, emu: &mut Emulator, lba: u64, count: u32, segment: u16, offset: u16) -> DiskResult {
+        if count == 0 || lba.saturating_add(count as u64) > self.total {
+            return Err(STATUS_SECTOR_NOT_FOUND);
+        }
+        let data = self.read_sectors(lba, count).map_err(|_| STATUS_READ_ERROR)?;
+        let buffer = ((segment as u32) << 4) + offset as u32;
+        for (i, byte) in data.iter().enumerate() {
+            emu.set_memory8(buffer + i as u32, *byte as u32).map_err(|_| STATUS_BOUNDARY_ERROR)?;
+        }
+        Ok(0x0)
+    }
+
+    fn read_chs(&mut self, emu: &mut Emulator) -> DiskResult {
+        // CL holds the sector in its low 6 bits and bits 8-9 of the cylinder in the high 2
+        let (ch, cl) = (emu.get_gpr8_value(&GPR8::CH) as u32, emu.get_gpr8_value(&GPR8::CL) as u32);
+        let (cylinder, sector, head) = (ch | (cl & 0xc0) << 2, cl & 0x3f, emu.get_gpr8_value(&GPR8::DH) as u32);
+        let lba = self.lba(cylinder, head, sector).ok_or(STATUS_SECTOR_NOT_FOUND)?;
+        let count = emu.get_gpr8_value(&GPR8::AL) as u32;
+        let (segment, offset) = (emu.get_sreg_value(&SREG::ES), emu.get_gpr16_value(&GPR::EBX));
+        self.transfer(emu, lba, count, segment, offset)
+    }
+
+    // disk address packet at DS:SI: size, reserved, count, offset, segment and a 64-bit LBA
+    fn read_extended(&mut self, emu: &mut Emulator) -> DiskResult {
+        let packet = emu.get_linear_address(&SREG::DS, emu.get_gpr16_value(&GPR::ESI) as u32);
+        let field = |offset: u32, size: u32| -> Result<u32, u8> {
+            match size {
+                1 => emu.get_memory8(packet + offset).map(|value| value as u32),
+                2 => emu.get_memory16(packet + offset).map(|value| value as u32),
+                _ => emu.get_memory32(packet + offset),
+            }.map_err(|_| STATUS_INVALID_COMMAND)
+        };
+        if field(0, 1)? < 16 {
+            return Err(STATUS_INVALID_COMMAND);
+        }
+        let (count, offset, segment) = (field(2, 2)?, field(4, 2)? as u16, field(6, 2)? as u16);
+        let lba = field(8, 4)? as u64 | (field(12, 4)? as u64) << 32;
+        self.transfer(emu, lba, count, segment, offset)
+    }
+
+    fn get_parameters(&self, emu: &mut Emulator) -> DiskResult {
+        let last_cylinder = self.cylinders - 1;
+        emu.set_gpr8(&GPR8::CH, last_cylinder as u8);
+        emu.set_gpr8(&GPR8::CL, self.sectors as u8 | (last_cylinder >> 2) as u8 & 0xc0);
+        emu.set_gpr8(&GPR8::DH, (self.heads - 1) as u8);
+        emu.set_gpr8(&GPR8::DL, 1);
+        if self.is_floppy() {
+            let kind = match (self.cylinders, self.sectors) {
+                (40, _) => 1,
+                (_, 15) => 2,
+                (_, 9) => 3,
+                (_, 36) => 6,
+                _ => 4,
+            };
+            emu.set_gpr8(&GPR8::BL, kind);
+        }
+        emu.set_gpr8(&GPR8::AL, 0x0);
+        Ok(0x0)
+    }
+}
+
+impl Bios {
+    pub(super) fn disk(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        let drive = emu.get_gpr8_value(&GPR8::DL);
+        let result = match self.disk.as_mut().filter(|disk| disk.drive == drive) {
+            None => Err(STATUS_NOT_READY),
+            Some(disk) => match emu.get_gpr8_value(&GPR8::AH) {
+                0x00 => Ok(0x0),
+                0x02 => disk.read_chs(emu),
+                0x03 | 0x43 => Err(STATUS_WRITE_PROTECTED),
+                0x08 => disk.get_parameters(emu),
+                0x15 if disk.is_floppy() => Ok(0x1),
+                0x15 => {
+                    emu.set_gpr16(&GPR::ECX, (disk.total >> 16) as u16);
+                    emu.set_gpr16(&GPR::EDX, disk.total as u16);
+                    Ok(0x3)
+                },
+                // EDD 3.0 with the packet functions
+                0x41 if emu.get_gpr16_value(&GPR::EBX) == 0x55aa => {
+                    emu.set_gpr16(&GPR::EBX, 0xaa55);
+                    emu.set_gpr16(&GPR::ECX, 0x1);
+                    Ok(0x30)
+                },
+                0x42 => disk.read_extended(emu),
+                _ => Err(STATUS_INVALID_COMMAND),
+            },
+        };
+
+        let (ah, carry) = match result {
+            Ok(value) => (value, 0),
+            Err(status) => (status, 1),
+        };
+        emu.set_gpr8(&GPR8::AH, ah);
+        emu.set_carry(carry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::DISK_VECTOR;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    #[test]
+    fn disk_test() {
+        let image: Vec<u8> = (0..4).flat_map(|sector| vec![sector as u8 + 1; SECTOR_SIZE as usize]).collect();
+        let disk = Disk::new(Box::new(Cursor::new(image))).unwrap();
+        assert_eq!(disk.get_drive(), 0x00);
+        assert_eq!((disk.cylinders, disk.heads, disk.sectors), (80, 2, 18));
+
+        let mut emu = Emulator::new(0x100000, 0x0, 0x0);
+        emu.set_real_mode();
+        let bios = Bios::new(&mut emu, mpsc::channel().1, Some(disk)).unwrap();
+        emu.attach_interrupt_handler(DISK_VECTOR..=DISK_VECTOR, Box::new(bios));
+
+        // CHS: cylinder 0, head 0, sectors 2 to 5 to 0800:0000, the last one is past the image
+        emu.set_sreg(&SREG::ES, 0x800);
+        emu.set_gpr16(&GPR::EAX, 0x0204);
+        emu.set_gpr16(&GPR::EBX, 0x0);
+        emu.set_gpr16(&GPR::ECX, 0x0002);
+        emu.set_gpr16(&GPR::EDX, 0x0000);
+        emu.software_interrupt(DISK_VECTOR).unwrap();
+        assert!(!emu.is_carry());
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0x0004);
+        assert_eq!(emu.get_memory8(0x8000), Ok(2));
+        assert_eq!(emu.get_memory8(0x85ff), Ok(4));
+        assert_eq!(emu.get_memory8(0x8600), Ok(0));
+
+        // LBA 3 through a disk address packet at 0000:0600
+        let packet = [0x10, 0x0, 0x1, 0x0, 0x00, 0x90, 0x00, 0x00, 0x3, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0];
+        emu.load_bin(packet.to_vec(), 0x600).unwrap();
+        emu.set_sreg(&SREG::DS, 0x0);
+        emu.set_gpr16(&GPR::ESI, 0x600);
+        emu.set_gpr16(&GPR::EAX, 0x4200);
+        emu.software_interrupt(DISK_VECTOR).unwrap();
+        assert!(!emu.is_carry());
+        assert_eq!(emu.get_memory8(0x9000), Ok(4));
+
+        emu.set_gpr16(&GPR::EAX, 0x0800);
+        emu.software_interrupt(DISK_VECTOR).unwrap();
+        assert_eq!(emu.get_gpr16_value(&GPR::ECX), 0x4f12);
+        assert_eq!(emu.get_gpr16_value(&GPR::EDX), 0x0101);
+        assert_eq!(emu.get_gpr8_value(&GPR8::BL), 4);
+
+        // sector 19 does not exist on a 1.44M floppy, nor does the first hard disk here
+        emu.set_gpr16(&GPR::EAX, 0x0201);
+        emu.set_gpr16(&GPR::ECX, 0x0013);
+        emu.set_gpr16(&GPR::EDX, 0x0000);
+        emu.software_interrupt(DISK_VECTOR).unwrap();
+        assert!(emu.is_carry());
+        assert_eq!(emu.get_gpr8_value(&GPR8::AH), STATUS_SECTOR_NOT_FOUND);
+        emu.set_gpr16(&GPR::EAX, 0x0000);
+        emu.set_gpr16(&GPR::EDX, 0x0080);
+        emu.software_interrupt(DISK_VECTOR).unwrap();
+        assert!(emu.is_carry());
+        assert_eq!(emu.get_gpr8_value(&GPR8::AH), STATUS_NOT_READY);
+    }
+}
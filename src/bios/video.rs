@@ -0,0 +1,289 @@
+//
+// INT 10h video services
+//
+use super::{io_error, putchar, Bios, ACTIVE_PAGE, COLUMNS, CURSOR, CURSOR_SHAPE, ROWS, VIDEO_MODE};
+use crate::device::vga::{TEXT_BUFFER, TEXT_BUFFER_SIZE};
+use crate::emulator::{Emulator, GPR, GPR8};
+use crate::emulator::error::EmuError;
+use crate::emulator::segment::SREG;
+
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_CURSOR_START: u32 = 0x0a;
+const CRTC_CURSOR_END: u32 = 0x0b;
+const CRTC_START_ADDRESS: u32 = 0x0c;
+const CRTC_CURSOR: u32 = 0x0e;
+
+// each of the 8 pages of the 80x25 mode takes 4 KiB
+const PAGE_SIZE: u32 = 0x1000;
+const BLANK: u32 = 0x0720;
+
+impl Bios {
+    pub(super) fn video(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        let page = emu.get_gpr8_value(&GPR8::BH) & 0x7;
+        match emu.get_gpr8_value(&GPR8::AH) {
+            0x00 => {
+                emu.set_memory8(VIDEO_MODE, (emu.get_gpr8_value(&GPR8::AL) & 0x7f) as u32)?;
+                emu.set_memory8(ACTIVE_PAGE, 0)?;
+                if self.vga {
+                    for offset in (0..TEXT_BUFFER_SIZE).step_by(2) {
+                        emu.set_memory16(TEXT_BUFFER + offset, BLANK)?;
+                    }
+                    set_crtc16(emu, CRTC_START_ADDRESS, 0)?;
+                }
+                for page in 0..8 {
+                    self.set_cursor(emu, page, 0, 0)?;
+                }
+            },
+            0x01 => {
+                let shape = emu.get_gpr16_value(&GPR::ECX);
+                emu.set_memory16(CURSOR_SHAPE, shape as u32)?;
+                if self.vga {
+                    emu.io_out(CRTC_INDEX, 2, CRTC_CURSOR_START | (shape as u32 & 0xff00))?;
+                    emu.io_out(CRTC_INDEX, 2, CRTC_CURSOR_END | (shape as u32 & 0xff) << 8)?;
+                }
+            },
+            0x02 => self.set_cursor(emu, page, emu.get_gpr8_value(&GPR8::DH), emu.get_gpr8_value(&GPR8::DL))?,
+            0x03 => {
+                let (row, column) = get_cursor(emu, page)?;
+                emu.set_gpr8(&GPR8::DH, row);
+                emu.set_gpr8(&GPR8::DL, column);
+                emu.set_gpr16(&GPR::ECX, emu.get_memory16(CURSOR_SHAPE)?);
+            },
+            0x05 => {
+                let page = emu.get_gpr8_value(&GPR8::AL) & 0x7;
+                emu.set_memory8(ACTIVE_PAGE, page as u32)?;
+                if self.vga {
+                    set_crtc16(emu, CRTC_START_ADDRESS, page as u32 * PAGE_SIZE / 2)?;
+                    let (row, column) = get_cursor(emu, page)?;
+                    self.set_cursor(emu, page, row, column)?;
+                }
+            },
+            function @ (0x06 | 0x07) if self.vga => {
+                let window = (emu.get_gpr8_value(&GPR8::CH), emu.get_gpr8_value(&GPR8::CL), emu.get_gpr8_value(&GPR8::DH), emu.get_gpr8_value(&GPR8::DL));
+                let (lines, attribute) = (emu.get_gpr8_value(&GPR8::AL), emu.get_gpr8_value(&GPR8::BH));
+                let active = emu.get_memory8(ACTIVE_PAGE)?;
+                scroll(emu, active, window, lines, attribute, function == 0x06)?;
+            },
+            0x08 => {
+                // nothing can be read back from a terminal, report a blank there
+                let cell = match self.vga {
+                    true => emu.get_memory16(cell_address(emu, page, get_cursor(emu, page)?)?)?,
+                    false => BLANK as u16,
+                };
+                emu.set_gpr16(&GPR::EAX, cell);
+            },
+            function @ (0x09 | 0x0A) => {
+                // CX copies from the cursor on, which stays where it is
+                let (char, count) = (emu.get_gpr8_value(&GPR8::AL), emu.get_gpr16_value(&GPR::ECX));
+                if self.vga {
+                    let start = cell_address(emu, page, get_cursor(emu, page)?)?;
+                    let end = TEXT_BUFFER + (page as u32 + 1) * PAGE_SIZE;
+                    for address in (start..end).step_by(2).take(count as usize) {
+                        emu.set_memory8(address, char as u32)?;
+                        if function == 0x09 {
+                            emu.set_memory8(address + 1, emu.get_gpr8_value(&GPR8::BL) as u32)?;
+                        }
+                    }
+                } else {
+                    for _ in 0..count {
+                        putchar(char).map_err(io_error)?;
+                    }
+                }
+            },
+            0x0E => self.teletype(emu, page, emu.get_gpr8_value(&GPR8::AL), None)?,
+            0x0F => {
+                emu.set_gpr8(&GPR8::AL, emu.get_memory8(VIDEO_MODE)?);
+                emu.set_gpr8(&GPR8::AH, emu.get_memory8(COLUMNS)?);
+                emu.set_gpr8(&GPR8::BH, emu.get_memory8(ACTIVE_PAGE)?);
+            },
+            0x13 => {
+                // CX characters at ES:BP written from DH/DL in the attribute BL,
+                // or interleaved with their own attributes when bit 1 of AL is set
+                let mode = emu.get_gpr8_value(&GPR8::AL);
+                let saved = get_cursor(emu, page)?;
+                self.set_cursor(emu, page, emu.get_gpr8_value(&GPR8::DH), emu.get_gpr8_value(&GPR8::DL))?;
+                let (string, stride) = (emu.get_gpr16_value(&GPR::EBP), if mode & 0x2 != 0 { 2 } else { 1 });
+                for i in 0..emu.get_gpr16_value(&GPR::ECX) {
+                    let offset = string.wrapping_add(i.wrapping_mul(stride));
+                    let char = emu.get_memory8(emu.get_linear_address(&SREG::ES, offset as u32))?;
+                    let attribute = match stride {
+                        2 => emu.get_memory8(emu.get_linear_address(&SREG::ES, offset.wrapping_add(1) as u32))?,
+                        _ => emu.get_gpr8_value(&GPR8::BL),
+                    };
+                    self.teletype(emu, page, char, Some(attribute))?;
+                }
+                if mode & 0x1 == 0 {
+                    self.set_cursor(emu, page, saved.0, saved.1)?;
+                }
+            },
+            // palettes and the like have nothing to do, and neither has scrolling on a terminal
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // the hardware cursor follows the one of the page on display
+    fn set_cursor(&self, emu: &mut Emulator, page: u8, row: u8, column: u8) -> Result<(), EmuError> {
+        emu.set_memory16(CURSOR + page as u32 * 2, (row as u32) << 8 | column as u32)?;
+        if self.vga && page == emu.get_memory8(ACTIVE_PAGE)? {
+            let location = (cell_address(emu, page, (row, column))? - TEXT_BUFFER) / 2;
+            set_crtc16(emu, CRTC_CURSOR, location)?;
+        }
+        Ok(())
+    }
+
+    // a character at the cursor, which moves on. the screen scrolls up instead of the cursor moving past the last row.
+    fn teletype(&self, emu: &mut Emulator, page: u8, char: u8, attribute: Option<u8>) -> Result<(), EmuError> {
+        if !self.vga {
+            putchar(char).map_err(io_error)?;
+        }
+        let (mut row, mut column) = get_cursor(emu, page)?;
+        let (columns, rows) = (emu.get_memory8(COLUMNS)?.max(1), emu.get_memory8(ROWS)?.saturating_add(1));
+        match char {
+            b'\r' => column = 0,
+            b'\n' => row = row.saturating_add(1),
+            0x08 => column = column.saturating_sub(1),
+            0x07 => {},
+            _ => {
+                if self.vga {
+                    let address = cell_address(emu, page, (row, column))?;
+                    emu.set_memory8(address, char as u32)?;
+                    if let Some(attribute) = attribute {
+                        emu.set_memory8(address + 1, attribute as u32)?;
+                    }
+                }
+                column = column.saturating_add(1);
+                if column >= columns {
+                    column = 0;
+                    row = row.saturating_add(1);
+                }
+            },
+        }
+        if row >= rows {
+            if self.vga {
+                scroll(emu, page, (0, 0, rows - 1, columns - 1), 1, 0x07, true)?;
+            }
+            row = rows - 1;
+        }
+        self.set_cursor(emu, page, row, column)
+    }
+}
+
+// cursor row and column of a page
+fn get_cursor(emu: &Emulator, page: u8) -> Result<(u8, u8), EmuError> {
+    let cursor = emu.get_memory16(CURSOR + page as u32 * 2)?;
+    Ok(((cursor >> 8) as u8, cursor as u8))
+}
+
+fn cell_address(emu: &Emulator, page: u8, (row, column): (u8, u8)) -> Result<u32, EmuError> {
+    let columns = emu.get_memory8(COLUMNS)? as u32;
+    Ok(TEXT_BUFFER + page as u32 * PAGE_SIZE + (row as u32 * columns + column as u32) * 2)
+}
+
+// a word register of the CRTC, high byte first
+fn set_crtc16(emu: &mut Emulator, index: u32, value: u32) -> Result<(), EmuError> {
+    emu.io_out(CRTC_INDEX, 2, index | (value & 0xff00))?;
+    emu.io_out(CRTC_INDEX, 2, (index + 1) | (value & 0xff) << 8)
+}
+
+// scroll a window of a page by lines, blanking the rows coming in with attribute. 0 lines blanks the whole window.
+fn scroll(emu: &mut Emulator, page: u8, window: (u8, u8, u8, u8), lines: u8, attribute: u8, up: bool) -> Result<(), EmuError> {
+    let (columns, rows) = (emu.get_memory8(COLUMNS)? as u32, emu.get_memory8(ROWS)? as u32 + 1);
+    // the guest may have cleared the column count in the BDA
+    if columns == 0 {
+        return Ok(());
+    }
+    let (top, left) = (window.0 as u32, window.1 as u32);
+    let (bottom, right) = ((window.2 as u32).min(rows - 1), (window.3 as u32).min(columns - 1));
+    if top > bottom || left > right {
+        return Ok(());
+    }
+    let height = bottom - top + 1;
+    let lines = match lines as u32 {
+        0 => height,
+        lines => lines.min(height),
+    };
+
+    let blank = (attribute as u32) << 8 | 0x20;
+    let address = |row: u32, column: u32| TEXT_BUFFER + page as u32 * PAGE_SIZE + (row * columns + column) * 2;
+    for i in 0..height {
+        let (row, source) = match up {
+            true => (top + i, top + i + lines),
+            false => (bottom - i, (bottom - i).wrapping_sub(lines)),
+        };
+        for column in left..=right {
+            let cell = match i + lines < height {
+                true => emu.get_memory16(address(source, column))? as u32,
+                false => blank,
+            };
+            emu.set_memory16(address(row, column), cell)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::VIDEO_VECTOR;
+    use crate::device::vga::{self, Vga};
+    use std::sync::mpsc;
+
+    #[test]
+    fn video_test() {
+        let mut emu = Emulator::new(0x100000, 0x0, 0x0);
+        emu.set_real_mode();
+        let vga = Vga::new();
+        emu.map_device(TEXT_BUFFER, TEXT_BUFFER_SIZE, Box::new(vga.clone()));
+        emu.attach_port_device(vga::CRTC_PORTS, Box::new(vga.clone()));
+        let mut bios = Bios::new(&mut emu, mpsc::channel().1, None).unwrap();
+        bios.set_vga(true);
+        emu.attach_interrupt_handler(VIDEO_VECTOR..=VIDEO_VECTOR, Box::new(bios));
+
+        let int10 = |emu: &mut Emulator, ax: u16, bx: u16, cx: u16, dx: u16| {
+            emu.set_gpr16(&GPR::EAX, ax);
+            emu.set_gpr16(&GPR::EBX, bx);
+            emu.set_gpr16(&GPR::ECX, cx);
+            emu.set_gpr16(&GPR::EDX, dx);
+            emu.software_interrupt(VIDEO_VECTOR).unwrap();
+        };
+
+        // teletype into the buffer, the hardware cursor follows
+        for char in b"Hi\r\n" {
+            int10(&mut emu, 0x0e00 | *char as u16, 0x0, 0x0, 0x0);
+        }
+        assert_eq!(emu.get_memory16(0xb8000), Ok(0x0748));
+        assert_eq!(vga.get_cursor(), 80);
+        // three yellow on blue stars on the second row
+        int10(&mut emu, 0x092a, 0x001e, 0x3, 0x0);
+        assert_eq!(emu.get_memory16(0xb80a4), Ok(0x1e2a));
+        assert_eq!(vga.get_cursor(), 80);
+        assert_eq!(vga.dump_text(), "Hi\n***");
+
+        // a line feed on the last row scrolls the screen up
+        int10(&mut emu, 0x0200, 0x0, 0x0, 0x1800);
+        int10(&mut emu, 0x0e0a, 0x0, 0x0, 0x0);
+        assert_eq!(vga.dump_text(), "***");
+        assert_eq!(vga.get_cursor(), 24 * 80);
+
+        // scrolling a window down by one
+        int10(&mut emu, 0x0701, 0x7000, 0x0000, 0x0102);
+        assert_eq!(emu.get_memory16(0xb8000), Ok(0x7020));
+        assert_eq!(emu.get_memory16(0xb80a0), Ok(0x1e2a));
+        assert_eq!(emu.get_memory16(0xb80a6), Ok(0x0720));
+
+        // a zero column count in the BDA does not break teletype nor scrolling
+        emu.set_memory16(COLUMNS, 0x0).unwrap();
+        int10(&mut emu, 0x0200, 0x0, 0x0, 0x1800);
+        int10(&mut emu, 0x0e0a, 0x0, 0x0, 0x0);
+        int10(&mut emu, 0x0601, 0x0700, 0x0000, 0x184f);
+        assert_eq!(emu.get_memory16(CURSOR), Ok(0x1800));
+        emu.set_memory16(COLUMNS, 80).unwrap();
+
+        int10(&mut emu, 0x0800, 0x0, 0x0, 0x0);
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0x0720);
+        int10(&mut emu, 0x0003, 0x0, 0x0, 0x0);
+        assert_eq!(vga.dump_text(), "");
+        assert_eq!(vga.get_cursor(), 0);
+    }
+}
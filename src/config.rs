@@ -1,25 +1,207 @@
 //
 // File read
 //
+const USAGE: &str = "Usage: rpx86 [--real] [--bios] [--disk image] [--vga|--vga-dump] [--comN backend] [--serial-wait] [--trace] [--debug] [--gdb host:port|path] [--org address] [bin]
+       rpx86 --boot [--vga|--vga-dump] [--comN backend] [--serial-wait] [--trace] [--debug] [--gdb host:port|path] [image]
+       rpx86 --linux [--trace] [--debug] [--gdb host:port|path] [elf] [args]...
+       rpx86 --dos [--dos-root dir] [--vga|--vga-dump] [--comN backend] [--serial-wait] [--trace] [--debug] [--gdb host:port|path] [com] [args]...
+       rpx86 disasm [--real] [--org address] [bin]
+       rpx86 disasm --boot [image]";
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Command {
+    Run,
+    Disasm,
+}
+
 #[derive(Debug)]
 pub struct Config {
+    command: Command,
     file_path: String,
+    args: Vec<String>,
+    real_mode: bool,
+    linux: bool,
+    dos: bool,
+    dos_root: Option<String>,
+    bios: bool,
+    disk: Option<String>,
+    boot: bool,
+    vga: bool,
+    vga_dump: bool,
+    serial: [Option<String>; 4],
+    serial_wait: bool,
+    trace: bool,
+    debug: bool,
+    gdb: Option<String>,
+    org: Option<u32>,
 }
 
 impl Config {
     pub fn build(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 2 {
-            return Err("Usage: rpx86 [bin]");
+        let mut command = Command::Run;
+        let mut file_path = None;
+        let mut guest_args = Vec::new();
+        let mut real_mode = false;
+        let mut linux = false;
+        let mut dos = false;
+        let mut dos_root = None;
+        let mut bios = false;
+        let mut disk = None;
+        let mut boot = false;
+        let mut vga = false;
+        let mut vga_dump = false;
+        let mut serial: [Option<String>; 4] = Default::default();
+        let mut serial_wait = false;
+        let mut trace = false;
+        let mut debug = false;
+        let mut gdb = None;
+        let mut org = None;
+
+        let mut args = args.iter().skip(1).peekable();
+        if args.peek().is_some_and(|arg| arg.as_str() == "disasm") {
+            command = Command::Disasm;
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // everything after the executable belongs to the Linux or DOS program
+                _ if (linux || dos) && file_path.is_some() => guest_args.push(arg.clone()),
+                "--real" => real_mode = true,
+                "--linux" if command == Command::Run => linux = true,
+                "--dos" if command == Command::Run => dos = true,
+                "--dos-root" if command == Command::Run => dos_root = Some(args.next().ok_or(USAGE)?.clone()),
+                "--bios" if command == Command::Run => bios = true,
+                "--disk" if command == Command::Run => disk = Some(args.next().ok_or(USAGE)?.clone()),
+                "--boot" => boot = true,
+                "--vga" if command == Command::Run => vga = true,
+                "--vga-dump" if command == Command::Run => vga_dump = true,
+                "--com1" | "--com2" | "--com3" | "--com4" if command == Command::Run => {
+                    let index = (arg.as_bytes()[5] - b'1') as usize;
+                    serial[index] = Some(args.next().ok_or(USAGE)?.clone());
+                },
+                "--serial-wait" if command == Command::Run => serial_wait = true,
+                "--trace" if command == Command::Run => trace = true,
+                "--debug" if command == Command::Run => debug = true,
+                "--gdb" if command == Command::Run => gdb = Some(args.next().ok_or(USAGE)?.clone()),
+                "--org" => {
+                    let address = args.next().ok_or(USAGE)?;
+                    org = Some(parse_number(address).ok_or("Invalid address given to --org")?);
+                },
+                _ if arg.starts_with("--") => return Err(USAGE),
+                _ => file_path = Some(arg.clone()),
+            }
         }
 
-        let file_path = args[1].clone();
+        let file_path = match file_path {
+            Some(file_path) => file_path,
+            None => return Err(USAGE),
+        };
 
-        Ok(Self { file_path })
+        // the BIOS is real mode firmware and reads the keyboard from stdin, which DOS programs use directly
+        let bios = bios || disk.is_some();
+        if linux && (real_mode || dos) || dos_root.is_some() && !dos || bios && (linux || dos) || linux && (vga || vga_dump) {
+            return Err(USAGE);
+        }
+        // a boot image is the disk itself and always goes to 0000:7C00
+        if boot && (linux || dos || disk.is_some() || org.is_some()) {
+            return Err(USAGE);
+        }
+        let bios = bios || boot && command == Command::Run;
+
+        Ok(Self { command, file_path, args: guest_args, real_mode, linux, dos, dos_root, bios, disk, boot, vga, vga_dump, serial, serial_wait, trace, debug, gdb, org })
+    }
+
+    pub fn get_command(&self) -> Command {
+        self.command
     }
 
     pub fn get_fp(&self) -> &str {
         &self.file_path
     }
+
+    pub fn get_args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn is_real_mode(&self) -> bool {
+        self.real_mode
+    }
+
+    pub fn is_linux(&self) -> bool {
+        self.linux
+    }
+
+    pub fn is_dos(&self) -> bool {
+        self.dos
+    }
+
+    // host directory DOS programs see as their drive
+    pub fn get_dos_root(&self) -> &str {
+        self.dos_root.as_deref().unwrap_or(".")
+    }
+
+    pub fn is_bios(&self) -> bool {
+        self.bios
+    }
+
+    // host file behind the BIOS disk, the booted image itself with --boot
+    pub fn get_disk(&self) -> Option<&str> {
+        match self.boot {
+            true => Some(&self.file_path),
+            false => self.disk.as_deref(),
+        }
+    }
+
+    pub fn is_boot(&self) -> bool {
+        self.boot
+    }
+
+    // a VGA text buffer, painted into the terminal or dumped as text at the end with --vga-dump
+    pub fn is_vga(&self) -> bool {
+        self.vga || self.vga_dump
+    }
+
+    pub fn is_vga_dump(&self) -> bool {
+        self.vga_dump
+    }
+
+    // backend of a COM port: stdio, file:path or unix:path. COM1 is on stdio unless told otherwise.
+    pub fn get_serial(&self, index: usize) -> Option<&str> {
+        match (index, self.serial[index].as_deref()) {
+            (0, None) => Some("stdio"),
+            (_, backend) => backend,
+        }
+    }
+
+    // reads from an empty receiver wait for input, for programs that never look at the line status
+    pub fn is_serial_wait(&self) -> bool {
+        self.serial_wait
+    }
+
+    pub fn is_trace(&self) -> bool {
+        self.trace
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.debug
+    }
+
+    pub fn get_gdb(&self) -> Option<&str> {
+        self.gdb.as_deref()
+    }
+
+    pub fn get_org(&self) -> Option<u32> {
+        self.org
+    }
+}
+
+// hexadecimal with a 0x prefix, decimal otherwise
+pub fn parse_number(number: &str) -> Option<u32> {
+    match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => number.parse().ok(),
+    }
 }
 
 #[cfg(test)]
@@ -29,7 +211,7 @@ mod tests {
     fn build_test() {
         let args = vec!["0".to_string()];
         let config = Config::build(&args);
-        assert_eq!(config.unwrap_err(), "Usage: rpx86 [bin]");
+        assert_eq!(config.unwrap_err(), USAGE);
 
         let args = vec!["0".to_string(), "helloworld.bin".to_string()];
         let config = Config::build(&args);
@@ -42,4 +224,95 @@ mod tests {
         let config = Config::build(&args);
         assert_eq!(config.unwrap().get_fp(), "helloworld.bin");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn real_mode_test() {
+        let args = vec!["0".to_string(), "--real".to_string(), "boot.bin".to_string()];
+        let config = Config::build(&args).unwrap();
+        assert!(config.is_real_mode());
+        assert!(!config.is_trace());
+        assert!(!config.is_debug());
+        assert_eq!(config.get_fp(), "boot.bin");
+
+        let args = vec!["0".to_string(), "--gdb".to_string(), "localhost:1234".to_string(), "boot.bin".to_string()];
+        assert_eq!(Config::build(&args).unwrap().get_gdb(), Some("localhost:1234"));
+
+        let args: Vec<String> = ["0", "--com2", "file:com2.log", "boot.bin"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert_eq!(config.get_serial(0), Some("stdio"));
+        assert_eq!(config.get_serial(1), Some("file:com2.log"));
+        assert_eq!(config.get_serial(3), None);
+        assert!(!config.is_serial_wait());
+
+        let args = vec!["0".to_string(), "--unknown".to_string(), "boot.bin".to_string()];
+        assert!(Config::build(&args).is_err());
+    }
+
+    #[test]
+    fn linux_test() {
+        let args: Vec<String> = ["0", "--linux", "a.out", "--real", "file"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert!(config.is_linux());
+        assert!(!config.is_real_mode());
+        assert_eq!(config.get_fp(), "a.out");
+        assert_eq!(config.get_args(), ["--real", "file"]);
+
+        let args: Vec<String> = ["0", "--real", "--linux", "a.out"].iter().map(|arg| arg.to_string()).collect();
+        assert!(Config::build(&args).is_err());
+    }
+
+    #[test]
+    fn dos_test() {
+        let args: Vec<String> = ["0", "--dos", "--dos-root", "/tmp", "A.COM", "/q"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert!(config.is_dos());
+        assert_eq!(config.get_dos_root(), "/tmp");
+        assert_eq!(config.get_args(), ["/q"]);
+
+        let args: Vec<String> = ["0", "--dos-root", "/tmp", "A.COM"].iter().map(|arg| arg.to_string()).collect();
+        assert!(Config::build(&args).is_err());
+    }
+
+    #[test]
+    fn bios_test() {
+        let args: Vec<String> = ["0", "--disk", "floppy.img", "boot.bin"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert!(config.is_bios());
+        assert_eq!(config.get_disk(), Some("floppy.img"));
+
+        let args: Vec<String> = ["0", "--bios", "--dos", "A.COM"].iter().map(|arg| arg.to_string()).collect();
+        assert!(Config::build(&args).is_err());
+
+        let args: Vec<String> = ["0", "--boot", "disk.img"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert!(config.is_boot());
+        assert!(config.is_bios());
+        assert_eq!(config.get_disk(), Some("disk.img"));
+
+        let args: Vec<String> = ["0", "--boot", "--vga-dump", "disk.img"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert!(config.is_vga());
+        assert!(config.is_vga_dump());
+
+        let args: Vec<String> = ["0", "--boot", "--org", "0x0", "disk.img"].iter().map(|arg| arg.to_string()).collect();
+        assert!(Config::build(&args).is_err());
+    }
+
+    #[test]
+    fn disasm_test() {
+        let args: Vec<String> = ["0", "disasm", "boot.bin", "--org", "0x7c00"].iter().map(|arg| arg.to_string()).collect();
+        let config = Config::build(&args).unwrap();
+        assert_eq!(config.get_command(), Command::Disasm);
+        assert_eq!(config.get_org(), Some(0x7c00));
+        assert_eq!(config.get_fp(), "boot.bin");
+
+        let args: Vec<String> = ["0", "disasm", "--trace", "boot.bin"].iter().map(|arg| arg.to_string()).collect();
+        assert!(Config::build(&args).is_err());
+
+        let args: Vec<String> = ["0", "disasm", "--linux", "a.out"].iter().map(|arg| arg.to_string()).collect();
+        assert!(Config::build(&args).is_err());
+
+        let args: Vec<String> = ["0", "--org", "0x7g00", "boot.bin"].iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(Config::build(&args).unwrap_err(), "Invalid address given to --org");
+    }
+}
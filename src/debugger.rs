@@ -0,0 +1,262 @@
+//
+// Debugger
+//
+use std::io::{self, BufRead, Write};
+
+use crate::config::parse_number;
+use crate::disasm;
+use crate::emulator::{Emulator, Step, GPR, GPR8};
+use crate::emulator::segment::SREG;
+use crate::instruction::InstructionVector;
+
+const HELP: &str = "\
+step [n]              execute n instructions (s)
+continue              run until a breakpoint, halt or stop condition (c)
+break <address>       set a breakpoint at a linear address or symbol (b)
+delete <address>      remove a breakpoint (d)
+breakpoints           list breakpoints (bl)
+regs                  show registers and EFLAGS (r)
+dump                  show the whole emulator state
+x <address> [len]     hexdump memory at a linear address
+set <reg> <value>     modify a register, eip or eflags
+write <address> <byte>...
+                      modify memory at a linear address (w)
+disas [eip] [n]       disassemble n instructions, around EIP by default (u)
+quit                  leave the debugger (q)";
+
+const FLAGS: [(u32, &str); 9] = [
+    (0, "CF"), (2, "PF"), (4, "AF"), (6, "ZF"), (7, "SF"), (8, "TF"), (9, "IF"), (10, "DF"), (11, "OF"),
+];
+
+pub struct Debugger<'a> {
+    emu: &'a mut Emulator,
+    instructions: &'a InstructionVector,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(emu: &'a mut Emulator, instructions: &'a InstructionVector) -> Debugger<'a> {
+        Debugger { emu, instructions }
+    }
+
+    // read commands until quit or the end of the input
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        self.show_next(&mut output)?;
+        write!(output, "(rpx86) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "q" | "quit") {
+                break;
+            }
+            if let Err(err) = self.execute(&line, &mut output) {
+                writeln!(output, "{}", err)?;
+            }
+            write!(output, "(rpx86) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    pub fn execute(&mut self, line: &str, output: &mut impl Write) -> Result<(), String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = args.split_first() else {
+            return Ok(());
+        };
+
+        match command {
+            "s" | "step" => {
+                let count = args.first().map_or(Ok(1), |count| parse(count))?;
+                let step = self.emu.run_until(self.instructions, count as u64);
+                self.report(step, output)
+            },
+            "c" | "continue" => {
+                let step = match self.emu.run(self.instructions) {
+                    Ok(step) => step,
+                    Err(err) => Step::Fault(err),
+                };
+                self.report(step, output)
+            },
+            "b" | "break" => {
+                let address = self.address(args.first().ok_or("Usage: break <address>")?)?;
+                self.emu.add_breakpoint(address);
+                writeln!(output, "Breakpoint at {:#x}", address).map_err(io_error)
+            },
+            "d" | "delete" => {
+                let address = self.address(args.first().ok_or("Usage: delete <address>")?)?;
+                if !self.emu.remove_breakpoint(address) {
+                    return Err(format!("No breakpoint at {:#x}", address));
+                }
+                Ok(())
+            },
+            "bl" | "breakpoints" => {
+                for address in self.emu.get_breakpoints() {
+                    writeln!(output, "{:#x}", address).map_err(io_error)?;
+                }
+                Ok(())
+            },
+            "r" | "regs" => self.show_registers(output).map_err(io_error),
+            "dump" => writeln!(output, "{:#x?}", self.emu).map_err(io_error),
+            "x" => {
+                let address = self.address(args.first().ok_or("Usage: x <address> [len]")?)?;
+                let length = args.get(1).map_or(Ok(0x40), |length| parse(length))?;
+                self.hexdump(address, length, output)
+            },
+            "set" => {
+                let (reg, value) = match args {
+                    [reg, value] => (*reg, parse(value)?),
+                    _ => return Err("Usage: set <reg> <value>".to_string()),
+                };
+                self.set_register(reg, value)
+            },
+            "w" | "write" => {
+                let address = parse(args.first().ok_or("Usage: write <address> <byte>...")?)?;
+                for (i, byte) in args[1..].iter().enumerate() {
+                    let value = parse(byte)?;
+                    self.emu.set_memory8(address.wrapping_add(i as u32), value).map_err(|err| err.to_string())?;
+                }
+                Ok(())
+            },
+            "u" | "disas" => {
+                let eip = args.first().map_or(Ok(self.emu.get_eip()), |eip| self.address(eip))?;
+                let count = args.get(1).map_or(Ok(8), |count| parse(count))?;
+                self.disassemble(eip, count, output)
+            },
+            "h" | "help" => writeln!(output, "{}", HELP).map_err(io_error),
+            _ => Err(format!("Unknown command: {} (try help)", command)),
+        }
+    }
+
+    // a symbol name or a number
+    fn address(&self, value: &str) -> Result<u32, String> {
+        self.emu.find_symbol(value).map_or_else(|| parse(value), Ok)
+    }
+
+    fn report(&mut self, step: Step, output: &mut impl Write) -> Result<(), String> {
+        match step {
+            Step::Continue => {},
+            Step::Halt => writeln!(output, "CPU halted").map_err(io_error)?,
+            Step::Exit(status) => writeln!(output, "Program exited with status {}", status).map_err(io_error)?,
+            Step::Breakpoint => writeln!(output, "Breakpoint hit").map_err(io_error)?,
+            Step::Watchpoint(kind, address) => {
+                writeln!(output, "Watchpoint hit: {:?} at {:#x}", kind, address).map_err(io_error)?
+            },
+            Step::Stop => writeln!(output, "End of program").map_err(io_error)?,
+            Step::Fault(err) => writeln!(output, "Emulation stopped: {}", err).map_err(io_error)?,
+        }
+        self.show_next(output).map_err(io_error)
+    }
+
+    fn show_next(&mut self, output: &mut impl Write) -> io::Result<()> {
+        match disasm::disassemble(self.emu, self.emu.get_eip()) {
+            Ok(line) => writeln!(output, "{}", line),
+            Err(err) => writeln!(output, "{:08x}  <{}>", self.emu.get_eip(), err),
+        }
+    }
+
+    fn show_registers(&self, output: &mut impl Write) -> io::Result<()> {
+        let emu = &self.emu;
+        writeln!(output, "eax={:08x} ebx={:08x} ecx={:08x} edx={:08x}",
+            emu.get_gpr_value(&GPR::EAX), emu.get_gpr_value(&GPR::EBX),
+            emu.get_gpr_value(&GPR::ECX), emu.get_gpr_value(&GPR::EDX))?;
+        writeln!(output, "esi={:08x} edi={:08x} ebp={:08x} esp={:08x}",
+            emu.get_gpr_value(&GPR::ESI), emu.get_gpr_value(&GPR::EDI),
+            emu.get_gpr_value(&GPR::EBP), emu.get_gpr_value(&GPR::ESP))?;
+        let flags: Vec<&str> = FLAGS.iter()
+            .filter(|(bit, _)| emu.get_eflags() & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        writeln!(output, "eip={:08x} eflags={:08x} [{}]", emu.get_eip(), emu.get_eflags(), flags.join(" "))?;
+        writeln!(output, "cs={:04x} ds={:04x} es={:04x} ss={:04x} fs={:04x} gs={:04x}",
+            emu.get_sreg_value(&SREG::CS), emu.get_sreg_value(&SREG::DS),
+            emu.get_sreg_value(&SREG::ES), emu.get_sreg_value(&SREG::SS),
+            emu.get_sreg_value(&SREG::FS), emu.get_sreg_value(&SREG::GS))
+    }
+
+    fn set_register(&mut self, reg: &str, value: u32) -> Result<(), String> {
+        let id = |names: [&str; 8]| names.iter().position(|name| *name == reg);
+        if let Some(id) = id(disasm::REG32) {
+            let reg = *self.emu.get_gpr_id(id as u32).ok_or("Invalid register")?;
+            self.emu.set_gpr(&reg, value);
+        } else if let Some(id) = id(disasm::REG16) {
+            let reg = *self.emu.get_gpr_id(id as u32).ok_or("Invalid register")?;
+            self.emu.set_gpr16(&reg, value as u16);
+        } else if let Some(id) = id(disasm::REG8) {
+            let reg: GPR8 = *self.emu.get_gpr8_id(id as u32).ok_or("Invalid register")?.0;
+            self.emu.set_gpr8(&reg, value as u8);
+        } else {
+            match reg {
+                "eip" => self.emu.set_eip(value),
+                "eflags" => self.emu.set_eflags(value),
+                _ => return Err(format!("Unknown register: {}", reg)),
+            }
+        }
+        Ok(())
+    }
+
+    fn hexdump(&self, address: u32, length: u32, output: &mut impl Write) -> Result<(), String> {
+        for line in (0..length).step_by(16) {
+            let start = address.wrapping_add(line);
+            let bytes = (0..(length - line).min(16))
+                .map(|i| self.emu.get_memory8(start.wrapping_add(i)))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|err| err.to_string())?;
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = bytes.iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+                .collect();
+            writeln!(output, "{:08x}  {:<48}{}", start, hex.join(" "), ascii).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    fn disassemble(&mut self, eip: u32, count: u32, output: &mut impl Write) -> Result<(), String> {
+        let mut eip = eip;
+        for _ in 0..count {
+            let line = disasm::disassemble(self.emu, eip).map_err(|err| err.to_string())?;
+            let marker = if eip == self.emu.get_eip() { "=>" } else { "  " };
+            writeln!(output, "{} {}", marker, line).map_err(io_error)?;
+            eip = eip.wrapping_add(line.get_length());
+        }
+        Ok(())
+    }
+}
+
+fn parse(value: &str) -> Result<u32, String> {
+    parse_number(value).ok_or_else(|| format!("Invalid number: {}", value))
+}
+
+fn io_error(err: io::Error) -> String {
+    err.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debugger_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100, 0x0, 0x100);
+        // mov eax, 0x29; inc eax; inc eax; hlt
+        emu.load_bin(vec![0xb8, 0x29, 0x00, 0x00, 0x00, 0x40, 0x40, 0xf4], 0x0).unwrap();
+
+        let input = "step\nbreak 0x6\nc\nr\nset ebx 0x1234\nw 0x80 0x41 0x42\nx 0x80 4\nu 0x5 2\nfoo\nc\nq\nstep\n";
+        let mut output = Vec::new();
+        Debugger::new(&mut emu, &instructions).repl(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("00000005  40                      inc eax"));
+        assert!(output.contains("Breakpoint at 0x6\n(rpx86) Breakpoint hit\n00000006"));
+        assert!(output.contains("eax=0000002a ebx=00000000"));
+        assert!(output.contains("00000080  41 42 00 00"));
+        assert!(output.contains("=> 00000006  40"));
+        assert!(output.contains("Unknown command: foo"));
+        assert!(output.contains("CPU halted"));
+
+        // nothing after quit is executed
+        assert_eq!(emu.get_eip(), 0x8);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x2b);
+        assert_eq!(emu.get_gpr_value(&GPR::EBX), 0x1234);
+        assert_eq!(emu.get_memory8(0x81), Ok(0x42));
+    }
+}
@@ -0,0 +1,166 @@
+//
+// Devices
+//
+use std::cell::Cell;
+use std::io::{self, stdin, Read};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::emulator::error::EmuError;
+
+pub mod pic;
+pub mod pit;
+pub mod uart;
+pub mod vga;
+
+// a device decoding a range of I/O ports. size is the access width in bytes (1, 2 or 4),
+// values are zero-extended to 32 bits. a host-side failure stops the emulation.
+pub trait PortDevice {
+    fn read(&mut self, port: u16, size: u8) -> io::Result<u32>;
+    fn write(&mut self, port: u16, size: u8, value: u32) -> io::Result<()>;
+}
+
+// a device backing a range of physical memory. offsets are relative to the start of the region.
+// reads go through a shared reference so that memory reads stay side-effect free for the CPU.
+pub trait MmioDevice {
+    fn read8(&self, offset: u32) -> u8;
+    fn write8(&mut self, offset: u32, value: u8);
+}
+
+// an interrupt request output of a device, sampled by whatever it is wired to
+pub trait IrqSource {
+    fn is_asserted(&mut self) -> bool;
+}
+
+// the INTR side of an interrupt controller. the CPU checks for a request between instructions
+// and takes its vector with an acknowledge cycle.
+pub trait InterruptController {
+    fn is_pending(&mut self) -> bool;
+    fn acknowledge(&mut self) -> Option<u8>;
+    // whether waiting in HLT can end with an interrupt
+    fn is_armed(&self) -> bool;
+}
+
+// virtual time counted in retired instructions, so that timers behave the same on every run.
+// clones share the count.
+#[derive(Clone, Default)]
+pub struct Clock(Rc<Cell<u64>>);
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock(Rc::new(Cell::new(0)))
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.get()
+    }
+
+    pub fn advance(&self, instructions: u64) {
+        self.0.set(self.0.get() + instructions);
+    }
+}
+
+// maps port ranges to the devices attached to them
+#[derive(Default)]
+pub struct IoBus {
+    devices: Vec<(RangeInclusive<u16>, Box<dyn PortDevice>)>,
+}
+
+impl IoBus {
+    pub fn new() -> IoBus {
+        IoBus { devices: Vec::new() }
+    }
+
+    // a device attached later shadows the overlapping part of an earlier one
+    pub fn attach(&mut self, ports: RangeInclusive<u16>, device: Box<dyn PortDevice>) {
+        self.devices.insert(0, (ports, device));
+    }
+
+    fn find(&mut self, port: u16) -> Option<&mut Box<dyn PortDevice>> {
+        self.devices.iter_mut()
+            .find(|(ports, _)| ports.contains(&port))
+            .map(|(_, device)| device)
+    }
+
+    // nothing drives the data lines of an unclaimed port, so it reads as all ones
+    pub fn read(&mut self, port: u16, size: u8) -> Result<u32, EmuError> {
+        match self.find(port) {
+            Some(device) => device.read(port, size)
+                .map(|value| value & size_mask(size))
+                .map_err(|err| EmuError::Io { port, kind: err.kind() }),
+            None => Ok(size_mask(size)),
+        }
+    }
+
+    pub fn write(&mut self, port: u16, size: u8, value: u32) -> Result<(), EmuError> {
+        match self.find(port) {
+            Some(device) => device.write(port, size, value & size_mask(size))
+                .map_err(|err| EmuError::Io { port, kind: err.kind() }),
+            None => Ok(()),
+        }
+    }
+}
+
+// host stdin read on a thread of its own, so that devices can check for input without blocking
+pub fn stdin_channel() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in stdin().lock().bytes().map_while(Result::ok) {
+            if sender.send(byte).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn size_mask(size: u8) -> u32 {
+    match size {
+        1 => 0xff,
+        2 => 0xffff,
+        _ => 0xffffffff,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Latch(Rc<RefCell<Vec<(u16, u8, u32)>>>);
+
+    impl PortDevice for Latch {
+        fn read(&mut self, port: u16, _size: u8) -> io::Result<u32> {
+            match port {
+                0x63 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                _ => Ok(0x12345600 | port as u32),
+            }
+        }
+
+        fn write(&mut self, port: u16, size: u8, value: u32) -> io::Result<()> {
+            self.0.borrow_mut().push((port, size, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn io_bus_test() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut bus = IoBus::new();
+        bus.attach(0x60..=0x64, Box::new(Latch(log.clone())));
+
+        assert_eq!(bus.read(0x60, 1), Ok(0x60));
+        assert_eq!(bus.read(0x64, 2), Ok(0x5664));
+        assert_eq!(bus.read(0x64, 4), Ok(0x12345664));
+        assert_eq!(bus.read(0x65, 1), Ok(0xff));
+        assert_eq!(bus.read(0x65, 4), Ok(0xffffffff));
+        assert_eq!(bus.read(0x63, 1), Err(EmuError::Io { port: 0x63, kind: io::ErrorKind::UnexpectedEof }));
+
+        bus.write(0x61, 1, 0xabcd).unwrap();
+        bus.write(0x70, 1, 0x1).unwrap();
+        assert_eq!(*log.borrow(), vec![(0x61, 1, 0xcd)]);
+    }
+}
@@ -0,0 +1,404 @@
+//
+// 8259A programmable interrupt controllers
+//
+use std::cell::RefCell;
+use std::io;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use super::{InterruptController, IrqSource, PortDevice};
+
+pub const MASTER_PORTS: RangeInclusive<u16> = 0x20..=0x21;
+pub const SLAVE_PORTS: RangeInclusive<u16> = 0xa0..=0xa1;
+
+// the slave is wired to IR2 of the master
+const CASCADE_LINE: u8 = 2;
+
+const ICW1: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_SINGLE: u8 = 0x02;
+const ICW1_LEVEL: u8 = 0x08;
+const ICW4_AUTO_EOI: u8 = 0x02;
+
+const OCW3: u8 = 0x08;
+const OCW3_READ: u8 = 0x02;
+const OCW3_READ_ISR: u8 = 0x01;
+const OCW3_POLL: u8 = 0x04;
+
+// what the next write to the odd port is
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+enum Expect {
+    Icw2,
+    Icw3,
+    Icw4,
+    Ocw1,
+}
+
+struct Controller {
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    // the request lines as last sampled, for the edge detection
+    lines: u8,
+    vector_base: u8,
+    lowest_priority: u8,
+    expect: Expect,
+    icw4: bool,
+    single: bool,
+    level: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    read_isr: bool,
+    poll: bool,
+}
+
+impl Controller {
+    // as left by a BIOS: the master on vectors 0x08-0x0f and the slave on 0x70-0x77, all lines masked
+    fn new(vector_base: u8) -> Controller {
+        Controller {
+            irr: 0,
+            isr: 0,
+            imr: 0xff,
+            lines: 0,
+            vector_base,
+            lowest_priority: 7,
+            expect: Expect::Ocw1,
+            icw4: true,
+            single: false,
+            level: false,
+            auto_eoi: false,
+            rotate_on_auto_eoi: false,
+            read_isr: false,
+            poll: false,
+        }
+    }
+
+    // the levels in priority order, highest first
+    fn priorities(&self) -> impl Iterator<Item = u8> {
+        let first = (self.lowest_priority + 1) % 8;
+        (0..8).map(move |i| (first + i) % 8)
+    }
+
+    // the unmasked request that beats everything in service
+    fn get_request(&self) -> Option<u8> {
+        let pending = self.irr & !self.imr;
+        self.priorities()
+            .take_while(|level| self.isr & 1 << level == 0)
+            .find(|level| pending & 1 << level != 0)
+    }
+
+    fn get_in_service(&self) -> Option<u8> {
+        self.priorities().find(|level| self.isr & 1 << level != 0)
+    }
+
+    // edge-triggered inputs latch a request on the rising edge, level-triggered ones follow the line
+    fn set_lines(&mut self, lines: u8) {
+        if self.level {
+            self.irr = lines;
+        } else {
+            self.irr |= lines & !self.lines;
+        }
+        self.lines = lines;
+    }
+
+    // INTA: the request moves from IRR to ISR
+    fn acknowledge(&mut self, level: u8) {
+        self.irr &= !(1 << level);
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.lowest_priority = level;
+            }
+        } else {
+            self.isr |= 1 << level;
+        }
+    }
+
+    fn end_of_interrupt(&mut self, level: Option<u8>, rotate: bool) {
+        if let Some(level) = level {
+            self.isr &= !(1 << level);
+            if rotate {
+                self.lowest_priority = level;
+            }
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & ICW1 != 0 {
+            // initialization clears the mask and the edge detection, then waits for ICW2-ICW4
+            self.irr = 0;
+            self.isr = 0;
+            self.imr = 0;
+            self.lines = 0xff;
+            self.lowest_priority = 7;
+            self.icw4 = value & ICW1_ICW4 != 0;
+            self.single = value & ICW1_SINGLE != 0;
+            self.level = value & ICW1_LEVEL != 0;
+            self.auto_eoi = false;
+            self.read_isr = false;
+            self.expect = Expect::Icw2;
+        } else if value & OCW3 != 0 {
+            if value & OCW3_READ != 0 {
+                self.read_isr = value & OCW3_READ_ISR != 0;
+            }
+            self.poll = value & OCW3_POLL != 0;
+        } else {
+            // OCW2: R, SL and EOI, with the level in the low bits
+            let level = value & 0x7;
+            match value >> 5 {
+                0b001 => self.end_of_interrupt(self.get_in_service(), false),
+                0b011 => self.end_of_interrupt(Some(level), false),
+                0b101 => self.end_of_interrupt(self.get_in_service(), true),
+                0b111 => self.end_of_interrupt(Some(level), true),
+                0b100 => self.rotate_on_auto_eoi = true,
+                0b000 => self.rotate_on_auto_eoi = false,
+                0b110 => self.lowest_priority = level,
+                _ => {},
+            }
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.expect = match self.expect {
+            Expect::Icw2 => {
+                self.vector_base = value & 0xf8;
+                match (self.single, self.icw4) {
+                    (false, _) => Expect::Icw3,
+                    (true, true) => Expect::Icw4,
+                    (true, false) => Expect::Ocw1,
+                }
+            },
+            // the cascade wiring is fixed, ICW3 is taken as given
+            Expect::Icw3 if self.icw4 => Expect::Icw4,
+            Expect::Icw3 => Expect::Ocw1,
+            Expect::Icw4 => {
+                self.auto_eoi = value & ICW4_AUTO_EOI != 0;
+                Expect::Ocw1
+            },
+            Expect::Ocw1 => {
+                self.imr = value;
+                Expect::Ocw1
+            },
+        };
+    }
+}
+
+struct Pair {
+    controllers: [Controller; 2],
+    sources: Vec<(u8, Box<dyn IrqSource>)>,
+}
+
+impl Pair {
+    // sample the devices. lines shared by several devices are asserted by any of them.
+    fn sample(&mut self) {
+        let mut lines = 0u16;
+        for (irq, source) in self.sources.iter_mut() {
+            if source.is_asserted() {
+                lines |= 1 << *irq;
+            }
+        }
+        self.controllers[1].set_lines((lines >> 8) as u8);
+        let mut master = lines as u8;
+        if self.cascade() {
+            // IR2 of the master follows the INT output of the slave
+            let request = self.controllers[1].get_request().is_some();
+            master = master & !(1 << CASCADE_LINE) | (request as u8) << CASCADE_LINE;
+        }
+        self.controllers[0].set_lines(master);
+    }
+
+    fn cascade(&self) -> bool {
+        !self.controllers[0].single
+    }
+
+    // the vector of the highest priority request, taken out of IRR
+    fn acknowledge(&mut self) -> Option<u8> {
+        let level = self.controllers[0].get_request()?;
+        self.controllers[0].acknowledge(level);
+        if level == CASCADE_LINE && self.cascade() {
+            let slave = &mut self.controllers[1];
+            // a request that went away before INTA shows up as IR7
+            let level = match slave.get_request() {
+                Some(level) => {
+                    slave.acknowledge(level);
+                    level
+                },
+                None => 7,
+            };
+            return Some(slave.vector_base | level);
+        }
+        Some(self.controllers[0].vector_base | level)
+    }
+
+    // a read after the poll command acknowledges the request it reports
+    fn poll(&mut self, index: usize) -> u8 {
+        self.controllers[index].poll = false;
+        self.sample();
+        match self.controllers[index].get_request() {
+            Some(level) => {
+                self.controllers[index].acknowledge(level);
+                0x80 | level
+            },
+            None => 0,
+        }
+    }
+}
+
+// the master and slave PICs of a PC. devices are wired to IRQ lines 0-15, and the CPU takes the
+// interrupts through the InterruptController side. clones share the chips.
+#[derive(Clone)]
+pub struct Pic(Rc<RefCell<Pair>>);
+
+impl Pic {
+    pub fn new() -> Pic {
+        Pic(Rc::new(RefCell::new(Pair {
+            controllers: [Controller::new(0x08), Controller::new(0x70)],
+            sources: Vec::new(),
+        })))
+    }
+
+    pub fn attach_irq(&self, irq: u8, source: Box<dyn IrqSource>) {
+        assert!(irq < 16);
+        self.0.borrow_mut().sources.push((irq, source));
+    }
+
+    pub fn get_irr(&self) -> u16 {
+        let pair = self.0.borrow();
+        pair.controllers[0].irr as u16 | (pair.controllers[1].irr as u16) << 8
+    }
+
+    pub fn get_isr(&self) -> u16 {
+        let pair = self.0.borrow();
+        pair.controllers[0].isr as u16 | (pair.controllers[1].isr as u16) << 8
+    }
+
+    pub fn get_imr(&self) -> u16 {
+        let pair = self.0.borrow();
+        pair.controllers[0].imr as u16 | (pair.controllers[1].imr as u16) << 8
+    }
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Pic::new()
+    }
+}
+
+impl PortDevice for Pic {
+    fn read(&mut self, port: u16, _size: u8) -> io::Result<u32> {
+        let mut pair = self.0.borrow_mut();
+        let index = (port >> 7 & 0x1) as usize;
+        if pair.controllers[index].poll {
+            return Ok(pair.poll(index) as u32);
+        }
+        let controller = &pair.controllers[index];
+        let value = match port & 0x1 {
+            0 if controller.read_isr => controller.isr,
+            0 => controller.irr,
+            _ => controller.imr,
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, port: u16, _size: u8, value: u32) -> io::Result<()> {
+        let mut pair = self.0.borrow_mut();
+        let controller = &mut pair.controllers[(port >> 7 & 0x1) as usize];
+        match port & 0x1 {
+            0 => controller.write_command(value as u8),
+            _ => controller.write_data(value as u8),
+        }
+        Ok(())
+    }
+}
+
+impl InterruptController for Pic {
+    fn is_pending(&mut self) -> bool {
+        let mut pair = self.0.borrow_mut();
+        pair.sample();
+        pair.controllers[0].get_request().is_some()
+    }
+
+    fn acknowledge(&mut self) -> Option<u8> {
+        let mut pair = self.0.borrow_mut();
+        pair.sample();
+        pair.acknowledge()
+    }
+
+    // something unmasked is wired up and may still raise an interrupt
+    fn is_armed(&self) -> bool {
+        let pair = self.0.borrow();
+        pair.sources.iter().any(|(irq, _)| {
+            let master = &pair.controllers[0];
+            if *irq < 8 {
+                return master.imr & 1 << irq == 0;
+            }
+            pair.cascade() && master.imr & 1 << CASCADE_LINE == 0 && pair.controllers[1].imr & 1 << (irq - 8) == 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct Line(Rc<Cell<bool>>);
+
+    impl IrqSource for Line {
+        fn is_asserted(&mut self) -> bool {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn pic_test() {
+        let mut pic = Pic::new();
+        let (timer, keyboard, disk) = (Rc::new(Cell::new(false)), Rc::new(Cell::new(false)), Rc::new(Cell::new(false)));
+        pic.attach_irq(0, Box::new(Line(timer.clone())));
+        pic.attach_irq(1, Box::new(Line(keyboard.clone())));
+        pic.attach_irq(14, Box::new(Line(disk.clone())));
+        assert!(!pic.is_armed());
+
+        // remap to 0x20-0x2f, mask the keyboard
+        for (port, value) in [(0x20, 0x11), (0x21, 0x20), (0x21, 0x04), (0x21, 0x01), (0xa0, 0x11), (0xa1, 0x28), (0xa1, 0x02), (0xa1, 0x01)] {
+            pic.write(port, 1, value).unwrap();
+        }
+        pic.write(0x21, 1, 0x02).unwrap();
+        assert_eq!(pic.get_imr(), 0x0002);
+        assert!(pic.is_armed());
+        assert!(!pic.is_pending());
+
+        // a masked line is still latched in IRR
+        timer.set(true);
+        keyboard.set(true);
+        assert!(pic.is_pending());
+        assert_eq!(pic.read(0x20, 1).unwrap(), 0x03);
+        assert_eq!(pic.acknowledge(), Some(0x20));
+        assert_eq!(pic.get_isr(), 0x0001);
+        // no new edge while the line stays up
+        assert!(!pic.is_pending());
+
+        // the slave requests through IR2, which beats nothing in service on IR0
+        disk.set(true);
+        assert!(!pic.is_pending());
+        pic.write(0x20, 1, 0x20).unwrap();
+        assert!(pic.is_pending());
+        assert_eq!(pic.acknowledge(), Some(0x2e));
+        assert_eq!(pic.get_isr(), 0x4004);
+        pic.write(0xa0, 1, 0x0b).unwrap();
+        assert_eq!(pic.read(0xa0, 1).unwrap(), 0x40);
+
+        // a higher priority request nests, then specific EOIs
+        timer.set(false);
+        assert!(!pic.is_pending());
+        timer.set(true);
+        assert_eq!(pic.acknowledge(), Some(0x20));
+        pic.write(0x20, 1, 0x60).unwrap();
+        pic.write(0xa0, 1, 0x66).unwrap();
+        pic.write(0x20, 1, 0x62).unwrap();
+        assert_eq!(pic.get_isr(), 0x0000);
+
+        // unmasking the keyboard lets the latched request through
+        pic.write(0x21, 1, 0x00).unwrap();
+        assert_eq!(pic.acknowledge(), Some(0x21));
+    }
+}
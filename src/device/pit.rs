@@ -0,0 +1,430 @@
+//
+// 8254 programmable interval timer
+//
+use std::cell::RefCell;
+use std::io;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use super::{Clock, IrqSource, PortDevice};
+
+pub const PIT_PORTS: RangeInclusive<u16> = 0x40..=0x43;
+pub const SPEAKER_PORT: u16 = 0x61;
+pub const PIT_IRQ: u8 = 0;
+// the input clock of the counters in Hz
+pub const FREQUENCY: u32 = 1193182;
+
+// the virtual CPU retires this many instructions per input clock tick, which makes it a 4.8 MIPS machine
+const INSTRUCTIONS_PER_TICK: u64 = 4;
+// DRAM refresh requests toggle a bit of port 0x61 about every 15us
+const REFRESH_TICKS: u64 = 18;
+
+const CONTROL_PORT: u16 = 0x43;
+const READ_BACK: u8 = 0x3;
+const READ_BACK_COUNT: u8 = 0x20;
+const READ_BACK_STATUS: u8 = 0x10;
+
+const ACCESS_LATCH: u8 = 0;
+const ACCESS_LOW: u8 = 1;
+const ACCESS_HIGH: u8 = 2;
+
+const SPEAKER_GATE: u8 = 0x01;
+const SPEAKER_DATA: u8 = 0x02;
+const REFRESH_TOGGLE: u8 = 0x10;
+const TIMER2_OUTPUT: u8 = 0x20;
+
+struct Channel {
+    mode: u8,
+    access: u8,
+    bcd: bool,
+    reload: u16,
+    // a count has been written since the control word
+    armed: bool,
+    null_count: bool,
+    gate: bool,
+    // the tick counting started at. modes 1 and 5 wait for the gate, modes 2 and 3 stop while it is low.
+    start: Option<u64>,
+    // ticks counted when a low gate paused modes 0 and 4
+    held: Option<u64>,
+    // the low byte of a count being written, and which byte of the count a read gets next
+    write_low: Option<u8>,
+    read_high: bool,
+    latch: Option<u16>,
+    status: Option<u8>,
+    // output edges already passed on as interrupt requests
+    reported: u64,
+    irq_line: bool,
+}
+
+impl Channel {
+    // as left by a BIOS: a square wave of the longest period, running from the start
+    fn new(gate: bool) -> Channel {
+        Channel {
+            mode: 3,
+            access: 3,
+            bcd: false,
+            reload: 0,
+            armed: true,
+            null_count: false,
+            gate,
+            start: gate.then_some(0),
+            held: None,
+            write_low: None,
+            read_high: false,
+            latch: None,
+            status: None,
+            reported: 0,
+            irq_line: false,
+        }
+    }
+
+    // a count of 0 stands for the largest one
+    fn period(&self) -> u64 {
+        match (self.reload, self.bcd) {
+            (0, false) => 0x10000,
+            (0, true) => 10000,
+            (reload, false) => reload as u64,
+            (reload, true) => from_bcd(reload),
+        }
+    }
+
+    fn elapsed(&self, now: u64) -> Option<u64> {
+        self.held.or_else(|| self.start.map(|start| now - start))
+    }
+
+    fn counter(&self, now: u64) -> u16 {
+        let period = self.period();
+        let value = match (self.mode, self.elapsed(now)) {
+            (_, None) => period,
+            (2, Some(elapsed)) => period - elapsed % period,
+            // mode 3 counts down by two, through both halves of the period
+            (3, Some(elapsed)) => {
+                let (phase, high) = (elapsed % period, period.div_ceil(2));
+                if phase < high { period - phase * 2 } else { period - (phase - high) * 2 }
+            },
+            // the one-shot modes keep counting past zero
+            (_, Some(elapsed)) => {
+                let modulus = if self.bcd { 10000 } else { 0x10000 };
+                (period + modulus - elapsed % modulus) % modulus
+            },
+        };
+        if self.bcd { to_bcd(value % 10000) } else { value as u16 }
+    }
+
+    fn output(&self, now: u64) -> bool {
+        let period = self.period();
+        match (self.mode, self.elapsed(now)) {
+            (0, None) => false,
+            (_, None) => true,
+            (0 | 1, Some(elapsed)) => elapsed >= period,
+            (2, Some(elapsed)) => elapsed % period != period - 1,
+            (3, Some(elapsed)) => elapsed % period < period.div_ceil(2),
+            (_, Some(elapsed)) => elapsed != period,
+        }
+    }
+
+    // rising edges of the output since counting started
+    fn edges(&self, now: u64) -> u64 {
+        let period = self.period();
+        match (self.mode, self.elapsed(now)) {
+            (_, None) => 0,
+            (0 | 1, Some(elapsed)) => (elapsed >= period) as u64,
+            (2 | 3, Some(elapsed)) => elapsed / period,
+            (_, Some(elapsed)) => (elapsed > period) as u64,
+        }
+    }
+
+    // counting starts over with a new count, or waits for a trigger of the gate
+    fn restart(&mut self, now: u64) {
+        self.reported = 0;
+        (self.start, self.held) = match self.mode {
+            1 | 5 => (None, None),
+            2 | 3 if !self.gate => (None, None),
+            0 | 4 if !self.gate => (Some(now), Some(0)),
+            _ => (Some(now), None),
+        };
+    }
+
+    fn set_gate(&mut self, gate: bool, now: u64) {
+        if gate == self.gate {
+            return;
+        }
+        self.gate = gate;
+        match self.mode {
+            0 | 4 if gate => {
+                if let Some(held) = self.held.take() {
+                    self.start = self.start.map(|_| now - held);
+                }
+            },
+            0 | 4 => self.held = self.elapsed(now),
+            // a rising gate triggers modes 1 and 5 and reloads modes 2 and 3
+            _ if gate && self.armed => {
+                self.start = Some(now);
+                self.reported = 0;
+            },
+            2 | 3 => self.start = None,
+            _ => {},
+        }
+    }
+
+    fn write_control(&mut self, value: u8, now: u64) {
+        let access = value >> 4 & 0x3;
+        if access == ACCESS_LATCH {
+            self.latch.get_or_insert(self.counter(now));
+            return;
+        }
+        self.mode = match value >> 1 & 0x7 {
+            mode @ 6..=7 => mode - 4,
+            mode => mode,
+        };
+        self.access = access;
+        self.bcd = value & 0x1 != 0;
+        self.armed = false;
+        self.null_count = true;
+        self.start = None;
+        self.held = None;
+        self.write_low = None;
+        self.read_high = false;
+        self.latch = None;
+        self.status = None;
+        self.reported = 0;
+    }
+
+    fn write_count(&mut self, value: u8, now: u64) {
+        let count = match self.access {
+            ACCESS_LOW => value as u16,
+            ACCESS_HIGH => (value as u16) << 8,
+            _ => match self.write_low.take() {
+                Some(low) => low as u16 | (value as u16) << 8,
+                None => {
+                    // the first byte stops a one-shot count until the second one arrives
+                    self.write_low = Some(value);
+                    if self.mode == 0 {
+                        (self.start, self.held) = (None, None);
+                    }
+                    return;
+                },
+            },
+        };
+        self.reload = count;
+        self.armed = true;
+        self.null_count = false;
+        self.restart(now);
+    }
+
+    fn read_count(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.status.take() {
+            return status;
+        }
+        let value = self.latch.unwrap_or_else(|| self.counter(now));
+        let high = match self.access {
+            ACCESS_LOW => false,
+            ACCESS_HIGH => true,
+            _ => {
+                self.read_high = !self.read_high;
+                !self.read_high
+            },
+        };
+        if high || self.access == ACCESS_LOW {
+            self.latch = None;
+        }
+        if high { (value >> 8) as u8 } else { value as u8 }
+    }
+
+    fn get_status(&self, now: u64) -> u8 {
+        (self.output(now) as u8) << 7 | (self.null_count as u8) << 6 | self.access << 4 | self.mode << 1 | self.bcd as u8
+    }
+}
+
+struct Timers {
+    clock: Clock,
+    channels: [Channel; 3],
+    port_b: u8,
+}
+
+impl Timers {
+    fn now(&self) -> u64 {
+        self.clock.now() / INSTRUCTIONS_PER_TICK
+    }
+}
+
+// the three counters on ports 0x40-0x43 and the speaker control on port 0x61. channel 0 drives IRQ0,
+// channel 1 is the DRAM refresh timer and channel 2 the speaker. clones share the chip.
+#[derive(Clone)]
+pub struct Pit(Rc<RefCell<Timers>>);
+
+impl Pit {
+    pub fn new(clock: Clock) -> Pit {
+        Pit(Rc::new(RefCell::new(Timers {
+            clock,
+            channels: [Channel::new(true), Channel::new(true), Channel::new(false)],
+            port_b: 0,
+        })))
+    }
+
+    pub fn get_output(&self, channel: usize) -> bool {
+        let timers = self.0.borrow();
+        timers.channels[channel].output(timers.now())
+    }
+
+    // the tone of the speaker while channel 2 is gated on and connected to it
+    pub fn get_speaker_frequency(&self) -> Option<u32> {
+        let timers = self.0.borrow();
+        let channel = &timers.channels[2];
+        if timers.port_b & (SPEAKER_GATE | SPEAKER_DATA) != SPEAKER_GATE | SPEAKER_DATA || channel.mode != 3 || !channel.armed {
+            return None;
+        }
+        Some(FREQUENCY / channel.period() as u32)
+    }
+}
+
+impl PortDevice for Pit {
+    fn read(&mut self, port: u16, _size: u8) -> io::Result<u32> {
+        let mut timers = self.0.borrow_mut();
+        let now = timers.now();
+        let value = match port {
+            SPEAKER_PORT => {
+                let refresh = if !(now / REFRESH_TICKS).is_multiple_of(2) { REFRESH_TOGGLE } else { 0 };
+                let output = if timers.channels[2].output(now) { TIMER2_OUTPUT } else { 0 };
+                timers.port_b & 0x0f | refresh | output
+            },
+            // the control word is write-only
+            CONTROL_PORT => 0xff,
+            _ => timers.channels[(port & 0x3) as usize].read_count(now),
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, port: u16, _size: u8, value: u32) -> io::Result<()> {
+        let mut timers = self.0.borrow_mut();
+        let now = timers.now();
+        let value = value as u8;
+        match port {
+            SPEAKER_PORT => {
+                timers.port_b = value & 0x0f;
+                timers.channels[2].set_gate(value & SPEAKER_GATE != 0, now);
+            },
+            CONTROL_PORT if value >> 6 == READ_BACK => {
+                // the read-back command latches the count and the status of several channels at once
+                for (index, channel) in timers.channels.iter_mut().enumerate() {
+                    if value & 2 << index == 0 {
+                        continue;
+                    }
+                    if value & READ_BACK_COUNT == 0 {
+                        channel.latch.get_or_insert(channel.counter(now));
+                    }
+                    if value & READ_BACK_STATUS == 0 {
+                        channel.status.get_or_insert(channel.get_status(now));
+                    }
+                }
+            },
+            CONTROL_PORT => timers.channels[(value >> 6) as usize].write_control(value, now),
+            _ => timers.channels[(port & 0x3) as usize].write_count(value, now),
+        }
+        Ok(())
+    }
+}
+
+// the output of channel 0 as IRQ0. every rising edge since the last sample shows as a separate pulse,
+// so that short pulses between two samples are not lost.
+impl IrqSource for Pit {
+    fn is_asserted(&mut self) -> bool {
+        let mut timers = self.0.borrow_mut();
+        let now = timers.now();
+        let channel = &mut timers.channels[0];
+        if channel.irq_line {
+            channel.irq_line = false;
+            return false;
+        }
+        let edges = channel.edges(now);
+        if edges > channel.reported {
+            channel.reported = edges;
+            channel.irq_line = true;
+        }
+        channel.irq_line
+    }
+}
+
+fn from_bcd(value: u16) -> u64 {
+    (0..4).rev().fold(0, |acc, digit| acc * 10 + (value >> (digit * 4) & 0xf) as u64)
+}
+
+fn to_bcd(value: u64) -> u16 {
+    (0..4).fold(0, |acc, digit| acc | ((value / 10u64.pow(digit) % 10) as u16) << (digit * 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pit_test() {
+        let clock = Clock::new();
+        let mut pit = Pit::new(clock.clone());
+
+        // channel 0 as a rate generator of 100 ticks
+        pit.write(0x43, 1, 0x34).unwrap();
+        pit.write(0x40, 1, 100).unwrap();
+        pit.write(0x40, 1, 0).unwrap();
+        assert!(!pit.is_asserted());
+        clock.advance(10 * INSTRUCTIONS_PER_TICK);
+        pit.write(0x43, 1, 0x00).unwrap();
+        clock.advance(5 * INSTRUCTIONS_PER_TICK);
+        assert_eq!(pit.read(0x40, 1).unwrap(), 90);
+        assert_eq!(pit.read(0x40, 1).unwrap(), 0);
+        assert_eq!(pit.read(0x40, 1).unwrap(), 85);
+        pit.read(0x40, 1).unwrap();
+
+        // one pulse per period, with a low sample in between to make an edge
+        clock.advance(84 * INSTRUCTIONS_PER_TICK);
+        assert!(!pit.get_output(0));
+        assert!(!pit.is_asserted());
+        clock.advance(INSTRUCTIONS_PER_TICK);
+        assert!(pit.is_asserted());
+        assert!(!pit.is_asserted());
+        clock.advance(250 * INSTRUCTIONS_PER_TICK);
+        assert!(pit.is_asserted());
+        assert!(!pit.is_asserted());
+        assert!(!pit.is_asserted());
+
+        // a one-shot in mode 0 on channel 1, read back with its status
+        pit.write(0x43, 1, 0x70).unwrap();
+        pit.write(0x41, 1, 0x10).unwrap();
+        pit.write(0x43, 1, 0xc4).unwrap();
+        assert_eq!(pit.read(0x41, 1).unwrap(), 0x70);
+        pit.write(0x41, 1, 0x00).unwrap();
+        clock.advance(0x10 * INSTRUCTIONS_PER_TICK);
+        pit.write(0x43, 1, 0xc4).unwrap();
+        assert_eq!(pit.read(0x41, 1).unwrap(), 0xb0);
+        assert_eq!(pit.read(0x41, 1).unwrap(), 0x00);
+        assert_eq!(pit.read(0x41, 1).unwrap(), 0x00);
+
+        // channel 2 as a BCD square wave behind the speaker gate
+        pit.write(0x43, 1, 0xb7).unwrap();
+        pit.write(0x42, 1, 0x00).unwrap();
+        pit.write(0x42, 1, 0x10).unwrap();
+        assert_eq!(pit.get_speaker_frequency(), None);
+        assert_eq!(pit.read(0x61, 1).unwrap() & 0x20, 0x20);
+        pit.write(0x61, 1, 0x03).unwrap();
+        assert_eq!(pit.get_speaker_frequency(), Some(FREQUENCY / 1000));
+        clock.advance(500 * INSTRUCTIONS_PER_TICK);
+        assert_eq!(pit.read(0x61, 1).unwrap() & 0x23, 0x03);
+        clock.advance(500 * INSTRUCTIONS_PER_TICK);
+        assert_eq!(pit.read(0x61, 1).unwrap() & 0x23, 0x23);
+        pit.write(0x43, 1, 0x80).unwrap();
+        assert_eq!(pit.read(0x42, 1).unwrap(), 0x00);
+        assert_eq!(pit.read(0x42, 1).unwrap(), 0x10);
+
+        // mode 5 waits for the gate to rise
+        pit.write(0x43, 1, 0x9a).unwrap();
+        pit.write(0x42, 1, 0x02).unwrap();
+        pit.write(0x61, 1, 0x00).unwrap();
+        clock.advance(10 * INSTRUCTIONS_PER_TICK);
+        assert!(pit.get_output(2));
+        pit.write(0x61, 1, 0x01).unwrap();
+        clock.advance(2 * INSTRUCTIONS_PER_TICK);
+        assert!(!pit.get_output(2));
+        clock.advance(INSTRUCTIONS_PER_TICK);
+        assert!(pit.get_output(2));
+    }
+}
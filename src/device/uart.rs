@@ -0,0 +1,458 @@
+//
+// 16550A UART
+//
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, stdout, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+use super::{stdin_channel, IrqSource, PortDevice};
+
+// base ports and IRQ lines of COM1-COM4
+pub const COM_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+pub const COM_IRQS: [u8; 4] = [4, 3, 4, 3];
+
+const FIFO_SIZE: usize = 16;
+
+const IER_RECEIVED: u8 = 0x01;
+const IER_TRANSMITTER_EMPTY: u8 = 0x02;
+const IER_LINE_STATUS: u8 = 0x04;
+const IER_MODEM_STATUS: u8 = 0x08;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TRANSMITTER_EMPTY: u8 = 0x02;
+const IIR_RECEIVED: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RECEIVER: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOPBACK: u8 = 0x10;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_OVERRUN: u8 = 0x02;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+
+// what the other end of a port is. poll never waits, receive does and returns None at the end of the input.
+pub trait SerialBackend {
+    fn transmit(&mut self, byte: u8) -> io::Result<()>;
+    fn poll(&mut self) -> io::Result<Option<u8>>;
+    fn receive(&mut self) -> io::Result<Option<u8>>;
+}
+
+// the host terminal. stdin is only read once the guest asks for input.
+#[derive(Default)]
+pub struct Stdio {
+    input: Option<Receiver<u8>>,
+}
+
+impl Stdio {
+    fn input(&mut self) -> &Receiver<u8> {
+        self.input.get_or_insert_with(stdin_channel)
+    }
+}
+
+impl SerialBackend for Stdio {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        let mut stdout = stdout();
+        stdout.write_all(&[byte])?;
+        stdout.flush()
+    }
+
+    fn poll(&mut self) -> io::Result<Option<u8>> {
+        match self.input().try_recv() {
+            Ok(byte) => Ok(Some(byte)),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input().recv().ok())
+    }
+}
+
+// output logged to a file, nothing ever comes in
+impl SerialBackend for File {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        self.write_all(&[byte])
+    }
+
+    fn poll(&mut self) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+}
+
+impl SerialBackend for UnixStream {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        self.write_all(&[byte])
+    }
+
+    fn poll(&mut self) -> io::Result<Option<u8>> {
+        self.set_nonblocking(true)?;
+        let received = self.receive();
+        self.set_nonblocking(false)?;
+        match received {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            received => received,
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8; 1];
+        match self.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+}
+
+// in-memory ends for tests and embedders: input is queued up front, output collected
+#[derive(Clone, Default)]
+pub struct Buffer(Rc<RefCell<(VecDeque<u8>, Vec<u8>)>>);
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer::default()
+    }
+
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.0.borrow_mut().0.extend(bytes);
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut().1)
+    }
+}
+
+impl SerialBackend for Buffer {
+    fn transmit(&mut self, byte: u8) -> io::Result<()> {
+        self.0.borrow_mut().1.push(byte);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.0.borrow_mut().0.pop_front())
+    }
+
+    fn receive(&mut self) -> io::Result<Option<u8>> {
+        self.poll()
+    }
+}
+
+// a backend from its command line form: stdio, file:path or unix:path
+pub fn open_backend(spec: &str) -> io::Result<Box<dyn SerialBackend>> {
+    match spec.split_once(':') {
+        None if spec == "stdio" => Ok(Box::new(Stdio::default())),
+        Some(("file", path)) => Ok(Box::new(File::create(path)?)),
+        Some(("unix", path)) => Ok(Box::new(UnixStream::connect(path)?)),
+        _ => Err(io::Error::new(ErrorKind::InvalidInput, format!("unknown serial backend {spec}"))),
+    }
+}
+
+struct Registers {
+    backend: Box<dyn SerialBackend>,
+    receiver: VecDeque<u8>,
+    divisor: u16,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    msr: u8,
+    scratch: u8,
+    overrun: bool,
+    // the transmitter empty interrupt is acknowledged by reading IIR and comes back with the next THR write
+    thr_interrupt: bool,
+    // the next character takes a while to come in after RBR is read, which the interrupt line shows
+    rbr_read: bool,
+    // RBR keeps the last character received until the next one comes in
+    rbr: u8,
+    wait_for_input: bool,
+}
+
+impl Registers {
+    fn capacity(&self) -> usize {
+        if self.fcr & FCR_ENABLE != 0 { FIFO_SIZE } else { 1 }
+    }
+
+    fn trigger_level(&self) -> usize {
+        match self.fcr & FCR_ENABLE {
+            0 => 1,
+            _ => [1, 4, 8, 14][(self.fcr >> 6) as usize],
+        }
+    }
+
+    // take what the backend has without waiting, as far as the receiver has room
+    fn fill(&mut self) -> io::Result<()> {
+        while self.mcr & MCR_LOOPBACK == 0 && self.receiver.len() < self.capacity() {
+            match self.backend.poll()? {
+                Some(byte) => self.receiver.push_back(byte),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.receiver.len() < self.capacity() {
+            self.receiver.push_back(byte);
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    // data below the trigger level reports as a character timeout right away, there is no clock to wait on
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_LINE_STATUS != 0 && self.overrun {
+            IIR_LINE_STATUS
+        } else if self.ier & IER_RECEIVED != 0 && !self.receiver.is_empty() {
+            if self.receiver.len() >= self.trigger_level() { IIR_RECEIVED } else { IIR_TIMEOUT }
+        } else if self.ier & IER_TRANSMITTER_EMPTY != 0 && self.thr_interrupt {
+            IIR_TRANSMITTER_EMPTY
+        } else if self.ier & IER_MODEM_STATUS != 0 && self.msr & 0x0f != 0 {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NONE
+        }
+    }
+
+    // the modem lines follow the modem control outputs in loopback, and a connected peer otherwise
+    fn update_modem_status(&mut self) {
+        let lines = match self.mcr & MCR_LOOPBACK {
+            0 => MSR_CTS | MSR_DSR | MSR_DCD,
+            _ => [(MCR_RTS, MSR_CTS), (MCR_DTR, MSR_DSR), (MCR_OUT1, MSR_RI), (MCR_OUT2, MSR_DCD)].iter()
+                .filter(|(output, _)| self.mcr & output != 0)
+                .fold(0, |lines, (_, line)| lines | line),
+        };
+        let changed = (self.msr ^ lines) & 0xf0;
+        // the ring indicator only reports its trailing edge
+        let mut deltas = (changed & (MSR_CTS | MSR_DSR | MSR_DCD)) >> 4;
+        if changed & MSR_RI != 0 && lines & MSR_RI == 0 {
+            deltas |= 0x04;
+        }
+        self.msr = lines | (self.msr & 0x0f) | deltas;
+    }
+}
+
+// a 16550A on 8 consecutive ports. clones share the chip, so that its interrupt line can be wired up
+// while the I/O bus owns the registers.
+#[derive(Clone)]
+pub struct Uart(Rc<RefCell<Registers>>);
+
+impl Uart {
+    pub fn new(backend: Box<dyn SerialBackend>) -> Uart {
+        Uart(Rc::new(RefCell::new(Registers {
+            backend,
+            receiver: VecDeque::new(),
+            divisor: 0x0c,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            msr: MSR_CTS | MSR_DSR | MSR_DCD,
+            scratch: 0,
+            overrun: false,
+            thr_interrupt: true,
+            rbr_read: false,
+            rbr: 0,
+            wait_for_input: false,
+        })))
+    }
+
+    // legacy behaviour for programs that never look at LSR: an RBR read with nothing received waits for the backend
+    pub fn set_wait_for_input(&self, wait: bool) {
+        self.0.borrow_mut().wait_for_input = wait;
+    }
+
+    // the line speed the guest programmed into the divisor latch
+    pub fn get_baud_rate(&self) -> u32 {
+        115200 / self.0.borrow().divisor.max(1) as u32
+    }
+}
+
+impl PortDevice for Uart {
+    fn read(&mut self, port: u16, _size: u8) -> io::Result<u32> {
+        let mut uart = self.0.borrow_mut();
+        let dlab = uart.lcr & LCR_DLAB != 0;
+        let value = match port & 0x7 {
+            0 if dlab => uart.divisor as u8,
+            0 => match uart.receiver.pop_front() {
+                Some(byte) => {
+                    uart.rbr_read = true;
+                    uart.rbr = byte;
+                    byte
+                },
+                None if uart.wait_for_input && uart.mcr & MCR_LOOPBACK == 0 => {
+                    let byte = uart.backend.receive()?.ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+                    uart.rbr = byte;
+                    byte
+                },
+                // drivers flush RBR when they set the port up, which must not touch the backend
+                None => uart.rbr,
+            },
+            1 if dlab => (uart.divisor >> 8) as u8,
+            1 => uart.ier,
+            2 => {
+                uart.fill()?;
+                let id = uart.interrupt_id();
+                if id == IIR_TRANSMITTER_EMPTY {
+                    uart.thr_interrupt = false;
+                }
+                id | if uart.fcr & FCR_ENABLE != 0 { IIR_FIFO } else { 0 }
+            },
+            3 => uart.lcr,
+            4 => uart.mcr,
+            5 => {
+                uart.fill()?;
+                let data_ready = if uart.receiver.is_empty() { 0 } else { LSR_DATA_READY };
+                let overrun = if uart.overrun { LSR_OVERRUN } else { 0 };
+                uart.overrun = false;
+                // transmission is instant, so the transmitter is always empty
+                data_ready | overrun | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            },
+            6 => {
+                let msr = uart.msr;
+                uart.msr &= 0xf0;
+                msr
+            },
+            _ => uart.scratch,
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, port: u16, _size: u8, value: u32) -> io::Result<()> {
+        let mut uart = self.0.borrow_mut();
+        let (dlab, value) = (uart.lcr & LCR_DLAB != 0, value as u8);
+        match port & 0x7 {
+            0 if dlab => uart.divisor = uart.divisor & 0xff00 | value as u16,
+            0 => {
+                if uart.mcr & MCR_LOOPBACK != 0 {
+                    uart.receive(value);
+                } else {
+                    uart.backend.transmit(value)?;
+                }
+                uart.thr_interrupt = true;
+            },
+            1 if dlab => uart.divisor = uart.divisor & 0x00ff | (value as u16) << 8,
+            1 => {
+                if value & IER_TRANSMITTER_EMPTY != 0 && uart.ier & IER_TRANSMITTER_EMPTY == 0 {
+                    uart.thr_interrupt = true;
+                }
+                uart.ier = value & 0x0f;
+            },
+            2 => {
+                // switching the FIFOs on or off empties them
+                if value & FCR_CLEAR_RECEIVER != 0 || (value ^ uart.fcr) & FCR_ENABLE != 0 {
+                    uart.receiver.clear();
+                }
+                uart.fcr = value & (FCR_ENABLE | 0xc0);
+            },
+            3 => uart.lcr = value,
+            4 => {
+                uart.mcr = value & 0x1f;
+                uart.update_modem_status();
+            },
+            7 => uart.scratch = value,
+            // LSR and MSR are read-only
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+// OUT2 gates the interrupt output on PC serial ports
+impl IrqSource for Uart {
+    fn is_asserted(&mut self) -> bool {
+        let mut uart = self.0.borrow_mut();
+        if std::mem::take(&mut uart.rbr_read) || uart.fill().is_err() {
+            return false;
+        }
+        uart.mcr & MCR_OUT2 != 0 && uart.interrupt_id() & IIR_NONE == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uart_test() {
+        let buffer = Buffer::new();
+        let mut uart = Uart::new(Box::new(buffer.clone()));
+        let base = COM_PORTS[0];
+
+        // 9600 baud, 8N1
+        uart.write(base + 3, 1, 0x83).unwrap();
+        uart.write(base, 1, 0x0c).unwrap();
+        uart.write(base + 1, 1, 0x00).unwrap();
+        uart.write(base + 3, 1, 0x03).unwrap();
+        assert_eq!(uart.get_baud_rate(), 9600);
+        assert_eq!(uart.read(base + 5, 1).unwrap(), 0x60);
+        uart.write(base, 1, b'A' as u32).unwrap();
+        assert_eq!(buffer.take_output(), b"A");
+        uart.write(base + 7, 1, 0x5a).unwrap();
+        assert_eq!(uart.read(base + 7, 1).unwrap(), 0x5a);
+
+        // the interrupt line needs OUT2, transmitter empty is acknowledged through IIR
+        uart.write(base + 1, 1, (IER_RECEIVED | IER_TRANSMITTER_EMPTY) as u32).unwrap();
+        assert!(!uart.is_asserted());
+        uart.write(base + 4, 1, (MCR_DTR | MCR_RTS | MCR_OUT2) as u32).unwrap();
+        assert!(uart.is_asserted());
+        assert_eq!(uart.read(base + 2, 1).unwrap(), 0x02);
+        assert_eq!(uart.read(base + 2, 1).unwrap(), 0x01);
+        assert!(!uart.is_asserted());
+
+        // received data below the FIFO trigger level is a timeout, then it reaches the level
+        uart.write(base + 2, 1, 0x47).unwrap();
+        buffer.push_input(b"hello");
+        assert!(uart.is_asserted());
+        assert_eq!(uart.read(base + 5, 1).unwrap() & 0x01, 0x01);
+        assert_eq!(uart.read(base + 2, 1).unwrap(), 0xc4);
+        assert_eq!(uart.read(base, 1).unwrap(), b'h' as u32);
+        assert_eq!(uart.read(base + 2, 1).unwrap(), 0xc4);
+        assert_eq!(uart.read(base, 1).unwrap(), b'e' as u32);
+        assert_eq!(uart.read(base + 2, 1).unwrap(), 0xcc);
+        for char in b"llo" {
+            assert_eq!(uart.read(base, 1).unwrap(), *char as u32);
+        }
+        assert_eq!(uart.read(base + 5, 1).unwrap(), 0x60);
+        assert_eq!(uart.read(base, 1).unwrap(), b'o' as u32);
+        uart.set_wait_for_input(true);
+        assert!(uart.read(base, 1).is_err());
+        uart.set_wait_for_input(false);
+
+        // loopback: transmitted bytes come back in and the modem lines follow MCR
+        uart.write(base + 4, 1, (MCR_LOOPBACK | MCR_RTS) as u32).unwrap();
+        assert_eq!(uart.read(base + 6, 1).unwrap(), (MSR_CTS | 0x02 | 0x08) as u32);
+        assert_eq!(uart.read(base + 6, 1).unwrap(), MSR_CTS as u32);
+        for char in 0..17 {
+            uart.write(base, 1, char).unwrap();
+        }
+        assert!(buffer.take_output().is_empty());
+        assert_eq!(uart.read(base + 5, 1).unwrap(), 0x63);
+        assert_eq!(uart.read(base, 1).unwrap(), 0);
+
+        assert!(open_backend("serial:/dev/ttyS0").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//
+// VGA text mode
+//
+use std::fmt::Write as _;
+use std::io::{self, stdout, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::{MmioDevice, PortDevice};
+
+pub const TEXT_BUFFER: u32 = 0xb8000;
+pub const TEXT_BUFFER_SIZE: u32 = 0x8000;
+pub const CRTC_PORTS: RangeInclusive<u16> = 0x3d4..=0x3da;
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 25;
+
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const INPUT_STATUS: u16 = 0x3da;
+
+const CURSOR_START: usize = 0x0a;
+const START_ADDRESS_HIGH: usize = 0x0c;
+const START_ADDRESS_LOW: usize = 0x0d;
+const CURSOR_HIGH: usize = 0x0e;
+const CURSOR_LOW: usize = 0x0f;
+const CURSOR_DISABLE: u8 = 0x20;
+
+const REFRESH: Duration = Duration::from_millis(40);
+
+// ANSI numbers of the VGA colors, which list blue before red
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+// code page 437 glyphs of the control characters and of the upper half
+const CP437_LOW: &str = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■ ";
+
+struct TextMode {
+    memory: Vec<u8>,
+    crtc_index: u8,
+    crtc: [u8; 0x19],
+    retrace: bool,
+    dirty: bool,
+}
+
+impl TextMode {
+    // character and attribute of a cell counted from the start address
+    fn cell(&self, index: usize) -> (u8, u8) {
+        let start = (self.crtc[START_ADDRESS_HIGH] as usize) << 8 | self.crtc[START_ADDRESS_LOW] as usize;
+        let offset = (start + index) * 2 % self.memory.len();
+        (self.memory[offset], self.memory[offset + 1])
+    }
+
+    // cursor row and column on the screen, if it is shown there
+    fn cursor(&self) -> Option<(usize, usize)> {
+        let start = (self.crtc[START_ADDRESS_HIGH] as usize) << 8 | self.crtc[START_ADDRESS_LOW] as usize;
+        let cursor = (self.crtc[CURSOR_HIGH] as usize) << 8 | self.crtc[CURSOR_LOW] as usize;
+        let index = cursor.checked_sub(start).filter(|index| *index < COLUMNS * ROWS)?;
+        match self.crtc[CURSOR_START] & CURSOR_DISABLE {
+            0 => Some((index / COLUMNS, index % COLUMNS)),
+            _ => None,
+        }
+    }
+}
+
+// 80x25 color text mode. the buffer at 0xB8000 and the CRTC ports share the state through clones of the handle.
+#[derive(Clone)]
+pub struct Vga(Arc<Mutex<TextMode>>);
+
+impl Vga {
+    // a blank screen of light gray on black
+    pub fn new() -> Vga {
+        let memory = [0x20, 0x07].repeat(TEXT_BUFFER_SIZE as usize / 2);
+        let mut crtc = [0; 0x19];
+        crtc[CURSOR_START] = 0x06;
+        Vga(Arc::new(Mutex::new(TextMode { memory, crtc_index: 0, crtc, retrace: false, dirty: true })))
+    }
+
+    fn state(&self) -> MutexGuard<'_, TextMode> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // cell offset of the cursor as programmed in the CRTC
+    pub fn get_cursor(&self) -> u16 {
+        let state = self.state();
+        (state.crtc[CURSOR_HIGH] as u16) << 8 | state.crtc[CURSOR_LOW] as u16
+    }
+
+    // the screen as lines of text without trailing blanks
+    pub fn dump_text(&self) -> String {
+        let state = self.state();
+        let lines: Vec<String> = (0..ROWS)
+            .map(|row| {
+                let line: String = (0..COLUMNS).map(|column| glyph(state.cell(row * COLUMNS + column).0)).collect();
+                line.trim_end().to_string()
+            })
+            .collect();
+        let used = lines.iter().rposition(|line| !line.is_empty()).map_or(0, |last| last + 1);
+        lines[..used].join("\n")
+    }
+
+    // repaint the whole screen from the top left corner of the terminal
+    pub fn render(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut state = self.state();
+        let mut frame = String::from("\x1b[H");
+        for row in 0..ROWS {
+            let mut current = None;
+            for column in 0..COLUMNS {
+                let (char, attribute) = state.cell(row * COLUMNS + column);
+                if current != Some(attribute) {
+                    let (foreground, background) = (attribute & 0xf, attribute >> 4 & 0x7);
+                    let bright = if foreground & 0x8 != 0 { 90 } else { 30 };
+                    let _ = write!(frame, "\x1b[{};{}m", bright + ANSI_COLORS[foreground as usize & 0x7], 40 + ANSI_COLORS[background as usize]);
+                    current = Some(attribute);
+                }
+                frame.push(glyph(char));
+            }
+            frame.push_str("\x1b[0m");
+            if row + 1 < ROWS {
+                frame.push_str("\r\n");
+            }
+        }
+        match state.cursor() {
+            Some((row, column)) => {
+                let _ = write!(frame, "\x1b[{};{}H\x1b[?25h", row + 1, column + 1);
+            },
+            None => frame.push_str("\x1b[?25l"),
+        }
+        state.dirty = false;
+        out.write_all(frame.as_bytes())?;
+        out.flush()
+    }
+
+    // repaint the host terminal whenever the screen changed, until stdout goes away
+    pub fn spawn_renderer(&self) {
+        let vga = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(REFRESH);
+            if vga.state().dirty && vga.render(&mut stdout()).is_err() {
+                break;
+            }
+        });
+    }
+}
+
+impl Default for Vga {
+    fn default() -> Vga {
+        Vga::new()
+    }
+}
+
+impl MmioDevice for Vga {
+    fn read8(&self, offset: u32) -> u8 {
+        self.state().memory[offset as usize]
+    }
+
+    fn write8(&mut self, offset: u32, value: u8) {
+        let mut state = self.state();
+        state.memory[offset as usize] = value;
+        state.dirty = true;
+    }
+}
+
+impl PortDevice for Vga {
+    fn read(&mut self, port: u16, _size: u8) -> io::Result<u32> {
+        let mut state = self.state();
+        Ok(match port {
+            CRTC_INDEX => state.crtc_index as u32,
+            CRTC_DATA => state.crtc.get(state.crtc_index as usize).copied().unwrap_or(0xff) as u32,
+            // the retrace bits flip on every read so that loops waiting for either edge go on
+            INPUT_STATUS => {
+                state.retrace = !state.retrace;
+                if state.retrace { 0x09 } else { 0x00 }
+            },
+            _ => 0xff,
+        })
+    }
+
+    fn write(&mut self, port: u16, size: u8, value: u32) -> io::Result<()> {
+        let mut state = self.state();
+        match port {
+            CRTC_INDEX => {
+                state.crtc_index = value as u8;
+                // a word write sets the index and the data at once
+                if size > 1 {
+                    let index = state.crtc_index as usize;
+                    if let Some(register) = state.crtc.get_mut(index) {
+                        *register = (value >> 8) as u8;
+                    }
+                }
+            },
+            CRTC_DATA => {
+                let index = state.crtc_index as usize;
+                if let Some(register) = state.crtc.get_mut(index) {
+                    *register = value as u8;
+                }
+            },
+            _ => return Ok(()),
+        }
+        state.dirty = true;
+        Ok(())
+    }
+}
+
+fn glyph(char: u8) -> char {
+    match char {
+        0x20..=0x7e => char as char,
+        0x7f => '⌂',
+        0x00..=0x1f => CP437_LOW.chars().nth(char as usize).unwrap_or(' '),
+        _ => CP437_HIGH.chars().nth(char as usize - 0x80).unwrap_or(' '),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vga_test() {
+        let mut vga = Vga::new();
+        let mut device = vga.clone();
+        for (i, char) in b"Hi".iter().enumerate() {
+            device.write8(i as u32 * 2, *char);
+            device.write8(i as u32 * 2 + 1, 0x1e);
+        }
+        device.write8((COLUMNS * 2) as u32 * 2, 0xc9);
+        assert_eq!(vga.read8(0x2), b'i');
+        assert_eq!(vga.dump_text(), "Hi\n\n╔");
+
+        // cursor at row 1, column 3 through the index and data ports, then as a word write
+        vga.write(0x3d4, 1, 0x0e).unwrap();
+        vga.write(0x3d5, 1, 0x00).unwrap();
+        vga.write(0x3d4, 2, 0x530f).unwrap();
+        assert_eq!(vga.get_cursor(), 0x53);
+        assert_eq!(vga.read(0x3d5, 1).unwrap(), 0x53);
+        assert_ne!(vga.read(0x3da, 1).unwrap(), vga.read(0x3da, 1).unwrap());
+
+        let mut out = Vec::new();
+        vga.render(&mut out).unwrap();
+        let frame = String::from_utf8(out).unwrap();
+        assert!(frame.starts_with("\x1b[H\x1b[93;44mHi\x1b[37;40m "));
+        assert!(frame.ends_with("\x1b[2;4H\x1b[?25h"));
+        assert!(!vga.state().dirty);
+
+        // scrolling the start address moves the first row out
+        vga.write(0x3d4, 2, 0x500d).unwrap();
+        assert_eq!(vga.dump_text(), "\n╔");
+    }
+}
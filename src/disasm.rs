@@ -0,0 +1,507 @@
+//
+// Disassembler
+//
+use std::fmt;
+
+use crate::emulator::Emulator;
+use crate::emulator::error::EmuError;
+use crate::emulator::modrm::ModRM;
+
+pub const REG32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+pub const REG16: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+pub const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const SREG_NAMES: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+const ADDRESS16: [&str; 8] = ["bx+si", "bx+di", "bp+si", "bp+di", "si", "di", "bp", "bx"];
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const GROUP3: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+const DESCRIPTOR_TABLE: [&str; 4] = ["sgdt", "sidt", "lgdt", "lidt"];
+const CONDITION: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+// one decoded instruction
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Line {
+    address: u32,
+    bytes: Vec<u8>,
+    text: String,
+}
+
+impl Line {
+    pub fn get_address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn get_length(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    pub fn get_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn get_text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "{:08x}  {:<24}{}", self.address, bytes.join(" "), self.text)
+    }
+}
+
+// decode the instruction at CS:eip with the same prefix and ModR/M decoder the CPU uses.
+// bytes that do not form a known instruction come out as a single `db`.
+// all bytes are read as instruction fetches, so disassembling never hits a watchpoint.
+pub fn disassemble(emu: &mut Emulator, eip: u32) -> Result<Line, EmuError> {
+    let (text, bytes) = emu.decode_at(eip, |emu| {
+        emu.parse_prefix()?;
+        let text = decode(emu)?;
+        let length = emu.get_eip().wrapping_sub(eip);
+        emu.set_eip(eip);
+        let (text, length) = match text {
+            Some(text) => (text, length),
+            None => (format!("db {}", hex(emu.get_code8(0)? as u32)), 1),
+        };
+        let bytes = (0..length as usize).map(|i| emu.get_code8(i)).collect::<Result<Vec<u8>, EmuError>>()?;
+        Ok::<_, EmuError>((text, bytes))
+    })?;
+    Ok(Line { address: eip, bytes, text })
+}
+
+fn decode(emu: &mut Emulator) -> Result<Option<String>, EmuError> {
+    let code = fetch8(emu)?;
+    let bits = operand_bits(emu);
+    let reg = (code & 0b111) as usize;
+
+    let text = match code {
+        0x00..=0x3F if code & 0b111 < 6 => {
+            let op = ALU[(code >> 3) as usize];
+            match code & 0b111 {
+                0 => rm_r(emu, op, 8)?,
+                1 => rm_r(emu, op, bits)?,
+                2 => r_rm(emu, op, 8)?,
+                3 => r_rm(emu, op, bits)?,
+                4 => format!("{} al, {}", op, hex(fetch8(emu)? as u32)),
+                _ => format!("{} {}, {}", op, reg_name(0, bits), imm(emu, bits)?),
+            }
+        },
+        0x06 | 0x0E | 0x16 | 0x1E => format!("push {}", SREG_NAMES[(code >> 3) as usize]),
+        0x07 | 0x17 | 0x1F => format!("pop {}", SREG_NAMES[(code >> 3) as usize]),
+        0x0F => return decode_0f(emu),
+        0x40..=0x47 => format!("inc {}", reg_name(reg, bits)),
+        0x48..=0x4F => format!("dec {}", reg_name(reg, bits)),
+        0x50..=0x57 => format!("push {}", reg_name(reg, bits)),
+        0x58..=0x5F => format!("pop {}", reg_name(reg, bits)),
+        0x68 => format!("push {}", imm(emu, bits)?),
+        0x6A => format!("push {}", simm8(emu, bits)?),
+        0x70..=0x7F => {
+            let diff = fetch8(emu)? as i8 as i32;
+            format!("j{} {}", CONDITION[(code & 0xf) as usize], target(emu, diff))
+        },
+        0x80 | 0x82 => {
+            let modrm = parse_modrm(emu)?;
+            let dst = rm(emu, &modrm, 8, true);
+            format!("{} {}, {}", ALU[modrm.get_opcode() as usize], dst, hex(fetch8(emu)? as u32))
+        },
+        0x81 | 0x83 => {
+            let modrm = parse_modrm(emu)?;
+            let dst = rm(emu, &modrm, bits, true);
+            let src = if code == 0x81 { imm(emu, bits)? } else { simm8(emu, bits)? };
+            format!("{} {}, {}", ALU[modrm.get_opcode() as usize], dst, src)
+        },
+        0x84 => rm_r(emu, "test", 8)?,
+        0x85 => rm_r(emu, "test", bits)?,
+        0x88 => rm_r(emu, "mov", 8)?,
+        0x89 => rm_r(emu, "mov", bits)?,
+        0x8A => r_rm(emu, "mov", 8)?,
+        0x8B => r_rm(emu, "mov", bits)?,
+        0x8C => {
+            let modrm = parse_modrm(emu)?;
+            // only a register destination is widened to the operand size
+            let size = if modrm.get_mod() == 0b11 { bits } else { 16 };
+            // segment registers 6 and 7 do not exist and raise #UD
+            let Some(sreg) = SREG_NAMES.get(modrm.get_reg_index() as usize) else {
+                return Ok(None);
+            };
+            format!("mov {}, {}", rm(emu, &modrm, size, false), sreg)
+        },
+        0x8D => r_rm(emu, "lea", bits)?,
+        0x8E => {
+            let modrm = parse_modrm(emu)?;
+            let Some(sreg) = SREG_NAMES.get(modrm.get_reg_index() as usize) else {
+                return Ok(None);
+            };
+            format!("mov {}, {}", sreg, rm(emu, &modrm, 16, false))
+        },
+        0x9A => format!("call {}", far_pointer(emu, bits)?),
+        0x9C => if bits == 16 { "pushf".to_string() } else { "pushfd".to_string() },
+        0x9D => if bits == 16 { "popf".to_string() } else { "popfd".to_string() },
+        0xA8 => format!("test al, {}", hex(fetch8(emu)? as u32)),
+        0xA9 => format!("test {}, {}", reg_name(0, bits), imm(emu, bits)?),
+        0xB0..=0xB7 => format!("mov {}, {}", REG8[reg], hex(fetch8(emu)? as u32)),
+        0xB8..=0xBF => format!("mov {}, {}", reg_name(reg, bits), imm(emu, bits)?),
+        0xC2 => format!("ret {}", hex(fetch16(emu)? as u32)),
+        0xC3 => "ret".to_string(),
+        0xC6 => {
+            let modrm = parse_modrm(emu)?;
+            let dst = rm(emu, &modrm, 8, true);
+            format!("mov {}, {}", dst, hex(fetch8(emu)? as u32))
+        },
+        0xC7 => {
+            let modrm = parse_modrm(emu)?;
+            let dst = rm(emu, &modrm, bits, true);
+            format!("mov {}, {}", dst, imm(emu, bits)?)
+        },
+        0xC9 => "leave".to_string(),
+        0xCA => format!("retf {}", hex(fetch16(emu)? as u32)),
+        0xCB => "retf".to_string(),
+        0xCC => "int3".to_string(),
+        0xCD => format!("int {}", hex(fetch8(emu)? as u32)),
+        0xCE => "into".to_string(),
+        0xCF => if bits == 16 { "iret".to_string() } else { "iretd".to_string() },
+        0xE4 => format!("in al, {}", hex(fetch8(emu)? as u32)),
+        0xE5 => format!("in {}, {}", reg_name(0, bits), hex(fetch8(emu)? as u32)),
+        0xE6 => format!("out {}, al", hex(fetch8(emu)? as u32)),
+        0xE7 => format!("out {}, {}", hex(fetch8(emu)? as u32), reg_name(0, bits)),
+        0xE8 | 0xE9 => {
+            let diff = if bits == 16 { fetch16(emu)? as i16 as i32 } else { fetch32(emu)? as i32 };
+            let op = if code == 0xE8 { "call" } else { "jmp" };
+            format!("{} {}", op, target(emu, diff))
+        },
+        0xEA => format!("jmp {}", far_pointer(emu, bits)?),
+        0xEB => {
+            let diff = fetch8(emu)? as i8 as i32;
+            format!("jmp short {}", target(emu, diff))
+        },
+        0xEC => "in al, dx".to_string(),
+        0xED => format!("in {}, dx", reg_name(0, bits)),
+        0xEE => "out dx, al".to_string(),
+        0xEF => format!("out dx, {}", reg_name(0, bits)),
+        0xF4 => "hlt".to_string(),
+        0xF6 | 0xF7 => {
+            let size = if code == 0xF6 { 8 } else { bits };
+            let modrm = parse_modrm(emu)?;
+            let dst = rm(emu, &modrm, size, true);
+            let op = GROUP3[modrm.get_opcode() as usize];
+            match modrm.get_opcode() {
+                0b000 | 0b001 => format!("{} {}, {}", op, dst, imm(emu, size)?),
+                _ => format!("{} {}", op, dst),
+            }
+        },
+        0xFA => "cli".to_string(),
+        0xFB => "sti".to_string(),
+        0xFE => {
+            let modrm = parse_modrm(emu)?;
+            match modrm.get_opcode() {
+                0b000 => format!("inc {}", rm(emu, &modrm, 8, true)),
+                0b001 => format!("dec {}", rm(emu, &modrm, 8, true)),
+                _ => return Ok(None),
+            }
+        },
+        0xFF => {
+            let modrm = parse_modrm(emu)?;
+            match modrm.get_opcode() {
+                0b000 => format!("inc {}", rm(emu, &modrm, bits, true)),
+                0b001 => format!("dec {}", rm(emu, &modrm, bits, true)),
+                0b010 => format!("call {}", rm(emu, &modrm, bits, false)),
+                0b011 => format!("call far {}", rm(emu, &modrm, bits, false)),
+                0b100 => format!("jmp {}", rm(emu, &modrm, bits, false)),
+                0b101 => format!("jmp far {}", rm(emu, &modrm, bits, false)),
+                0b110 => format!("push {}", rm(emu, &modrm, bits, true)),
+                _ => return Ok(None),
+            }
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(text))
+}
+
+fn decode_0f(emu: &mut Emulator) -> Result<Option<String>, EmuError> {
+    let code = fetch8(emu)?;
+    let bits = operand_bits(emu);
+
+    let text = match code {
+        0x80..=0x8F => {
+            let diff = if bits == 16 { fetch16(emu)? as i16 as i32 } else { fetch32(emu)? as i32 };
+            format!("j{} {}", CONDITION[(code & 0xf) as usize], target(emu, diff))
+        },
+        0x00 => {
+            let modrm = parse_modrm(emu)?;
+            match modrm.get_opcode() {
+                0b001 => format!("str {}", rm(emu, &modrm, 16, false)),
+                0b011 => format!("ltr {}", rm(emu, &modrm, 16, false)),
+                _ => return Ok(None),
+            }
+        },
+        0x01 => {
+            let modrm = parse_modrm(emu)?;
+            match modrm.get_opcode() {
+                0b100 => format!("smsw {}", rm(emu, &modrm, 16, false)),
+                0b110 => format!("lmsw {}", rm(emu, &modrm, 16, false)),
+                _ if modrm.get_mod() == 0b11 => return Ok(None),
+                0b111 => format!("invlpg {}", rm(emu, &modrm, 32, false)),
+                op @ 0b000..=0b011 => format!("{} {}", DESCRIPTOR_TABLE[op as usize], rm(emu, &modrm, 32, false)),
+                _ => return Ok(None),
+            }
+        },
+        0x20 | 0x22 => {
+            let modrm = ModRM::new(emu)?;
+            let cr = format!("cr{}", modrm.get_reg_index());
+            let reg = REG32[modrm.get_rm() as usize];
+            if code == 0x20 { format!("mov {}, {}", reg, cr) } else { format!("mov {}, {}", cr, reg) }
+        },
+        0xA0 => "push fs".to_string(),
+        0xA1 => "pop fs".to_string(),
+        0xA8 => "push gs".to_string(),
+        0xA9 => "pop gs".to_string(),
+        _ => return Ok(None),
+    };
+    Ok(Some(text))
+}
+
+fn fetch8(emu: &mut Emulator) -> Result<u8, EmuError> {
+    let value = emu.get_code8(0)?;
+    emu.inc_eip(1);
+    Ok(value)
+}
+
+fn fetch16(emu: &mut Emulator) -> Result<u16, EmuError> {
+    let value = emu.get_code16(0)?;
+    emu.inc_eip(2);
+    Ok(value)
+}
+
+fn fetch32(emu: &mut Emulator) -> Result<u32, EmuError> {
+    let value = emu.get_code32(0)?;
+    emu.inc_eip(4);
+    Ok(value)
+}
+
+fn parse_modrm(emu: &mut Emulator) -> Result<ModRM, EmuError> {
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    Ok(modrm)
+}
+
+fn operand_bits(emu: &Emulator) -> u32 {
+    if emu.is_operand16() { 16 } else { 32 }
+}
+
+fn hex(value: u32) -> String {
+    format!("{:#x}", value)
+}
+
+fn signed_hex(value: i32) -> String {
+    if value < 0 {
+        format!("-{:#x}", value.unsigned_abs())
+    } else {
+        format!("+{:#x}", value)
+    }
+}
+
+fn imm(emu: &mut Emulator, bits: u32) -> Result<String, EmuError> {
+    let value = match bits {
+        8 => fetch8(emu)? as u32,
+        16 => fetch16(emu)? as u32,
+        _ => fetch32(emu)?,
+    };
+    Ok(hex(value))
+}
+
+// sign-extended imm8 shown at the operand size
+fn simm8(emu: &mut Emulator, bits: u32) -> Result<String, EmuError> {
+    let value = fetch8(emu)? as i8 as i32 as u32;
+    Ok(hex(if bits == 16 { value & 0xffff } else { value }))
+}
+
+// branch target relative to the end of the instruction, which is where EIP is after decoding
+fn target(emu: &Emulator, diff: i32) -> String {
+    let eip = emu.get_eip().wrapping_add(diff as u32);
+    hex(if emu.is_operand16() { eip & 0xffff } else { eip })
+}
+
+fn far_pointer(emu: &mut Emulator, bits: u32) -> Result<String, EmuError> {
+    let offset = if bits == 16 { fetch16(emu)? as u32 } else { fetch32(emu)? };
+    let selector = fetch16(emu)?;
+    Ok(format!("{:#x}:{:#x}", selector, offset))
+}
+
+fn reg_name(id: usize, bits: u32) -> &'static str {
+    match bits {
+        8 => REG8[id],
+        16 => REG16[id],
+        _ => REG32[id],
+    }
+}
+
+fn size_keyword(bits: u32) -> &'static str {
+    match bits {
+        8 => "byte",
+        16 => "word",
+        _ => "dword",
+    }
+}
+
+fn rm_r(emu: &mut Emulator, op: &str, bits: u32) -> Result<String, EmuError> {
+    let modrm = parse_modrm(emu)?;
+    Ok(format!("{} {}, {}", op, rm(emu, &modrm, bits, false), reg_name(modrm.get_reg_index() as usize, bits)))
+}
+
+fn r_rm(emu: &mut Emulator, op: &str, bits: u32) -> Result<String, EmuError> {
+    let modrm = parse_modrm(emu)?;
+    Ok(format!("{} {}, {}", op, reg_name(modrm.get_reg_index() as usize, bits), rm(emu, &modrm, bits, false)))
+}
+
+// r/m operand; a memory operand gets a size keyword when no register operand implies the size
+fn rm(emu: &Emulator, modrm: &ModRM, bits: u32, sized: bool) -> String {
+    if modrm.get_mod() == 0b11 {
+        return reg_name(modrm.get_rm() as usize, bits).to_string();
+    }
+
+    let address = if emu.is_address16() { address16(modrm) } else { address32(modrm) };
+    let segment = match emu.get_segment_override() {
+        Some(reg) => format!("{}:", SREG_NAMES[reg as usize]),
+        None => String::new(),
+    };
+    if sized {
+        format!("{} [{}{}]", size_keyword(bits), segment, address)
+    } else {
+        format!("[{}{}]", segment, address)
+    }
+}
+
+fn address32(modrm: &ModRM) -> String {
+    let mut terms = Vec::new();
+    let mut disp = match modrm.get_mod() {
+        0b01 => modrm.get_disp8().unwrap_or(0) as i32,
+        0b10 => modrm.get_disp32().unwrap_or(0),
+        _ => 0,
+    };
+
+    match (modrm.get_mod(), modrm.get_rm()) {
+        (0b00, 0b101) => return hex(modrm.get_disp32().unwrap_or(0) as u32),
+        (_, 0b100) => {
+            // base == 0b101 with mod == 0b00 means disp32 with no base register
+            if modrm.get_mod() == 0b00 && modrm.get_base() == 0b101 {
+                disp = modrm.get_disp32().unwrap_or(0);
+            } else {
+                terms.push(REG32[modrm.get_base() as usize].to_string());
+            }
+            // index == 0b100 (ESP) means no index
+            if modrm.get_index() != 0b100 {
+                let index = REG32[modrm.get_index() as usize];
+                match modrm.get_scale() {
+                    0 => terms.push(index.to_string()),
+                    scale => terms.push(format!("{}*{}", index, 1 << scale)),
+                }
+            }
+        },
+        (_, rm) => terms.push(REG32[rm as usize].to_string()),
+    }
+
+    join_address(terms, disp)
+}
+
+fn address16(modrm: &ModRM) -> String {
+    let disp = match modrm.get_mod() {
+        0b00 if modrm.get_rm() == 0b110 => return hex(modrm.get_disp16().unwrap_or(0) as u16 as u32),
+        0b01 => modrm.get_disp8().unwrap_or(0) as i32,
+        0b10 => modrm.get_disp16().unwrap_or(0) as i32,
+        _ => 0,
+    };
+    join_address(vec![ADDRESS16[modrm.get_rm() as usize].to_string()], disp)
+}
+
+fn join_address(terms: Vec<String>, disp: i32) -> String {
+    if terms.is_empty() {
+        return hex(disp as u32);
+    }
+    let base = terms.join("+");
+    if disp == 0 {
+        base
+    } else {
+        base + &signed_hex(disp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disasm(emu: &mut Emulator, code: &[u8]) -> Vec<String> {
+        emu.load_bin(code.to_vec(), 0x0).unwrap();
+        let mut eip = 0;
+        let mut lines = Vec::new();
+        while eip < code.len() as u32 {
+            let line = disassemble(emu, eip).unwrap();
+            eip += line.get_length();
+            lines.push(line.get_text().to_string());
+        }
+        lines
+    }
+
+    #[test]
+    fn disassemble_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x100);
+        let lines = disasm(&mut emu, &[
+            0x8b, 0x45, 0xf8,                   // mov eax, [ebp-0x8]
+            0x89, 0x44, 0x88, 0x10,             // mov [eax+ecx*4+0x10], eax
+            0x83, 0xec, 0x10,                   // sub esp, 0x10
+            0xc7, 0x05, 0x00, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,  // mov dword [0x1000], 0x1
+            0x26, 0x88, 0x04, 0xcd, 0x20, 0x00, 0x00, 0x00,  // mov [es:ecx*8+0x20], al
+            0x66, 0xb8, 0x34, 0x12,             // mov ax, 0x1234
+            0x74, 0xfe,                         // je 0x20
+            0xe8, 0x00, 0x00, 0x00, 0x00,       // call 0x27
+            0xf7, 0x7d, 0x08,                   // idiv dword [ebp+0x8]
+            0xed,                               // in eax, dx
+            0xd6,                               // db 0xd6
+        ]);
+        assert_eq!(lines, vec![
+            "mov eax, [ebp-0x8]",
+            "mov [eax+ecx*4+0x10], eax",
+            "sub esp, 0x10",
+            "mov dword [0x1000], 0x1",
+            "mov [es:ecx*8+0x20], al",
+            "mov ax, 0x1234",
+            "je 0x20",
+            "call 0x27",
+            "idiv dword [ebp+0x8]",
+            "in eax, dx",
+            "db 0xd6",
+        ]);
+        assert_eq!(emu.get_eip(), 0x0);
+    }
+
+    #[test]
+    fn disassemble16_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        let lines = disasm(&mut emu, &[
+            0x8b, 0x46, 0xfe,                   // mov ax, [bp-0x2]
+            0x8e, 0xd8,                         // mov ds, ax
+            0xea, 0x00, 0x7c, 0x00, 0x00,       // jmp 0x0:0x7c00
+            0xcd, 0x10,                         // int 0x10
+            0x66, 0x40,                         // inc eax
+            0x0f, 0x01, 0x16, 0x40, 0x7e,       // lgdt [0x7e40]
+            0x0f, 0x20, 0xc0,                   // mov eax, cr0
+            0x0f, 0x22, 0xd8,                   // mov cr3, eax
+            0x0f, 0x01, 0x3f,                   // invlpg [bx]
+            0x0f, 0x00, 0xd8,                   // ltr ax
+        ]);
+        assert_eq!(lines, vec![
+            "mov ax, [bp-0x2]", "mov ds, ax", "jmp 0x0:0x7c00", "int 0x10", "inc eax",
+            "lgdt [0x7e40]", "mov eax, cr0", "mov cr3, eax", "invlpg [bx]", "ltr ax",
+        ]);
+
+        let line = disassemble(&mut emu, 0x0).unwrap();
+        assert_eq!(line.to_string(), "00000000  8b 46 fe                mov ax, [bp-0x2]");
+
+        // there is no segment register 6 or 7
+        emu.load_bin(vec![0x8c, 0xf0, 0x8e, 0xf8], 0x100).unwrap();
+        assert_eq!(disassemble(&mut emu, 0x100).unwrap().get_text(), "db 0x8c");
+        assert_eq!(disassemble(&mut emu, 0x102).unwrap().get_text(), "db 0x8e");
+    }
+}
@@ -1,14 +1,34 @@
 //
 // Emulator stuff
 //
+use crate::disasm;
 use crate::instruction::InstructionVector;
+use crate::emulator::segment::{SREG, Segment};
+use crate::emulator::interrupt::{Exception, InterruptHandler};
+use crate::emulator::system::DescriptorTable;
+use crate::emulator::paging::Translation;
+use crate::emulator::error::EmuError;
+use crate::emulator::memory::MappedRegion;
+use crate::device::{Clock, InterruptController, IoBus, PortDevice};
 
 pub mod modrm;
+pub mod segment;
+pub mod interrupt;
+pub mod memory;
+pub mod error;
+pub mod elf;
+pub mod system;
+pub mod paging;
+pub mod privilege;
 
 const CARRY_FLAG: u32 = 1;
+const PARITY_FLAG: u32 = 1 << 2;
 const ZERO_FLAG: u32 = 1 << 6;
 const SIGN_FLAG: u32 = 1 << 7;
+const TRAP_FLAG: u32 = 1 << 8;
+const INTERRUPT_FLAG: u32 = 1 << 9;
 const OVERFLOW_FLAG: u32 = 1 << 11;
+const IOPL_MASK: u32 = 0x3 << 12;
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone, Copy)]
 pub enum GPR {
@@ -35,19 +55,81 @@ pub enum GPR8 {
     BH = 7,
 }
 
+type StopCondition = Box<dyn Fn(&Emulator) -> bool>;
+
+// what happened to the CPU after a step
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Step {
+    Continue,
+    Halt,
+    Exit(i32),
+    Breakpoint,
+    Watchpoint(Watch, u32),
+    Stop,
+    Fault(EmuError),
+}
+
+// kind of data access a watchpoint triggers on
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct Watchpoint {
+    address: u32,
+    length: u32,
+    kind: Watch,
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub struct SPR {
     eflags: u32,
     eip: u32,
 }
 
-use std::collections::BTreeMap;
+// prefixes of the instruction currently being executed
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy)]
+pub struct Prefix {
+    operand_size: bool,
+    address_size: bool,
+    segment: Option<SREG>,
+}
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::ops::RangeInclusive;
 
 pub struct Emulator {
     reg_file: BTreeMap<GPR, u32>,
     sp_reg: SPR,
+    seg_reg: BTreeMap<SREG, Segment>,
+    cr: [u32; 5],
+    gdtr: DescriptorTable,
+    idtr: DescriptorTable,
+    tlb: RefCell<BTreeMap<u32, Translation>>,
+    page_updates: RefCell<Vec<(u32, u32)>>,
+    supervisor: Cell<bool>,
+    tr: Segment,
     memory: Vec<u8>,
+    memory_map: Vec<MappedRegion>,
+    a20_mask: u32,
+    prefix: Prefix,
+    instruction_start: u32,
+    io_bus: IoBus,
+    interrupt_handlers: Vec<(RangeInclusive<u8>, Box<dyn InterruptHandler>)>,
+    interrupt_controller: Option<Box<dyn InterruptController>>,
+    interrupt_shadow: bool,
+    clock: Clock,
+    stop_condition: Option<StopCondition>,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<(Watch, u32)>>,
+    symbols: BTreeMap<String, u32>,
+    trace: bool,
 }
 
 impl Emulator {
@@ -68,9 +150,47 @@ impl Emulator {
             eflags: 0x0,
             eip: eip_value,
         };
+        // flat 32-bit segments so that raw 32-bit code runs without any setup
+        let seg_reg = BTreeMap::from([
+            (SREG::ES, Segment::new(0x0, 0x0, 0xffffffff, true)),
+            (SREG::CS, Segment::new(0x0, 0x0, 0xffffffff, true)),
+            (SREG::SS, Segment::new(0x0, 0x0, 0xffffffff, true)),
+            (SREG::DS, Segment::new(0x0, 0x0, 0xffffffff, true)),
+            (SREG::FS, Segment::new(0x0, 0x0, 0xffffffff, true)),
+            (SREG::GS, Segment::new(0x0, 0x0, 0xffffffff, true)),
+        ]);
         let memory = vec![0; size];
-
-        Self { reg_file, sp_reg, memory }
+        let a20_mask = 0xffffffff;
+        let prefix = Prefix::default();
+
+        Self {
+            reg_file,
+            sp_reg,
+            seg_reg,
+            cr: [system::CR0_ET, 0, 0, 0, 0],
+            gdtr: DescriptorTable::new(0x0, 0x0),
+            idtr: DescriptorTable::new(0x0, 0x3ff),
+            tlb: RefCell::new(BTreeMap::new()),
+            page_updates: RefCell::new(Vec::new()),
+            supervisor: Cell::new(false),
+            tr: Segment::null(0x0),
+            memory,
+            memory_map: Vec::new(),
+            a20_mask,
+            prefix,
+            instruction_start: eip_value,
+            io_bus: IoBus::new(),
+            interrupt_handlers: Vec::new(),
+            interrupt_controller: None,
+            interrupt_shadow: false,
+            clock: Clock::new(),
+            stop_condition: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            symbols: BTreeMap::new(),
+            trace: false,
+        }
     }
 
     pub fn get_gpr_id(&self, reg: u32) -> Option<&GPR> {
@@ -105,6 +225,21 @@ impl Emulator {
         }
     }
 
+    // register encoded in the low 3 bits of the opcode byte, as in push r32 or mov r32, imm32
+    pub fn get_opcode_gpr(&self) -> Result<GPR, EmuError> {
+        let code = self.get_code8(0)?;
+        self.get_gpr_id((code & 0b111).into()).copied().ok_or_else(|| {
+            EmuError::Decode(format!("Invalid register id in opcode: {:#x}", code))
+        })
+    }
+
+    pub fn get_opcode_gpr8(&self) -> Result<GPR8, EmuError> {
+        let code = self.get_code8(0)?;
+        self.get_gpr8_id((code & 0b111).into()).map(|(reg8, _)| *reg8).ok_or_else(|| {
+            EmuError::Decode(format!("Invalid register id in opcode: {:#x}", code))
+        })
+    }
+
     pub fn get_gpr_value(&self, reg: &GPR) -> u32 {
         *self.reg_file.get(reg).unwrap_or_else(|| {
             panic!("Could not find the register specified by: {:#x?}", reg);
@@ -117,6 +252,15 @@ impl Emulator {
         });
     }
 
+    pub fn get_gpr16_value(&self, reg: &GPR) -> u16 {
+        (self.get_gpr_value(reg) & 0xffff) as u16
+    }
+
+    pub fn set_gpr16(&mut self, reg: &GPR, new_value: u16) {
+        let value = self.get_gpr_value(reg) & 0xffff0000 | new_value as u32;
+        self.set_gpr(reg, value);
+    }
+
     pub fn get_gpr8_id(&self, reg: u32) -> Option<(&GPR8, &GPR)> {
         match reg {
             v if v == GPR8::AL as u32 => {
@@ -151,43 +295,40 @@ impl Emulator {
 
     pub fn convert_gpr8_id(&self, reg: GPR8) -> Option<(&GPR8, &GPR)> {
         match reg {
-            v if v == GPR8::AL => {
+            GPR8::AL => {
                 Some((&GPR8::AL, &GPR::EAX))
             }
-            v if v == GPR8::AH => {
+            GPR8::AH => {
                 Some((&GPR8::AH, &GPR::EAX))
             }
-            v if v == GPR8::CL => {
+            GPR8::CL => {
                 Some((&GPR8::CL, &GPR::ECX))
             }
-            v if v == GPR8::CH => {
+            GPR8::CH => {
                 Some((&GPR8::CH, &GPR::ECX))
             }
-            v if v == GPR8::DL => {
+            GPR8::DL => {
                 Some((&GPR8::DL, &GPR::EDX))
             }
-            v if v == GPR8::DH => {
+            GPR8::DH => {
                 Some((&GPR8::DH, &GPR::EDX))
             }
-            v if v == GPR8::BL => {
+            GPR8::BL => {
                 Some((&GPR8::BL, &GPR::EBX))
             }
-            v if v == GPR8::BH => {
+            GPR8::BH => {
                 Some((&GPR8::BH, &GPR::EBX))
             }
-            _ => {
-                None
-            }
         }
     }
 
     pub fn get_gpr8_value(&self, reg: &GPR8) -> u8 {
         let reg = self.convert_gpr8_id(*reg);
-        let (_reg8, reg32) = reg.unwrap();
+        let (reg8, reg32) = reg.unwrap();
         let mut ret = *self.reg_file.get(reg32).unwrap_or_else(|| {
             panic!("Could not find the register specified by: {:#x?}", reg);
         });
-        if (*reg32 as u32) < 4 {
+        if (*reg8 as u32) < 4 {
             ret &= 0xff;
         } else {
             ret = (ret >> 8) & 0xff;
@@ -197,12 +338,12 @@ impl Emulator {
 
     pub fn set_gpr8(&mut self, reg: &GPR8, new_value: u8) {
         let reg = self.convert_gpr8_id(*reg);
-        let (_reg8, reg32) = reg.unwrap();
+        let (reg8, reg32) = reg.unwrap();
         let mut value = self.get_gpr_value(reg32);
-        if (*reg32 as u32) < 4 {
+        if (*reg8 as u32) < 4 {
             value = value & 0xffffff00 | new_value as u32;
         } else {
-            value = value & 0xffff00ff | new_value as u32;
+            value = value & 0xffff00ff | (new_value as u32) << 8;
         }
         self.reg_file.entry(*reg32).and_modify(|reg_value| {
             *reg_value = value;
@@ -218,19 +359,25 @@ impl Emulator {
     }
 
     pub fn inc_eip(&mut self, increment_by: i32) {
-        let mut eip = self.sp_reg.eip as i32;
-        eip += increment_by;
-        self.set_eip(eip as u32);
+        self.set_eip(self.sp_reg.eip.wrapping_add_signed(increment_by));
     }
 
     pub fn get_eflags(&self) -> u32 {
         self.sp_reg.eflags
     }
 
+    pub fn set_eflags(&mut self, new_value: u32) {
+        self.sp_reg.eflags = new_value;
+    }
+
     pub fn is_carry(&self) -> bool {
         self.get_eflags() & CARRY_FLAG != 0
     }
 
+    pub fn is_parity(&self) -> bool {
+        self.get_eflags() & PARITY_FLAG != 0
+    }
+
     pub fn is_zero(&self) -> bool {
         self.get_eflags() & ZERO_FLAG != 0
     }
@@ -243,18 +390,141 @@ impl Emulator {
         self.get_eflags() & OVERFLOW_FLAG != 0
     }
 
+    pub fn is_interrupt(&self) -> bool {
+        self.get_eflags() & INTERRUPT_FLAG != 0
+    }
+
+    pub fn set_interrupt(&mut self, is_interrupt: bool) {
+        if is_interrupt {
+            self.sp_reg.eflags |= INTERRUPT_FLAG;
+        } else {
+            self.sp_reg.eflags &= !INTERRUPT_FLAG;
+        }
+    }
+
+    pub fn set_trap(&mut self, is_trap: bool) {
+        if is_trap {
+            self.sp_reg.eflags |= TRAP_FLAG;
+        } else {
+            self.sp_reg.eflags &= !TRAP_FLAG;
+        }
+    }
+
+    pub fn update_eflags_add(&mut self, v1: u32, v2: u32, result: u64) {
+        self.update_eflags_add_sized(v1 as u64, v2 as u64, result, 32);
+    }
+
+    pub fn update_eflags_add8(&mut self, v1: u8, v2: u8, result: u16) {
+        self.update_eflags_add_sized(v1 as u64, v2 as u64, result as u64, 8);
+    }
+
+    pub fn update_eflags_add16(&mut self, v1: u16, v2: u16, result: u32) {
+        self.update_eflags_add_sized(v1 as u64, v2 as u64, result as u64, 16);
+    }
+
     pub fn update_eflags_sub(&mut self, v1: u32, v2: u32, result: u64) {
-        let sign1 = v1 >> 31;
-        let sign2 = v2 >> 31;
-        let signr = (result >> 31) & 1;
+        self.update_eflags_sub_sized(v1 as u64, v2 as u64, result, 32);
+    }
 
-        self.set_carry(result >> 32);
-        self.set_zero(result == 0);
+    pub fn update_eflags_sub8(&mut self, v1: u8, v2: u8, result: u16) {
+        self.update_eflags_sub_sized(v1 as u64, v2 as u64, result as u64, 8);
+    }
+
+    pub fn update_eflags_sub16(&mut self, v1: u16, v2: u16, result: u32) {
+        self.update_eflags_sub_sized(v1 as u64, v2 as u64, result as u64, 16);
+    }
+
+    pub fn update_eflags_logic(&mut self, result: u32) {
+        self.update_eflags_logic_sized(result as u64, 32);
+    }
+
+    pub fn update_eflags_logic8(&mut self, result: u8) {
+        self.update_eflags_logic_sized(result as u64, 8);
+    }
+
+    pub fn update_eflags_logic16(&mut self, result: u16) {
+        self.update_eflags_logic_sized(result as u64, 16);
+    }
+
+    pub fn update_eflags_inc(&mut self, v1: u32, result: u64) {
+        let carry = self.is_carry();
+        self.update_eflags_add_sized(v1 as u64, 1, result, 32);
+        self.set_carry(carry as u64);
+    }
+
+    pub fn update_eflags_inc8(&mut self, v1: u8, result: u16) {
+        let carry = self.is_carry();
+        self.update_eflags_add_sized(v1 as u64, 1, result as u64, 8);
+        self.set_carry(carry as u64);
+    }
+
+    pub fn update_eflags_inc16(&mut self, v1: u16, result: u32) {
+        let carry = self.is_carry();
+        self.update_eflags_add_sized(v1 as u64, 1, result as u64, 16);
+        self.set_carry(carry as u64);
+    }
+
+    pub fn update_eflags_dec(&mut self, v1: u32, result: u64) {
+        let carry = self.is_carry();
+        self.update_eflags_sub_sized(v1 as u64, 1, result, 32);
+        self.set_carry(carry as u64);
+    }
+
+    pub fn update_eflags_dec8(&mut self, v1: u8, result: u16) {
+        let carry = self.is_carry();
+        self.update_eflags_sub_sized(v1 as u64, 1, result as u64, 8);
+        self.set_carry(carry as u64);
+    }
+
+    pub fn update_eflags_dec16(&mut self, v1: u16, result: u32) {
+        let carry = self.is_carry();
+        self.update_eflags_sub_sized(v1 as u64, 1, result as u64, 16);
+        self.set_carry(carry as u64);
+    }
+
+    // MUL/IMUL set both CF and OF when the upper half of the result is significant
+    pub fn update_eflags_mul(&mut self, is_overflow: bool) {
+        self.set_carry(is_overflow as u64);
+        self.set_overflow(is_overflow);
+    }
+
+    fn update_eflags_add_sized(&mut self, v1: u64, v2: u64, result: u64, bits: u32) {
+        let sign1 = (v1 >> (bits - 1)) & 1;
+        let sign2 = (v2 >> (bits - 1)) & 1;
+        let signr = (result >> (bits - 1)) & 1;
+
+        self.set_carry((result >> bits) & 1);
+        self.set_zero(result & Self::size_mask(bits) == 0);
+        self.set_sign(signr);
+        self.set_parity(result);
+        self.set_overflow(sign1 == sign2 && sign1 != signr);
+    }
+
+    fn update_eflags_sub_sized(&mut self, v1: u64, v2: u64, result: u64, bits: u32) {
+        let sign1 = (v1 >> (bits - 1)) & 1;
+        let sign2 = (v2 >> (bits - 1)) & 1;
+        let signr = (result >> (bits - 1)) & 1;
+
+        self.set_carry((result >> bits) & 1);
+        self.set_zero(result & Self::size_mask(bits) == 0);
         self.set_sign(signr);
-        self.set_overflow(sign1 != sign2 && sign1 as u64 != signr);
+        self.set_parity(result);
+        self.set_overflow(sign1 != sign2 && sign1 != signr);
     }
 
-    fn set_carry(&mut self, is_carry: u64) {
+    fn update_eflags_logic_sized(&mut self, result: u64, bits: u32) {
+        self.set_carry(0);
+        self.set_zero(result & Self::size_mask(bits) == 0);
+        self.set_sign((result >> (bits - 1)) & 1);
+        self.set_parity(result);
+        self.set_overflow(false);
+    }
+
+    fn size_mask(bits: u32) -> u64 {
+        (1 << bits) - 1
+    }
+
+    pub fn set_carry(&mut self, is_carry: u64) {
         if is_carry != 0 {
             self.sp_reg.eflags |= CARRY_FLAG;
         } else {
@@ -262,7 +532,16 @@ impl Emulator {
         }
     }
 
-    fn set_zero(&mut self, is_zero: bool) {
+    fn set_parity(&mut self, result: u64) {
+        // PF reflects the even parity of the least significant byte only
+        if (result & 0xff).count_ones().is_multiple_of(2) {
+            self.sp_reg.eflags |= PARITY_FLAG;
+        } else {
+            self.sp_reg.eflags &= !PARITY_FLAG;
+        }
+    }
+
+    pub fn set_zero(&mut self, is_zero: bool) {
         if is_zero {
             self.sp_reg.eflags |= ZERO_FLAG;
         } else {
@@ -286,69 +565,283 @@ impl Emulator {
         }
     }
 
-    pub fn load_bin(&mut self, binary: Vec<u8>, address: u32) {
+    pub fn get_memory_size(&self) -> u32 {
+        self.memory.len() as u32
+    }
+
+    pub fn load_bin(&mut self, binary: Vec<u8>, address: u32) -> Result<(), EmuError> {
         let end_index = address as usize + binary.len();
+        if end_index > self.memory.len() {
+            return Err(EmuError::MemoryOutOfRange(self.memory.len() as u32));
+        }
         self.memory.splice(address as usize..end_index, binary);
+        Ok(())
     }
 
-    pub fn get_code8(&self, index: usize) -> u8 {
-        self.memory[self.sp_reg.eip as usize + index] as u8
+    // instruction fetches do not trigger watchpoints
+    pub fn get_code8(&self, index: usize) -> Result<u8, EmuError> {
+        let address = self.get_linear_address(&SREG::CS, self.sp_reg.eip.wrapping_add(index as u32));
+        self.read_physical8(self.translate(address, false)? & self.a20_mask)
     }
 
-    pub fn get_signed_code8(&self, index: usize) -> i8 {
-        self.memory[self.sp_reg.eip as usize + index] as i8
+    pub fn get_signed_code8(&self, index: usize) -> Result<i8, EmuError> {
+        Ok(self.get_code8(index)? as i8)
     }
 
-    pub fn get_code32(&self, index: usize) -> u32 {
+    pub fn get_code16(&self, index: usize) -> Result<u16, EmuError> {
+        Ok(self.get_code8(index)? as u16 | (self.get_code8(index + 1)? as u16) << 8)
+    }
+
+    pub fn get_signed_code16(&self, index: usize) -> Result<i16, EmuError> {
+        Ok(self.get_code16(index)? as i16)
+    }
+
+    pub fn get_code32(&self, index: usize) -> Result<u32, EmuError> {
         let mut ret: u32 = 0x0;
         // convert little endian to the correct byte order
         for i in 0..4 {
-            ret |= (self.get_code8(i + index) as u32) << (i * 8);
+            ret |= (self.get_code8(i + index)? as u32) << (i * 8);
         }
-        ret
+        Ok(ret)
     }
 
-    pub fn get_signed_code32(&self, index: usize) -> i32 {
-        let mut ret: i32 = 0x0;
+    pub fn get_signed_code32(&self, index: usize) -> Result<i32, EmuError> {
+        Ok(self.get_code32(index)? as i32)
+    }
+
+    // memory accesses take linear addresses. watchpoints are on linear addresses as well.
+    pub fn get_memory8(&self, address: u32) -> Result<u8, EmuError> {
+        self.check_watchpoints(address, Watch::Read);
+        self.read_physical8(self.translate(address, false)? & self.a20_mask)
+    }
+
+    pub fn get_memory16(&self, address: u32) -> Result<u16, EmuError> {
+        Ok(self.get_memory8(address)? as u16 | (self.get_memory8(address.wrapping_add(1))? as u16) << 8)
+    }
+
+    pub fn get_memory32(&self, address: u32) -> Result<u32, EmuError> {
+        let mut ret: u32 = 0x0;
         for i in 0..4 {
-            ret |= (self.get_code8(i + index) as i32) << (i * 8);
+            ret |= (self.get_memory8(address.wrapping_add(i))? as u32) << (i * 8);
         }
-        ret
+        Ok(ret)
     }
 
-    pub fn get_memory8(&self, address: u32) -> u8 {
-        self.memory[address as usize]
+    pub fn set_memory8(&mut self, address: u32, value: u32) -> Result<(), EmuError> {
+        self.check_watchpoints(address, Watch::Write);
+        let physical = self.translate(address, true)?;
+        self.update_page_entries()?;
+        self.write_physical8(physical & self.a20_mask, (value & 0xff) as u8)
     }
 
-    pub fn get_memory32(&self, address: u32) -> u32 {
-        let mut ret: u32 = 0x0;
+    // a write crossing into a page that faults must not change the first page either
+    fn check_page_crossing(&self, address: u32, size: u32) -> Result<(), EmuError> {
+        let last = address.wrapping_add(size - 1);
+        if self.is_paging() && last >> 12 != address >> 12 {
+            self.translate(last, true)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_memory16(&mut self, address: u32, value: u32) -> Result<(), EmuError> {
+        self.check_page_crossing(address, 2)?;
+        for i in 0..2 {
+            self.set_memory8(address.wrapping_add(i), value >> (i * 8))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_memory32(&mut self, address: u32, value: u32) -> Result<(), EmuError> {
+        self.check_page_crossing(address, 4)?;
         for i in 0..4 {
-            ret |= (self.get_memory8(address + i) as u32) << (i * 8);
+            self.set_memory8(address.wrapping_add(i), value >> (i * 8))?;
         }
+        Ok(())
+    }
+
+    // the virtual time devices run on, one unit per instruction
+    pub fn get_clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    pub fn attach_port_device(&mut self, ports: RangeInclusive<u16>, device: Box<dyn PortDevice>) {
+        self.io_bus.attach(ports, device);
+    }
+
+    pub fn io_in(&mut self, port: u16, size: u8) -> Result<u32, EmuError> {
+        self.io_bus.read(port, size)
+    }
+
+    pub fn io_out(&mut self, port: u16, size: u8, value: u32) -> Result<(), EmuError> {
+        self.io_bus.write(port, size, value)
+    }
+
+    // the size prefixes toggle the default size given by the D bit of CS
+    pub fn is_operand16(&self) -> bool {
+        self.prefix.operand_size == self.get_segment(&SREG::CS).is_big()
+    }
+
+    pub fn is_address16(&self) -> bool {
+        self.prefix.address_size == self.get_segment(&SREG::CS).is_big()
+    }
+
+    pub fn get_segment_override(&self) -> Option<SREG> {
+        self.prefix.segment
+    }
+
+    // run a decoder at another EIP, leaving the state of the instruction being executed untouched.
+    // page walks made while decoding are dropped again, so looking at code never sets accessed bits.
+    pub fn decode_at<T>(&mut self, eip: u32, decode: impl FnOnce(&mut Emulator) -> T) -> T {
+        let (saved_eip, saved_prefix) = (self.sp_reg.eip, self.prefix);
+        let (saved_tlb, saved_updates) = (self.tlb.borrow().clone(), self.page_updates.borrow().len());
+        self.sp_reg.eip = eip;
+        let ret = decode(self);
+        self.sp_reg.eip = saved_eip;
+        self.prefix = saved_prefix;
+        *self.tlb.get_mut() = saved_tlb;
+        self.page_updates.get_mut().truncate(saved_updates);
         ret
     }
 
-    pub fn set_memory8(&mut self, address: u32, value: u32) {
-        self.memory[address as usize] = (value & 0xff) as u8;
+    pub fn parse_prefix(&mut self) -> Result<(), EmuError> {
+        self.prefix = Prefix::default();
+        loop {
+            match self.get_code8(0)? {
+                0x26 => self.prefix.segment = Some(SREG::ES),
+                0x2E => self.prefix.segment = Some(SREG::CS),
+                0x36 => self.prefix.segment = Some(SREG::SS),
+                0x3E => self.prefix.segment = Some(SREG::DS),
+                0x64 => self.prefix.segment = Some(SREG::FS),
+                0x65 => self.prefix.segment = Some(SREG::GS),
+                0x66 => self.prefix.operand_size = true,
+                0x67 => self.prefix.address_size = true,
+                _ => return Ok(()),
+            }
+            self.inc_eip(1);
+        }
     }
 
-    pub fn set_memory32(&mut self, address: u32, value: u32) {
-        for i in 0..4 {
-            self.set_memory8(address + i, value >> (i * 8));
+    pub fn get_linear_eip(&self) -> u32 {
+        self.get_linear_address(&SREG::CS, self.sp_reg.eip)
+    }
+
+    // the run loop stops once the condition holds after an instruction
+    pub fn set_stop_condition(&mut self, condition: impl Fn(&Emulator) -> bool + 'static) {
+        self.stop_condition = Some(Box::new(condition));
+    }
+
+    pub fn clear_stop_condition(&mut self) {
+        self.stop_condition = None;
+    }
+
+    // breakpoints are linear addresses, reported when EIP arrives at one
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn get_breakpoints(&self) -> &BTreeSet<u32> {
+        &self.breakpoints
+    }
+
+    // watchpoints cover linear addresses and are reported after the accessing instruction
+    pub fn add_watchpoint(&mut self, address: u32, length: u32, kind: Watch) {
+        self.watchpoints.push(Watchpoint { address, length: length.max(1), kind });
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u32, length: u32, kind: Watch) -> bool {
+        let watchpoint = Watchpoint { address, length: length.max(1), kind };
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    // remember the first watchpoint an access hits until the step reports it
+    fn check_watchpoints(&self, address: u32, access: Watch) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        let hit = self.watchpoints.iter().find(|w| {
+            (w.kind == access || w.kind == Watch::Access) && address.wrapping_sub(w.address) < w.length
+        });
+        if let Some(w) = hit {
+            self.watch_hit.set(Some((w.kind, address)));
         }
     }
 
-    pub fn run(&mut self, instructions: InstructionVector) {
-        while self.sp_reg.eip < self.memory.len() as u32 {
-            let code = self.get_code8(0);
-            println!("eip: 0x{:x}, code: 0x{:x}", self.sp_reg.eip, code);
-            match instructions.0[code as usize] {
-                Some(instruction) =>  instruction(self),
-                _ => panic!("Not implemented: code 0x{:x}", code)     // TODO: error propagation
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    fn execute(&mut self, instructions: &InstructionVector) -> Result<(), EmuError> {
+        if self.trace {
+            // an instruction on a page that is not mapped is shown by the fault it raises
+            if let Ok(line) = disasm::disassemble(self, self.sp_reg.eip) {
+                println!("{}", line);
             }
-            if self.sp_reg.eip == 0x00 {
-                println!("End of program");
-                break;
+        }
+        self.instruction_start = self.sp_reg.eip;
+        let result = self.parse_prefix().and_then(|_| {
+            match instructions.0[self.get_code8(0)? as usize] {
+                Some(instruction) => instruction(self),
+                _ => Err(self.unimplemented(self.sp_reg.eip.wrapping_add(1))),
+            }
+        });
+        self.update_page_entries()?;
+        match result {
+            Err(EmuError::Exception(exception)) => self.deliver_exception(exception),
+            result => result,
+        }
+    }
+
+    // execute exactly one instruction. guest exceptions with a handler installed are delivered and count as progress.
+    pub fn step(&mut self, instructions: &InstructionVector) -> Step {
+        // drop hits left over from accesses made outside of the CPU, e.g. by a debugger
+        self.watch_hit.set(None);
+        self.clock.advance(1);
+        if let Err(err) = self.service_interrupt() {
+            return Step::Fault(err);
+        }
+        match self.execute(instructions) {
+            Ok(()) => {},
+            Err(EmuError::Halt) => return Step::Halt,
+            Err(EmuError::Exit(status)) => return Step::Exit(status),
+            Err(err) => return Step::Fault(err),
+        }
+        if self.stop_condition.as_ref().is_some_and(|condition| condition(self)) {
+            return Step::Stop;
+        }
+        if let Some((kind, address)) = self.watch_hit.take() {
+            return Step::Watchpoint(kind, address);
+        }
+        if self.breakpoints.contains(&self.get_linear_eip()) {
+            return Step::Breakpoint;
+        }
+        Step::Continue
+    }
+
+    // execute at most limit instructions; Step::Continue means the budget ran out
+    // HLT with interrupts enabled waits for one, as long as anything could raise it
+    pub fn run_until(&mut self, instructions: &InstructionVector, limit: u64) -> Step {
+        for _ in 0..limit {
+            match self.step(instructions) {
+                Step::Continue => {},
+                Step::Halt if self.can_wake() => self.wait_for_interrupt(),
+                step => return step,
+            }
+        }
+        Step::Continue
+    }
+
+    pub fn run(&mut self, instructions: &InstructionVector) -> Result<Step, EmuError> {
+        loop {
+            match self.run_until(instructions, u64::MAX) {
+                Step::Continue => {},
+                Step::Fault(err) => return Err(err),
+                step => return Ok(step),
             }
         }
     }
@@ -363,6 +856,11 @@ impl fmt::Debug for Emulator {
         f.debug_struct("Emulator")
             .field("reg_file", &self.reg_file)
             .field("sp_reg", &self.sp_reg)
+            .field("seg_reg", &self.seg_reg)
+            .field("cr", &self.cr)
+            .field("gdtr", &self.gdtr)
+            .field("idtr", &self.idtr)
+            .field("tr", &self.tr)
             //.field("memory", &self.memory)
             .finish()
     }
@@ -383,5 +881,125 @@ mod tests {
         emu.set_gpr(&GPR::EAX, 0xff);
         println!("{:?}", emu);
         assert_eq!(emu.reg_file.get(&GPR::EAX), Some(&0xff));
+
+        // accesses at the top of the address space wrap around instead of overflowing
+        emu.set_eip(0xffffffff);
+        assert_eq!(emu.get_code16(0), Err(EmuError::MemoryOutOfRange(0xffffffff)));
+        assert_eq!(emu.get_memory32(0xfffffffe), Err(EmuError::MemoryOutOfRange(0xfffffffe)));
+        assert_eq!(emu.set_memory16(0xffffffff, 0x1234), Err(EmuError::MemoryOutOfRange(0xffffffff)));
+    }
+
+    #[test]
+    fn top_of_memory_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x10000, 0x0, 0x8000);
+        emu.map_ram(0xfffff000, 0x1000);
+
+        // an unknown opcode in the last byte
+        emu.set_memory8(0xffffffff, 0xd6).unwrap();
+        emu.set_eip(0xffffffff);
+        assert_eq!(emu.step(&instructions), Step::Fault(EmuError::Unimplemented { address: 0xffffffff, bytes: vec![0xd6] }));
+
+        // call 0x10 from 0xfffffffb returns to 0x0
+        for (i, byte) in [0xe8, 0x10, 0x00, 0x00, 0x00].iter().enumerate() {
+            emu.set_memory8(0xfffffffb + i as u32, *byte).unwrap();
+        }
+        emu.set_eip(0xfffffffb);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x10);
+        assert_eq!(emu.get_memory32(0x7ffc), Ok(0x0));
+
+        // jmp far [0xfffffffc], with the selector wrapping around to 0x0
+        emu.load_bin(vec![0xff, 0x2d, 0xfc, 0xff, 0xff, 0xff], 0x10).unwrap();
+        emu.set_memory32(0xfffffffc, 0x200).unwrap();
+        emu.set_memory16(0x0, 0x0).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x200);
+    }
+
+    #[test]
+    fn prefix_test() {
+        let mut emu = Emulator::new(0x10, 0x0, 0x0);
+        emu.load_bin(vec![0x66, 0x67, 0x89, 0x90], 0x0).unwrap();
+        emu.parse_prefix().unwrap();
+        assert!(emu.is_operand16() && emu.is_address16());
+        assert_eq!(emu.get_eip(), 2);
+
+        emu.parse_prefix().unwrap();
+        assert!(!emu.is_operand16() && !emu.is_address16());
+        assert_eq!(emu.get_eip(), 2);
+
+        // the same prefixes select 32-bit operation in real mode
+        emu.set_real_mode();
+        emu.load_bin(vec![0x2e, 0x66, 0x8b, 0x00], 0x0).unwrap();
+        emu.set_eip(0x0);
+        emu.parse_prefix().unwrap();
+        assert!(!emu.is_operand16() && emu.is_address16());
+        assert_eq!(emu.get_data_segment(SREG::DS), SREG::CS);
+        assert_eq!(emu.get_eip(), 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn gpr8_test() {
+        let mut emu = Emulator::new(0x3, 0x0, 0x0);
+        emu.set_gpr(&GPR::EDX, 0x12345678);
+        assert_eq!(emu.get_gpr8_value(&GPR8::DL), 0x78);
+        assert_eq!(emu.get_gpr8_value(&GPR8::DH), 0x56);
+
+        emu.set_gpr8(&GPR8::DL, 0xaa);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), 0x123456aa);
+        emu.set_gpr8(&GPR8::DH, 0xbb);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), 0x1234bbaa);
+        assert_eq!(emu.get_gpr8_value(&GPR8::DH), 0xbb);
+    }
+
+    #[test]
+    fn step_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100, 0x0, 0x100);
+        // inc eax; inc eax; jmp short -4; hlt; salc
+        emu.load_bin(vec![0x40, 0x40, 0xeb, 0xfc, 0xf4, 0xd6], 0x0).unwrap();
+
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 1);
+        assert_eq!(emu.run_until(&instructions, 10), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 8);
+        assert_eq!(emu.get_eip(), 2);
+
+        // the breakpoint under EIP does not stop the first instruction
+        emu.add_breakpoint(0x2);
+        assert_eq!(emu.run(&instructions), Ok(Step::Breakpoint));
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 10);
+        assert!(emu.remove_breakpoint(0x2));
+
+        emu.set_stop_condition(|emu| emu.get_gpr_value(&GPR::EAX) == 12);
+        assert_eq!(emu.run(&instructions), Ok(Step::Stop));
+        emu.clear_stop_condition();
+
+        emu.set_eip(0x4);
+        assert_eq!(emu.step(&instructions), Step::Halt);
+        assert_eq!(emu.get_eip(), 5);
+        assert_eq!(emu.step(&instructions), Step::Fault(emu.unimplemented(0x6)));
+    }
+
+    #[test]
+    fn watchpoint_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100, 0x0, 0x100);
+        // mov [0x80], eax; mov eax, [0x82]; mov eax, [0x90]
+        emu.load_bin(vec![0x89, 0x05, 0x80, 0x00, 0x00, 0x00, 0x8b, 0x05, 0x82, 0x00, 0x00, 0x00, 0x8b, 0x05, 0x90, 0x00, 0x00, 0x00], 0x0).unwrap();
+        emu.add_watchpoint(0x82, 2, Watch::Write);
+        emu.add_watchpoint(0x83, 1, Watch::Access);
+        // tracing disassembles the code without reading it as data
+        emu.add_watchpoint(0x0, 0x12, Watch::Read);
+        emu.set_trace(true);
+
+        // reads from outside of the CPU are not reported
+        emu.get_memory8(0x83).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Watchpoint(Watch::Write, 0x82));
+        assert_eq!(emu.step(&instructions), Step::Watchpoint(Watch::Access, 0x83));
+        assert!(emu.remove_watchpoint(0x83, 1, Watch::Access));
+        assert!(!emu.remove_watchpoint(0x83, 1, Watch::Access));
+        assert_eq!(emu.step(&instructions), Step::Continue);
+    }
+}
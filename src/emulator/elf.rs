@@ -0,0 +1,315 @@
+//
+// ELF32 loader
+//
+use super::*;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_386: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SHN_UNDEF: u16 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+// a PT_LOAD segment as it was placed in memory
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct LoadSegment {
+    address: u32,
+    file_size: u32,
+    memory_size: u32,
+    executable: bool,
+}
+
+impl LoadSegment {
+    pub fn get_address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn get_file_size(&self) -> u32 {
+        self.file_size
+    }
+
+    pub fn get_memory_size(&self) -> u32 {
+        self.memory_size
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.executable
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Elf {
+    entry: u32,
+    segments: Vec<LoadSegment>,
+    code: Vec<(u32, u32)>,
+}
+
+impl Elf {
+    pub fn is_elf(image: &[u8]) -> bool {
+        image.starts_with(&ELF_MAGIC)
+    }
+
+    pub fn get_entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn get_segments(&self) -> &[LoadSegment] {
+        &self.segments
+    }
+
+    // address ranges of the executable sections, or of the executable segments in a stripped image
+    pub fn get_code(&self) -> &[(u32, u32)] {
+        &self.code
+    }
+}
+
+// little endian fields of the image, out of bounds reads are reported as a truncated image
+struct Image<'a>(&'a [u8]);
+
+impl Image<'_> {
+    fn bytes(&self, offset: u32, size: u32) -> Result<&[u8], EmuError> {
+        let start = offset as usize;
+        self.0.get(start..start + size as usize).ok_or_else(|| invalid("truncated image"))
+    }
+
+    fn u8(&self, offset: u32) -> Result<u8, EmuError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: u32) -> Result<u16, EmuError> {
+        let bytes = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&self, offset: u32) -> Result<u32, EmuError> {
+        let bytes = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // NUL-terminated string at offset in a string table
+    fn string(&self, table: u32, offset: u32) -> Result<String, EmuError> {
+        let start = table.checked_add(offset).ok_or_else(|| invalid("truncated image"))? as usize;
+        let bytes = self.0.get(start..).ok_or_else(|| invalid("truncated image"))?;
+        let end = bytes.iter().position(|byte| *byte == 0).ok_or_else(|| invalid("unterminated string"))?;
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+fn invalid(message: &str) -> EmuError {
+    EmuError::InvalidImage(message.to_string())
+}
+
+impl Emulator {
+    // map the PT_LOAD segments of an i386 executable, set EIP to its entry point and pick up its symbols.
+    // segments beyond the main RAM get a RAM region of their own.
+    pub fn load_elf(&mut self, image: &[u8]) -> Result<Elf, EmuError> {
+        let elf = Image(image);
+        if !Elf::is_elf(image) {
+            return Err(invalid("not an ELF image"));
+        }
+        if elf.u8(4)? != ELFCLASS32 || elf.u8(5)? != ELFDATA2LSB {
+            return Err(invalid("not a 32-bit little endian ELF image"));
+        }
+        if elf.u16(16)? != ET_EXEC || elf.u16(18)? != EM_386 {
+            return Err(invalid("not an i386 executable"));
+        }
+
+        let entry = elf.u32(24)?;
+        let (phoff, phentsize, phnum) = (elf.u32(28)?, elf.u16(42)? as u32, elf.u16(44)? as u32);
+        // header tables are checked up front so that the offsets computed below stay in the image
+        elf.bytes(phoff, phnum * phentsize)?;
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let header = phoff + i * phentsize;
+            if elf.u32(header)? != PT_LOAD {
+                continue;
+            }
+            let segment = LoadSegment {
+                address: elf.u32(header + 8)?,
+                file_size: elf.u32(header + 16)?,
+                memory_size: elf.u32(header + 20)?,
+                executable: elf.u32(header + 24)? & PF_X != 0,
+            };
+            if segment.file_size > segment.memory_size {
+                return Err(invalid("segment larger in the file than in memory"));
+            }
+            self.load_segment(&segment, elf.bytes(elf.u32(header + 4)?, segment.file_size)?)?;
+            segments.push(segment);
+        }
+
+        let (shoff, shentsize, shnum) = (elf.u32(32)?, elf.u16(46)? as u32, elf.u16(48)? as u32);
+        elf.bytes(shoff, shnum * shentsize)?;
+        let mut code = Vec::new();
+        for i in 0..shnum {
+            let header = shoff + i * shentsize;
+            if elf.u32(header + 8)? & SHF_EXECINSTR != 0 {
+                let address = elf.u32(header + 12)?;
+                code.push((address, address.wrapping_add(elf.u32(header + 20)?)));
+            }
+            if elf.u32(header + 4)? == SHT_SYMTAB {
+                let link = elf.u32(header + 24)?;
+                if link >= shnum {
+                    return Err(invalid("symbol table without a string table"));
+                }
+                let strtab = shoff + link * shentsize;
+                self.load_symbols(&elf, header, elf.u32(strtab + 16)?)?;
+            }
+        }
+
+        if code.is_empty() {
+            code = segments.iter()
+                .filter(|segment| segment.executable)
+                .map(|segment| (segment.address, segment.address.wrapping_add(segment.file_size)))
+                .collect();
+        }
+
+        self.set_eip(entry);
+        Ok(Elf { entry, segments, code })
+    }
+
+    fn load_segment(&mut self, segment: &LoadSegment, data: &[u8]) -> Result<(), EmuError> {
+        let (address, size) = (segment.address, segment.memory_size);
+        if size == 0 {
+            return Ok(());
+        }
+        let end = address.checked_add(size).ok_or_else(|| invalid("segment wraps around the address space"))?;
+        // back the segment with RAM from the first byte that is not in memory yet, which also covers
+        // a segment straddling the end of the main RAM
+        if let Some(unbacked) = (address..end).find(|&address| self.read_physical8(address).is_err()) {
+            self.map_ram(unbacked, end - unbacked);
+        }
+        // the part not backed by the file is .bss
+        for offset in 0..size {
+            let value = data.get(offset as usize).copied().unwrap_or(0);
+            self.write_physical8(address.wrapping_add(offset), value)?;
+        }
+        Ok(())
+    }
+
+    fn load_symbols(&mut self, elf: &Image, header: u32, strtab: u32) -> Result<(), EmuError> {
+        let (offset, size) = (elf.u32(header + 16)?, elf.u32(header + 20)?);
+        let entsize = elf.u32(header + 36)?.max(16);
+        elf.bytes(offset, size)?;
+        for symbol in (offset..offset + size).step_by(entsize as usize) {
+            let kind = elf.u8(symbol + 12)? & 0xf;
+            if elf.u16(symbol + 14)? == SHN_UNDEF || !matches!(kind, STT_OBJECT | STT_FUNC) {
+                continue;
+            }
+            let name = elf.string(strtab, elf.u32(symbol)?)?;
+            if !name.is_empty() {
+                self.add_symbol(elf.u32(symbol + 4)?, name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_symbol(&mut self, address: u32, name: String) {
+        self.symbols.insert(name, address);
+    }
+
+    pub fn find_symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    // name of a symbol placed exactly at address
+    pub fn get_symbol(&self, address: u32) -> Option<&str> {
+        self.symbols.iter().find(|(_, value)| **value == address).map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // ELF header, one program header and a section table holding a null section, .symtab and .strtab
+    pub(crate) fn build_elf(address: u32, code: &[u8], bss: u32) -> Vec<u8> {
+        let mut image = vec![0; 0x200];
+        let mut put = |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+        put(0x0, &ELF_MAGIC);
+        put(0x4, &[ELFCLASS32, ELFDATA2LSB, 1]);
+        put(16, &ET_EXEC.to_le_bytes());
+        put(18, &EM_386.to_le_bytes());
+        put(24, &(address + 5).to_le_bytes());
+        put(28, &0x34u32.to_le_bytes());
+        put(32, &0x100u32.to_le_bytes());
+        put(42, &0x20u16.to_le_bytes());
+        put(44, &1u16.to_le_bytes());
+        put(46, &0x28u16.to_le_bytes());
+        put(48, &3u16.to_le_bytes());
+
+        // PT_LOAD: the code at 0x80, followed by .bss
+        put(0x34, &PT_LOAD.to_le_bytes());
+        put(0x38, &0x80u32.to_le_bytes());
+        put(0x3c, &address.to_le_bytes());
+        put(0x44, &(code.len() as u32).to_le_bytes());
+        put(0x48, &(code.len() as u32 + bss).to_le_bytes());
+        put(0x4c, &(PF_X | 4).to_le_bytes());
+        put(0x80, code);
+
+        // .symtab at 0x180 linked to .strtab at 0x1c0
+        put(0x128 + 4, &SHT_SYMTAB.to_le_bytes());
+        put(0x128 + 16, &0x180u32.to_le_bytes());
+        put(0x128 + 20, &0x30u32.to_le_bytes());
+        put(0x128 + 24, &2u32.to_le_bytes());
+        put(0x128 + 36, &0x10u32.to_le_bytes());
+        put(0x150 + 4, &3u32.to_le_bytes());
+        put(0x150 + 16, &0x1c0u32.to_le_bytes());
+        put(0x150 + 20, &0x10u32.to_le_bytes());
+        put(0x1c0, b"\0add\0main\0");
+
+        // a null symbol, add and main
+        put(0x190, &1u32.to_le_bytes());
+        put(0x194, &address.to_le_bytes());
+        put(0x19c, &[STT_FUNC, 0, 1, 0]);
+        put(0x1a0, &5u32.to_le_bytes());
+        put(0x1a4, &(address + 5).to_le_bytes());
+        put(0x1ac, &[0x10 | STT_FUNC, 0, 1, 0]);
+        image
+    }
+
+    #[test]
+    fn load_elf_test() {
+        let mut emu = Emulator::new(0x10000, 0x0, 0x7c00);
+        // add: mov eax, 0x7 ... main: jmp add
+        let image = build_elf(0x8048000, &[0xb8, 0x07, 0x00, 0x00, 0x00, 0xeb, 0xf9], 0x10);
+        let elf = emu.load_elf(&image).unwrap();
+
+        assert_eq!(elf.get_entry(), 0x8048005);
+        assert_eq!(emu.get_eip(), 0x8048005);
+        assert_eq!(elf.get_segments().len(), 1);
+        assert!(elf.get_segments()[0].is_executable());
+        assert_eq!(elf.get_code(), [(0x8048000, 0x8048007)]);
+        assert_eq!(elf.get_segments()[0].get_memory_size(), 0x17);
+        assert_eq!(emu.get_memory32(0x8048000), Ok(0x7b8));
+        assert_eq!(emu.get_memory8(0x8048016), Ok(0x0));
+        assert!(emu.get_memory8(0x8048017).is_err());
+
+        assert_eq!(emu.find_symbol("main"), Some(0x8048005));
+        assert_eq!(emu.get_symbol(0x8048000), Some("add"));
+        assert_eq!(emu.get_symbol(0x8048001), None);
+
+        // a segment running past the end of the main RAM is mapped for its whole length
+        let mut emu = Emulator::new(0x10000, 0x0, 0x7c00);
+        emu.load_elf(&build_elf(0xfff8, &[0xb8, 0x07, 0x00, 0x00, 0x00, 0xeb, 0xf9], 0x10)).unwrap();
+        assert_eq!(emu.get_memory8(0xfffe), Ok(0xf9));
+        assert_eq!(emu.get_memory32(0x10004), Ok(0x0));
+        assert_eq!(emu.get_memory8(0x1000e), Ok(0x0));
+        assert!(emu.get_memory8(0x1000f).is_err());
+        assert_eq!(emu.load_elf(&build_elf(0xfffffff8, &[0x0], 0x10)),
+            Err(EmuError::InvalidImage("segment wraps around the address space".to_string())));
+
+        assert_eq!(emu.load_elf(&image[..0x40]), Err(EmuError::InvalidImage("truncated image".to_string())));
+        let mut image = image;
+        image[18] = 0x3e;
+        assert_eq!(emu.load_elf(&image), Err(EmuError::InvalidImage("not an i386 executable".to_string())));
+    }
+}
@@ -0,0 +1,83 @@
+//
+// Errors
+//
+use std::error;
+use std::io;
+
+use super::*;
+
+// reasons for the emulation to stop. guest-visible faults are carried as exceptions
+// so that they can be delivered to the guest before giving up.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum EmuError {
+    Unimplemented { address: u32, bytes: Vec<u8> },
+    MemoryOutOfRange(u32),
+    Decode(String),
+    InvalidImage(String),
+    Io { port: u16, kind: io::ErrorKind },
+    Console(io::ErrorKind),
+    Halt,
+    Exit(i32),
+    Exception(Exception),
+}
+
+impl From<Exception> for EmuError {
+    fn from(exception: Exception) -> Self {
+        EmuError::Exception(exception)
+    }
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::Unimplemented { address, bytes } => {
+                write!(f, "unimplemented instruction at {:#x}:", address)?;
+                for byte in bytes {
+                    write!(f, " {:02x}", byte)?;
+                }
+                Ok(())
+            },
+            EmuError::MemoryOutOfRange(address) => write!(f, "memory access out of range at {:#x}", address),
+            EmuError::Decode(message) => write!(f, "decode error: {}", message),
+            EmuError::InvalidImage(message) => write!(f, "invalid image: {}", message),
+            EmuError::Io { port, kind } => write!(f, "I/O error on port {:#x}: {}", port, kind),
+            EmuError::Console(kind) => write!(f, "console I/O error: {}", kind),
+            EmuError::Halt => write!(f, "CPU halted"),
+            EmuError::Exit(status) => write!(f, "program exited with status {}", status),
+            EmuError::Exception(exception) => write!(f, "unhandled exception {}", exception),
+        }
+    }
+}
+
+impl error::Error for EmuError {}
+
+impl Emulator {
+    // report the instruction starting at instruction_start up to (but not including) end
+    pub fn unimplemented(&self, end: u32) -> EmuError {
+        let bytes = (0..end.wrapping_sub(self.instruction_start))
+            .take(15)
+            .map(|i| self.instruction_start.wrapping_add(i))
+            .map_while(|offset| self.get_memory8(self.get_linear_address(&SREG::CS, offset)).ok())
+            .collect();
+        EmuError::Unimplemented {
+            address: self.get_linear_address(&SREG::CS, self.instruction_start),
+            bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_display_test() {
+        let mut emu = Emulator::new(0x100, 0x10, 0x0);
+        emu.load_bin(vec![0x66, 0x0f, 0x0b], 0x10).unwrap();
+        emu.instruction_start = 0x10;
+        assert_eq!(emu.unimplemented(0x13).to_string(), "unimplemented instruction at 0x10: 66 0f 0b");
+        assert_eq!(EmuError::from(Exception::GP(0)).to_string(), "unhandled exception #GP(0x0)");
+        assert_eq!(EmuError::Console(io::ErrorKind::BrokenPipe).to_string(), "console I/O error: broken pipe");
+        assert_eq!(emu.get_memory8(0x100), Err(EmuError::MemoryOutOfRange(0x100)));
+    }
+}
@@ -0,0 +1,413 @@
+//
+// Interrupts and exceptions
+//
+use super::*;
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Exception {
+    DE,         // divide error
+    UD,         // invalid opcode
+    TS(u32),    // invalid TSS
+    NP(u32),    // segment not present
+    SS(u32),    // stack-segment fault
+    GP(u32),    // general protection
+    PF(u32, u32),   // page fault: error code and linear address
+}
+
+impl Exception {
+    pub fn get_vector(&self) -> u8 {
+        match self {
+            Exception::DE => 0,
+            Exception::UD => 6,
+            Exception::TS(_) => 10,
+            Exception::NP(_) => 11,
+            Exception::SS(_) => 12,
+            Exception::GP(_) => 13,
+            Exception::PF(..) => 14,
+        }
+    }
+
+    pub fn get_error_code(&self) -> Option<u32> {
+        match self {
+            Exception::TS(code) | Exception::NP(code) | Exception::SS(code) | Exception::GP(code) | Exception::PF(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::DE => write!(f, "#DE"),
+            Exception::UD => write!(f, "#UD"),
+            Exception::TS(code) => write!(f, "#TS({:#x})", code),
+            Exception::NP(code) => write!(f, "#NP({:#x})", code),
+            Exception::SS(code) => write!(f, "#SS({:#x})", code),
+            Exception::GP(code) => write!(f, "#GP({:#x})", code),
+            Exception::PF(code, address) => write!(f, "#PF({:#x}) at {:#x}", code, address),
+        }
+    }
+}
+
+// software interrupts serviced on the host instead of through the guest IVT, like the
+// system calls of an OS personality or BIOS services. false hands the interrupt to the guest.
+pub trait InterruptHandler {
+    fn interrupt(&mut self, emu: &mut Emulator, vector: u8) -> Result<bool, EmuError>;
+}
+
+impl Emulator {
+    // a handler attached later shadows the overlapping part of an earlier one
+    pub fn attach_interrupt_handler(&mut self, vectors: RangeInclusive<u8>, handler: Box<dyn InterruptHandler>) {
+        self.interrupt_handlers.insert(0, (vectors, handler));
+    }
+
+    // INT n, INT3 and INTO: host handlers get the first say, the IVT or the IDT gets the rest
+    pub fn software_interrupt(&mut self, vector: u8) -> Result<(), EmuError> {
+        // the handlers are taken out while they run so that they can get at the whole emulator
+        let mut handlers = std::mem::take(&mut self.interrupt_handlers);
+        let result = match handlers.iter_mut().find(|(vectors, _)| vectors.contains(&vector)) {
+            Some((_, handler)) => handler.interrupt(self, vector),
+            None => Ok(false),
+        };
+        handlers.append(&mut self.interrupt_handlers);
+        self.interrupt_handlers = handlers;
+
+        if result? {
+            return Ok(());
+        }
+        if self.is_protected_mode() {
+            return self.interrupt_gate(vector, None, true);
+        }
+        self.interrupt(vector)
+    }
+
+    pub fn get_ivt_entry(&self, vector: u8) -> Result<(u16, u16), EmuError> {
+        let address = vector as u32 * 4;
+        Ok((self.get_memory16(address + 2)?, self.get_memory16(address)?))
+    }
+
+    pub fn set_ivt_entry(&mut self, vector: u8, segment: u16, offset: u16) -> Result<(), EmuError> {
+        let address = vector as u32 * 4;
+        self.set_memory16(address, offset as u32)?;
+        self.set_memory16(address + 2, segment as u32)
+    }
+
+    pub fn set_interrupt_controller(&mut self, controller: Box<dyn InterruptController>) {
+        self.interrupt_controller = Some(controller);
+    }
+
+    // STI holds off interrupts until the instruction after it is done, so that STI; HLT cannot miss one
+    pub fn hold_interrupts(&mut self) {
+        self.interrupt_shadow = true;
+    }
+
+    // INTR: the controller samples its lines between instructions, then a pending request is
+    // acknowledged and delivered like an INT without the DPL check
+    pub(super) fn service_interrupt(&mut self) -> Result<(), EmuError> {
+        let shadow = std::mem::take(&mut self.interrupt_shadow);
+        let enabled = !shadow && self.is_interrupt();
+        let controller = match self.interrupt_controller.as_mut() {
+            Some(controller) => controller,
+            None => return Ok(()),
+        };
+        if !controller.is_pending() || !enabled {
+            return Ok(());
+        }
+        let vector = match controller.acknowledge() {
+            Some(vector) => vector,
+            None => return Ok(()),
+        };
+        self.instruction_start = self.get_eip();
+        match self.interrupt(vector) {
+            Err(EmuError::Exception(exception)) => self.deliver_exception(exception),
+            result => result,
+        }
+    }
+
+    pub(super) fn can_wake(&self) -> bool {
+        self.is_interrupt() && self.interrupt_controller.as_ref().is_some_and(|controller| controller.is_armed())
+    }
+
+    // HLT: idle until the controller has a request for the next step to take. virtual time goes on
+    // as if instructions were running, so that timers fire at the same point on every run.
+    pub(super) fn wait_for_interrupt(&mut self) {
+        if let Some(controller) = self.interrupt_controller.as_mut() {
+            while !controller.is_pending() {
+                self.clock.advance(1);
+            }
+        }
+    }
+
+    // deliver an interrupt through the real-mode IVT.
+    // the frame follows the default operand size of CS, so that flat 32-bit code can IRET as usual.
+    pub fn interrupt(&mut self, vector: u8) -> Result<(), EmuError> {
+        if self.is_protected_mode() {
+            return self.interrupt_gate(vector, None, false);
+        }
+        let (segment, offset) = self.get_ivt_entry(vector)?;
+        let flags = self.get_eflags();
+        let cs = self.get_sreg_value(&SREG::CS);
+        let eip = self.get_eip();
+
+        if self.get_segment(&SREG::CS).is_big() {
+            self.push32(flags)?;
+            self.push32(cs as u32)?;
+            self.push32(eip)?;
+        } else {
+            self.push16(flags as u16)?;
+            self.push16(cs)?;
+            self.push16(eip as u16)?;
+        }
+        self.set_interrupt(false);
+        self.set_trap(false);
+
+        self.set_sreg(&SREG::CS, segment);
+        self.set_eip(offset as u32);
+        Ok(())
+    }
+
+    // a fault is delivered with EIP pointing back at the faulting instruction.
+    // the exception is handed back when the guest did not install a handler or the delivery itself faulted.
+    pub fn deliver_exception(&mut self, exception: Exception) -> Result<(), EmuError> {
+        self.set_eip(self.instruction_start);
+        if let Exception::PF(_, address) = exception {
+            self.cr[2] = address;
+        }
+        let result = if self.is_protected_mode() {
+            self.interrupt_gate(exception.get_vector(), exception.get_error_code(), false)
+        } else if self.get_ivt_entry(exception.get_vector())? == (0, 0) {
+            return Err(exception.into());
+        } else {
+            self.interrupt(exception.get_vector())
+        };
+        match result {
+            Err(EmuError::Exception(_)) => Err(exception.into()),
+            result => result,
+        }
+    }
+
+    // deliver an interrupt through an interrupt or trap gate of the protected-mode IDT.
+    // INT n from user code needs a gate DPL of at least CPL. a handler of an inner level runs on
+    // the stack given by the TSS, with the old SS:ESP saved on it. the frame size follows the gate.
+    fn interrupt_gate(&mut self, vector: u8, error_code: Option<u32>, software: bool) -> Result<(), EmuError> {
+        let error = Exception::GP(vector as u32 * 8 + 2);
+        let offset = vector as u32 * 8;
+        if offset + 7 > self.get_idtr().get_limit() as u32 {
+            return Err(error.into());
+        }
+        let address = self.get_idtr().get_base().wrapping_add(offset);
+        let gate = self.get_system32(address)? as u64 | (self.get_system32(address.wrapping_add(4))? as u64) << 32;
+        let access = (gate >> 40) as u8;
+        let big = match access & 0x1f {
+            0x06 | 0x07 => false,
+            0x0e | 0x0f => true,
+            _ => return Err(error.into()),
+        };
+        if software && access >> 5 & 0x3 < self.get_cpl() {
+            return Err(error.into());
+        }
+        if access & 0x80 == 0 {
+            return Err(Exception::NP(vector as u32 * 8 + 2).into());
+        }
+        let selector = (gate >> 16) as u16;
+        let target = (gate & 0xffff | (gate >> 48) << 16) as u32;
+
+        let flags = self.get_eflags();
+        let (cs, eip) = (self.get_sreg_value(&SREG::CS), self.get_eip());
+        let (ss, esp) = (self.get_sreg_value(&SREG::SS), self.get_gpr_value(&GPR::ESP));
+        self.transfer(|emu| {
+            let mut code = emu.fetch_code_segment(selector)?;
+            let (cpl, dpl) = (emu.get_cpl(), code.get_dpl());
+            if dpl > cpl {
+                return Err(Exception::GP(selector as u32 & 0xfffc).into());
+            }
+            if target > code.get_limit() {
+                return Err(Exception::GP(0).into());
+            }
+            if code.is_conforming() || dpl == cpl {
+                code.set_rpl(cpl);
+                emu.set_segment(&SREG::CS, code);
+            } else {
+                emu.switch_stack(dpl)?;
+                code.set_rpl(dpl);
+                emu.set_segment(&SREG::CS, code);
+                emu.push_value(ss as u32, big)?;
+                emu.push_value(esp, big)?;
+            }
+            emu.push_value(flags, big)?;
+            emu.push_value(cs as u32, big)?;
+            emu.push_value(eip, big)?;
+            error_code.map_or(Ok(()), |code| emu.push_value(code, big))?;
+            emu.set_eip(target);
+            Ok(())
+        })?;
+        // interrupt gates mask interrupts, trap gates leave IF alone
+        if access & 0x1 == 0 {
+            self.set_interrupt(false);
+        }
+        self.set_trap(false);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use crate::device::{Clock, IrqSource};
+    use crate::device::pic::{Pic, MASTER_PORTS};
+    use crate::emulator::system::{DescriptorTable, CR0_PE};
+    use crate::instruction::InstructionVector;
+
+    #[test]
+    fn interrupt_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        emu.set_ivt_entry(0x10, 0x1000, 0x0020).unwrap();
+        emu.set_eflags(0x202);
+        emu.set_sreg(&SREG::CS, 0x0700);
+
+        emu.interrupt(0x10).unwrap();
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x1000);
+        assert_eq!(emu.get_eip(), 0x20);
+        assert!(!emu.is_interrupt());
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7c00 - 6);
+        assert_eq!(emu.get_memory16(0x7c00 - 2), Ok(0x202));
+        assert_eq!(emu.get_memory16(0x7c00 - 4), Ok(0x0700));
+        assert_eq!(emu.get_memory16(0x7c00 - 6), Ok(0x7c00));
+    }
+
+    struct Counter(u32);
+
+    impl InterruptHandler for Counter {
+        fn interrupt(&mut self, emu: &mut Emulator, vector: u8) -> Result<bool, EmuError> {
+            if vector != 0x21 {
+                return Ok(false);
+            }
+            self.0 += 1;
+            emu.set_gpr(&GPR::EAX, self.0);
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn software_interrupt_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        emu.set_ivt_entry(0x20, 0x1000, 0x0020).unwrap();
+        emu.attach_interrupt_handler(0x20..=0x21, Box::new(Counter(0)));
+
+        emu.software_interrupt(0x21).unwrap();
+        emu.software_interrupt(0x21).unwrap();
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 2);
+        assert_eq!(emu.get_eip(), 0x7c00);
+
+        // declined by the handler, delivered through the IVT
+        emu.software_interrupt(0x20).unwrap();
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x1000);
+        assert_eq!(emu.get_eip(), 0x20);
+    }
+
+    #[test]
+    fn unhandled_exception_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        emu.instruction_start = 0x7c00;
+        emu.set_eip(0x7c02);
+
+        assert_eq!(emu.deliver_exception(Exception::UD), Err(EmuError::Exception(Exception::UD)));
+        assert_eq!(emu.get_eip(), 0x7c00);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7c00);
+    }
+
+    #[test]
+    fn interrupt_gate_test() {
+        let mut emu = Emulator::new(0x100000, 0x1000, 0x8000);
+        emu.set_real_mode();
+        emu.set_a20(true);
+        emu.map_ram(0xfffff000, 0x1000);
+        // null and flat ring 0 code and data
+        for (i, descriptor) in [0x0u64, 0x00cf9a000000ffff, 0x00cf92000000ffff].iter().enumerate() {
+            emu.set_memory32(0x500 + i as u32 * 8, *descriptor as u32).unwrap();
+            emu.set_memory32(0x504 + i as u32 * 8, (*descriptor >> 32) as u32).unwrap();
+        }
+        emu.set_gdtr(DescriptorTable::new(0x500, 0x17));
+        emu.set_cr(0, CR0_PE).unwrap();
+        emu.load_sreg(&SREG::SS, 0x10).unwrap();
+        emu.load_cs(0x8, 0x1000).unwrap();
+
+        // the gate for vector 0 ends at 0xffffffff and its upper half wraps around to 0x0
+        emu.set_memory32(0xfffffffc, 0x00082000).unwrap();
+        emu.set_memory32(0x0, 0x00008e00).unwrap();
+        emu.set_idtr(DescriptorTable::new(0xfffffffc, 0x7));
+        emu.interrupt(0x0).unwrap();
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x8);
+        assert_eq!(emu.get_eip(), 0x2000);
+        assert_eq!(emu.get_memory32(0x8000 - 8), Ok(0x8));
+    }
+
+    struct Line(Rc<Cell<bool>>);
+
+    impl IrqSource for Line {
+        fn is_asserted(&mut self) -> bool {
+            self.0.get()
+        }
+    }
+
+    struct Alarm(Clock, u64);
+
+    impl IrqSource for Alarm {
+        fn is_asserted(&mut self) -> bool {
+            self.0.now() >= self.1
+        }
+    }
+
+    #[test]
+    fn hardware_interrupt_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        let pic = Pic::new();
+        let line = Rc::new(Cell::new(false));
+        pic.attach_irq(0, Box::new(Line(line.clone())));
+        emu.attach_port_device(MASTER_PORTS, Box::new(pic.clone()));
+        emu.set_interrupt_controller(Box::new(pic.clone()));
+        emu.set_ivt_entry(0x08, 0x0000, 0x1000).unwrap();
+
+        // unmask IRQ0: mov al, 0xfe; out 0x21, al; sti; inc cx; inc cx
+        emu.load_bin(vec![0xb0, 0xfe, 0xe6, 0x21, 0xfb, 0x41, 0x41], 0x7c00).unwrap();
+        // mov al, 0x20; out 0x20, al; iret
+        emu.load_bin(vec![0xb0, 0x20, 0xe6, 0x20, 0xcf], 0x1000).unwrap();
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        line.set(true);
+
+        // nothing is taken with IF clear, nor right after STI
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x7c06);
+        assert_eq!(pic.get_irr(), 0x1);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x1002);
+        assert_eq!(pic.get_isr(), 0x1);
+        assert_eq!(emu.get_memory16(0x7c00 - 6), Ok(0x7c06));
+
+        // the EOI and the IRET, then the line has to drop and rise again
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        assert_eq!(pic.get_isr(), 0x0);
+        assert_eq!(emu.get_eip(), 0x7c06);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x7c07);
+
+        // HLT waits for IRQ1 to rise instead of stopping
+        let deadline = emu.get_clock().now() + 100;
+        pic.attach_irq(1, Box::new(Alarm(emu.get_clock(), deadline)));
+        emu.io_out(0x21, 1, 0xfc).unwrap();
+        emu.set_ivt_entry(0x09, 0x0000, 0x1000).unwrap();
+        emu.load_bin(vec![0xf4], 0x7c07).unwrap();
+        assert_eq!(emu.run_until(&instructions, 1), Step::Continue);
+        assert!(emu.get_clock().now() >= deadline);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x1002);
+        assert_eq!(pic.get_isr(), 0x2);
+    }
+}
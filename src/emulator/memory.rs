@@ -0,0 +1,142 @@
+//
+// Memory map
+//
+use super::*;
+use crate::device::MmioDevice;
+
+// what backs a mapped range of physical memory. addresses outside of every region go to the main RAM.
+pub enum Region {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Device(Box<dyn MmioDevice>),
+}
+
+impl Region {
+    fn read8(&self, offset: u32) -> u8 {
+        match self {
+            Region::Ram(data) | Region::Rom(data) => data[offset as usize],
+            Region::Device(device) => device.read8(offset),
+        }
+    }
+
+    fn write8(&mut self, offset: u32, value: u8) {
+        match self {
+            Region::Ram(data) => data[offset as usize] = value,
+            Region::Rom(_) => {},  // writes to ROM are dropped
+            Region::Device(device) => device.write8(offset, value),
+        }
+    }
+}
+
+pub struct MappedRegion {
+    base: u32,
+    size: u32,
+    region: Region,
+}
+
+impl MappedRegion {
+    fn contains(&self, address: u32) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+
+    fn overlaps(&self, base: u32, size: u32) -> bool {
+        (self.base as u64) < base as u64 + size as u64 && (base as u64) < self.base as u64 + self.size as u64
+    }
+}
+
+impl Emulator {
+    // a region mapped later shadows the overlapping part of an earlier one
+    fn map_region(&mut self, base: u32, size: u32, region: Region) {
+        assert!(size > 0);
+        self.memory_map.insert(0, MappedRegion { base, size, region });
+    }
+
+    pub fn map_ram(&mut self, base: u32, size: u32) {
+        self.map_region(base, size, Region::Ram(vec![0; size as usize]));
+    }
+
+    pub fn map_rom(&mut self, base: u32, image: Vec<u8>) {
+        self.map_region(base, image.len() as u32, Region::Rom(image));
+    }
+
+    pub fn map_device(&mut self, base: u32, size: u32, device: Box<dyn MmioDevice>) {
+        self.map_region(base, size, Region::Device(device));
+    }
+
+    pub fn unmap(&mut self, base: u32) {
+        self.memory_map.retain(|mapped| mapped.base != base);
+    }
+
+    // base and size of the mapped regions overlapping [base, base + size)
+    pub fn find_regions(&self, base: u32, size: u32) -> Vec<(u32, u32)> {
+        self.memory_map.iter()
+            .filter(|mapped| mapped.overlaps(base, size))
+            .map(|mapped| (mapped.base, mapped.size))
+            .collect()
+    }
+
+    pub(super) fn read_physical8(&self, address: u32) -> Result<u8, EmuError> {
+        match self.memory_map.iter().find(|mapped| mapped.contains(address)) {
+            Some(mapped) => Ok(mapped.region.read8(address - mapped.base)),
+            None => self.memory.get(address as usize).copied().ok_or(EmuError::MemoryOutOfRange(address)),
+        }
+    }
+
+    pub(super) fn write_physical8(&mut self, address: u32, value: u8) -> Result<(), EmuError> {
+        match self.memory_map.iter_mut().find(|mapped| mapped.contains(address)) {
+            Some(mapped) => mapped.region.write8(address - mapped.base, value),
+            None => {
+                let byte = self.memory.get_mut(address as usize).ok_or(EmuError::MemoryOutOfRange(address))?;
+                *byte = value;
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Screen(Rc<RefCell<Vec<u8>>>);
+
+    impl MmioDevice for Screen {
+        fn read8(&self, offset: u32) -> u8 {
+            self.0.borrow()[offset as usize]
+        }
+
+        fn write8(&mut self, offset: u32, value: u8) {
+            self.0.borrow_mut()[offset as usize] = value;
+        }
+    }
+
+    #[test]
+    fn memory_map_test() {
+        let mut emu = Emulator::new(0x100000, 0x0, 0x0);
+        let screen = Rc::new(RefCell::new(vec![0; 0x1000]));
+        emu.map_device(0xb8000, 0x1000, Box::new(Screen(screen.clone())));
+        emu.map_rom(0xf0000, vec![0xea, 0x5b, 0xe0, 0x00, 0xf0]);
+        emu.map_ram(0x100000, 0x1000);
+
+        emu.set_memory16(0xb8000, 0x0741).unwrap();
+        assert_eq!(screen.borrow()[0..2], [0x41, 0x07]);
+        assert_eq!(emu.get_memory8(0xb8001).unwrap(), 0x07);
+        assert_eq!(emu.memory[0xb8000], 0x0);
+
+        emu.set_memory32(0xf0000, 0xffffffff).unwrap();
+        assert_eq!(emu.get_memory32(0xf0001).unwrap(), 0xf000e05b);
+
+        // memory past the main RAM
+        emu.set_memory32(0x100ffc, 0x12345678).unwrap();
+        assert_eq!(emu.get_memory32(0x100ffc).unwrap(), 0x12345678);
+
+        assert_eq!(emu.find_regions(0xb8fff, 0x38001), vec![(0xf0000, 0x5), (0xb8000, 0x1000)]);
+        assert_eq!(emu.find_regions(0xb9000, 0x37000), vec![]);
+        emu.unmap(0xb8000);
+        emu.set_memory8(0xb8000, 0x42).unwrap();
+        assert_eq!(emu.memory[0xb8000], 0x42);
+        assert_eq!(screen.borrow()[0], 0x41);
+    }
+}
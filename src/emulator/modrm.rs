@@ -6,6 +6,7 @@ use super::*;
 #[derive(Debug)]
 pub struct Disp {
     disp8: Option<i8>,
+    disp16: Option<i16>,
     disp32: Option<i32>,
 }
 
@@ -19,21 +20,22 @@ pub struct ModRM {
 }
 
 impl ModRM {
-    pub fn new(emu: &mut Emulator) -> ModRM {
-        let code = emu.get_code8(0);
+    pub fn new(emu: &mut Emulator) -> Result<ModRM, EmuError> {
+        let code = emu.get_code8(0)?;
 
         emu.inc_eip(1);
 
-        ModRM {
+        Ok(ModRM {
             r#mod: (code & 0b11000000) >> 6,
             reg_bit: (code & 0b00111000) >> 3,
             rm: code & 0b00000111,
             sib: 0x0,
             disp: Disp {
                 disp8: None,
+                disp16: None,
                 disp32: None,
             },
-        }
+        })
     }
 
     pub fn get_mod(&self) -> u8 {
@@ -60,6 +62,10 @@ impl ModRM {
         self.disp.disp8
     }
 
+    pub fn get_disp16(&self) -> Option<i16> {
+        self.disp.disp16
+    }
+
     pub fn get_disp32(&self) -> Option<i32> {
         self.disp.disp32
     }
@@ -84,121 +90,389 @@ impl ModRM {
         self.disp.disp8 = Some(disp);
     }
 
+    pub fn set_disp16(&mut self, disp: i16) {
+        self.disp.disp16 = Some(disp);
+    }
+
     pub fn set_disp32(&mut self, disp: i32) {
         self.disp.disp32 = Some(disp);
     }
 
-    pub fn get_r32(&self, emu: &Emulator) -> u32 {
-        emu.get_gpr_value(emu.get_gpr_id(self.get_reg_index().into()).unwrap_or_else(|| {
-            panic!("Could not find the register specified by Mod/RM: {:#x?}", self);
-        }))
+    fn decode_gpr(&self, emu: &Emulator, id: u8) -> Result<GPR, EmuError> {
+        emu.get_gpr_id(id.into()).copied().ok_or_else(|| {
+            self.decode_error("Could not find the register specified by Mod/RM")
+        })
+    }
+
+    fn decode_gpr8(&self, emu: &Emulator, id: u8) -> Result<GPR8, EmuError> {
+        emu.get_gpr8_id(id.into()).map(|(reg8, _)| *reg8).ok_or_else(|| {
+            self.decode_error("Could not find the register specified by Mod/RM")
+        })
+    }
+
+    pub fn get_r32(&self, emu: &Emulator) -> Result<u32, EmuError> {
+        Ok(emu.get_gpr_value(&self.decode_gpr(emu, self.get_reg_index())?))
     }
 
-    pub fn get_rm32(&self, emu: &Emulator) -> u32 {
+    pub fn get_rm32(&self, emu: &Emulator) -> Result<u32, EmuError> {
         match self.get_mod() {
-            0b11 => emu.get_gpr_value(emu.get_gpr_id(self.get_rm().into()).unwrap_or_else(|| {
-                panic!("Could not find the register specified by Mod/RM: {:#x?}", self);
-            })),
-            _ => {
-                emu.get_memory32(self.calc_memory_address(emu) as u32)
-            }
+            0b11 => Ok(emu.get_gpr_value(&self.decode_gpr(emu, self.get_rm())?)),
+            _ => emu.get_memory32(self.calc_linear_address(emu, 4)?),
         }
     }
 
-    pub fn set_r32(&self, emu: &mut Emulator, new_value: u32) {
-        let reg = *emu.get_gpr_id(self.get_reg_index().into()).unwrap_or_else(|| {
-            panic!("Could not find the register specified by Mod/RM: {:#x?}", self);
-        });
+    pub fn set_r32(&self, emu: &mut Emulator, new_value: u32) -> Result<(), EmuError> {
+        let reg = self.decode_gpr(emu, self.get_reg_index())?;
         emu.set_gpr(&reg, new_value);
+        Ok(())
     }
 
-    pub fn set_rm32(&self, emu: &mut Emulator, value: u32) {
+    pub fn set_rm32(&self, emu: &mut Emulator, value: u32) -> Result<(), EmuError> {
         match self.get_mod() {
             0b11 => {
-                let reg = *emu.get_gpr_id(self.get_rm().into()).unwrap_or_else(|| {
-                    panic!("Could not find the register specified by Mod/RM: {:#x?}", self);
-                });
+                let reg = self.decode_gpr(emu, self.get_rm())?;
                 emu.set_gpr(&reg, value);
+                Ok(())
             },
-            _ => {
-                emu.set_memory32(self.calc_memory_address(emu) as u32, value);
-            }
+            _ => emu.set_memory32(self.calc_linear_address(emu, 4)?, value),
+        }
+    }
+
+    pub fn get_scale(&self) -> u8 {
+        (self.sib & 0b11000000) >> 6
+    }
+
+    pub fn get_index(&self) -> u8 {
+        (self.sib & 0b00111000) >> 3
+    }
+
+    pub fn get_base(&self) -> u8 {
+        self.sib & 0b00000111
+    }
+
+    pub fn get_r16(&self, emu: &Emulator) -> Result<u16, EmuError> {
+        Ok(emu.get_gpr16_value(&self.decode_gpr(emu, self.get_reg_index())?))
+    }
+
+    pub fn get_rm16(&self, emu: &Emulator) -> Result<u16, EmuError> {
+        match self.get_mod() {
+            0b11 => Ok(emu.get_gpr16_value(&self.decode_gpr(emu, self.get_rm())?)),
+            _ => emu.get_memory16(self.calc_linear_address(emu, 2)?),
+        }
+    }
+
+    pub fn set_r16(&self, emu: &mut Emulator, new_value: u16) -> Result<(), EmuError> {
+        let reg = self.decode_gpr(emu, self.get_reg_index())?;
+        emu.set_gpr16(&reg, new_value);
+        Ok(())
+    }
+
+    pub fn set_rm16(&self, emu: &mut Emulator, value: u16) -> Result<(), EmuError> {
+        match self.get_mod() {
+            0b11 => {
+                let reg = self.decode_gpr(emu, self.get_rm())?;
+                emu.set_gpr16(&reg, value);
+                Ok(())
+            },
+            _ => emu.set_memory16(self.calc_linear_address(emu, 2)?, value as u32),
+        }
+    }
+
+    pub fn get_r8(&self, emu: &Emulator) -> Result<u8, EmuError> {
+        Ok(emu.get_gpr8_value(&self.decode_gpr8(emu, self.get_reg_index())?))
+    }
+
+    pub fn get_rm8(&self, emu: &Emulator) -> Result<u8, EmuError> {
+        match self.get_mod() {
+            0b11 => Ok(emu.get_gpr8_value(&self.decode_gpr8(emu, self.get_rm())?)),
+            _ => emu.get_memory8(self.calc_linear_address(emu, 1)?),
+        }
+    }
+
+    pub fn set_r8(&self, emu: &mut Emulator, new_value: u8) -> Result<(), EmuError> {
+        let reg = self.decode_gpr8(emu, self.get_reg_index())?;
+        emu.set_gpr8(&reg, new_value);
+        Ok(())
+    }
+
+    pub fn set_rm8(&self, emu: &mut Emulator, value: u8) -> Result<(), EmuError> {
+        match self.get_mod() {
+            0b11 => {
+                let reg = self.decode_gpr8(emu, self.get_rm())?;
+                emu.set_gpr8(&reg, value);
+                Ok(())
+            },
+            _ => emu.set_memory8(self.calc_linear_address(emu, 1)?, value as u32),
         }
     }
 
-    pub fn parse_modrm(&mut self, emu: &mut Emulator) {
-        if self.get_mod() != 0b11 && self.get_rm() == 0b11 {
-            self.set_sib(emu.get_code8(0));
+    pub fn parse_modrm(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        if emu.is_address16() {
+            return self.parse_modrm16(emu);
+        }
+
+        if self.get_mod() != 0b11 && self.get_rm() == 0b100 {
+            self.set_sib(emu.get_code8(0)?);
             emu.inc_eip(1);
         }
 
-        if (self.get_mod() == 0b00 && self.get_rm() == 0b101) || self.get_mod() == 0b10 {
-            self.set_disp32(emu.get_signed_code32(0));
+        if (self.get_mod() == 0b00 && self.get_rm() == 0b101)
+            || (self.get_mod() == 0b00 && self.get_rm() == 0b100 && self.get_base() == 0b101)
+            || self.get_mod() == 0b10 {
+            self.set_disp32(emu.get_signed_code32(0)?);
             emu.inc_eip(4);
         } else if self.get_mod() == 0b01 {
-            self.set_disp8(emu.get_signed_code8(0));
+            self.set_disp8(emu.get_signed_code8(0)?);
+            emu.inc_eip(1);
+        }
+        Ok(())
+    }
+
+    fn parse_modrm16(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        if (self.get_mod() == 0b00 && self.get_rm() == 0b110) || self.get_mod() == 0b10 {
+            self.set_disp16(emu.get_signed_code16(0)?);
+            emu.inc_eip(2);
+        } else if self.get_mod() == 0b01 {
+            self.set_disp8(emu.get_signed_code8(0)?);
             emu.inc_eip(1);
         }
+        Ok(())
     }
 
-    pub fn calc_memory_address(&self, emu: &Emulator) -> i32 {
+    pub fn calc_linear_address(&self, emu: &Emulator, size: u32) -> Result<u32, EmuError> {
+        let segment = self.get_segment(emu);
+        let offset = self.calc_memory_address(emu)? as u32;
+        emu.check_limit(&segment, offset, size)?;
+        Ok(emu.get_linear_address(&segment, offset))
+    }
+
+    pub fn get_segment(&self, emu: &Emulator) -> SREG {
+        emu.get_data_segment(self.get_default_segment(emu))
+    }
+
+    // addressing through EBP/ESP (BP in 16-bit addressing) defaults to the stack segment
+    fn get_default_segment(&self, emu: &Emulator) -> SREG {
+        if emu.is_address16() {
+            return match (self.get_mod(), self.get_rm()) {
+                (_, 0b010) | (_, 0b011) => SREG::SS,
+                (0b01, 0b110) | (0b10, 0b110) => SREG::SS,
+                _ => SREG::DS,
+            };
+        }
+
+        match (self.get_mod(), self.get_rm()) {
+            (_, 0b100) => {
+                if self.get_base() == 0b100 || (self.get_base() == 0b101 && self.get_mod() != 0b00) {
+                    SREG::SS
+                } else {
+                    SREG::DS
+                }
+            },
+            (0b01, 0b101) | (0b10, 0b101) => SREG::SS,
+            _ => SREG::DS,
+        }
+    }
+
+    pub fn calc_memory_address(&self, emu: &Emulator) -> Result<i32, EmuError> {
+        if emu.is_address16() {
+            return self.calc_memory_address16(emu);
+        }
+
         match self.get_mod() {
             0b00 => {
                 match self.get_rm() {
                     0b100 => {
-                        // SIB
-                        panic!("Not implemented: {:#x?}", self);
+                        self.calc_sib_address(emu)
                     },
                     0b101 => {
-                        self.get_disp32().unwrap_or_else(|| {
-                            panic!("disp32 not found: {:?}", self);
-                        }) as i32
+                        self.get_disp32().ok_or_else(|| self.decode_error("disp32 not found"))
                     },
                     _ => {
-                        emu.get_gpr_value(emu.get_gpr_id(self.get_rm().into())
-                            .unwrap_or_else(|| {
-                                panic!("Could not find the register specified by Mod/RM: {:#x?}", self)
-                            })) as i32
+                        self.get_rm_base(emu)
                     },
                 }
             },
             0b01 => {
-                match self.get_rm() {
-                    0b100 => {
-                        // SIB
-                        panic!("Not implemented: {:#x?}", self);
-                    },
-                    _ => {
-                        emu.get_gpr_value(emu.get_gpr_id(self.get_rm().into())
-                            .unwrap_or_else(|| {
-                                panic!("Could not find the register specified by Mod/RM: {:#x?}", self);
-                            })) as i32
-                        + self.get_disp8().unwrap_or_else(|| {
-                            panic!("disp8 not found: {:#x?}", self);
-                        }) as i32
-                    },
-                }
+                let base = match self.get_rm() {
+                    0b100 => self.calc_sib_address(emu)?,
+                    _ => self.get_rm_base(emu)?,
+                };
+                let disp = self.get_disp8().ok_or_else(|| self.decode_error("disp8 not found"))?;
+                Ok(base.wrapping_add(disp as i32))
             },
             0b10 => {
-                match self.get_rm() {
-                    0b100 => {
-                        // SIB
-                        panic!("Not implemented: {:#x?}", self);
-                    }, 
-                    _ => {
-                        emu.get_gpr_value(emu.get_gpr_id(self.get_rm().into())
-                            .unwrap_or_else(|| {
-                                panic!("Could not find the register specified by Mod/RM: {:#x?}", self);
-                            })) as i32
-                        + self.get_disp32().unwrap_or_else(|| {
-                            panic!("disp32 not found: {:?}", self);
-                        }) as i32
-                    },
-                }
+                let base = match self.get_rm() {
+                    0b100 => self.calc_sib_address(emu)?,
+                    _ => self.get_rm_base(emu)?,
+                };
+                let disp = self.get_disp32().ok_or_else(|| self.decode_error("disp32 not found"))?;
+                Ok(base.wrapping_add(disp))
             }
             _ => {
-                panic!("Not implemented: {:#x?}", self);
+                Err(self.decode_error("Register operand used as a memory address"))
             }
         }
     }
-}
\ No newline at end of file
+
+    fn calc_memory_address16(&self, emu: &Emulator) -> Result<i32, EmuError> {
+        let bx = emu.get_gpr16_value(&GPR::EBX);
+        let bp = emu.get_gpr16_value(&GPR::EBP);
+        let si = emu.get_gpr16_value(&GPR::ESI);
+        let di = emu.get_gpr16_value(&GPR::EDI);
+
+        let base = match self.get_rm() {
+            0b000 => bx.wrapping_add(si),
+            0b001 => bx.wrapping_add(di),
+            0b010 => bp.wrapping_add(si),
+            0b011 => bp.wrapping_add(di),
+            0b100 => si,
+            0b101 => di,
+            0b110 => {
+                // mod == 0b00 means disp16 with no base register
+                if self.get_mod() == 0b00 {
+                    0
+                } else {
+                    bp
+                }
+            },
+            _ => bx,
+        };
+
+        let disp = match self.get_mod() {
+            0b00 => {
+                if self.get_rm() == 0b110 {
+                    self.get_disp16().ok_or_else(|| self.decode_error("disp16 not found"))?
+                } else {
+                    0
+                }
+            },
+            0b01 => {
+                self.get_disp8().ok_or_else(|| self.decode_error("disp8 not found"))? as i16
+            },
+            0b10 => {
+                self.get_disp16().ok_or_else(|| self.decode_error("disp16 not found"))?
+            },
+            _ => {
+                return Err(self.decode_error("Register operand used as a memory address"));
+            }
+        };
+
+        Ok(base.wrapping_add(disp as u16) as i32)
+    }
+
+    fn get_rm_base(&self, emu: &Emulator) -> Result<i32, EmuError> {
+        Ok(emu.get_gpr_value(&self.decode_gpr(emu, self.get_rm())?) as i32)
+    }
+
+    fn calc_sib_address(&self, emu: &Emulator) -> Result<i32, EmuError> {
+        // base == 0b101 with mod == 0b00 means disp32 with no base register
+        let base = if self.get_mod() == 0b00 && self.get_base() == 0b101 {
+            self.get_disp32().ok_or_else(|| self.decode_error("disp32 not found"))?
+        } else {
+            emu.get_gpr_value(&self.decode_gpr(emu, self.get_base())?) as i32
+        };
+
+        // index == 0b100 (ESP) means no index
+        let index = match self.get_index() {
+            0b100 => 0,
+            _ => emu.get_gpr_value(&self.decode_gpr(emu, self.get_index())?) as i32,
+        };
+
+        Ok(base.wrapping_add(index.wrapping_shl(self.get_scale() as u32)))
+    }
+
+    fn decode_error(&self, message: &str) -> EmuError {
+        EmuError::Decode(format!("{}: {:#x?}", message, self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(emu: &mut Emulator, code: &[u8]) -> ModRM {
+        emu.load_bin(code.to_vec(), 0x0).unwrap();
+        emu.set_eip(0x0);
+        let mut modrm = ModRM::new(emu).unwrap();
+        modrm.parse_modrm(emu).unwrap();
+        modrm
+    }
+
+    #[test]
+    fn sib_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        emu.set_gpr(&GPR::EAX, 0x10);
+        emu.set_gpr(&GPR::ECX, 0x3);
+
+        // [eax+ecx*4]
+        let modrm = decode(&mut emu, &[0x04, 0x88]);
+        assert_eq!(emu.get_eip(), 2);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x10 + 0x3 * 4);
+
+        // [esp+0x8]
+        let modrm = decode(&mut emu, &[0x44, 0x24, 0x08]);
+        assert_eq!(emu.get_eip(), 3);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x80 + 0x8);
+
+        // [ecx*8+0x20]
+        let modrm = decode(&mut emu, &[0x04, 0xcd, 0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(emu.get_eip(), 6);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x3 * 8 + 0x20);
+
+        // [eax+ecx*2-0x4]
+        let modrm = decode(&mut emu, &[0x84, 0x48, 0xfc, 0xff, 0xff, 0xff]);
+        assert_eq!(emu.get_eip(), 6);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x10 + 0x3 * 2 - 0x4);
+    }
+
+    #[test]
+    fn modrm16_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        emu.load_bin(vec![0x67], 0x0).unwrap();
+        emu.parse_prefix().unwrap();
+        emu.set_gpr(&GPR::EBX, 0x10010);
+        emu.set_gpr(&GPR::ESI, 0x4);
+        emu.set_gpr(&GPR::EBP, 0xfff0);
+
+        // [bx+si]
+        let modrm = decode(&mut emu, &[0x00]);
+        assert_eq!(emu.get_eip(), 1);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x14);
+
+        // [0x1234]
+        let modrm = decode(&mut emu, &[0x06, 0x34, 0x12]);
+        assert_eq!(emu.get_eip(), 3);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x1234);
+
+        // [bp+si+0x20] wraps around within 64KiB
+        let modrm = decode(&mut emu, &[0x42, 0x20]);
+        assert_eq!(emu.get_eip(), 2);
+        assert_eq!(modrm.calc_memory_address(&emu).unwrap(), 0x14);
+        assert_eq!(modrm.get_segment(&emu), SREG::SS);
+    }
+
+    #[test]
+    fn segment_test() {
+        let mut emu = Emulator::new(0x100000, 0x0, 0x0);
+        emu.set_real_mode();
+        emu.set_sreg(&SREG::DS, 0x100);
+        emu.set_sreg(&SREG::SS, 0x200);
+        emu.set_sreg(&SREG::ES, 0x300);
+        emu.set_gpr(&GPR::EBP, 0x10);
+
+        // [bp+0x2] defaults to SS
+        let modrm = decode(&mut emu, &[0x46, 0x02]);
+        assert_eq!(modrm.calc_linear_address(&emu, 1).unwrap(), 0x2012);
+
+        // [0x20] defaults to DS
+        let modrm = decode(&mut emu, &[0x06, 0x20, 0x00]);
+        assert_eq!(modrm.calc_linear_address(&emu, 1).unwrap(), 0x1020);
+
+        // es: [bp+0x2]
+        emu.load_bin(vec![0x26], 0x0).unwrap();
+        emu.set_eip(0x0);
+        emu.parse_prefix().unwrap();
+        let modrm = decode(&mut emu, &[0x46, 0x02]);
+        assert_eq!(modrm.calc_linear_address(&emu, 1).unwrap(), 0x3012);
+    }
+}
@@ -0,0 +1,219 @@
+//
+// Paging
+//
+use super::*;
+use super::system::{CR0_PG, CR0_WP, CR4_PSE};
+
+const PAGE_PRESENT: u32 = 0x01;
+const PAGE_WRITABLE: u32 = 0x02;
+const PAGE_USER: u32 = 0x04;
+const PAGE_ACCESSED: u32 = 0x20;
+const PAGE_DIRTY: u32 = 0x40;
+const PAGE_LARGE: u32 = 0x80;
+
+// #PF error code bits
+const FAULT_PROTECTION: u32 = 0x1;
+const FAULT_WRITE: u32 = 0x2;
+const FAULT_USER: u32 = 0x4;
+
+// cached result of a page walk
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Translation {
+    frame: u32,
+    writable: bool,
+    user: bool,
+    dirty: bool,
+}
+
+impl Emulator {
+    pub fn is_paging(&self) -> bool {
+        self.cr[0] & CR0_PG != 0
+    }
+
+    // linear to physical address of an access from the current privilege level
+    pub fn translate(&self, linear: u32, write: bool) -> Result<u32, EmuError> {
+        if !self.is_paging() {
+            return Ok(linear);
+        }
+        let user = self.get_cpl() == 3 && !self.supervisor.get();
+        let page = linear >> 12;
+        let hit = self.tlb.borrow().get(&page).copied();
+        let translation = match hit {
+            Some(translation) if self.is_permitted(&translation, write, user) && (!write || translation.dirty) => translation,
+            _ => self.walk(linear, write, user)?,
+        };
+        Ok(translation.frame | linear & 0xfff)
+    }
+
+    fn is_permitted(&self, translation: &Translation, write: bool, user: bool) -> bool {
+        if user {
+            translation.user && (!write || translation.writable)
+        } else {
+            // supervisor writes ignore read-only pages unless CR0.WP is set
+            !write || translation.writable || self.cr[0] & CR0_WP == 0
+        }
+    }
+
+    // walk the page directory and the page table, then fill the TLB.
+    // the accessed and dirty bits are written back once the instruction is done.
+    fn walk(&self, linear: u32, write: bool, user: bool) -> Result<Translation, EmuError> {
+        let fault = |code: u32| {
+            let code = code | if write { FAULT_WRITE } else { 0 } | if user { FAULT_USER } else { 0 };
+            EmuError::from(Exception::PF(code, linear))
+        };
+
+        let pde_address = self.cr[3] & 0xfffff000 | (linear >> 22) << 2;
+        let pde = self.read_entry(pde_address)?;
+        if pde & PAGE_PRESENT == 0 {
+            return Err(fault(0));
+        }
+        let (entry, pte, frame) = if pde & PAGE_LARGE != 0 && self.cr[4] & CR4_PSE != 0 {
+            (pde_address, pde, pde & 0xffc00000 | linear & 0x3ff000)
+        } else {
+            let pte_address = pde & 0xfffff000 | (linear >> 12 & 0x3ff) << 2;
+            let pte = self.read_entry(pte_address)?;
+            if pte & PAGE_PRESENT == 0 {
+                return Err(fault(0));
+            }
+            (pte_address, pte, pte & 0xfffff000)
+        };
+
+        let translation = Translation {
+            frame,
+            writable: pde & pte & PAGE_WRITABLE != 0,
+            user: pde & pte & PAGE_USER != 0,
+            dirty: write || pte & PAGE_DIRTY != 0,
+        };
+        if !self.is_permitted(&translation, write, user) {
+            return Err(fault(FAULT_PROTECTION));
+        }
+
+        let mut updates = self.page_updates.borrow_mut();
+        if entry != pde_address && pde & PAGE_ACCESSED == 0 {
+            updates.push((pde_address, PAGE_ACCESSED));
+        }
+        let bits = if write { PAGE_ACCESSED | PAGE_DIRTY } else { PAGE_ACCESSED };
+        if pte & bits != bits {
+            updates.push((entry, bits));
+        }
+        self.tlb.borrow_mut().insert(linear >> 12, translation);
+        Ok(translation)
+    }
+
+    fn read_entry(&self, address: u32) -> Result<u32, EmuError> {
+        let mut ret = 0;
+        for i in 0..4 {
+            ret |= (self.read_physical8((address + i) & self.a20_mask)? as u32) << (i * 8);
+        }
+        Ok(ret)
+    }
+
+    // set the accessed and dirty bits left by the page walks
+    pub(super) fn update_page_entries(&mut self) -> Result<(), EmuError> {
+        let updates = std::mem::take(self.page_updates.get_mut());
+        for (address, bits) in updates {
+            let address = address & self.a20_mask;
+            let value = self.read_physical8(address)?;
+            self.write_physical8(address, value | bits as u8)?;
+        }
+        Ok(())
+    }
+
+    // the GDT, the IDT and the TSS are accessed with supervisor rights whatever the CPL
+    pub fn get_system8(&self, address: u32) -> Result<u8, EmuError> {
+        self.supervisor.set(true);
+        let ret = self.get_memory8(address);
+        self.supervisor.set(false);
+        ret
+    }
+
+    pub fn get_system16(&self, address: u32) -> Result<u16, EmuError> {
+        Ok(self.get_system8(address)? as u16 | (self.get_system8(address.wrapping_add(1))? as u16) << 8)
+    }
+
+    pub fn get_system32(&self, address: u32) -> Result<u32, EmuError> {
+        Ok(self.get_system16(address)? as u32 | (self.get_system16(address.wrapping_add(2))? as u32) << 16)
+    }
+
+    pub fn set_system8(&mut self, address: u32, value: u8) -> Result<(), EmuError> {
+        self.supervisor.set(true);
+        let ret = self.set_memory8(address, value as u32);
+        self.supervisor.set(false);
+        ret
+    }
+
+    pub fn flush_tlb(&mut self) {
+        self.tlb.get_mut().clear();
+    }
+
+    // INVLPG: drop the translation of a single page
+    pub fn invalidate_page(&mut self, linear: u32) {
+        self.tlb.get_mut().remove(&(linear >> 12));
+    }
+
+    pub fn get_tlb_entry(&self, linear: u32) -> Option<Translation> {
+        self.tlb.borrow().get(&(linear >> 12)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::system::CR0_PE;
+    use crate::instruction::InstructionVector;
+
+    #[test]
+    fn paging_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100000, 0x0, 0x8000);
+        emu.set_real_mode();
+        emu.set_segment(&SREG::CS, Segment::new(0x8, 0x0, 0xffffffff, true));
+        emu.set_segment(&SREG::DS, Segment::new(0x10, 0x0, 0xffffffff, true));
+        emu.set_segment(&SREG::SS, Segment::new(0x10, 0x0, 0xffffffff, true));
+        emu.set_a20(true);
+        // the first 64KiB identity mapped, 0x400000 on 0x20000 and 0x401000 read-only on 0x21000
+        emu.set_memory32(0x1000, 0x2003).unwrap();
+        emu.set_memory32(0x1004, 0x3003).unwrap();
+        for page in 0..0x10 {
+            emu.set_memory32(0x2000 + page * 4, page << 12 | 0x3).unwrap();
+        }
+        emu.set_memory32(0x3000, 0x20003).unwrap();
+        emu.set_memory32(0x3004, 0x21001).unwrap();
+        emu.set_cr(3, 0x1000).unwrap();
+        emu.set_cr(0, CR0_PE | CR0_PG).unwrap();
+
+        // mov dword [0x400010], 0x12345678; mov eax, [0x401000]; invlpg [0x400000]; mov [0x402000], eax
+        emu.load_bin(vec![
+            0xc7, 0x05, 0x10, 0x00, 0x40, 0x00, 0x78, 0x56, 0x34, 0x12,
+            0x8b, 0x05, 0x00, 0x10, 0x40, 0x00,
+            0x0f, 0x01, 0x3d, 0x00, 0x00, 0x40, 0x00,
+            0x89, 0x05, 0x00, 0x20, 0x40, 0x00,
+        ], 0x0).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.translate(0x400010, false), Ok(0x20010));
+        assert_eq!(emu.get_memory32(0x400010), Ok(0x12345678));
+        assert_eq!(emu.get_memory8(0x1000), Ok(0x23));
+        assert_eq!(emu.get_memory8(0x3000), Ok(0x63));
+        assert_eq!(emu.get_memory8(0x2000), Ok(0x23));
+
+        // a stale translation survives page table changes until INVLPG
+        emu.set_memory32(0x3000, 0x22003).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_memory8(0x3004), Ok(0x21));
+        assert_eq!(emu.translate(0x400000, false), Ok(0x20000));
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_tlb_entry(0x400000), None);
+        assert_eq!(emu.translate(0x400000, false), Ok(0x22000));
+
+        // not present page
+        assert_eq!(emu.step(&instructions), Step::Fault(Exception::PF(0x2, 0x402000).into()));
+        assert_eq!(emu.get_cr(2), Some(0x402000));
+        assert_eq!(emu.get_eip(), 0x17);
+
+        // a read-only page is writable by the supervisor until CR0.WP is set
+        emu.set_memory8(0x401000, 0x1).unwrap();
+        emu.set_cr(0, CR0_PE | CR0_PG | CR0_WP).unwrap();
+        assert_eq!(emu.get_tlb_entry(0x401000), None);
+        assert_eq!(emu.set_memory8(0x401000, 0x1), Err(Exception::PF(0x3, 0x401000).into()));
+    }
+}
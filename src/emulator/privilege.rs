@@ -0,0 +1,449 @@
+//
+// Privilege levels and the TSS
+//
+use super::*;
+
+const TSS16_AVAILABLE: u8 = 0x01;
+const TSS32_AVAILABLE: u8 = 0x09;
+const TSS_BUSY: u8 = 0x02;
+const CALL_GATE16: u8 = 0x04;
+const CALL_GATE32: u8 = 0x0c;
+
+// the offset of the I/O permission bitmap in a 32-bit TSS
+const IO_MAP_BASE: u32 = 0x66;
+
+// a call gate names the entry point of more privileged code
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct CallGate {
+    selector: u16,
+    offset: u32,
+    params: u32,
+    big: bool,
+}
+
+impl Emulator {
+    pub fn get_iopl(&self) -> u8 {
+        ((self.get_eflags() & IOPL_MASK) >> 12) as u8
+    }
+
+    // instructions reserved to ring 0, like HLT or the loads of system registers
+    pub fn check_cpl0(&self) -> Result<(), EmuError> {
+        if self.get_cpl() != 0 {
+            return Err(Exception::GP(0).into());
+        }
+        Ok(())
+    }
+
+    // CLI and STI in protected mode need a CPL up to IOPL
+    pub fn check_iopl(&self) -> Result<(), EmuError> {
+        if self.get_cpl() > self.get_iopl() {
+            return Err(Exception::GP(0).into());
+        }
+        Ok(())
+    }
+
+    // port I/O from a CPL above IOPL is left to the I/O permission bitmap of the TSS
+    pub fn check_io_permission(&self, port: u16, size: u8) -> Result<(), EmuError> {
+        if self.get_cpl() <= self.get_iopl() {
+            return Ok(());
+        }
+        let tss = self.tr;
+        let error = Exception::GP(0);
+        if !tss.is_present() || tss.get_access() & 0x1f != TSS32_AVAILABLE | TSS_BUSY || tss.get_limit() < IO_MAP_BASE + 1 {
+            return Err(error.into());
+        }
+        let offset = self.get_system16(tss.get_base().wrapping_add(IO_MAP_BASE))? as u32 + port as u32 / 8;
+        if offset + 1 > tss.get_limit() {
+            return Err(error.into());
+        }
+        let bits = self.get_system16(tss.get_base().wrapping_add(offset))? >> (port % 8);
+        if bits & ((1 << size) - 1) != 0 {
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    pub fn get_tr(&self) -> &Segment {
+        &self.tr
+    }
+
+    // LTR: the selector has to name an available TSS, which is marked busy
+    pub fn load_tr(&mut self, selector: u16) -> Result<(), EmuError> {
+        if selector & 0xfffc == 0 {
+            return Err(Exception::GP(0).into());
+        }
+        let segment = Segment::from_descriptor(selector, self.get_descriptor(selector)?);
+        let kind = segment.get_access() & 0x1f;
+        if kind != TSS16_AVAILABLE && kind != TSS32_AVAILABLE {
+            return Err(Exception::GP(selector as u32 & 0xfffc).into());
+        }
+        if !segment.is_present() {
+            return Err(Exception::NP(selector as u32 & 0xfffc).into());
+        }
+        self.set_descriptor_flags(selector, TSS_BUSY)?;
+        self.tr = Segment::from_descriptor(selector, self.get_descriptor(selector)?);
+        Ok(())
+    }
+
+    // SS:ESP of an inner privilege level as found in the TSS
+    fn get_tss_stack(&self, dpl: u8) -> Result<(u16, u32), EmuError> {
+        let tss = self.tr;
+        let error = Exception::TS(tss.get_selector() as u32 & 0xfffc);
+        if !tss.is_present() {
+            return Err(error.into());
+        }
+        // ESPn and SSn follow each other from offset 4, or SPn and SSn from offset 2 in a 16-bit TSS
+        if tss.get_access() & 0x8 != 0 {
+            let offset = 4 + dpl as u32 * 8;
+            if offset + 5 > tss.get_limit() {
+                return Err(error.into());
+            }
+            let address = tss.get_base().wrapping_add(offset);
+            Ok((self.get_system16(address.wrapping_add(4))?, self.get_system32(address)?))
+        } else {
+            let offset = 2 + dpl as u32 * 4;
+            if offset + 3 > tss.get_limit() {
+                return Err(error.into());
+            }
+            let address = tss.get_base().wrapping_add(offset);
+            Ok((self.get_system16(address.wrapping_add(2))?, self.get_system16(address)? as u32))
+        }
+    }
+
+    // move to the stack of an inner level. a bad stack in the TSS raises #TS.
+    pub(super) fn switch_stack(&mut self, dpl: u8) -> Result<(), EmuError> {
+        let (ss, esp) = self.get_tss_stack(dpl)?;
+        let segment = self.fetch_segment(&SREG::SS, ss, dpl).map_err(|err| match err {
+            EmuError::Exception(_) => Exception::TS(ss as u32 & 0xfffc).into(),
+            err => err,
+        })?;
+        self.set_segment(&SREG::SS, segment);
+        self.set_sp(esp);
+        Ok(())
+    }
+
+    // run a control transfer, putting the registers back when it faults halfway
+    pub(super) fn transfer(&mut self, f: impl FnOnce(&mut Emulator) -> Result<(), EmuError>) -> Result<(), EmuError> {
+        let (seg_reg, esp, eip) = (self.seg_reg.clone(), self.get_gpr_value(&GPR::ESP), self.get_eip());
+        let result = f(self);
+        if result.is_err() {
+            self.seg_reg = seg_reg;
+            self.set_gpr(&GPR::ESP, esp);
+            self.set_eip(eip);
+        }
+        result
+    }
+
+    pub(super) fn push_value(&mut self, value: u32, big: bool) -> Result<(), EmuError> {
+        if big {
+            self.push32(value)
+        } else {
+            self.push16(value as u16)
+        }
+    }
+
+    fn pop_value(&mut self, big: bool) -> Result<u32, EmuError> {
+        if big {
+            self.pop32()
+        } else {
+            Ok(self.pop16()? as u32)
+        }
+    }
+
+    // the call gate a far CALL or JMP goes through, if the selector names one
+    fn get_call_gate(&self, selector: u16) -> Result<Option<CallGate>, EmuError> {
+        if !self.is_protected_mode() || selector & 0xfffc == 0 {
+            return Ok(None);
+        }
+        let descriptor = self.get_descriptor(selector)?;
+        let access = (descriptor >> 40) as u8;
+        if access & 0x10 != 0 {
+            return Ok(None);
+        }
+        let error = Exception::GP(selector as u32 & 0xfffc);
+        let big = match access & 0x1f {
+            CALL_GATE16 => false,
+            CALL_GATE32 => true,
+            _ => return Err(error.into()),
+        };
+        let dpl = access >> 5 & 0x3;
+        if dpl < self.get_cpl() || dpl < (selector & 0x3) as u8 {
+            return Err(error.into());
+        }
+        if access & 0x80 == 0 {
+            return Err(Exception::NP(selector as u32 & 0xfffc).into());
+        }
+        let offset = (descriptor & 0xffff) as u32;
+        Ok(Some(CallGate {
+            selector: (descriptor >> 16) as u16,
+            offset: if big { offset | ((descriptor >> 48) as u32) << 16 } else { offset },
+            params: (descriptor >> 32) as u32 & 0x1f,
+            big,
+        }))
+    }
+
+    // far JMP. a call gate only leads to code at the current privilege level.
+    pub fn jump_far(&mut self, selector: u16, offset: u32) -> Result<(), EmuError> {
+        let gate = match self.get_call_gate(selector)? {
+            Some(gate) => gate,
+            None => return self.load_cs(selector, offset),
+        };
+        let mut code = self.fetch_code_segment(gate.selector)?;
+        let cpl = self.get_cpl();
+        if code.is_conforming() && code.get_dpl() > cpl || !code.is_conforming() && code.get_dpl() != cpl {
+            return Err(Exception::GP(gate.selector as u32 & 0xfffc).into());
+        }
+        if gate.offset > code.get_limit() {
+            return Err(Exception::GP(0).into());
+        }
+        code.set_rpl(cpl);
+        self.set_segment(&SREG::CS, code);
+        self.set_eip(gate.offset);
+        Ok(())
+    }
+
+    // far CALL. a call gate to more privileged code switches to the stack of that level,
+    // which gets the old stack pointer and a copy of the parameters.
+    pub fn call_far(&mut self, selector: u16, offset: u32) -> Result<(), EmuError> {
+        let big = !self.is_operand16();
+        self.transfer(|emu| {
+            let (cs, eip) = (emu.get_sreg_value(&SREG::CS), emu.get_eip());
+            let gate = match emu.get_call_gate(selector)? {
+                Some(gate) => gate,
+                None => {
+                    emu.push_value(cs as u32, big)?;
+                    emu.push_value(eip, big)?;
+                    return emu.load_cs(selector, offset);
+                },
+            };
+
+            let mut code = emu.fetch_code_segment(gate.selector)?;
+            let (cpl, dpl) = (emu.get_cpl(), code.get_dpl());
+            if dpl > cpl {
+                return Err(Exception::GP(gate.selector as u32 & 0xfffc).into());
+            }
+            if gate.offset > code.get_limit() {
+                return Err(Exception::GP(0).into());
+            }
+            if code.is_conforming() || dpl == cpl {
+                code.set_rpl(cpl);
+                emu.set_segment(&SREG::CS, code);
+            } else {
+                let (ss, esp) = (emu.get_sreg_value(&SREG::SS), emu.get_sp());
+                let size = if gate.big { 4 } else { 2 };
+                let mut params = Vec::new();
+                for i in 0..gate.params {
+                    let offset = esp.wrapping_add(i * size);
+                    emu.check_limit(&SREG::SS, offset, size)?;
+                    let address = emu.get_linear_address(&SREG::SS, offset);
+                    params.push(if gate.big { emu.get_memory32(address)? } else { emu.get_memory16(address)? as u32 });
+                }
+                emu.switch_stack(dpl)?;
+                code.set_rpl(dpl);
+                emu.set_segment(&SREG::CS, code);
+                emu.push_value(ss as u32, gate.big)?;
+                emu.push_value(esp, gate.big)?;
+                for param in params.iter().rev() {
+                    emu.push_value(*param, gate.big)?;
+                }
+            }
+            emu.push_value(cs as u32, gate.big)?;
+            emu.push_value(eip, gate.big)?;
+            emu.set_eip(gate.offset);
+            Ok(())
+        })
+    }
+
+    // far RET, releasing bytes of parameters. a return to an outer level pops its stack pointer as well.
+    pub fn return_far(&mut self, release: u32) -> Result<(), EmuError> {
+        let big = !self.is_operand16();
+        self.transfer(|emu| {
+            let eip = emu.pop_value(big)?;
+            let cs = emu.pop_value(big)? as u16;
+            emu.set_sp(emu.get_sp().wrapping_add(release));
+            if !emu.is_outer_return(cs)? {
+                return emu.load_cs(cs, eip);
+            }
+            let esp = emu.pop_value(big)?;
+            let ss = emu.pop_value(big)? as u16;
+            emu.return_outer(cs, eip, ss, esp.wrapping_add(release))
+        })
+    }
+
+    // IRET. the flags are restored with the privileges of the interrupted handler.
+    pub fn interrupt_return(&mut self) -> Result<(), EmuError> {
+        let big = !self.is_operand16();
+        let cpl = self.get_cpl();
+        self.transfer(|emu| {
+            let eip = emu.pop_value(big)?;
+            let cs = emu.pop_value(big)? as u16;
+            let flags = emu.pop_value(big)?;
+            if emu.is_outer_return(cs)? {
+                let esp = emu.pop_value(big)?;
+                let ss = emu.pop_value(big)? as u16;
+                emu.return_outer(cs, eip, ss, esp)?;
+            } else {
+                emu.load_cs(cs, eip)?;
+            }
+            emu.load_eflags(flags, !big, cpl);
+            Ok(())
+        })
+    }
+
+    // returns go to the same or an outer level, never to an inner one
+    fn is_outer_return(&self, cs: u16) -> Result<bool, EmuError> {
+        if !self.is_protected_mode() {
+            return Ok(false);
+        }
+        let (rpl, cpl) = ((cs & 0x3) as u8, self.get_cpl());
+        if rpl < cpl {
+            return Err(Exception::GP(cs as u32 & 0xfffc).into());
+        }
+        Ok(rpl > cpl)
+    }
+
+    fn return_outer(&mut self, cs: u16, eip: u32, ss: u16, esp: u32) -> Result<(), EmuError> {
+        let rpl = (cs & 0x3) as u8;
+        let code = self.fetch_code_segment(cs)?;
+        if code.is_conforming() && code.get_dpl() > rpl || !code.is_conforming() && code.get_dpl() != rpl {
+            return Err(Exception::GP(cs as u32 & 0xfffc).into());
+        }
+        if eip > code.get_limit() {
+            return Err(Exception::GP(0).into());
+        }
+        let stack = self.fetch_segment(&SREG::SS, ss, rpl)?;
+        self.set_segment(&SREG::CS, code);
+        self.set_eip(eip);
+        self.set_segment(&SREG::SS, stack);
+        self.set_sp(esp);
+
+        // data segments of the inner level must not stay reachable from the outer one
+        for reg in [SREG::ES, SREG::DS, SREG::FS, SREG::GS] {
+            let segment = *self.get_segment(&reg);
+            if segment.is_present() && !segment.is_conforming() && segment.get_dpl() < rpl {
+                self.set_segment(&reg, Segment::null(0x0));
+            }
+        }
+        Ok(())
+    }
+
+    // POPF and IRET: IOPL only changes in ring 0 and IF only at a CPL up to IOPL
+    pub fn load_eflags(&mut self, value: u32, size16: bool, cpl: u8) {
+        let mut keep = if size16 { 0xffff0000 } else { 0x0 };
+        if self.is_protected_mode() && cpl > 0 {
+            keep |= IOPL_MASK;
+        }
+        if self.is_protected_mode() && cpl > self.get_iopl() {
+            keep |= INTERRUPT_FLAG;
+        }
+        self.set_eflags(self.get_eflags() & keep | value & !keep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::system::{DescriptorTable, CR0_PE};
+    use crate::instruction::InstructionVector;
+
+    #[test]
+    fn ring_transition_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100000, 0x1000, 0x8000);
+        emu.set_real_mode();
+        // null, ring 0 code and data, ring 3 code and data, the TSS, a call gate to 0x8:0x3000
+        let gdt: [u64; 7] = [
+            0x0,
+            0x00cf9a000000ffff,
+            0x00cf92000000ffff,
+            0x00cffa000000ffff,
+            0x00cff2000000ffff,
+            0x00008900600000e8,
+            0x0000ec0000083000,
+        ];
+        for (i, descriptor) in gdt.iter().enumerate() {
+            emu.set_memory32(0x500 + i as u32 * 8, *descriptor as u32).unwrap();
+            emu.set_memory32(0x504 + i as u32 * 8, (*descriptor >> 32) as u32).unwrap();
+        }
+        emu.set_gdtr(DescriptorTable::new(0x500, 0x37));
+        // int 0x80 reaches a ring 0 interrupt gate at 0x8:0x2000 that user code may call
+        emu.set_memory32(0x7400, 0x00082000).unwrap();
+        emu.set_memory32(0x7404, 0x0000ee00).unwrap();
+        emu.set_idtr(DescriptorTable::new(0x7000, 0x7ff));
+        // ESP0 and SS0, and an I/O bitmap that allows ports 0x3f8-0x3ff
+        emu.set_memory32(0x6004, 0x9000).unwrap();
+        emu.set_memory32(0x6008, 0x10).unwrap();
+        emu.set_memory16(0x6066, 0x68).unwrap();
+        for offset in 0x68..0xe8 {
+            emu.set_memory8(0x6000 + offset, 0xff).unwrap();
+        }
+        emu.set_memory8(0x6000 + 0x68 + 0x7f, 0x00).unwrap();
+        emu.set_cr(0, CR0_PE).unwrap();
+        emu.load_sreg(&SREG::SS, 0x10).unwrap();
+        emu.load_sreg(&SREG::DS, 0x10).unwrap();
+        emu.load_cs(0x8, 0x1000).unwrap();
+
+        // ltr ax; str cx; push 0x23; push 0x7000; push 0x1b; push 0x1100; retf
+        emu.set_gpr(&GPR::EAX, 0x28);
+        emu.load_bin(vec![
+            0x0f, 0x00, 0xd8,
+            0x0f, 0x00, 0xc9,
+            0x6a, 0x23,
+            0x68, 0x00, 0x70, 0x00, 0x00,
+            0x6a, 0x1b,
+            0x68, 0x00, 0x11, 0x00, 0x00,
+            0xcb,
+        ], 0x1000).unwrap();
+        assert_eq!(emu.run_until(&instructions, 7), Step::Continue);
+        assert_eq!(emu.get_tr().get_selector(), 0x28);
+        assert_eq!(emu.get_memory8(0x52d), Ok(0x8b));
+        assert_eq!(emu.get_gpr_value(&GPR::ECX), 0x28);
+        assert_eq!(emu.get_cpl(), 3);
+        assert_eq!(emu.get_sreg_value(&SREG::SS), 0x23);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7000);
+        // DS held ring 0 data
+        assert!(!emu.get_segment(&SREG::DS).is_present());
+
+        // out 0x3f8, al; out 0x80, al; cli; int 0x80
+        emu.set_gpr(&GPR::EDX, 0x3f8);
+        emu.load_bin(vec![0xee, 0xe6, 0x80, 0xfa, 0xcd, 0x80], 0x1100).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.step(&instructions), Step::Fault(Exception::GP(0).into()));
+        emu.set_eip(0x1103);
+        assert_eq!(emu.step(&instructions), Step::Fault(Exception::GP(0).into()));
+        emu.set_eip(0x1104);
+
+        // the handler runs on the ring 0 stack: iret
+        emu.set_eflags(0x202);
+        emu.load_bin(vec![0xcf], 0x2000).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_cpl(), 0);
+        assert_eq!(emu.get_eip(), 0x2000);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x9000 - 20);
+        assert_eq!(emu.get_memory32(0x9000 - 4), Ok(0x23));
+        assert_eq!(emu.get_memory32(0x9000 - 8), Ok(0x7000));
+        assert_eq!(emu.get_memory32(0x9000 - 16), Ok(0x1b));
+        assert!(!emu.is_interrupt());
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_cpl(), 3);
+        assert_eq!(emu.get_eip(), 0x1106);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7000);
+        assert!(emu.is_interrupt());
+
+        // push 0x5; call 0x33:0x0 through the call gate, which copies no parameters; hlt
+        emu.load_bin(vec![0x6a, 0x05, 0x9a, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0xf4], 0x1106).unwrap();
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        assert_eq!(emu.get_cpl(), 0);
+        assert_eq!(emu.get_eip(), 0x3000);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x9000 - 16);
+        assert_eq!(emu.get_memory32(0x9000 - 12), Ok(0x1b));
+        assert_eq!(emu.get_memory32(0x9000 - 16), Ok(0x110f));
+
+        // retf back to ring 3, then hlt is privileged
+        emu.load_bin(vec![0xcb], 0x3000).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_cpl(), 3);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7000 - 4);
+        assert_eq!(emu.step(&instructions), Step::Fault(Exception::GP(0).into()));
+    }
+}
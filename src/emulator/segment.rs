@@ -0,0 +1,403 @@
+//
+// Segmentation
+//
+use super::*;
+use super::system::CR0_ET;
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum SREG {
+    ES = 0,
+    CS = 1,
+    SS = 2,
+    DS = 3,
+    FS = 4,
+    GS = 5,
+}
+
+// access rights byte of a descriptor: P, DPL, S and the type
+const ACCESS_PRESENT: u8 = 0x80;
+const ACCESS_SEGMENT: u8 = 0x10;
+const ACCESS_CODE: u8 = 0x08;
+const ACCESS_CONFORMING: u8 = 0x04;    // expand-down for data segments
+const ACCESS_READABLE: u8 = 0x02;      // writable for data segments
+
+// present, writable data at DPL 0, as loaded in real mode
+const ACCESS_DEFAULT: u8 = 0x93;
+
+// visible selector plus the hidden descriptor cache loaded along with it
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct Segment {
+    selector: u16,
+    base: u32,
+    limit: u32,
+    access: u8,
+    big: bool,  // D/B bit: 32-bit default operand/address size (CS) or stack size (SS)
+}
+
+impl Segment {
+    pub fn new(selector: u16, base: u32, limit: u32, big: bool) -> Segment {
+        Segment { selector, base, limit, access: ACCESS_DEFAULT, big }
+    }
+
+    // what a null selector loads into a data segment register: unusable until reloaded
+    pub fn null(selector: u16) -> Segment {
+        Segment { selector, base: 0, limit: 0, access: 0, big: false }
+    }
+
+    // unpack a GDT descriptor. the limit is scaled to bytes when the G bit is set.
+    pub fn from_descriptor(selector: u16, descriptor: u64) -> Segment {
+        let base = (descriptor >> 16 & 0xffffff | (descriptor >> 56) << 24) as u32;
+        let limit = (descriptor & 0xffff | (descriptor >> 48 & 0xf) << 16) as u32;
+        let limit = if descriptor & 1 << 55 != 0 { limit << 12 | 0xfff } else { limit };
+        let access = (descriptor >> 40) as u8;
+        Segment { selector, base, limit, access, big: descriptor & 1 << 54 != 0 }
+    }
+
+    pub fn get_selector(&self) -> u16 {
+        self.selector
+    }
+
+    pub fn set_rpl(&mut self, rpl: u8) {
+        self.selector = self.selector & 0xfffc | rpl as u16;
+    }
+
+    pub fn get_base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn get_limit(&self) -> u32 {
+        self.limit
+    }
+
+    pub fn get_access(&self) -> u8 {
+        self.access
+    }
+
+    pub fn get_dpl(&self) -> u8 {
+        self.access >> 5 & 0x3
+    }
+
+    pub fn is_big(&self) -> bool {
+        self.big
+    }
+
+    // a null selector leaves a data segment register unusable
+    pub fn is_present(&self) -> bool {
+        self.access & ACCESS_PRESENT != 0
+    }
+
+    // code and data segments, as opposed to system descriptors like gates and TSSs
+    pub fn is_segment(&self) -> bool {
+        self.access & ACCESS_SEGMENT != 0
+    }
+
+    pub fn is_code(&self) -> bool {
+        self.is_segment() && self.access & ACCESS_CODE != 0
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.is_segment() && self.access & ACCESS_CODE == 0
+    }
+
+    pub fn is_conforming(&self) -> bool {
+        self.is_code() && self.access & ACCESS_CONFORMING != 0
+    }
+
+    pub fn is_expand_down(&self) -> bool {
+        self.is_data() && self.access & ACCESS_CONFORMING != 0
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.is_data() || self.is_code() && self.access & ACCESS_READABLE != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.is_data() && self.access & ACCESS_READABLE != 0
+    }
+}
+
+impl Emulator {
+    pub fn get_sreg_id(&self, reg: u32) -> Option<&SREG> {
+        match reg {
+            v if v == SREG::ES as u32 => {
+                Some(&SREG::ES)
+            }
+            v if v == SREG::CS as u32 => {
+                Some(&SREG::CS)
+            }
+            v if v == SREG::SS as u32 => {
+                Some(&SREG::SS)
+            }
+            v if v == SREG::DS as u32 => {
+                Some(&SREG::DS)
+            }
+            v if v == SREG::FS as u32 => {
+                Some(&SREG::FS)
+            }
+            v if v == SREG::GS as u32 => {
+                Some(&SREG::GS)
+            }
+            _ => {
+                None
+            }
+        }
+    }
+
+    pub fn get_segment(&self, reg: &SREG) -> &Segment {
+        self.seg_reg.get(reg).unwrap_or_else(|| {
+            panic!("Could not find the segment register specified by: {:#x?}", reg);
+        })
+    }
+
+    pub fn set_segment(&mut self, reg: &SREG, segment: Segment) {
+        self.seg_reg.insert(*reg, segment);
+    }
+
+    pub fn get_sreg_value(&self, reg: &SREG) -> u16 {
+        self.get_segment(reg).selector
+    }
+
+    // real-mode segment load: the base follows the selector, the rest of the cache is kept
+    pub fn set_sreg(&mut self, reg: &SREG, selector: u16) {
+        let mut segment = *self.get_segment(reg);
+        segment.selector = selector;
+        segment.base = (selector as u32) << 4;
+        self.set_segment(reg, segment);
+    }
+
+    // segment load by an instruction. protected mode reads the descriptor from the GDT and checks it first.
+    pub fn load_sreg(&mut self, reg: &SREG, selector: u16) -> Result<(), EmuError> {
+        let segment = self.fetch_segment(reg, selector, self.get_cpl())?;
+        self.set_segment(reg, segment);
+        Ok(())
+    }
+
+    // far transfer within the current privilege level. the offset has to be within the new code segment.
+    pub fn load_cs(&mut self, selector: u16, offset: u32) -> Result<(), EmuError> {
+        let segment = self.fetch_segment(&SREG::CS, selector, self.get_cpl())?;
+        if offset > segment.limit {
+            return Err(Exception::GP(0).into());
+        }
+        self.set_segment(&SREG::CS, segment);
+        self.set_eip(offset);
+        Ok(())
+    }
+
+    // the descriptor cache a selector loads at the given privilege level
+    pub(super) fn fetch_segment(&mut self, reg: &SREG, selector: u16, cpl: u8) -> Result<Segment, EmuError> {
+        if !self.is_protected_mode() {
+            let mut segment = *self.get_segment(reg);
+            segment.selector = selector;
+            segment.base = (selector as u32) << 4;
+            return Ok(segment);
+        }
+
+        let error = Exception::GP(selector as u32 & 0xfffc);
+        if selector & 0xfffc == 0 {
+            return match reg {
+                SREG::CS | SREG::SS => Err(Exception::GP(0).into()),
+                _ => Ok(Segment::null(selector)),
+            };
+        }
+        let mut segment = Segment::from_descriptor(selector, self.get_descriptor(selector)?);
+        let (rpl, dpl) = ((selector & 0x3) as u8, segment.get_dpl());
+        let not_present = match reg {
+            SREG::CS => {
+                if !segment.is_code() || segment.is_conforming() && dpl > cpl
+                    || !segment.is_conforming() && (rpl > cpl || dpl != cpl) {
+                    return Err(error.into());
+                }
+                // the CPL does not change through a conforming segment
+                segment.selector = selector & 0xfffc | cpl as u16;
+                Exception::NP(selector as u32 & 0xfffc)
+            },
+            SREG::SS => {
+                if !segment.is_writable() || rpl != cpl || dpl != cpl {
+                    return Err(error.into());
+                }
+                Exception::SS(selector as u32 & 0xfffc)
+            },
+            _ => {
+                if !segment.is_readable() || !segment.is_conforming() && (rpl > dpl || cpl > dpl) {
+                    return Err(error.into());
+                }
+                Exception::NP(selector as u32 & 0xfffc)
+            },
+        };
+        if !segment.is_present() {
+            return Err(not_present.into());
+        }
+        self.set_descriptor_flags(selector, 0x1)?;
+        segment.access |= 0x1;
+        Ok(segment)
+    }
+
+    // the target of a control transfer that may change the privilege level, which the caller checks
+    pub(super) fn fetch_code_segment(&mut self, selector: u16) -> Result<Segment, EmuError> {
+        if selector & 0xfffc == 0 {
+            return Err(Exception::GP(0).into());
+        }
+        let mut segment = Segment::from_descriptor(selector, self.get_descriptor(selector)?);
+        if !segment.is_code() {
+            return Err(Exception::GP(selector as u32 & 0xfffc).into());
+        }
+        if !segment.is_present() {
+            return Err(Exception::NP(selector as u32 & 0xfffc).into());
+        }
+        self.set_descriptor_flags(selector, 0x1)?;
+        segment.access |= 0x1;
+        Ok(segment)
+    }
+
+    pub fn get_linear_address(&self, reg: &SREG, offset: u32) -> u32 {
+        self.get_segment(reg).base.wrapping_add(offset)
+    }
+
+    // segment used for a memory operand: the override prefix if any, the default otherwise
+    pub fn get_data_segment(&self, default: SREG) -> SREG {
+        self.prefix.segment.unwrap_or(default)
+    }
+
+    pub fn is_a20_enabled(&self) -> bool {
+        self.a20_mask == 0xffffffff
+    }
+
+    pub fn set_a20(&mut self, enabled: bool) {
+        // with the A20 line masked, addresses past 1 MiB wrap around to 0 like on the 8086
+        self.a20_mask = if enabled { 0xffffffff } else { !(1 << 20) };
+    }
+
+    // switch to the state of a CPU right after reset: 16-bit segments based at 0, A20 masked,
+    // protection off and the IDT at the real-mode IVT
+    pub fn set_real_mode(&mut self) {
+        for reg in [SREG::ES, SREG::CS, SREG::SS, SREG::DS, SREG::FS, SREG::GS] {
+            self.set_segment(&reg, Segment::new(0x0, 0x0, 0xffff, false));
+        }
+        self.cr = [CR0_ET, 0, 0, 0, 0];
+        self.set_gdtr(DescriptorTable::new(0x0, 0x0));
+        self.set_idtr(DescriptorTable::new(0x0, 0x3ff));
+        self.tr = Segment::null(0x0);
+        self.set_a20(false);
+    }
+
+    pub fn get_sp(&self) -> u32 {
+        if self.get_segment(&SREG::SS).big {
+            self.get_gpr_value(&GPR::ESP)
+        } else {
+            self.get_gpr_value(&GPR::ESP) & 0xffff
+        }
+    }
+
+    // the stack pointer wraps around within 64KiB on a 16-bit stack
+    fn wrap_sp(&self, sp: u32) -> u32 {
+        if self.get_segment(&SREG::SS).big {
+            sp
+        } else {
+            sp & 0xffff
+        }
+    }
+
+    pub fn set_sp(&mut self, new_value: u32) {
+        if self.get_segment(&SREG::SS).big {
+            self.set_gpr(&GPR::ESP, new_value);
+        } else {
+            self.set_gpr16(&GPR::ESP, new_value as u16);
+        }
+    }
+
+    // limit check on the offset of an access of the given size.
+    // expand-down segments cover the offsets above the limit instead.
+    pub fn check_limit(&self, reg: &SREG, offset: u32, size: u32) -> Result<(), Exception> {
+        let segment = self.get_segment(reg);
+        let last = offset.wrapping_add(size - 1);
+        let valid = if !segment.is_present() {
+            false
+        } else if segment.is_expand_down() {
+            let upper = if segment.big { 0xffffffff } else { 0xffff };
+            offset > segment.limit && last >= offset && last <= upper
+        } else {
+            last <= segment.limit
+        };
+        if !valid {
+            return match reg {
+                SREG::SS => Err(Exception::SS(0)),
+                _ => Err(Exception::GP(0)),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn push16(&mut self, value: u16) -> Result<(), EmuError> {
+        let sp = self.wrap_sp(self.get_sp().wrapping_sub(2));
+        self.check_limit(&SREG::SS, sp, 2)?;
+        self.set_sp(sp);
+        let address = self.get_linear_address(&SREG::SS, sp);
+        self.set_memory16(address, value as u32)
+    }
+
+    pub fn push32(&mut self, value: u32) -> Result<(), EmuError> {
+        let sp = self.wrap_sp(self.get_sp().wrapping_sub(4));
+        self.check_limit(&SREG::SS, sp, 4)?;
+        self.set_sp(sp);
+        let address = self.get_linear_address(&SREG::SS, sp);
+        self.set_memory32(address, value)
+    }
+
+    pub fn pop16(&mut self) -> Result<u16, EmuError> {
+        let sp = self.get_sp();
+        self.check_limit(&SREG::SS, sp, 2)?;
+        let ret = self.get_memory16(self.get_linear_address(&SREG::SS, sp))?;
+        self.set_sp(sp.wrapping_add(2));
+        Ok(ret)
+    }
+
+    pub fn pop32(&mut self) -> Result<u32, EmuError> {
+        let sp = self.get_sp();
+        self.check_limit(&SREG::SS, sp, 4)?;
+        let ret = self.get_memory32(self.get_linear_address(&SREG::SS, sp))?;
+        self.set_sp(sp.wrapping_add(4));
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn real_mode_address_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        assert!(!emu.is_a20_enabled());
+
+        emu.set_sreg(&SREG::DS, 0x1234);
+        assert_eq!(emu.get_segment(&SREG::DS).get_base(), 0x12340);
+        assert_eq!(emu.get_linear_address(&SREG::DS, 0x5678), 0x179b8);
+
+        // ffff:0010 wraps around to 0000:0000
+        emu.set_sreg(&SREG::ES, 0xffff);
+        let address = emu.get_linear_address(&SREG::ES, 0x10);
+        emu.set_memory8(address, 0xaa).unwrap();
+        assert_eq!(emu.get_memory8(0x0), Ok(0xaa));
+    }
+
+    #[test]
+    fn stack_test() {
+        let mut emu = Emulator::new(0x100000, 0x0, 0x0);
+        emu.set_real_mode();
+        emu.set_sreg(&SREG::SS, 0x1000);
+        emu.set_gpr(&GPR::ESP, 0x12340000);
+
+        // SP wraps within the 16-bit stack segment
+        emu.push16(0xbeef).unwrap();
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x1234fffe);
+        assert_eq!(emu.get_memory16(0x1fffe), Ok(0xbeef));
+        assert_eq!(emu.pop16(), Ok(0xbeef));
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x12340000);
+
+        // a dword straddling the end of the stack segment faults
+        emu.set_gpr(&GPR::ESP, 0xfffe);
+        assert_eq!(emu.pop32(), Err(EmuError::Exception(Exception::SS(0))));
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0xfffe);
+    }
+}
@@ -0,0 +1,172 @@
+//
+// System registers
+//
+use super::*;
+
+pub const CR0_PE: u32 = 1;
+pub const CR0_ET: u32 = 1 << 4;
+pub const CR0_WP: u32 = 1 << 16;
+pub const CR0_PG: u32 = 1 << 31;
+pub const CR4_PSE: u32 = 1 << 4;
+
+// base and limit of the GDT or the IDT
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct DescriptorTable {
+    base: u32,
+    limit: u16,
+}
+
+impl DescriptorTable {
+    pub fn new(base: u32, limit: u16) -> DescriptorTable {
+        DescriptorTable { base, limit }
+    }
+
+    pub fn get_base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn get_limit(&self) -> u16 {
+        self.limit
+    }
+}
+
+impl Emulator {
+    // CR0, CR2, CR3 and CR4. CR1 and the rest do not exist.
+    pub fn get_cr(&self, index: u8) -> Option<u32> {
+        match index {
+            0 | 2..=4 => Some(self.cr[index as usize]),
+            _ => None,
+        }
+    }
+
+    pub fn set_cr(&mut self, index: u8, value: u32) -> Result<(), Exception> {
+        match index {
+            // paging needs protected mode. ET is hardwired on the 387-aware CPUs.
+            0 if value & CR0_PG != 0 && value & CR0_PE == 0 => return Err(Exception::GP(0)),
+            0 => self.cr[0] = value | CR0_ET,
+            2..=4 => self.cr[index as usize] = value,
+            _ => return Err(Exception::UD),
+        }
+        // anything but CR2 changes how linear addresses translate
+        if index != 2 {
+            self.flush_tlb();
+        }
+        Ok(())
+    }
+
+    pub fn is_protected_mode(&self) -> bool {
+        self.cr[0] & CR0_PE != 0
+    }
+
+    // the RPL of CS in protected mode, 0 in real mode
+    pub fn get_cpl(&self) -> u8 {
+        if self.is_protected_mode() {
+            (self.get_sreg_value(&SREG::CS) & 0x3) as u8
+        } else {
+            0
+        }
+    }
+
+    pub fn get_gdtr(&self) -> DescriptorTable {
+        self.gdtr
+    }
+
+    pub fn set_gdtr(&mut self, gdtr: DescriptorTable) {
+        self.gdtr = gdtr;
+    }
+
+    pub fn get_idtr(&self) -> DescriptorTable {
+        self.idtr
+    }
+
+    pub fn set_idtr(&mut self, idtr: DescriptorTable) {
+        self.idtr = idtr;
+    }
+
+    // the 8 bytes of a descriptor in the GDT. there is no LDT, so selectors with the TI bit are invalid too.
+    pub fn get_descriptor(&self, selector: u16) -> Result<u64, EmuError> {
+        let offset = (selector & 0xfff8) as u32;
+        if selector & 0x4 != 0 || offset + 7 > self.gdtr.limit as u32 {
+            return Err(Exception::GP(selector as u32 & 0xfffc).into());
+        }
+        let address = self.gdtr.base.wrapping_add(offset);
+        Ok(self.get_system32(address)? as u64 | (self.get_system32(address.wrapping_add(4))? as u64) << 32)
+    }
+
+    // set bits in the access byte of a descriptor, like the accessed bit of a loaded segment or the busy bit of a TSS
+    pub(super) fn set_descriptor_flags(&mut self, selector: u16, flags: u8) -> Result<(), EmuError> {
+        let address = self.gdtr.base.wrapping_add((selector & 0xfff8) as u32 + 5);
+        let access = self.get_system8(address)?;
+        if access & flags != flags {
+            self.set_system8(address, access | flags)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::InstructionVector;
+
+    #[test]
+    fn protected_mode_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        emu.set_real_mode();
+        // null, flat 32-bit code, flat data, 16-byte read-only data at 0x20000
+        let gdt: [u64; 4] = [0x0, 0x00cf9a000000ffff, 0x00cf92000000ffff, 0x0040900200000010];
+        for (i, descriptor) in gdt.iter().enumerate() {
+            emu.set_memory32(0x7e00 + i as u32 * 8, *descriptor as u32).unwrap();
+            emu.set_memory32(0x7e04 + i as u32 * 8, (*descriptor >> 32) as u32).unwrap();
+        }
+        emu.set_memory16(0x7e40, 0x1f).unwrap();
+        emu.set_memory32(0x7e42, 0x7e00).unwrap();
+
+        // lgdt [0x7e40]; mov eax, cr0; or al, 1; mov cr0, eax; jmp 0x8:0x7c15
+        // mov ax, 0x10; mov ds, ax; mov ss, ax; mov ax, 0x18; mov es, ax; mov eax, [es:0x10]
+        emu.load_bin(vec![
+            0x0f, 0x01, 0x16, 0x40, 0x7e,
+            0x0f, 0x20, 0xc0,
+            0x0c, 0x01,
+            0x0f, 0x22, 0xc0,
+            0x66, 0xea, 0x15, 0x7c, 0x00, 0x00, 0x08, 0x00,
+            0x66, 0xb8, 0x10, 0x00,
+            0x8e, 0xd8,
+            0x8e, 0xd0,
+            0x66, 0xb8, 0x18, 0x00,
+            0x8e, 0xc0,
+            0x26, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00,
+        ], 0x7c00).unwrap();
+        assert_eq!(emu.run_until(&instructions, 4), Step::Continue);
+        assert_eq!(emu.get_gdtr(), DescriptorTable::new(0x7e00, 0x1f));
+        assert!(emu.is_protected_mode());
+        // the old descriptor cache stays in use until the far jump
+        assert!(emu.is_operand16());
+
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x8);
+        assert_eq!(emu.get_eip(), 0x7c15);
+        assert!(emu.get_segment(&SREG::CS).is_big());
+        assert_eq!(emu.get_segment(&SREG::CS).get_limit(), 0xffffffff);
+        assert_eq!(emu.get_memory8(0x7e0d), Ok(0x9b));
+
+        assert_eq!(emu.run_until(&instructions, 5), Step::Continue);
+        assert_eq!(emu.get_segment(&SREG::ES).get_base(), 0x20000);
+        assert_eq!(emu.get_segment(&SREG::ES).get_limit(), 0x10);
+        // the dword at offset 0x10 runs past the limit of ES
+        assert_eq!(emu.step(&instructions), Step::Fault(Exception::GP(0).into()));
+        assert_eq!(emu.get_eip(), 0x7c23);
+
+        // selectors past the GDT limit, data selectors in CS and code selectors in SS
+        assert_eq!(emu.load_sreg(&SREG::DS, 0x20), Err(Exception::GP(0x20).into()));
+        assert_eq!(emu.load_cs(0x10, 0x0), Err(Exception::GP(0x10).into()));
+        assert_eq!(emu.load_sreg(&SREG::SS, 0x8), Err(Exception::GP(0x8).into()));
+        assert_eq!(emu.load_sreg(&SREG::SS, 0x0), Err(Exception::GP(0).into()));
+        assert_eq!(emu.set_cr(0, CR0_PG), Err(Exception::GP(0)));
+
+        // a null selector can be loaded into DS but not used
+        emu.load_sreg(&SREG::DS, 0x0).unwrap();
+        assert_eq!(emu.check_limit(&SREG::DS, 0x0, 1), Err(Exception::GP(0)));
+    }
+}
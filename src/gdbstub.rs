@@ -0,0 +1,474 @@
+//
+// GDB remote serial protocol
+//
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::emulator::{Emulator, Step, Watch};
+use crate::emulator::error::EmuError;
+use crate::emulator::interrupt::Exception;
+use crate::emulator::segment::SREG;
+use crate::instruction::InstructionVector;
+
+// instructions executed between two checks for a ctrl-c from gdb
+const SLICE: u64 = 0x10000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+const ERROR: &str = "E01";
+
+// largest memory read answered in one packet; the hex reply is twice as long
+const MEMORY_CHUNK: u32 = 0x800;
+
+// gdb numbers the i386 registers eax..edi, eip, eflags and then the segment registers
+const REGISTERS: usize = 16;
+const SEGMENTS: [SREG; 6] = [SREG::CS, SREG::SS, SREG::DS, SREG::ES, SREG::FS, SREG::GS];
+
+// a stream gdb is connected through
+pub trait Connection: Read + Write {
+    // non-blocking check for the interrupt byte gdb sends while the target is running
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let ret = read_interrupt(self);
+        self.set_nonblocking(false)?;
+        ret
+    }
+}
+
+impl Connection for UnixStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let ret = read_interrupt(self);
+        self.set_nonblocking(false)?;
+        ret
+    }
+}
+
+fn read_interrupt(stream: &mut impl Read) -> io::Result<bool> {
+    let mut byte = [0; 1];
+    match stream.read(&mut byte) {
+        Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+        Ok(_) => Ok(byte[0] == 0x03),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// wait for gdb on host:port over TCP, or on a Unix socket at the given path
+pub fn listen(address: &str) -> io::Result<Box<dyn Connection>> {
+    eprintln!("Waiting for gdb on {}", address);
+    if address.contains(':') {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    } else {
+        let listener = UnixListener::bind(address)?;
+        let accepted = listener.accept();
+        fs::remove_file(address)?;
+        Ok(Box::new(accepted?.0))
+    }
+}
+
+pub struct GdbStub<'a> {
+    emu: &'a mut Emulator,
+    instructions: &'a InstructionVector,
+    conn: Box<dyn Connection>,
+    no_ack: bool,
+    attached: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(emu: &'a mut Emulator, instructions: &'a InstructionVector, conn: Box<dyn Connection>) -> GdbStub<'a> {
+        GdbStub { emu, instructions, conn, no_ack: false, attached: true }
+    }
+
+    // answer packets until gdb detaches, kills the target or hangs up
+    pub fn serve(&mut self) -> io::Result<()> {
+        while self.attached {
+            let Some(packet) = self.receive()? else {
+                return Ok(());
+            };
+            if let Some(reply) = self.handle(&packet)? {
+                self.send(&reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = match packet.char_indices().nth(1) {
+            Some((i, _)) => packet.split_at(i),
+            None => (packet, ""),
+        };
+
+        let reply = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "q" => Some(self.query(args)),
+            "Q" if args == "StartNoAckMode" => {
+                // the reply to this packet is the last one to be acknowledged
+                self.send("OK")?;
+                self.no_ack = true;
+                None
+            },
+            "H" | "T" => Some("OK".to_string()),
+            "g" => Some(self.read_registers()),
+            "G" => or_error(self.write_registers(args)),
+            "p" => or_error(self.read_register(args)),
+            "P" => or_error(self.write_register(args)),
+            "m" => or_error(self.read_memory(args)),
+            "M" => or_error(self.write_memory(args)),
+            "c" => Some(self.resume(args, false)?),
+            "s" => Some(self.resume(args, true)?),
+            "Z" => or_error(self.update_point(args, true)),
+            "z" => or_error(self.update_point(args, false)),
+            "D" => {
+                self.attached = false;
+                Some("OK".to_string())
+            },
+            "k" => {
+                self.attached = false;
+                None
+            },
+            // an empty reply tells gdb the packet is not supported
+            _ => Some(String::new()),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        match args {
+            args if args.starts_with("Supported") => "PacketSize=1000;QStartNoAckMode+".to_string(),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn get_register(&self, id: usize) -> Option<u32> {
+        match id {
+            0..=7 => self.emu.get_gpr_id(id as u32).map(|reg| self.emu.get_gpr_value(reg)),
+            8 => Some(self.emu.get_eip()),
+            9 => Some(self.emu.get_eflags()),
+            10..=15 => Some(self.emu.get_sreg_value(&SEGMENTS[id - 10]) as u32),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, id: usize, value: u32) -> Option<()> {
+        match id {
+            0..=7 => {
+                let reg = *self.emu.get_gpr_id(id as u32)?;
+                self.emu.set_gpr(&reg, value);
+            },
+            8 => self.emu.set_eip(value),
+            9 => self.emu.set_eflags(value),
+            10..=15 => {
+                // reloading an unchanged selector would throw away its descriptor cache
+                let reg = SEGMENTS[id - 10];
+                if self.emu.get_sreg_value(&reg) != value as u16 {
+                    self.emu.load_sreg(&reg, value as u16).ok()?;
+                }
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTERS).filter_map(|id| self.get_register(id)).map(encode_register).collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        if args.len() < REGISTERS * 8 {
+            return None;
+        }
+        for id in 0..REGISTERS {
+            let value = decode_register(args.get(id * 8..id * 8 + 8)?)?;
+            self.set_register(id, value)?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let id = parse_hex(args)? as usize;
+        self.get_register(id).map(encode_register)
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (id, value) = args.split_once('=')?;
+        self.set_register(parse_hex(id)? as usize, decode_register(value)?)?;
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = args.split_once(',')?;
+        let (address, length) = (parse_hex(address)?, parse_hex(length)?);
+        if length > MEMORY_CHUNK {
+            return None;
+        }
+        let mut ret = String::new();
+        for i in 0..length {
+            let byte = self.emu.get_memory8(address.wrapping_add(i)).ok()?;
+            ret.push_str(&format!("{:02x}", byte));
+        }
+        Some(ret)
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = range.split_once(',')?;
+        let (address, length) = (parse_hex(address)?, parse_hex(length)?);
+        let bytes = decode_hex(data)?;
+        if bytes.len() != length as usize {
+            return None;
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            self.emu.set_memory8(address.wrapping_add(i as u32), byte as u32).ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn resume(&mut self, args: &str, single_step: bool) -> io::Result<String> {
+        if let Some(address) = parse_hex(args) {
+            self.emu.set_eip(address);
+        }
+        if single_step {
+            return Ok(stop_reply(self.emu.run_until(self.instructions, 1)));
+        }
+        loop {
+            match self.emu.run_until(self.instructions, SLICE) {
+                Step::Continue => {
+                    if self.conn.poll_interrupt()? {
+                        return Ok(format!("S{:02x}", SIGINT));
+                    }
+                },
+                step => return Ok(stop_reply(step)),
+            }
+        }
+    }
+
+    // Z0/Z1 software and hardware breakpoints, Z2-Z4 write, read and access watchpoints
+    fn update_point(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let address = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?.split(';').next()?)?;
+
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.emu.add_breakpoint(address);
+                } else {
+                    self.emu.remove_breakpoint(address);
+                }
+                return Some("OK".to_string());
+            },
+            "2" => Watch::Write,
+            "3" => Watch::Read,
+            "4" => Watch::Access,
+            _ => return Some(String::new()),
+        };
+        if insert {
+            self.emu.add_watchpoint(address, length, watch);
+        } else {
+            self.emu.remove_watchpoint(address, length, watch);
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0; 1];
+        match self.conn.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // next $packet#checksum from gdb, None once the connection is closed
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // acks and interrupts sent while the target is stopped are skipped
+            match self.read_byte()? {
+                Some(b'$') => {},
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else {
+                return Ok(None);
+            };
+            let valid = decode_hex(&String::from_utf8_lossy(&[high, low])) == Some(vec![checksum(&data)]);
+            if !self.no_ack {
+                self.conn.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", reply, checksum(reply.as_bytes()));
+        loop {
+            self.conn.write_all(packet.as_bytes())?;
+            self.conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // resend until gdb acknowledges the packet
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => {},
+                }
+            }
+        }
+    }
+}
+
+fn or_error(reply: Option<String>) -> Option<String> {
+    Some(reply.unwrap_or_else(|| ERROR.to_string()))
+}
+
+fn stop_reply(step: Step) -> String {
+    match step {
+        Step::Continue | Step::Breakpoint => format!("S{:02x}", SIGTRAP),
+        Step::Watchpoint(kind, address) => {
+            let name = match kind {
+                Watch::Write => "watch",
+                Watch::Read => "rwatch",
+                Watch::Access => "awatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+        },
+        // the program is over, gdb sees it exit. a HLT that an interrupt can end is waited out before.
+        Step::Halt | Step::Stop => "W00".to_string(),
+        Step::Exit(status) => format!("W{:02x}", status as u8),
+        Step::Fault(err) => format!("S{:02x}", signal(&err)),
+    }
+}
+
+fn signal(err: &EmuError) -> u8 {
+    match err {
+        EmuError::Exception(Exception::DE) => SIGFPE,
+        EmuError::Exception(Exception::UD) | EmuError::Unimplemented { .. } | EmuError::Decode(_) => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value, 16).ok()
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len()).step_by(2).map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// registers travel as little endian hex strings
+fn encode_register(value: u32) -> String {
+    format!("{:08x}", value.swap_bytes())
+}
+
+fn decode_register(value: &str) -> Option<u32> {
+    if value.len() != 8 {
+        return None;
+    }
+    parse_hex(value).map(u32::swap_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn request(stream: &mut UnixStream, packet: &str) -> String {
+        write!(stream, "${}#{:02x}", packet, checksum(packet.as_bytes())).unwrap();
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+
+        let mut reply = Vec::new();
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum).unwrap();
+        assert_eq!(String::from_utf8_lossy(&sum), format!("{:02x}", checksum(&reply)));
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn gdbstub_test() {
+        let instructions = InstructionVector::new(0x100);
+        let mut emu = Emulator::new(0x100, 0x0, 0x100);
+        // mov eax, 0x29; inc eax; mov [0x80], eax; inc eax; hlt
+        emu.load_bin(vec![0xb8, 0x29, 0x00, 0x00, 0x00, 0x40, 0x89, 0x05, 0x80, 0x00, 0x00, 0x00, 0x40, 0xf4], 0x0).unwrap();
+
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let gdb = thread::spawn(move || {
+            // a corrupted packet is rejected
+            client.write_all(b"$?#00").unwrap();
+            let mut byte = [0; 1];
+            client.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'-');
+
+            let script = [
+                "qSupported:swbreak+", "?", "g", "s", "p0",
+                "Z0,6,1", "c", "p8", "Z2,80,4", "c", "m80,4",
+                "M90,2:abcd", "m90,2", "P3=78563412", "p3", "p100",
+                "m0,801", "z2,80,4", "z0,6,1", "c", "D",
+            ];
+            script.iter().map(|packet| request(&mut client, packet)).collect::<Vec<String>>()
+        });
+        GdbStub::new(&mut emu, &instructions, Box::new(server)).serve().unwrap();
+        let replies = gdb.join().unwrap();
+
+        assert!(replies[0].starts_with("PacketSize="));
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2].len(), REGISTERS * 8);
+        assert!(replies[2].starts_with("00000000"));
+        assert_eq!(replies[3], "S05");
+        assert_eq!(replies[4], "29000000");
+        assert_eq!(replies[5..8], ["OK", "S05", "06000000"]);
+        assert_eq!(replies[8..11], ["OK", "T05watch:80;", "2a000000"]);
+        assert_eq!(replies[11..16], ["OK", "abcd", "OK", "78563412", ERROR]);
+        assert_eq!(replies[16..21], [ERROR, "OK", "OK", "W00", "OK"]);
+
+        assert_eq!(emu.get_gpr_value(&crate::emulator::GPR::EBX), 0x12345678);
+        assert_eq!(emu.get_memory8(0x91), Ok(0xcd));
+    }
+}
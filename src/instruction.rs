@@ -2,12 +2,20 @@
 // Instruction table setup
 //
 use crate::emulator::Emulator;
+use crate::emulator::error::EmuError;
 use crate::instruction::operation::*;
+use crate::instruction::alu::*;
+use crate::instruction::interrupt::*;
+use crate::instruction::io::*;
+use crate::instruction::system::*;
 
 pub mod operation;
+pub mod alu;
+pub mod interrupt;
 pub mod io;
+pub mod system;
 
-type InstructionPtr = fn(&mut Emulator);
+type InstructionPtr = fn(&mut Emulator) -> Result<(), EmuError>;
 
 pub struct InstructionVector(pub Vec<Option<InstructionPtr>>);
 
@@ -16,8 +24,28 @@ impl InstructionVector {
         assert!(size >= 0xff);
         let mut instructions: Vec<Option<InstructionPtr>> = vec![None; size];
 
-        instructions[0x01] = Some(add_rm32_r32);
-        instructions[0x3B] = Some(cmp_r32_rm32);
+        for i in 0..8 {
+            instructions[i << 3] = Some(alu_rm8_r8);
+            instructions[i << 3 | 0x01] = Some(alu_rm32_r32);
+            instructions[i << 3 | 0x02] = Some(alu_r8_rm8);
+            instructions[i << 3 | 0x03] = Some(alu_r32_rm32);
+            instructions[i << 3 | 0x04] = Some(alu_al_imm8);
+            instructions[i << 3 | 0x05] = Some(alu_eax_imm32);
+        }
+        instructions[0x06] = Some(push_sreg);
+        instructions[0x07] = Some(pop_sreg);
+        instructions[0x0E] = Some(push_sreg);
+        instructions[0x0F] = Some(code_0f);
+        instructions[0x16] = Some(push_sreg);
+        instructions[0x17] = Some(pop_sreg);
+        instructions[0x1E] = Some(push_sreg);
+        instructions[0x1F] = Some(pop_sreg);
+        for i in 0..8 {
+            instructions[0x40 + i] = Some(inc_r32);
+        }
+        for i in 0..8 {
+            instructions[0x48 + i] = Some(dec_r32);
+        }
         for i in 0..8 {
             instructions[0x50 + i] = Some(push_r32);
         }
@@ -26,30 +54,62 @@ impl InstructionVector {
         }
         instructions[0x68] = Some(push_imm32);
         instructions[0x6A] = Some(push_imm8);
-        instructions[0x70] = Some(jo);
-        instructions[0x71] = Some(jno);
-        instructions[0x72] = Some(jc);
-        instructions[0x73] = Some(jnc);
-        instructions[0x74] = Some(jz);
-        instructions[0x75] = Some(jnz);
-        instructions[0x78] = Some(js);
-        instructions[0x79] = Some(jns);
-        instructions[0x7C] = Some(jl);
-        instructions[0x7E] = Some(jle);
+        for i in 0..16 {
+            instructions[0x70 + i] = Some(jcc_rel8);
+        }
+        instructions[0x80] = Some(code_80);
+        instructions[0x81] = Some(code_81);
+        instructions[0x82] = Some(code_80);
         instructions[0x83] = Some(code_83);
+        instructions[0x84] = Some(test_rm8_r8);
+        instructions[0x85] = Some(test_rm32_r32);
+        instructions[0x88] = Some(mov_rm8_r8);
         instructions[0x89] = Some(mov_rm32_r32);
+        instructions[0x8A] = Some(mov_r8_rm8);
         instructions[0x8B] = Some(mov_r32_rm32);
+        instructions[0x8C] = Some(mov_rm16_sreg);
+        instructions[0x8D] = Some(lea_r32_m);
+        instructions[0x8E] = Some(mov_sreg_rm16);
+        instructions[0x9A] = Some(call_far_ptr);
+        instructions[0x9C] = Some(pushf);
+        instructions[0x9D] = Some(popf);
+        instructions[0xA8] = Some(test_al_imm8);
+        instructions[0xA9] = Some(test_eax_imm32);
+        for i in 0..8 {
+            instructions[0xB0 + i] = Some(mov_r8_imm8);
+        }
         for i in 0..8 {
             instructions[0xB8 + i] = Some(mov_r32_imm32);
         }
+        instructions[0xC2] = Some(ret_imm16);
         instructions[0xC3] = Some(ret);
+        instructions[0xC6] = Some(mov_rm8_imm8);
         instructions[0xC7] = Some(mov_rm32_imm32);
         instructions[0xC9] = Some(leave);
+        instructions[0xCA] = Some(ret_far_imm16);
+        instructions[0xCB] = Some(ret_far);
+        instructions[0xCC] = Some(int3);
+        instructions[0xCD] = Some(int_imm8);
+        instructions[0xCE] = Some(into);
+        instructions[0xCF] = Some(iret);
+        instructions[0xE4] = Some(in_eax_imm8);
+        instructions[0xE5] = Some(in_eax_imm8);
+        instructions[0xE6] = Some(out_imm8_eax);
+        instructions[0xE7] = Some(out_imm8_eax);
         instructions[0xE8] = Some(call_rel32);
         instructions[0xE9] = Some(near_jump);
+        instructions[0xEA] = Some(jmp_far_ptr);
         instructions[0xEB] = Some(short_jump);
-        instructions[0xEC] = Some(in_al_dx);
-        instructions[0xEE] = Some(out_dx_al);
+        instructions[0xEC] = Some(in_eax_dx);
+        instructions[0xED] = Some(in_eax_dx);
+        instructions[0xEE] = Some(out_dx_eax);
+        instructions[0xEF] = Some(out_dx_eax);
+        instructions[0xF4] = Some(hlt);
+        instructions[0xF6] = Some(code_f6);
+        instructions[0xF7] = Some(code_f7);
+        instructions[0xFA] = Some(cli);
+        instructions[0xFB] = Some(sti);
+        instructions[0xFE] = Some(code_fe);
         instructions[0xFF] = Some(code_ff);
 
         InstructionVector(instructions)
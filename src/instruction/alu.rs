@@ -0,0 +1,808 @@
+//
+// Integer ALU instructions (ADD/OR/ADC/SBB/AND/SUB/XOR/CMP)
+//
+use super::*;
+use crate::emulator::{GPR, GPR8};
+use crate::emulator::modrm::ModRM;
+use crate::emulator::interrupt::Exception;
+use crate::emulator::error::EmuError;
+
+// operation index shared by opcode bits 5..3 and the ModR/M reg field of group 80/81/83
+const ADD: u8 = 0b000;
+const OR: u8 = 0b001;
+const ADC: u8 = 0b010;
+const SBB: u8 = 0b011;
+const AND: u8 = 0b100;
+const SUB: u8 = 0b101;
+const XOR: u8 = 0b110;
+const CMP: u8 = 0b111;
+
+pub fn calc_alu32(emu: &mut Emulator, op: u8, v1: u32, v2: u32) -> u32 {
+    let carry = emu.is_carry() as u64;
+    match op {
+        ADD => {
+            let result = v1 as u64 + v2 as u64;
+            emu.update_eflags_add(v1, v2, result);
+            result as u32
+        },
+        ADC => {
+            let result = v1 as u64 + v2 as u64 + carry;
+            emu.update_eflags_add(v1, v2, result);
+            result as u32
+        },
+        SUB | CMP => {
+            let result = (v1 as u64).wrapping_sub(v2 as u64);
+            emu.update_eflags_sub(v1, v2, result);
+            result as u32
+        },
+        SBB => {
+            let result = (v1 as u64).wrapping_sub(v2 as u64).wrapping_sub(carry);
+            emu.update_eflags_sub(v1, v2, result);
+            result as u32
+        },
+        OR => {
+            let result = v1 | v2;
+            emu.update_eflags_logic(result);
+            result
+        },
+        AND => {
+            let result = v1 & v2;
+            emu.update_eflags_logic(result);
+            result
+        },
+        XOR => {
+            let result = v1 ^ v2;
+            emu.update_eflags_logic(result);
+            result
+        },
+        _ => panic!("Invalid ALU operation: {}", op),
+    }
+}
+
+pub fn calc_alu16(emu: &mut Emulator, op: u8, v1: u16, v2: u16) -> u16 {
+    let carry = emu.is_carry() as u32;
+    match op {
+        ADD => {
+            let result = v1 as u32 + v2 as u32;
+            emu.update_eflags_add16(v1, v2, result);
+            result as u16
+        },
+        ADC => {
+            let result = v1 as u32 + v2 as u32 + carry;
+            emu.update_eflags_add16(v1, v2, result);
+            result as u16
+        },
+        SUB | CMP => {
+            let result = (v1 as u32).wrapping_sub(v2 as u32);
+            emu.update_eflags_sub16(v1, v2, result);
+            result as u16
+        },
+        SBB => {
+            let result = (v1 as u32).wrapping_sub(v2 as u32).wrapping_sub(carry);
+            emu.update_eflags_sub16(v1, v2, result);
+            result as u16
+        },
+        OR => {
+            let result = v1 | v2;
+            emu.update_eflags_logic16(result);
+            result
+        },
+        AND => {
+            let result = v1 & v2;
+            emu.update_eflags_logic16(result);
+            result
+        },
+        XOR => {
+            let result = v1 ^ v2;
+            emu.update_eflags_logic16(result);
+            result
+        },
+        _ => panic!("Invalid ALU operation: {}", op),
+    }
+}
+
+pub fn calc_alu8(emu: &mut Emulator, op: u8, v1: u8, v2: u8) -> u8 {
+    let carry = emu.is_carry() as u16;
+    match op {
+        ADD => {
+            let result = v1 as u16 + v2 as u16;
+            emu.update_eflags_add8(v1, v2, result);
+            result as u8
+        },
+        ADC => {
+            let result = v1 as u16 + v2 as u16 + carry;
+            emu.update_eflags_add8(v1, v2, result);
+            result as u8
+        },
+        SUB | CMP => {
+            let result = (v1 as u16).wrapping_sub(v2 as u16);
+            emu.update_eflags_sub8(v1, v2, result);
+            result as u8
+        },
+        SBB => {
+            let result = (v1 as u16).wrapping_sub(v2 as u16).wrapping_sub(carry);
+            emu.update_eflags_sub8(v1, v2, result);
+            result as u8
+        },
+        OR => {
+            let result = v1 | v2;
+            emu.update_eflags_logic8(result);
+            result
+        },
+        AND => {
+            let result = v1 & v2;
+            emu.update_eflags_logic8(result);
+            result
+        },
+        XOR => {
+            let result = v1 ^ v2;
+            emu.update_eflags_logic8(result);
+            result
+        },
+        _ => panic!("Invalid ALU operation: {}", op),
+    }
+}
+
+fn get_alu_op(emu: &Emulator) -> Result<u8, EmuError> {
+    Ok((emu.get_code8(0)? >> 3) & 0b111)
+}
+
+pub fn alu_rm8_r8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let op = get_alu_op(emu)?;
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let rm8 = modrm.get_rm8(emu)?;
+    let r8 = modrm.get_r8(emu)?;
+    let result = calc_alu8(emu, op, rm8, r8);
+    if op != CMP {
+        modrm.set_rm8(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn alu_r8_rm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let op = get_alu_op(emu)?;
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let r8 = modrm.get_r8(emu)?;
+    let rm8 = modrm.get_rm8(emu)?;
+    let result = calc_alu8(emu, op, r8, rm8);
+    if op != CMP {
+        modrm.set_r8(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn alu_rm32_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        return alu_rm16_r16(emu);
+    }
+    let op = get_alu_op(emu)?;
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let rm32 = modrm.get_rm32(emu)?;
+    let r32 = modrm.get_r32(emu)?;
+    let result = calc_alu32(emu, op, rm32, r32);
+    if op != CMP {
+        modrm.set_rm32(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn alu_r32_rm32(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        return alu_r16_rm16(emu);
+    }
+    let op = get_alu_op(emu)?;
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let r32 = modrm.get_r32(emu)?;
+    let rm32 = modrm.get_rm32(emu)?;
+    let result = calc_alu32(emu, op, r32, rm32);
+    if op != CMP {
+        modrm.set_r32(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn alu_al_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let op = get_alu_op(emu)?;
+    let al = emu.get_gpr8_value(&GPR8::AL);
+    let imm8 = emu.get_code8(1)?;
+    let result = calc_alu8(emu, op, al, imm8);
+    if op != CMP {
+        emu.set_gpr8(&GPR8::AL, result);
+    }
+    emu.inc_eip(2);
+    Ok(())
+}
+
+pub fn alu_eax_imm32(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        return alu_ax_imm16(emu);
+    }
+    let op = get_alu_op(emu)?;
+    let eax = emu.get_gpr_value(&GPR::EAX);
+    let imm32 = emu.get_code32(1)?;
+    let result = calc_alu32(emu, op, eax, imm32);
+    if op != CMP {
+        emu.set_gpr(&GPR::EAX, result);
+    }
+    emu.inc_eip(5);
+    Ok(())
+}
+
+pub fn alu_rm16_r16(emu: &mut Emulator) -> Result<(), EmuError> {
+    let op = get_alu_op(emu)?;
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let rm16 = modrm.get_rm16(emu)?;
+    let r16 = modrm.get_r16(emu)?;
+    let result = calc_alu16(emu, op, rm16, r16);
+    if op != CMP {
+        modrm.set_rm16(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn alu_r16_rm16(emu: &mut Emulator) -> Result<(), EmuError> {
+    let op = get_alu_op(emu)?;
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let r16 = modrm.get_r16(emu)?;
+    let rm16 = modrm.get_rm16(emu)?;
+    let result = calc_alu16(emu, op, r16, rm16);
+    if op != CMP {
+        modrm.set_r16(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn alu_ax_imm16(emu: &mut Emulator) -> Result<(), EmuError> {
+    let op = get_alu_op(emu)?;
+    let ax = emu.get_gpr16_value(&GPR::EAX);
+    let imm16 = emu.get_code16(1)?;
+    let result = calc_alu16(emu, op, ax, imm16);
+    if op != CMP {
+        emu.set_gpr16(&GPR::EAX, result);
+    }
+    emu.inc_eip(3);
+    Ok(())
+}
+
+pub fn code_80(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let imm8 = emu.get_code8(0)?;
+    emu.inc_eip(1);
+    let op = modrm.get_opcode();
+    let rm8 = modrm.get_rm8(emu)?;
+    let result = calc_alu8(emu, op, rm8, imm8);
+    if op != CMP {
+        modrm.set_rm8(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn code_81(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if emu.is_operand16() {
+        let imm16 = emu.get_code16(0)?;
+        emu.inc_eip(2);
+        return alu_rm16_imm(emu, &modrm, imm16);
+    }
+    let imm32 = emu.get_code32(0)?;
+    emu.inc_eip(4);
+    alu_rm32_imm(emu, &modrm, imm32)
+}
+
+pub fn code_83(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let imm8 = emu.get_signed_code8(0)? as i32;
+    emu.inc_eip(1);
+    if emu.is_operand16() {
+        return alu_rm16_imm(emu, &modrm, imm8 as u16);
+    }
+    alu_rm32_imm(emu, &modrm, imm8 as u32)
+}
+
+fn alu_rm32_imm(emu: &mut Emulator, modrm: &ModRM, imm: u32) -> Result<(), EmuError> {
+    let op = modrm.get_opcode();
+    let rm32 = modrm.get_rm32(emu)?;
+    let result = calc_alu32(emu, op, rm32, imm);
+    if op != CMP {
+        modrm.set_rm32(emu, result)?;
+    }
+    Ok(())
+}
+
+fn alu_rm16_imm(emu: &mut Emulator, modrm: &ModRM, imm: u16) -> Result<(), EmuError> {
+    let op = modrm.get_opcode();
+    let rm16 = modrm.get_rm16(emu)?;
+    let result = calc_alu16(emu, op, rm16, imm);
+    if op != CMP {
+        modrm.set_rm16(emu, result)?;
+    }
+    Ok(())
+}
+
+pub fn inc_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = emu.get_opcode_gpr()?;
+    if emu.is_operand16() {
+        let value = emu.get_gpr16_value(&reg);
+        let result = value as u32 + 1;
+        emu.update_eflags_inc16(value, result);
+        emu.set_gpr16(&reg, result as u16);
+        emu.inc_eip(1);
+        return Ok(());
+    }
+    let value = emu.get_gpr_value(&reg);
+    let result = value as u64 + 1;
+    emu.update_eflags_inc(value, result);
+    emu.set_gpr(&reg, result as u32);
+    emu.inc_eip(1);
+    Ok(())
+}
+
+pub fn dec_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = emu.get_opcode_gpr()?;
+    if emu.is_operand16() {
+        let value = emu.get_gpr16_value(&reg);
+        let result = (value as u32).wrapping_sub(1);
+        emu.update_eflags_dec16(value, result);
+        emu.set_gpr16(&reg, result as u16);
+        emu.inc_eip(1);
+        return Ok(());
+    }
+    let value = emu.get_gpr_value(&reg);
+    let result = (value as u64).wrapping_sub(1);
+    emu.update_eflags_dec(value, result);
+    emu.set_gpr(&reg, result as u32);
+    emu.inc_eip(1);
+    Ok(())
+}
+
+pub fn inc_rm32(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        return inc_rm16(emu, modrm);
+    }
+    let value = modrm.get_rm32(emu)?;
+    let result = value as u64 + 1;
+    emu.update_eflags_inc(value, result);
+    modrm.set_rm32(emu, result as u32)?;
+    Ok(())
+}
+
+pub fn dec_rm32(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        return dec_rm16(emu, modrm);
+    }
+    let value = modrm.get_rm32(emu)?;
+    let result = (value as u64).wrapping_sub(1);
+    emu.update_eflags_dec(value, result);
+    modrm.set_rm32(emu, result as u32)?;
+    Ok(())
+}
+
+pub fn inc_rm16(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let value = modrm.get_rm16(emu)?;
+    let result = value as u32 + 1;
+    emu.update_eflags_inc16(value, result);
+    modrm.set_rm16(emu, result as u16)?;
+    Ok(())
+}
+
+pub fn dec_rm16(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let value = modrm.get_rm16(emu)?;
+    let result = (value as u32).wrapping_sub(1);
+    emu.update_eflags_dec16(value, result);
+    modrm.set_rm16(emu, result as u16)?;
+    Ok(())
+}
+
+pub fn inc_rm8(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let value = modrm.get_rm8(emu)?;
+    let result = value as u16 + 1;
+    emu.update_eflags_inc8(value, result);
+    modrm.set_rm8(emu, result as u8)?;
+    Ok(())
+}
+
+pub fn dec_rm8(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let value = modrm.get_rm8(emu)?;
+    let result = (value as u16).wrapping_sub(1);
+    emu.update_eflags_dec8(value, result);
+    modrm.set_rm8(emu, result as u8)?;
+    Ok(())
+}
+
+pub fn code_fe(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+
+    match modrm.get_opcode() {
+        0b000 => inc_rm8(emu, &modrm),
+        0b001 => dec_rm8(emu, &modrm),
+        _ => Err(Exception::UD.into()),
+    }
+}
+
+pub fn test_rm8_r8(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let result = modrm.get_rm8(emu)? & modrm.get_r8(emu)?;
+    emu.update_eflags_logic8(result);
+    Ok(())
+}
+
+pub fn test_rm32_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if emu.is_operand16() {
+        let result = modrm.get_rm16(emu)? & modrm.get_r16(emu)?;
+        emu.update_eflags_logic16(result);
+        return Ok(());
+    }
+    let result = modrm.get_rm32(emu)? & modrm.get_r32(emu)?;
+    emu.update_eflags_logic(result);
+    Ok(())
+}
+
+pub fn test_al_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let result = emu.get_gpr8_value(&GPR8::AL) & emu.get_code8(1)?;
+    emu.update_eflags_logic8(result);
+    emu.inc_eip(2);
+    Ok(())
+}
+
+pub fn test_eax_imm32(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let result = emu.get_gpr16_value(&GPR::EAX) & emu.get_code16(1)?;
+        emu.update_eflags_logic16(result);
+        emu.inc_eip(3);
+        return Ok(());
+    }
+    let result = emu.get_gpr_value(&GPR::EAX) & emu.get_code32(1)?;
+    emu.update_eflags_logic(result);
+    emu.inc_eip(5);
+    Ok(())
+}
+
+pub fn code_f6(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+
+    match modrm.get_opcode() {
+        0b000 | 0b001 => {
+            let imm8 = emu.get_code8(0)?;
+            emu.inc_eip(1);
+            let result = modrm.get_rm8(emu)? & imm8;
+            emu.update_eflags_logic8(result);
+            Ok(())
+        },
+        0b010 => {
+            let value = modrm.get_rm8(emu)?;
+            modrm.set_rm8(emu, !value)
+        },
+        0b011 => {
+            let value = modrm.get_rm8(emu)?;
+            let result = calc_alu8(emu, SUB, 0, value);
+            modrm.set_rm8(emu, result)
+        },
+        op => {
+            let value = modrm.get_rm8(emu)?;
+            mul_div(emu, op, value as u64, 8)
+        },
+    }
+}
+
+pub fn code_f7(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+
+    if emu.is_operand16() {
+        return match modrm.get_opcode() {
+            0b000 | 0b001 => {
+                let imm16 = emu.get_code16(0)?;
+                emu.inc_eip(2);
+                let result = modrm.get_rm16(emu)? & imm16;
+                emu.update_eflags_logic16(result);
+                Ok(())
+            },
+            0b010 => {
+                let value = modrm.get_rm16(emu)?;
+                modrm.set_rm16(emu, !value)
+            },
+            0b011 => {
+                let value = modrm.get_rm16(emu)?;
+                let result = calc_alu16(emu, SUB, 0, value);
+                modrm.set_rm16(emu, result)
+            },
+            op => {
+                let value = modrm.get_rm16(emu)?;
+                mul_div(emu, op, value as u64, 16)
+            },
+        };
+    }
+
+    match modrm.get_opcode() {
+        0b000 | 0b001 => {
+            let imm32 = emu.get_code32(0)?;
+            emu.inc_eip(4);
+            let result = modrm.get_rm32(emu)? & imm32;
+            emu.update_eflags_logic(result);
+            Ok(())
+        },
+        0b010 => {
+            let value = modrm.get_rm32(emu)?;
+            modrm.set_rm32(emu, !value)
+        },
+        0b011 => {
+            let value = modrm.get_rm32(emu)?;
+            let result = calc_alu32(emu, SUB, 0, value);
+            modrm.set_rm32(emu, result)
+        },
+        op => {
+            let value = modrm.get_rm32(emu)?;
+            mul_div(emu, op, value as u64, 32)
+        },
+    }
+}
+
+// implicit accumulator of MUL/DIV: AX for byte operands, DX:AX or EDX:EAX otherwise
+fn get_accumulator(emu: &Emulator, bits: u32) -> u64 {
+    match bits {
+        8 => emu.get_gpr16_value(&GPR::EAX) as u64,
+        16 => (emu.get_gpr16_value(&GPR::EDX) as u64) << 16 | emu.get_gpr16_value(&GPR::EAX) as u64,
+        _ => (emu.get_gpr_value(&GPR::EDX) as u64) << 32 | emu.get_gpr_value(&GPR::EAX) as u64,
+    }
+}
+
+fn set_accumulator(emu: &mut Emulator, bits: u32, low: u64, high: u64) {
+    match bits {
+        8 => {
+            emu.set_gpr8(&GPR8::AL, low as u8);
+            emu.set_gpr8(&GPR8::AH, high as u8);
+        },
+        16 => {
+            emu.set_gpr16(&GPR::EAX, low as u16);
+            emu.set_gpr16(&GPR::EDX, high as u16);
+        },
+        _ => {
+            emu.set_gpr(&GPR::EAX, low as u32);
+            emu.set_gpr(&GPR::EDX, high as u32);
+        },
+    }
+}
+
+fn sign_extend(value: u64, bits: u32) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+// MUL/IMUL/DIV/IDIV of the accumulator by an operand of the given width
+fn mul_div(emu: &mut Emulator, op: u8, src: u64, bits: u32) -> Result<(), EmuError> {
+    let mask = (1u64 << bits) - 1;
+    let acc = get_accumulator(emu, bits);
+    match op {
+        0b100 => {
+            let result = (acc & mask) * src;
+            set_accumulator(emu, bits, result & mask, result >> bits);
+            emu.update_eflags_mul(result >> bits != 0);
+        },
+        0b101 => {
+            let result = sign_extend(acc & mask, bits) * sign_extend(src, bits);
+            set_accumulator(emu, bits, result as u64 & mask, (result >> bits) as u64 & mask);
+            emu.update_eflags_mul(result != sign_extend(result as u64 & mask, bits));
+        },
+        0b110 => {
+            if src == 0 {
+                return Err(Exception::DE.into());
+            }
+            let quotient = acc as u128 / src as u128;
+            if quotient > mask as u128 {
+                return Err(Exception::DE.into());
+            }
+            set_accumulator(emu, bits, quotient as u64, acc % src);
+        },
+        _ => {
+            let dividend = sign_extend(acc, bits * 2) as i128;
+            let divisor = sign_extend(src, bits) as i128;
+            if divisor == 0 {
+                return Err(Exception::DE.into());
+            }
+            let quotient = dividend / divisor;
+            if quotient != sign_extend(quotient as u64 & mask, bits) as i128 {
+                return Err(Exception::DE.into());
+            }
+            set_accumulator(emu, bits, quotient as u64 & mask, (dividend % divisor) as u64 & mask);
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Step;
+
+    #[test]
+    fn calc_alu32_test() {
+        let mut emu = Emulator::new(0x10, 0x0, 0x0);
+
+        assert_eq!(calc_alu32(&mut emu, ADD, 0xffffffff, 0x1), 0x0);
+        assert!(emu.is_carry() && emu.is_zero() && !emu.is_overflow());
+
+        assert_eq!(calc_alu32(&mut emu, ADC, 0x1, 0x1), 0x3);
+        assert!(!emu.is_carry() && !emu.is_zero());
+
+        assert_eq!(calc_alu32(&mut emu, ADD, 0x7fffffff, 0x1), 0x80000000);
+        assert!(emu.is_overflow() && emu.is_signed() && !emu.is_carry());
+
+        assert_eq!(calc_alu32(&mut emu, SUB, 0x1, 0x2), 0xffffffff);
+        assert!(emu.is_carry() && emu.is_signed() && !emu.is_overflow());
+
+        assert_eq!(calc_alu32(&mut emu, SBB, 0x5, 0x1), 0x3);
+        assert!(!emu.is_carry());
+
+        assert_eq!(calc_alu32(&mut emu, SUB, 0x80000000, 0x1), 0x7fffffff);
+        assert!(emu.is_overflow() && !emu.is_signed());
+
+        assert_eq!(calc_alu32(&mut emu, XOR, 0x1234, 0x1234), 0x0);
+        assert!(emu.is_zero() && !emu.is_carry() && !emu.is_overflow());
+
+        assert_eq!(calc_alu32(&mut emu, OR, 0x80000000, 0x1), 0x80000001);
+        assert_eq!(calc_alu32(&mut emu, AND, 0xf0f0, 0xff00), 0xf000);
+    }
+
+    #[test]
+    fn calc_alu8_test() {
+        let mut emu = Emulator::new(0x10, 0x0, 0x0);
+
+        assert_eq!(calc_alu8(&mut emu, ADD, 0xff, 0x1), 0x0);
+        assert!(emu.is_carry() && emu.is_zero());
+
+        assert_eq!(calc_alu8(&mut emu, CMP, b'h', b'h'), 0x0);
+        assert!(emu.is_zero() && !emu.is_carry());
+
+        assert_eq!(calc_alu8(&mut emu, SUB, 0x80, 0x1), 0x7f);
+        assert!(emu.is_overflow() && !emu.is_signed());
+    }
+
+    #[test]
+    fn alu_instruction_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        let instructions = InstructionVector::new(0x100);
+        // mov eax, 0x10; sub eax, 0x20; cmp eax, -0x10; and eax, 0xff; xor ecx, ecx; or ecx, eax
+        emu.load_bin(vec![
+            0xb8, 0x10, 0x00, 0x00, 0x00,
+            0x83, 0xe8, 0x20,
+            0x83, 0xf8, 0xf0,
+            0x25, 0xff, 0x00, 0x00, 0x00,
+            0x31, 0xc9,
+            0x09, 0xc1,
+        ], 0x0).unwrap();
+        for _ in 0..3 {
+            let code = emu.get_code8(0).unwrap() as usize;
+            instructions.0[code].unwrap()(&mut emu).unwrap();
+        }
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0xfffffff0);
+        assert!(emu.is_zero());
+        for _ in 0..3 {
+            let code = emu.get_code8(0).unwrap() as usize;
+            instructions.0[code].unwrap()(&mut emu).unwrap();
+        }
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0xf0);
+        assert_eq!(emu.get_gpr_value(&GPR::ECX), 0xf0);
+        assert_eq!(emu.get_eip(), 20);
+    }
+
+    #[test]
+    fn alu16_instruction_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        let instructions = InstructionVector::new(0x100);
+        // mov eax, 0x1ffff; add ax, 0x1; inc ax; sub word [0x40], 0x1 (addr16); xor ax, ax
+        emu.load_bin(vec![
+            0xb8, 0xff, 0xff, 0x01, 0x00,
+            0x66, 0x05, 0x01, 0x00,
+            0x66, 0x40,
+            0x66, 0x67, 0x83, 0x2e, 0x40, 0x00, 0x01,
+            0x66, 0x31, 0xc0,
+        ], 0x0).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x10000);
+        assert!(emu.is_carry() && emu.is_zero());
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x10001);
+        assert!(emu.is_carry() && !emu.is_zero());
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_memory16(0x40).unwrap(), 0xffff);
+        assert_eq!(emu.get_memory8(0x42).unwrap(), 0x0);
+        assert!(emu.is_carry() && emu.is_signed());
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x10000);
+        assert_eq!(emu.get_eip(), 21);
+    }
+
+    #[test]
+    fn alu8_instruction_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        let instructions = InstructionVector::new(0x100);
+        // mov ah, 0x7f; inc ah; mov byte [0x40], 0x1; add [0x40], ah; test ah, 0x80; dec bl
+        emu.load_bin(vec![
+            0xb4, 0x7f,
+            0xfe, 0xc4,
+            0xc6, 0x05, 0x40, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x25, 0x40, 0x00, 0x00, 0x00,
+            0xf6, 0xc4, 0x80,
+            0xfe, 0xcb,
+        ], 0x0).unwrap();
+        for _ in 0..2 {
+            let code = emu.get_code8(0).unwrap() as usize;
+            instructions.0[code].unwrap()(&mut emu).unwrap();
+        }
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x8000);
+        assert!(emu.is_overflow() && emu.is_signed());
+        for _ in 0..3 {
+            let code = emu.get_code8(0).unwrap() as usize;
+            instructions.0[code].unwrap()(&mut emu).unwrap();
+        }
+        assert_eq!(emu.get_memory8(0x40).unwrap(), 0x81);
+        assert!(!emu.is_parity());
+        assert!(!emu.is_zero());
+        let code = emu.get_code8(0).unwrap() as usize;
+        instructions.0[code].unwrap()(&mut emu).unwrap();
+        assert_eq!(emu.get_gpr_value(&GPR::EBX), 0xff);
+        assert!(emu.is_signed() && !emu.is_carry());
+        assert_eq!(emu.get_eip(), 22);
+    }
+
+    #[test]
+    fn mul_div_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+
+        emu.set_gpr(&GPR::EAX, 0x80000000);
+        mul_div(&mut emu, 0b100, 0x4, 32).unwrap();
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x0);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), 0x2);
+        assert!(emu.is_carry() && emu.is_overflow());
+
+        // -7 / 2 = -3 remainder -1
+        emu.set_gpr(&GPR::EAX, -7i32 as u32);
+        emu.set_gpr(&GPR::EDX, 0xffffffff);
+        mul_div(&mut emu, 0b111, 0x2, 32).unwrap();
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), -3i32 as u32);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), -1i32 as u32);
+
+        emu.set_gpr(&GPR::EAX, 0x0234);
+        mul_div(&mut emu, 0b110, 0x10, 8).unwrap();
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0x0423);
+
+        // quotient overflow and division by zero raise #DE
+        emu.set_gpr(&GPR::EAX, 0x1234);
+        assert_eq!(mul_div(&mut emu, 0b110, 0x1, 8), Err(Exception::DE.into()));
+        assert_eq!(mul_div(&mut emu, 0b111, 0x0, 16), Err(Exception::DE.into()));
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x1234);
+
+        emu.set_gpr(&GPR::EAX, 0xfe);
+        mul_div(&mut emu, 0b101, 0x3, 8).unwrap();
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), -6i16 as u16);
+        assert!(!emu.is_carry());
+    }
+}
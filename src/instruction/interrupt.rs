@@ -0,0 +1,56 @@
+//
+// Software interrupts
+//
+use super::*;
+use crate::emulator::error::EmuError;
+
+pub fn int_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let vector = emu.get_code8(1)?;
+    emu.inc_eip(2);
+    emu.software_interrupt(vector)
+}
+
+pub fn int3(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    emu.software_interrupt(3)
+}
+
+pub fn into(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    if emu.is_overflow() {
+        return emu.software_interrupt(4);
+    }
+    Ok(())
+}
+
+pub fn iret(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.interrupt_return()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::segment::SREG;
+
+    #[test]
+    fn int_iret_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        let instructions = InstructionVector::new(0x100);
+        emu.set_real_mode();
+        emu.set_ivt_entry(0x21, 0x1000, 0x0000).unwrap();
+        // int 0x21; int3
+        emu.load_bin(vec![0xcd, 0x21, 0xcc], 0x7c00).unwrap();
+        // handler: mov al, 0x41; iret
+        emu.load_bin(vec![0xb0, 0x41, 0xcf], 0x10000).unwrap();
+
+        for _ in 0..3 {
+            emu.parse_prefix().unwrap();
+            let code = emu.get_code8(0).unwrap() as usize;
+            instructions.0[code].unwrap()(&mut emu).unwrap();
+        }
+        assert_eq!(emu.get_gpr8_value(&crate::emulator::GPR8::AL), 0x41);
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x0);
+        assert_eq!(emu.get_eip(), 0x7c02);
+        assert_eq!(emu.get_gpr_value(&crate::emulator::GPR::ESP), 0x7c00);
+    }
+}
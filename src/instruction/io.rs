@@ -1,30 +1,127 @@
 //
-// io
+// Port I/O instructions
 //
-use std::io::{stdin, stdout, Read, Write};
+use super::*;
+use crate::emulator::{GPR, GPR8};
+use crate::emulator::error::EmuError;
 
-pub fn in8(address: u16) -> u8 {
-    match address {
-        0x03f8 => getchar(),
-        _ => 0,
+// width of the accumulator transfer: AL for the even opcodes, AX/EAX for the odd ones
+fn get_io_size(emu: &Emulator) -> Result<u8, EmuError> {
+    if emu.get_code8(0)? & 1 == 0 {
+        Ok(1)
+    } else if emu.is_operand16() {
+        Ok(2)
+    } else {
+        Ok(4)
     }
 }
 
-pub fn out8(address: u16, value: u8) {
-    match address {
-        0x03f8 => putchar(value),
-        _ => (),
+fn read_port(emu: &mut Emulator, port: u16) -> Result<(), EmuError> {
+    let size = get_io_size(emu)?;
+    emu.check_io_permission(port, size)?;
+    let value = emu.io_in(port, size)?;
+    match size {
+        1 => emu.set_gpr8(&GPR8::AL, value as u8),
+        2 => emu.set_gpr16(&GPR::EAX, value as u16),
+        _ => emu.set_gpr(&GPR::EAX, value),
     }
+    Ok(())
 }
 
-fn getchar() -> u8 {
-    let mut input = [0u8; 1];
-    stdin().read_exact(&mut input).unwrap();
-    input[0]
+fn write_port(emu: &mut Emulator, port: u16) -> Result<(), EmuError> {
+    let size = get_io_size(emu)?;
+    emu.check_io_permission(port, size)?;
+    let value = match size {
+        1 => emu.get_gpr8_value(&GPR8::AL) as u32,
+        2 => emu.get_gpr16_value(&GPR::EAX) as u32,
+        _ => emu.get_gpr_value(&GPR::EAX),
+    };
+    emu.io_out(port, size, value)
 }
 
-fn putchar(value: u8) {
-    stdout().flush().unwrap();
-    print!("{}", value as char);
-    stdout().flush().unwrap();
-}
\ No newline at end of file
+pub fn in_eax_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let port = emu.get_code8(1)? as u16;
+    read_port(emu, port)?;
+    emu.inc_eip(2);
+    Ok(())
+}
+
+pub fn out_imm8_eax(emu: &mut Emulator) -> Result<(), EmuError> {
+    let port = emu.get_code8(1)? as u16;
+    write_port(emu, port)?;
+    emu.inc_eip(2);
+    Ok(())
+}
+
+pub fn in_eax_dx(emu: &mut Emulator) -> Result<(), EmuError> {
+    let port = emu.get_gpr16_value(&GPR::EDX);
+    read_port(emu, port)?;
+    emu.inc_eip(1);
+    Ok(())
+}
+
+pub fn out_dx_eax(emu: &mut Emulator) -> Result<(), EmuError> {
+    let port = emu.get_gpr16_value(&GPR::EDX);
+    write_port(emu, port)?;
+    emu.inc_eip(1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PortDevice;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    // echoes the port number on reads and records every write
+    struct Recorder(Rc<RefCell<Vec<(u16, u8, u32)>>>);
+
+    impl PortDevice for Recorder {
+        fn read(&mut self, port: u16, _size: u8) -> io::Result<u32> {
+            Ok(0xabcd0000 | port as u32)
+        }
+
+        fn write(&mut self, port: u16, size: u8, value: u32) -> io::Result<()> {
+            self.0.borrow_mut().push((port, size, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn port_io_test() {
+        let instructions = InstructionVector::new(0x100);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut emu = Emulator::new(0x100, 0x0, 0x100);
+        emu.attach_port_device(0xf8..=0xff, Box::new(Recorder(log.clone())));
+        emu.attach_port_device(0x3f8..=0x3ff, Box::new(Recorder(log.clone())));
+        emu.set_gpr(&GPR::EAX, 0x11223344);
+        emu.set_gpr(&GPR::EDX, 0x3f8);
+
+        // in al, 0xf9; in eax, 0xfa; out 0xfb, ax; out dx, eax; in ax, dx; out dx, al; in al, 0x80
+        emu.load_bin(vec![
+            0xe4, 0xf9, 0xe5, 0xfa, 0x66, 0xe7, 0xfb, 0xef, 0x66, 0xed, 0xee, 0xe4, 0x80,
+        ], 0x0).unwrap();
+        let step = |emu: &mut Emulator| {
+            emu.parse_prefix().unwrap();
+            let code = emu.get_code8(0).unwrap() as usize;
+            instructions.0[code].unwrap()(emu).unwrap();
+        };
+        step(&mut emu);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x112233f9);
+        step(&mut emu);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0xabcd00fa);
+        step(&mut emu);
+        step(&mut emu);
+        step(&mut emu);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0xabcd03f8);
+        step(&mut emu);
+        assert_eq!(*log.borrow(), vec![(0xfb, 2, 0x00fa), (0x3f8, 4, 0xabcd00fa), (0x3f8, 1, 0xf8)]);
+
+        // nothing is attached to port 0x80
+        step(&mut emu);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0xabcd03ff);
+        assert_eq!(emu.get_eip(), 13);
+    }
+}
@@ -2,283 +2,620 @@
 // Instructions
 //
 use super::*;
-use crate::emulator::{GPR, GPR8};
+use crate::emulator::GPR;
 use crate::emulator::modrm::ModRM;
-
-
-pub fn mov_r32_imm32(emu: &mut Emulator) {
-    let reg = emu.get_code8(0) - 0xB8;
-    let value = emu.get_code32(1);
-    let reg = *emu.get_gpr_id(reg.into()).unwrap_or_else(|| {
-        panic!("Invalid register id");
-    });  // TODO: implement better error handling
+use crate::emulator::interrupt::Exception;
+use crate::emulator::error::EmuError;
+use crate::emulator::segment::SREG;
+
+
+pub fn mov_r32_imm32(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = emu.get_opcode_gpr()?;
+    if emu.is_operand16() {
+        let value = emu.get_code16(1)?;
+        emu.set_gpr16(&reg, value);
+        emu.inc_eip(3);
+        return Ok(());
+    }
+    let value = emu.get_code32(1)?;
     emu.set_gpr(&reg, value);
     emu.inc_eip(5);
+    Ok(())
+}
+
+pub fn mov_r8_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = emu.get_opcode_gpr8()?;
+    let value = emu.get_code8(1)?;
+    emu.set_gpr8(&reg, value);
+    emu.inc_eip(2);
+    Ok(())
+}
+
+// relative jumps truncate the instruction pointer to IP with a 16-bit operand size
+fn jump_relative(emu: &mut Emulator, len: i32, diff: i32) -> Result<(), EmuError> {
+    let eip = (emu.get_eip() as i32).wrapping_add(len).wrapping_add(diff) as u32;
+    if emu.is_operand16() {
+        emu.set_eip(eip & 0xffff);
+    } else {
+        emu.set_eip(eip);
+    }
+    Ok(())
 }
 
-pub fn short_jump(emu: &mut Emulator) {
-    let diff = emu.get_signed_code8(1);
-    emu.set_eip((emu.get_eip() as i32 + diff as i32 + 2) as u32);
+pub fn short_jump(emu: &mut Emulator) -> Result<(), EmuError> {
+    let diff = emu.get_signed_code8(1)?;
+    jump_relative(emu, 2, diff as i32)
 }
 
-pub fn near_jump(emu: &mut Emulator) {
-    let diff = emu.get_signed_code32(1);
-    emu.set_eip((emu.get_eip() as i32 + diff + 5) as u32);
+pub fn near_jump(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let diff = emu.get_signed_code16(1)?;
+        return jump_relative(emu, 3, diff as i32);
+    }
+    let diff = emu.get_signed_code32(1)?;
+    jump_relative(emu, 5, diff)
 }
 
-pub fn mov_rm32_imm32(emu: &mut Emulator) {
+pub fn mov_rm32_imm32(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
-    let value = emu.get_code32(0);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if emu.is_operand16() {
+        let value = emu.get_code16(0)?;
+        emu.inc_eip(2);
+        modrm.set_rm16(emu, value)?;
+        return Ok(());
+    }
+    let value = emu.get_code32(0)?;
     emu.inc_eip(4);
-    modrm.set_rm32(emu, value);
+    modrm.set_rm32(emu, value)?;
+    Ok(())
 }
 
-pub fn mov_rm32_r32(emu: &mut Emulator) {
+pub fn mov_rm8_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let value = emu.get_code8(0)?;
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
-    modrm.set_rm32(emu, modrm.get_r32(emu));
+    modrm.set_rm8(emu, value)?;
+    Ok(())
 }
 
-pub fn mov_r32_rm32(emu: &mut Emulator) {
+pub fn mov_rm8_r8(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
-    modrm.set_r32(emu, modrm.get_rm32(emu));
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    modrm.set_rm8(emu, modrm.get_r8(emu)?)?;
+    Ok(())
 }
 
-pub fn add_rm32_r32(emu: &mut Emulator) {
+pub fn mov_r8_rm8(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
-    let r32 = modrm.get_r32(emu);
-    let rm32 = modrm.get_rm32(emu);
-    modrm.set_rm32(emu, r32 + rm32);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    modrm.set_r8(emu, modrm.get_rm8(emu)?)?;
+    Ok(())
 }
 
-pub fn code_83(emu: &mut Emulator) {
+pub fn mov_rm32_r32(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if emu.is_operand16() {
+        modrm.set_rm16(emu, modrm.get_r16(emu)?)?;
+        return Ok(());
+    }
+    modrm.set_rm32(emu, modrm.get_r32(emu)?)?;
+    Ok(())
+}
 
-    match modrm.get_opcode() {
-        0b000 => add_rm32_imm8(emu, &modrm),
-        0b101 => sub_rm32_imm8(emu, &modrm),
-        0b111 => cmp_rm32_imm8(emu, &modrm),
-        _ => panic!("Not implemented: code 83 , {:#x?}", modrm),
+pub fn mov_r32_rm32(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if emu.is_operand16() {
+        modrm.set_r16(emu, modrm.get_rm16(emu)?)?;
+        return Ok(());
     }
+    modrm.set_r32(emu, modrm.get_rm32(emu)?)?;
+    Ok(())
 }
 
-pub fn code_ff(emu: &mut Emulator) {
+// only the effective address is computed, memory is not touched
+pub fn lea_r32_m(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if modrm.get_mod() == 0b11 {
+        return Err(Exception::UD.into());
+    }
+    let address = modrm.calc_memory_address(emu)? as u32;
+    if emu.is_operand16() {
+        return modrm.set_r16(emu, address as u16);
+    }
+    modrm.set_r32(emu, address)
+}
+
+pub fn code_ff(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
 
     match modrm.get_opcode() {
         0b000 => inc_rm32(emu, &modrm),
-        _ => panic!("Not implemented: code FF , {:#x?}", modrm),
+        0b001 => dec_rm32(emu, &modrm),
+        0b010 => call_rm32(emu, &modrm),
+        0b011 => call_far_m(emu, &modrm),
+        0b100 => jmp_rm32(emu, &modrm),
+        0b101 => jmp_far_m(emu, &modrm),
+        0b110 => push_rm32(emu, &modrm),
+        _ => Err(Exception::UD.into()),
     }
 }
 
-pub fn inc_rm32(emu: &mut Emulator, modrm: &ModRM) {
-    modrm.set_rm32(emu, modrm.get_rm32(emu) + 1);
+pub fn call_rm32(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let eip = emu.get_eip();
+    if emu.is_operand16() {
+        let target = modrm.get_rm16(emu)?;
+        emu.push16(eip as u16)?;
+        emu.set_eip(target as u32);
+        return Ok(());
+    }
+    let target = modrm.get_rm32(emu)?;
+    emu.push32(eip)?;
+    emu.set_eip(target);
+    Ok(())
 }
 
-pub fn push_r32(emu: &mut Emulator) {
-    let reg = emu.get_code8(0) - 0x50;
-    let value = emu.get_gpr_value(emu.get_gpr_id(reg.into()).unwrap());
-    push32(emu, value);
-    emu.inc_eip(1);
+pub fn jmp_rm32(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let target = modrm.get_rm16(emu)?;
+        emu.set_eip(target as u32);
+        return Ok(());
+    }
+    let target = modrm.get_rm32(emu)?;
+    emu.set_eip(target);
+    Ok(())
 }
 
-fn push32(emu: &mut Emulator, value: u32) {
-    let address = emu.get_gpr_value(&GPR::ESP) - 4;
-    emu.set_gpr(&GPR::ESP, address);
-    emu.set_memory32(address, value);
+pub fn push_rm32(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let value = modrm.get_rm16(emu)?;
+        return emu.push16(value);
+    }
+    let value = modrm.get_rm32(emu)?;
+    emu.push32(value)?;
+    Ok(())
 }
 
-pub fn pop_r32(emu: &mut Emulator) {
-    let reg = emu.get_code8(0) - 0x58;
-    let reg = *emu.get_gpr_id(reg.into()).unwrap();
-    let popped = pop32(emu);
-    emu.set_gpr(&reg, popped);
-    emu.inc_eip(1);
+// m16:16 or m16:32 far pointer operand: offset first, then selector
+fn get_far_pointer(emu: &Emulator, modrm: &ModRM) -> Result<(u16, u32), EmuError> {
+    if emu.is_operand16() {
+        let address = modrm.calc_linear_address(emu, 4)?;
+        Ok((emu.get_memory16(address.wrapping_add(2))?, emu.get_memory16(address)? as u32))
+    } else {
+        let address = modrm.calc_linear_address(emu, 6)?;
+        Ok((emu.get_memory16(address.wrapping_add(4))?, emu.get_memory32(address)?))
+    }
 }
 
-fn pop32(emu: &mut Emulator) -> u32 {
-    let address = emu.get_gpr_value(&GPR::ESP);
-    let ret = emu.get_memory32(address);
-    emu.set_gpr(&GPR::ESP, address + 4);
-    ret
+pub fn call_far_m(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let (selector, offset) = get_far_pointer(emu, modrm)?;
+    emu.call_far(selector, offset)
 }
 
-pub fn call_rel32(emu: &mut Emulator) {
-    let diff = emu.get_signed_code32(1);
-    push32(emu, emu.get_eip() + 5);
-    emu.inc_eip(diff + 5);
+pub fn jmp_far_m(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let (selector, offset) = get_far_pointer(emu, modrm)?;
+    emu.jump_far(selector, offset)
 }
 
-pub fn ret(emu: &mut Emulator) {
-    let popped = pop32(emu);
-    emu.set_eip(popped);
+pub fn call_far_ptr(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let offset = emu.get_code16(1)? as u32;
+        let selector = emu.get_code16(3)?;
+        emu.inc_eip(5);
+        return emu.call_far(selector, offset);
+    }
+    let offset = emu.get_code32(1)?;
+    let selector = emu.get_code16(5)?;
+    emu.inc_eip(7);
+    emu.call_far(selector, offset)
 }
 
-pub fn leave(emu: &mut Emulator) {
-    let ebp = emu.get_gpr_value(&GPR::EBP);
-    emu.set_gpr(&GPR::ESP, ebp);
-    let popped = pop32(emu);
-    emu.set_gpr(&GPR::EBP, popped);
-    emu.inc_eip(1);
+pub fn jmp_far_ptr(emu: &mut Emulator) -> Result<(), EmuError> {
+    let (selector, offset) = if emu.is_operand16() {
+        (emu.get_code16(3)?, emu.get_code16(1)? as u32)
+    } else {
+        (emu.get_code16(5)?, emu.get_code32(1)?)
+    };
+    emu.jump_far(selector, offset)
 }
 
-pub fn push_imm8(emu: &mut Emulator) {
-    let value = emu.get_code8(1);
-    push32(emu, value as u32);
-    emu.inc_eip(2);
+pub fn ret_far(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.return_far(0)
 }
 
-pub fn push_imm32(emu: &mut Emulator) {
-    let value = emu.get_code32(1);
-    push32(emu, value);
-    emu.inc_eip(5);
+// the parameters are released from both stacks on a return to an outer level
+pub fn ret_far_imm16(emu: &mut Emulator) -> Result<(), EmuError> {
+    let size = emu.get_code16(1)? as u32;
+    emu.return_far(size)
 }
 
-pub fn add_rm32_imm8(emu: &mut Emulator, modrm: &ModRM) {
-    let rm32 = modrm.get_rm32(emu);
-    let imm8 = emu.get_signed_code8(0) as i32;
-    emu.inc_eip(1);
-    modrm.set_rm32(emu, rm32 + imm8 as u32);
+pub fn ret_imm16(emu: &mut Emulator) -> Result<(), EmuError> {
+    let size = emu.get_code16(1)? as u32;
+    ret(emu)?;
+    emu.set_sp(emu.get_sp().wrapping_add(size));
+    Ok(())
 }
 
-pub fn cmp_r32_rm32(emu: &mut Emulator) {
+pub fn mov_rm16_sreg(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let mut modrm = ModRM::new(emu);
-    modrm.parse_modrm(emu);
-    let r32 = modrm.get_r32(emu);
-    let rm32 = modrm.get_rm32(emu);
-    let result = r32 as i64 - rm32 as i64;
-    emu.update_eflags_sub(r32, rm32, result as u64);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let reg = *emu.get_sreg_id(modrm.get_reg_index().into()).ok_or(Exception::UD)?;
+    let value = emu.get_sreg_value(&reg);
+    // a register destination is zero-extended with a 32-bit operand size
+    if modrm.get_mod() == 0b11 && !emu.is_operand16() {
+        modrm.set_rm32(emu, value as u32)?;
+    } else {
+        modrm.set_rm16(emu, value)?;
+    }
+    Ok(())
 }
 
-pub fn cmp_rm32_imm8(emu: &mut Emulator, modrm: &ModRM) {
-    let rm32 = modrm.get_rm32(emu);
-    let imm8 = emu.get_signed_code8(0) as i32;
+pub fn mov_sreg_rm16(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
-    let result = rm32 as u64 - imm8 as u64;
-    emu.update_eflags_sub(rm32, imm8 as u32, result);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    let reg = *emu.get_sreg_id(modrm.get_reg_index().into()).ok_or(Exception::UD)?;
+    // CS can only be loaded by far control transfers
+    if reg == SREG::CS {
+        return Err(Exception::UD.into());
+    }
+    let value = modrm.get_rm16(emu)?;
+    emu.load_sreg(&reg, value)
+}
+
+fn get_push_pop_sreg(code: u8) -> SREG {
+    match code {
+        0x06 | 0x07 => SREG::ES,
+        0x0E => SREG::CS,
+        0x16 | 0x17 => SREG::SS,
+        0x1E | 0x1F => SREG::DS,
+        0xA0 | 0xA1 => SREG::FS,
+        _ => SREG::GS,
+    }
 }
 
-pub fn sub_rm32_imm8(emu: &mut Emulator, modrm: &ModRM) {
-    let rm32 = modrm.get_rm32(emu);
-    let imm8 = emu.get_signed_code8(0) as i32;
+pub fn push_sreg(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = get_push_pop_sreg(emu.get_code8(0)?);
+    let value = emu.get_sreg_value(&reg);
+    if emu.is_operand16() {
+        emu.push16(value)?;
+    } else {
+        emu.push32(value as u32)?;
+    }
     emu.inc_eip(1);
-    let result = rm32 as u64 - imm8 as u64;
-    modrm.set_rm32(emu, result as u32);
-    emu.update_eflags_sub(rm32, imm8 as u32, result);
+    Ok(())
 }
 
-pub fn jc(emu: &mut Emulator) {
-    let diff = if emu.is_carry() {
-        emu.get_signed_code8(1)
+pub fn pop_sreg(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = get_push_pop_sreg(emu.get_code8(0)?);
+    let esp = emu.get_gpr_value(&GPR::ESP);
+    let value = if emu.is_operand16() {
+        emu.pop16()?
     } else {
-        0
+        emu.pop32()? as u16
     };
-    emu.inc_eip(diff as i32 + 2);
+    emu.load_sreg(&reg, value).inspect_err(|_| emu.set_gpr(&GPR::ESP, esp))?;
+    emu.inc_eip(1);
+    Ok(())
 }
 
-pub fn jnc(emu: &mut Emulator) {
-    let diff = if emu.is_carry() {
-        0
+pub fn push_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = emu.get_opcode_gpr()?;
+    if emu.is_operand16() {
+        let value = emu.get_gpr16_value(&reg);
+        emu.push16(value)?;
     } else {
-        emu.get_signed_code8(1)
-    };
-    emu.inc_eip(diff as i32 + 2);
+        let value = emu.get_gpr_value(&reg);
+        emu.push32(value)?;
+    }
+    emu.inc_eip(1);
+    Ok(())
 }
 
-pub fn jz(emu: &mut Emulator) {
-    let diff = if emu.is_zero() {
-        emu.get_signed_code8(1)
+pub fn pop_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    let reg = emu.get_opcode_gpr()?;
+    if emu.is_operand16() {
+        let popped = emu.pop16()?;
+        emu.set_gpr16(&reg, popped);
     } else {
-        0
-    };
-    emu.inc_eip(diff as i32 + 2);
+        let popped = emu.pop32()?;
+        emu.set_gpr(&reg, popped);
+    }
+    emu.inc_eip(1);
+    Ok(())
 }
 
-pub fn jnz(emu: &mut Emulator) {
-    let diff = if emu.is_zero() {
-        0
-    } else {
-        emu.get_signed_code8(1)
-    };
-    emu.inc_eip(diff as i32 + 2);
+pub fn call_rel32(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let diff = emu.get_signed_code16(1)?;
+        let eip = emu.get_eip();
+        emu.push16(eip.wrapping_add(3) as u16)?;
+        return jump_relative(emu, 3, diff as i32);
+    }
+    let diff = emu.get_signed_code32(1)?;
+    let eip = emu.get_eip();
+    emu.push32(eip.wrapping_add(5))?;
+    jump_relative(emu, 5, diff)
 }
 
-pub fn js(emu: &mut Emulator) {
-    let diff = if emu.is_signed() {
-        emu.get_signed_code8(1)
+pub fn ret(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let popped = emu.pop16()?;
+        emu.set_eip(popped as u32);
+        return Ok(());
+    }
+    let popped = emu.pop32()?;
+    emu.set_eip(popped);
+    Ok(())
+}
+
+pub fn leave(emu: &mut Emulator) -> Result<(), EmuError> {
+    let ebp = emu.get_gpr_value(&GPR::EBP);
+    emu.set_sp(ebp);
+    if emu.is_operand16() {
+        let popped = emu.pop16()?;
+        emu.set_gpr16(&GPR::EBP, popped);
     } else {
-        0
-    };
-    emu.inc_eip(diff as i32 + 2);
+        let popped = emu.pop32()?;
+        emu.set_gpr(&GPR::EBP, popped);
+    }
+    emu.inc_eip(1);
+    Ok(())
 }
 
-pub fn jns(emu: &mut Emulator) {
-    let diff = if emu.is_signed() {
-        0
+pub fn push_imm8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let value = emu.get_signed_code8(1)? as i32;
+    if emu.is_operand16() {
+        emu.push16(value as u16)?;
     } else {
-        emu.get_signed_code8(1)
-    };
-    emu.inc_eip(diff as i32 + 2);
+        emu.push32(value as u32)?;
+    }
+    emu.inc_eip(2);
+    Ok(())
 }
 
-pub fn jo(emu: &mut Emulator) {
-    let diff = if emu.is_overflow() {
-        emu.get_signed_code8(1)
+pub fn push_imm32(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let value = emu.get_code16(1)?;
+        emu.push16(value)?;
+        emu.inc_eip(3);
+        return Ok(());
+    }
+    let value = emu.get_code32(1)?;
+    emu.push32(value)?;
+    emu.inc_eip(5);
+    Ok(())
+}
+
+pub fn pushf(emu: &mut Emulator) -> Result<(), EmuError> {
+    let flags = emu.get_eflags();
+    if emu.is_operand16() {
+        emu.push16(flags as u16)?;
     } else {
-        0
-    };
-    emu.inc_eip(diff as i32 + 2);
+        emu.push32(flags)?;
+    }
+    emu.inc_eip(1);
+    Ok(())
 }
 
-pub fn jno(emu: &mut Emulator) {
-    let diff = if emu.is_overflow() {
-        0
+pub fn popf(emu: &mut Emulator) -> Result<(), EmuError> {
+    if emu.is_operand16() {
+        let flags = emu.pop16()? as u32;
+        emu.load_eflags(flags, true, emu.get_cpl());
     } else {
-        emu.get_signed_code8(1)
+        let flags = emu.pop32()?;
+        emu.load_eflags(flags, false, emu.get_cpl());
+    }
+    emu.inc_eip(1);
+    Ok(())
+}
+
+pub fn cli(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.check_iopl()?;
+    emu.set_interrupt(false);
+    emu.inc_eip(1);
+    Ok(())
+}
+
+pub fn sti(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.check_iopl()?;
+    if !emu.is_interrupt() {
+        emu.hold_interrupts();
+    }
+    emu.set_interrupt(true);
+    emu.inc_eip(1);
+    Ok(())
+}
+
+// EIP is left on the next instruction, which is where execution resumes after an interrupt
+pub fn hlt(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.check_cpl0()?;
+    emu.inc_eip(1);
+    Err(EmuError::Halt)
+}
+
+// evaluate the condition encoded in the low 4 bits of Jcc/SETcc/CMOVcc
+pub fn check_condition(emu: &Emulator, cc: u8) -> bool {
+    let result = match cc >> 1 {
+        0b000 => emu.is_overflow(),
+        0b001 => emu.is_carry(),
+        0b010 => emu.is_zero(),
+        0b011 => emu.is_carry() || emu.is_zero(),
+        0b100 => emu.is_signed(),
+        0b101 => emu.is_parity(),
+        0b110 => emu.is_signed() != emu.is_overflow(),
+        _ => emu.is_zero() || (emu.is_signed() != emu.is_overflow()),
     };
-    emu.inc_eip(diff as i32 + 2);
+    // odd condition codes are the negated forms
+    result != (cc & 1 != 0)
 }
 
-pub fn jl(emu: &mut Emulator) {
-    let diff = if emu.is_signed() != emu.is_overflow() {
-        emu.get_signed_code8(1)
+pub fn jcc_rel8(emu: &mut Emulator) -> Result<(), EmuError> {
+    let cc = emu.get_code8(0)? & 0x0f;
+    let diff = if check_condition(emu, cc) {
+        emu.get_signed_code8(1)?
     } else {
         0
     };
-    emu.inc_eip(diff as i32 + 2);
-}
-
-pub fn jle(emu: &mut Emulator) {
-    let diff = if emu.is_zero() || (emu.is_signed() != emu.is_overflow()) {
-        emu.get_signed_code8(1)
+    jump_relative(emu, 2, diff as i32)
+}
+
+pub fn jcc_rel32(emu: &mut Emulator) -> Result<(), EmuError> {
+    let cc = emu.get_code8(0)? & 0x0f;
+    if emu.is_operand16() {
+        let diff = if check_condition(emu, cc) {
+            emu.get_signed_code16(1)?
+        } else {
+            0
+        };
+        return jump_relative(emu, 3, diff as i32);
+    }
+    let diff = if check_condition(emu, cc) {
+        emu.get_signed_code32(1)?
     } else {
         0
     };
-    emu.inc_eip(diff as i32 + 2);
+    jump_relative(emu, 5, diff)
 }
 
-pub fn in_al_dx(emu: &mut Emulator) {
-    let address: u16 = (emu.get_gpr_value(&GPR::EDX) & 0xffff) as u16;
-    let value: u8 = io::in8(address);
-    emu.set_gpr8(&GPR8::AL, value);
+pub fn code_0f(emu: &mut Emulator) -> Result<(), EmuError> {
     emu.inc_eip(1);
+    let code = emu.get_code8(0)?;
+
+    match code {
+        0x80..=0x8F => jcc_rel32(emu),
+        0x00 => code_0f00(emu),
+        0x01 => code_0f01(emu),
+        0x20 => mov_r32_cr(emu),
+        0x22 => mov_cr_r32(emu),
+        0xA0 | 0xA8 => push_sreg(emu),
+        0xA1 | 0xA9 => pop_sreg(emu),
+        _ => Err(emu.unimplemented(emu.get_eip().wrapping_add(1))),
+    }
 }
 
-pub fn out_dx_al(emu: &mut Emulator) {
-    let address = (emu.get_gpr_value(&GPR::EDX) & 0xffff) as u16;
-    let value = emu.get_gpr8_value(&GPR8::AL);
-    io::out8(address, value);
-    emu.inc_eip(1);
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Step;
+
+    #[test]
+    fn check_condition_test() {
+        let mut emu = Emulator::new(0x10, 0x0, 0x0);
+        // cmp 1, 2
+        calc_alu32(&mut emu, 0b111, 1, 2);
+        assert!(check_condition(&emu, 0x2));    // jb
+        assert!(check_condition(&emu, 0x6));    // jbe
+        assert!(!check_condition(&emu, 0x7));   // ja
+        assert!(check_condition(&emu, 0xC));    // jl
+        assert!(!check_condition(&emu, 0xD));   // jge
+        assert!(!check_condition(&emu, 0xF));   // jg
+        assert!(check_condition(&emu, 0x5));    // jnz
+    }
+
+    #[test]
+    fn operand16_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        let instructions = InstructionVector::new(0x100);
+        // mov ecx, 0x12345678; mov cx, 0xabcd; push cx; pop dx; call (rel16) +0x10; jmp (rel16) -0x3; 0f 84 (rel16)
+        emu.load_bin(vec![
+            0xb9, 0x78, 0x56, 0x34, 0x12,
+            0x66, 0xb9, 0xcd, 0xab,
+            0x66, 0x51,
+            0x66, 0x5a,
+            0x66, 0xe8, 0x10, 0x00,
+        ], 0x0).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::ECX), 0x1234abcd);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7e);
+        assert_eq!(emu.get_memory16(0x7e).unwrap(), 0xabcd);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x80);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), 0xabcd);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x11 + 0x10);
+        assert_eq!(emu.get_memory16(0x7e).unwrap(), 0x11);
+
+        // xor eax, eax; jz (rel32) +0x10; ret (16-bit)
+        emu.load_bin(vec![0x31, 0xc0, 0x0f, 0x84, 0x10, 0x00, 0x00, 0x00], 0x21).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x29 + 0x10);
+        emu.load_bin(vec![0x66, 0xc3], 0x39).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_eip(), 0x11);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x80);
+    }
+
+    #[test]
+    fn lea_test() {
+        let mut emu = Emulator::new(0x100, 0x0, 0x80);
+        let instructions = InstructionVector::new(0x100);
+        // lea eax, [ebx+ecx*4+0x10]; lea dx, [ebx-0x1]; lea eax, eax
+        emu.load_bin(vec![0x8d, 0x44, 0x8b, 0x10, 0x66, 0x8d, 0x53, 0xff, 0x8d, 0xc0], 0x0).unwrap();
+        emu.set_gpr(&GPR::EBX, 0x1000);
+        emu.set_gpr(&GPR::ECX, 0x3);
+        emu.set_gpr(&GPR::EDX, 0xffffffff);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x101c);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EDX), 0xffff0fff);
+        assert_eq!(emu.step(&instructions), Step::Fault(Exception::UD.into()));
+    }
+
+    #[test]
+    fn segment_instruction_test() {
+        let mut emu = Emulator::new(0x100000, 0x7c00, 0x7c00);
+        let instructions = InstructionVector::new(0x100);
+        emu.set_real_mode();
+        // mov ax, 0x1000; mov ds, ax; push ds; pop es; mov byte [es:0x10], 0x55; call 0x2000:0x0
+        emu.load_bin(vec![
+            0xb8, 0x00, 0x10,
+            0x8e, 0xd8,
+            0x1e,
+            0x07,
+            0x26, 0xc6, 0x06, 0x10, 0x00, 0x55,
+            0x9a, 0x00, 0x00, 0x00, 0x20,
+        ], 0x7c00).unwrap();
+        for _ in 0..5 {
+            assert_eq!(emu.step(&instructions), Step::Continue);
+        }
+        assert_eq!(emu.get_sreg_value(&SREG::DS), 0x1000);
+        assert_eq!(emu.get_sreg_value(&SREG::ES), 0x1000);
+        assert_eq!(emu.get_memory8(0x10010).unwrap(), 0x55);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7c00);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x2000);
+        assert_eq!(emu.get_eip(), 0x0);
+
+        // mov ax, cs; retf
+        emu.load_bin(vec![0x8c, 0xc8, 0xcb], 0x20000).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x2000);
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_sreg_value(&SREG::CS), 0x0);
+        assert_eq!(emu.get_eip(), 0x7c12);
+        assert_eq!(emu.get_gpr_value(&GPR::ESP), 0x7c00);
+
+        // jmp 0x07c0:0x0
+        emu.load_bin(vec![0xea, 0x00, 0x00, 0xc0, 0x07], 0x7c12).unwrap();
+        assert_eq!(emu.step(&instructions), Step::Continue);
+        assert_eq!(emu.get_linear_address(&SREG::CS, emu.get_eip()), 0x7c00);
+    }
+}
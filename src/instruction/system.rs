@@ -0,0 +1,187 @@
+//
+// System instructions
+//
+use super::*;
+use crate::emulator::GPR;
+use crate::emulator::modrm::ModRM;
+use crate::emulator::interrupt::Exception;
+use crate::emulator::error::EmuError;
+use crate::emulator::system::{DescriptorTable, CR0_PE};
+
+// 0F 00 /1 and /3: str and ltr. the task register only exists in protected mode.
+pub fn code_0f00(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+    if !emu.is_protected_mode() {
+        return Err(Exception::UD.into());
+    }
+
+    match modrm.get_opcode() {
+        0b001 => {
+            let selector = emu.get_tr().get_selector();
+            if modrm.get_mod() == 0b11 && !emu.is_operand16() {
+                return modrm.set_rm32(emu, selector as u32);
+            }
+            modrm.set_rm16(emu, selector)
+        },
+        0b011 => {
+            emu.check_cpl0()?;
+            let selector = modrm.get_rm16(emu)?;
+            emu.load_tr(selector)
+        },
+        _ => Err(Exception::UD.into()),
+    }
+}
+
+// 0F 01 /0-/7: sgdt, sidt, lgdt, lidt, smsw, lmsw and invlpg
+pub fn code_0f01(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.inc_eip(1);
+    let mut modrm = ModRM::new(emu)?;
+    modrm.parse_modrm(emu)?;
+
+    match modrm.get_opcode() {
+        0b100 => smsw_rm16(emu, &modrm),
+        0b110 => lmsw_rm16(emu, &modrm),
+        _ if modrm.get_mod() == 0b11 => Err(Exception::UD.into()),
+        0b000 => store_descriptor_table(emu, &modrm, emu.get_gdtr()),
+        0b001 => store_descriptor_table(emu, &modrm, emu.get_idtr()),
+        0b010 => {
+            let table = load_descriptor_table(emu, &modrm)?;
+            emu.set_gdtr(table);
+            Ok(())
+        },
+        0b011 => {
+            let table = load_descriptor_table(emu, &modrm)?;
+            emu.set_idtr(table);
+            Ok(())
+        },
+        0b111 => invlpg_m(emu, &modrm),
+        _ => Err(Exception::UD.into()),
+    }
+}
+
+// the 6-byte pseudo-descriptor: limit, then base. a 16-bit operand size only takes 24 bits of the base.
+fn load_descriptor_table(emu: &Emulator, modrm: &ModRM) -> Result<DescriptorTable, EmuError> {
+    emu.check_cpl0()?;
+    let address = modrm.calc_linear_address(emu, 6)?;
+    let limit = emu.get_memory16(address)?;
+    let base = emu.get_memory32(address.wrapping_add(2))?;
+    if emu.is_operand16() {
+        return Ok(DescriptorTable::new(base & 0xffffff, limit));
+    }
+    Ok(DescriptorTable::new(base, limit))
+}
+
+fn store_descriptor_table(emu: &mut Emulator, modrm: &ModRM, table: DescriptorTable) -> Result<(), EmuError> {
+    let address = modrm.calc_linear_address(emu, 6)?;
+    emu.set_memory16(address, table.get_limit() as u32)?;
+    emu.set_memory32(address.wrapping_add(2), table.get_base())
+}
+
+fn smsw_rm16(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    let cr0 = emu.get_cr(0).unwrap_or(0);
+    if modrm.get_mod() == 0b11 && !emu.is_operand16() {
+        return modrm.set_rm32(emu, cr0);
+    }
+    modrm.set_rm16(emu, cr0 as u16)
+}
+
+// lmsw only touches PE, MP, EM and TS, and cannot leave protected mode
+fn lmsw_rm16(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    emu.check_cpl0()?;
+    let value = modrm.get_rm16(emu)? as u32 & 0xf;
+    let cr0 = emu.get_cr(0).unwrap_or(0);
+    emu.set_cr(0, cr0 & !0xe | cr0 & CR0_PE | value)?;
+    Ok(())
+}
+
+// the address is not checked against the segment limit, as nothing is accessed
+fn invlpg_m(emu: &mut Emulator, modrm: &ModRM) -> Result<(), EmuError> {
+    emu.check_cpl0()?;
+    let offset = modrm.calc_memory_address(emu)? as u32;
+    let linear = emu.get_linear_address(&modrm.get_segment(emu), offset);
+    emu.invalidate_page(linear);
+    Ok(())
+}
+
+// 0F 20 and 0F 22 always take a register operand, whatever the mod field says
+fn get_cr_operands(emu: &mut Emulator) -> Result<(u8, GPR), EmuError> {
+    emu.inc_eip(1);
+    let modrm = ModRM::new(emu)?;
+    let reg = *emu.get_gpr_id(modrm.get_rm().into()).ok_or(Exception::UD)?;
+    Ok((modrm.get_reg_index(), reg))
+}
+
+pub fn mov_r32_cr(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.check_cpl0()?;
+    let (cr, reg) = get_cr_operands(emu)?;
+    let value = emu.get_cr(cr).ok_or(Exception::UD)?;
+    emu.set_gpr(&reg, value);
+    Ok(())
+}
+
+pub fn mov_cr_r32(emu: &mut Emulator) -> Result<(), EmuError> {
+    emu.check_cpl0()?;
+    let (cr, reg) = get_cr_operands(emu)?;
+    emu.set_cr(cr, emu.get_gpr_value(&reg))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_table_test() {
+        let mut emu = Emulator::new(0x10000, 0x0, 0x1000);
+        emu.set_real_mode();
+        emu.load_bin(vec![0xff, 0x07, 0x00, 0x10, 0x34, 0x12], 0x100).unwrap();
+        // lidt [0x100]; sidt [0x200]; o32 lidt [0x100]; smsw ax; lmsw ax (with AX = 0xffff)
+        emu.load_bin(vec![
+            0x0f, 0x01, 0x1e, 0x00, 0x01,
+            0x0f, 0x01, 0x0e, 0x00, 0x02,
+            0x66, 0x0f, 0x01, 0x1e, 0x00, 0x01,
+            0x0f, 0x01, 0xe0,
+            0x0f, 0x01, 0xf0,
+        ], 0x0).unwrap();
+
+        let instructions = InstructionVector::new(0x100);
+        assert_eq!(emu.run_until(&instructions, 2), crate::emulator::Step::Continue);
+        assert_eq!(emu.get_idtr(), DescriptorTable::new(0x341000, 0x7ff));
+        assert_eq!(emu.get_memory32(0x202), Ok(0x341000));
+        assert_eq!(emu.run_until(&instructions, 2), crate::emulator::Step::Continue);
+        assert_eq!(emu.get_idtr(), DescriptorTable::new(0x12341000, 0x7ff));
+        assert_eq!(emu.get_gpr_value(&GPR::EAX), 0x10);
+
+        emu.set_gpr(&GPR::EAX, 0xffff);
+        assert_eq!(emu.step(&instructions), crate::emulator::Step::Continue);
+        assert_eq!(emu.get_cr(0), Some(0x1f));
+        assert!(emu.is_protected_mode());
+
+        // mov eax, cr1, with no IDT to deliver the #UD through
+        emu.set_idtr(DescriptorTable::new(0x0, 0x0));
+        emu.load_bin(vec![0x0f, 0x20, 0xc8], 0x16).unwrap();
+        assert_eq!(emu.step(&instructions), crate::emulator::Step::Fault(Exception::UD.into()));
+    }
+
+    #[test]
+    fn descriptor_table_wrap_test() {
+        let mut emu = Emulator::new(0x10000, 0x100, 0x1000);
+        emu.map_ram(0xfffff000, 0x1000);
+        // sgdt [0xfffffffe]; lgdt [0xfffffffe], with the base wrapping around to 0x0
+        emu.load_bin(vec![
+            0x0f, 0x01, 0x05, 0xfe, 0xff, 0xff, 0xff,
+            0x0f, 0x01, 0x15, 0xfe, 0xff, 0xff, 0xff,
+        ], 0x100).unwrap();
+        emu.set_gdtr(DescriptorTable::new(0x12345678, 0x37));
+
+        let instructions = InstructionVector::new(0x100);
+        assert_eq!(emu.step(&instructions), crate::emulator::Step::Continue);
+        assert_eq!(emu.get_memory16(0xfffffffe), Ok(0x37));
+        assert_eq!(emu.get_memory32(0x0), Ok(0x12345678));
+        emu.set_gdtr(DescriptorTable::new(0x0, 0x0));
+        assert_eq!(emu.step(&instructions), crate::emulator::Step::Continue);
+        assert_eq!(emu.get_gdtr(), DescriptorTable::new(0x12345678, 0x37));
+    }
+}
@@ -0,0 +1,12 @@
+//
+// rpx86
+//
+pub mod bios;
+pub mod config;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod emulator;
+pub mod gdbstub;
+pub mod instruction;
+pub mod personality;
@@ -3,35 +3,206 @@
 //
 use std::env;
 use std::fs;
+use std::io::{stdin, stdout, Cursor, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 
-use crate::{config::Config, emulator::Emulator, instruction::InstructionVector};
-
-pub mod config;
-pub mod emulator;
-pub mod instruction;
+use rpx86::{bios::{self, Bios, disk::{self, Disk}}, config::{Command, Config}, debugger::Debugger, device::{self, pic::{self, Pic}, pit::{self, Pit}, uart::{self, Uart}, vga::{self, Vga}}, disasm, gdbstub::{self, GdbStub}, emulator::{Emulator, Step, elf::Elf, error::EmuError, segment::SREG}, instruction::InstructionVector, personality::{dos::{self, Dos}, linux::{self, Linux}}};
 
 const MEM_SIZE: usize = 0xffff;
+const REAL_MEM_SIZE: usize = 0x100000;
 const INST_SIZE: usize = 0xff + 1;
 const ORG: u32 = 0x7c00;
 
 fn main() {
-    let mut emu = Emulator::new(MEM_SIZE, ORG, ORG);
-
     let args: Vec<String> = env::args().collect();
     let fp = Config::build(&args).unwrap_or_else(|err| {
         eprintln!("Problem parsing arguments: {err}");
         process::exit(1);
     });
 
-    emu.load_bin(fs::read(fp.get_fp()).unwrap_or_else(|err| {
+    let org = fp.get_org().unwrap_or(ORG);
+    let mut emu = if fp.is_real_mode() || fp.is_dos() || fp.is_bios() || fp.is_boot() {
+        let mut emu = Emulator::new(REAL_MEM_SIZE, org, ORG);
+        emu.set_real_mode();
+        emu
+    } else {
+        Emulator::new(MEM_SIZE, org, ORG)
+    };
+
+    let binary = fs::read(fp.get_fp()).unwrap_or_else(|err| {
+        eprintln!("Could not load binary: {err}");
+        process::exit(1);
+    });
+    // ELF executables say where they go, anything else is a raw image loaded at org
+    let loaded = if fp.is_boot() {
+        Disk::new(Box::new(Cursor::new(binary)))
+            .map_err(|err| EmuError::InvalidImage(err.to_string()))
+            .and_then(|mut disk| bios::boot(&mut emu, &mut disk))
+            .map(|_| vec![(bios::BOOT_ADDRESS, bios::BOOT_ADDRESS + disk::SECTOR_SIZE)])
+    } else if Elf::is_elf(&binary) {
+        emu.load_elf(&binary).and_then(|elf| {
+            if fp.is_linux() {
+                // argv[0] is the path of the executable
+                let args: Vec<String> = [fp.get_fp().to_string()].into_iter().chain(fp.get_args().iter().cloned()).collect();
+                let env: Vec<String> = env::vars().map(|(key, value)| format!("{key}={value}")).collect();
+                let linux = Linux::new(&mut emu, &elf, &args, &env)?;
+                emu.attach_interrupt_handler(linux::SYSCALL_VECTOR..=linux::SYSCALL_VECTOR, Box::new(linux));
+            } else {
+                // returning from the entry point ends the program, as with the crt0 of the raw images
+                emu.push32(0)?;
+            }
+            Ok(elf.get_code().to_vec())
+        })
+    } else if fp.is_linux() {
+        Err(EmuError::InvalidImage("--linux needs an ELF executable".to_string()))
+    } else if fp.is_dos() {
+        let tail: String = fp.get_args().iter().map(|arg| format!(" {arg}")).collect();
+        Dos::new(&mut emu, &binary, &tail, PathBuf::from(fp.get_dos_root())).map(|dos| {
+            emu.attach_interrupt_handler(dos::TERMINATE_VECTOR..=dos::SERVICE_VECTOR, Box::new(dos));
+            let start = emu.get_linear_eip();
+            vec![(start, start + binary.len() as u32)]
+        })
+    } else {
+        u32::try_from(binary.len()).ok()
+            .and_then(|length| org.checked_add(length))
+            .ok_or_else(|| EmuError::InvalidImage("the image runs past the end of the address space".to_string()))
+            .and_then(|end| emu.load_bin(binary, org).map(|_| vec![(org, end)]))
+    };
+    let code: Vec<(u32, u32)> = loaded.unwrap_or_else(|err| {
         eprintln!("Could not load binary: {err}");
         process::exit(1);
-    }), ORG);
+    });
+
+    match fp.get_command() {
+        Command::Run => run(emu, &fp),
+        Command::Disasm => {
+            for (start, end) in code {
+                disassemble(&mut emu, start, end);
+            }
+        },
+    }
+}
+
+fn run(mut emu: Emulator, fp: &Config) {
+    let pic = Pic::new();
+    emu.attach_port_device(pic::MASTER_PORTS, Box::new(pic.clone()));
+    emu.attach_port_device(pic::SLAVE_PORTS, Box::new(pic.clone()));
+    emu.set_interrupt_controller(Box::new(pic.clone()));
+    let pit = Pit::new(emu.get_clock());
+    emu.attach_port_device(pit::PIT_PORTS, Box::new(pit.clone()));
+    emu.attach_port_device(pit::SPEAKER_PORT..=pit::SPEAKER_PORT, Box::new(pit.clone()));
+    pic.attach_irq(pit::PIT_IRQ, Box::new(pit));
+    for (index, base) in uart::COM_PORTS.iter().enumerate() {
+        if let Some(backend) = fp.get_serial(index) {
+            let backend = uart::open_backend(backend).unwrap_or_else(|err| {
+                eprintln!("Could not open COM{}: {err}", index + 1);
+                process::exit(1);
+            });
+            let uart = Uart::new(backend);
+            uart.set_wait_for_input(fp.is_serial_wait());
+            pic.attach_irq(uart::COM_IRQS[index], Box::new(uart.clone()));
+            emu.attach_port_device(*base..=*base + 7, Box::new(uart));
+        }
+    }
+    let vga = fp.is_vga().then(Vga::new);
+    if let Some(vga) = &vga {
+        emu.map_device(vga::TEXT_BUFFER, vga::TEXT_BUFFER_SIZE, Box::new(vga.clone()));
+        emu.attach_port_device(vga::CRTC_PORTS, Box::new(vga.clone()));
+        if !fp.is_vga_dump() {
+            vga.spawn_renderer();
+        }
+    }
+    if fp.is_bios() {
+        let disk = fp.get_disk().map(|path| Disk::open(Path::new(path))).transpose().unwrap_or_else(|err| {
+            eprintln!("Could not open disk image: {err}");
+            process::exit(1);
+        });
+        let mut bios = Bios::new(&mut emu, device::stdin_channel(), disk).unwrap_or_else(|err| {
+            eprintln!("Could not set up the BIOS: {err}");
+            process::exit(1);
+        });
+        bios.set_vga(vga.is_some());
+        emu.attach_interrupt_handler(bios::VIDEO_VECTOR..=bios::TIME_VECTOR, Box::new(bios));
+    }
+
+    // flat binaries return to address 0 when they are done, Linux and DOS programs call exit
+    if !fp.is_linux() && !fp.is_dos() {
+        emu.set_stop_condition(|emu| emu.get_linear_eip() == 0);
+    }
+    emu.set_trace(fp.is_trace());
 
     let instructions = InstructionVector::new(INST_SIZE);
-    emu.run(instructions);
-    emu.dump();
+    if fp.is_debug() {
+        Debugger::new(&mut emu, &instructions).repl(stdin().lock(), stdout()).unwrap_or_else(|err| {
+            eprintln!("Debugger I/O error: {err}");
+            process::exit(1);
+        });
+        return;
+    }
+    if let Some(address) = fp.get_gdb() {
+        let result = gdbstub::listen(address).and_then(|conn| GdbStub::new(&mut emu, &instructions, conn).serve());
+        if let Err(err) = result {
+            eprintln!("GDB stub error: {err}");
+            process::exit(1);
+        }
+        return;
+    }
+    let result = emu.run(&instructions);
+    if let Some(vga) = &vga {
+        show_screen(vga, fp.is_vga_dump());
+    }
+    match result {
+        Ok(Step::Halt) => {
+            println!("CPU halted");
+            emu.dump();
+        },
+        Ok(Step::Exit(status)) => process::exit(status),
+        Ok(_) => {
+            println!("End of program");
+            emu.dump();
+        },
+        Err(err) => {
+            emu.dump();
+            eprintln!("Emulation stopped: {err}");
+            process::exit(2);
+        },
+    }
+}
+
+// the final state of the screen, then the cursor goes below it for what gets printed next
+fn show_screen(vga: &Vga, dump: bool) {
+    if dump {
+        println!("{}", vga.dump_text());
+        return;
+    }
+    let mut stdout = stdout();
+    let shown = vga.render(&mut stdout).and_then(|_| write!(stdout, "\x1b[{};1H\x1b[?25h", vga::ROWS + 1));
+    if let Err(err) = shown {
+        eprintln!("Could not draw the screen: {err}");
+    }
+}
+
+fn disassemble(emu: &mut Emulator, start: u32, end: u32) {
+    let mut eip = start;
+    while eip < end {
+        if let Some(symbol) = emu.get_symbol(eip) {
+            println!("{:08x} <{}>:", eip, symbol);
+        }
+        match disasm::disassemble(emu, eip) {
+            Ok(line) => {
+                println!("{line}");
+                // an instruction running past the top of memory ends the listing
+                eip = eip.saturating_add(line.get_length());
+            },
+            // an instruction cut off by the end of the image
+            Err(_) => {
+                let byte = emu.get_memory8(emu.get_linear_address(&SREG::CS, eip)).unwrap_or(0);
+                println!("{:08x}  {:<24}db {:#x}", eip, format!("{:02x}", byte), byte);
+                eip += 1;
+            },
+        }
+    }
 }
 
 #[cfg(test)]
@@ -39,16 +210,17 @@ mod tests {
     use super::*;
     #[test]
     fn main_test() {
-        let mut emu = Emulator::new(MEM_SIZE, 0x0000, ORG);
+        let mut emu = Emulator::new(MEM_SIZE, ORG, ORG);
     
         emu.load_bin(fs::read("bin/helloworld.bin").unwrap_or_else(|err| {
             eprintln!("Could not load binary: {err}");
             process::exit(1);
-        }), 0x0000);
+        }), ORG).unwrap();
     
+        emu.set_stop_condition(|emu| emu.get_linear_eip() == 0);
         let instructions = InstructionVector::new(INST_SIZE);
-        emu.run(instructions);
+        assert_eq!(emu.run(&instructions), Ok(Step::Stop));
         // emu.dump();
-        assert_eq!(emu.get_gpr_value(&emulator::GPR::EAX), 41);
+        assert_eq!(emu.get_gpr_value(&rpx86::emulator::GPR::EAX), 41);
     }
 }
\ No newline at end of file
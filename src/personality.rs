@@ -0,0 +1,5 @@
+//
+// OS personalities
+//
+pub mod dos;
+pub mod linux;
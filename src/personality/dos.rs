@@ -0,0 +1,331 @@
+//
+// MS-DOS .COM programs and INT 21h
+//
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stderr, stdin, stdout, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::emulator::{Emulator, GPR, GPR8};
+use crate::emulator::error::EmuError;
+use crate::emulator::interrupt::InterruptHandler;
+use crate::emulator::segment::SREG;
+
+pub const TERMINATE_VECTOR: u8 = 0x20;
+pub const SERVICE_VECTOR: u8 = 0x21;
+
+// segment of the PSP, the program follows it at offset 0x100
+const PSP_SEGMENT: u16 = 0x1000;
+const COM_OFFSET: u16 = 0x100;
+const COM_MAX_SIZE: usize = 0xff00;
+const TOP_OF_MEMORY: u16 = 0xa000;
+
+const DOS_VERSION: (u8, u8) = (5, 0);
+const MAX_HANDLES: u16 = 20;
+const FIRST_FILE_HANDLE: u16 = 5;
+// longest ASCIIZ path including the terminating NUL
+const MAX_PATH: usize = 128;
+
+const ERROR_INVALID_FUNCTION: u16 = 1;
+const ERROR_FILE_NOT_FOUND: u16 = 2;
+const ERROR_PATH_NOT_FOUND: u16 = 3;
+const ERROR_TOO_MANY_OPEN_FILES: u16 = 4;
+const ERROR_ACCESS_DENIED: u16 = 5;
+const ERROR_INVALID_HANDLE: u16 = 6;
+
+// a failed service sets CF and returns the DOS error code in AX
+type DosResult = Result<u16, u16>;
+
+// DOS personality: INT 20h/21h are serviced by the host, files live in a sandboxed host directory
+pub struct Dos {
+    root: PathBuf,
+    files: BTreeMap<u16, File>,
+}
+
+impl Dos {
+    // load a .COM image at PSP:0100 behind a synthesized PSP, with every segment register at the PSP
+    pub fn new(emu: &mut Emulator, image: &[u8], tail: &str, root: PathBuf) -> Result<Dos, EmuError> {
+        if image.len() > COM_MAX_SIZE {
+            return Err(EmuError::InvalidImage("a .COM program must fit in 0xff00 bytes".to_string()));
+        }
+        let psp = (PSP_SEGMENT as u32) << 4;
+
+        // INT 20h at PSP:0000 so that a near RET terminates the program
+        emu.set_memory16(psp, 0x20cd)?;
+        emu.set_memory16(psp + 0x2, TOP_OF_MEMORY as u32)?;
+        // the command tail: a length byte, the characters and a CR
+        let tail = &tail.as_bytes()[..tail.len().min(126)];
+        emu.set_memory8(psp + 0x80, tail.len() as u32)?;
+        for (i, byte) in tail.iter().chain(b"\r").enumerate() {
+            emu.set_memory8(psp + 0x81 + i as u32, *byte as u32)?;
+        }
+        emu.load_bin(image.to_vec(), psp + COM_OFFSET as u32)?;
+
+        for reg in [SREG::CS, SREG::DS, SREG::ES, SREG::SS] {
+            emu.set_sreg(&reg, PSP_SEGMENT);
+        }
+        emu.set_eip(COM_OFFSET as u32);
+        emu.set_gpr(&GPR::ESP, 0xfffe);
+        emu.push16(0x0)?;
+
+        Ok(Dos { root, files: BTreeMap::new() })
+    }
+
+    fn service(&mut self, emu: &mut Emulator) -> Result<(), EmuError> {
+        let function = emu.get_gpr8_value(&GPR8::AH);
+        let result = match function {
+            0x00 => return Err(EmuError::Exit(0)),
+            0x01 => {
+                let char = getchar().map_err(io_error)?;
+                putchar(char).map_err(io_error)?;
+                emu.set_gpr8(&GPR8::AL, char);
+                return Ok(());
+            },
+            0x02 => {
+                putchar(emu.get_gpr8_value(&GPR8::DL)).map_err(io_error)?;
+                return Ok(());
+            },
+            0x09 => {
+                // a '$'-terminated string at DS:DX. output stops after one segment when there is no '$'.
+                let offset = emu.get_gpr16_value(&GPR::EDX);
+                for i in 0..=u16::MAX {
+                    let char = emu.get_memory8(emu.get_linear_address(&SREG::DS, offset.wrapping_add(i) as u32))?;
+                    if char == b'$' {
+                        break;
+                    }
+                    putchar(char).map_err(io_error)?;
+                }
+                return Ok(());
+            },
+            0x30 => {
+                emu.set_gpr8(&GPR8::AL, DOS_VERSION.0);
+                emu.set_gpr8(&GPR8::AH, DOS_VERSION.1);
+                emu.set_gpr16(&GPR::EBX, 0x0);
+                emu.set_gpr16(&GPR::ECX, 0x0);
+                return Ok(());
+            },
+            0x3C => self.open(emu, 0x2, true),
+            0x3D => self.open(emu, emu.get_gpr8_value(&GPR8::AL) & 0x7, false),
+            0x3E => self.close(emu.get_gpr16_value(&GPR::EBX)),
+            0x3F => self.read(emu),
+            0x40 => self.write(emu),
+            0x4C => return Err(EmuError::Exit(emu.get_gpr8_value(&GPR8::AL) as i32)),
+            _ => Err(ERROR_INVALID_FUNCTION),
+        };
+
+        let (ax, carry) = match result {
+            Ok(value) => (value, 0),
+            Err(code) => (code, 1),
+        };
+        emu.set_gpr16(&GPR::EAX, ax);
+        emu.set_carry(carry);
+        Ok(())
+    }
+
+    // a DOS path relative to the sandbox. the drive letter is ignored and nothing may climb out of the root,
+    // neither through .. nor through a symlink.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = path.get(2..).filter(|_| path.as_bytes().get(1) == Some(&b':')).unwrap_or(path);
+        let mut resolved = self.root.clone();
+        for component in Path::new(&path.replace('\\', "/")).components() {
+            match component {
+                Component::Normal(name) => {
+                    // DOS names are case-insensitive, reuse the host spelling when the entry exists
+                    let name = name.to_str()?;
+                    let existing = fs::read_dir(&resolved).ok()?
+                        .filter_map(|entry| entry.ok())
+                        .map(|entry| entry.file_name())
+                        .find(|entry| entry.to_str().is_some_and(|entry| entry.eq_ignore_ascii_case(name)));
+                    resolved.push(existing.unwrap_or_else(|| name.into()));
+                },
+                Component::RootDir | Component::CurDir => {},
+                _ => return None,
+            }
+        }
+        // a file that does not exist yet is checked through its directory
+        let resolved = match fs::symlink_metadata(&resolved) {
+            Ok(_) => resolved.canonicalize().ok()?,
+            Err(_) => resolved.parent()?.canonicalize().ok()?.join(resolved.file_name()?),
+        };
+        Some(resolved).filter(|resolved| self.root.canonicalize().is_ok_and(|root| resolved.starts_with(root)))
+    }
+
+    fn open(&mut self, emu: &mut Emulator, mode: u8, create: bool) -> DosResult {
+        let path = read_asciiz(emu, emu.get_gpr16_value(&GPR::EDX))?;
+        let path = self.resolve(&path).ok_or(ERROR_PATH_NOT_FOUND)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => return Err(ERROR_ACCESS_DENIED),
+        };
+        if create {
+            options.create(true).truncate(true);
+        }
+        let file = options.open(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => ERROR_FILE_NOT_FOUND,
+            _ => ERROR_ACCESS_DENIED,
+        })?;
+
+        // 0-4 are the standard handles
+        let handle = (FIRST_FILE_HANDLE..MAX_HANDLES)
+            .find(|handle| !self.files.contains_key(handle))
+            .ok_or(ERROR_TOO_MANY_OPEN_FILES)?;
+        self.files.insert(handle, file);
+        Ok(handle)
+    }
+
+    fn close(&mut self, handle: u16) -> DosResult {
+        match handle {
+            0..FIRST_FILE_HANDLE => Ok(0),
+            _ => self.files.remove(&handle).map(|_| 0).ok_or(ERROR_INVALID_HANDLE),
+        }
+    }
+
+    fn read(&mut self, emu: &mut Emulator) -> DosResult {
+        let handle = emu.get_gpr16_value(&GPR::EBX);
+        let mut data = vec![0; emu.get_gpr16_value(&GPR::ECX) as usize];
+        let length = match handle {
+            0 => stdin().read(&mut data),
+            1..FIRST_FILE_HANDLE => return Err(ERROR_ACCESS_DENIED),
+            _ => self.files.get_mut(&handle).ok_or(ERROR_INVALID_HANDLE)?.read(&mut data),
+        }.map_err(|_| ERROR_ACCESS_DENIED)?;
+
+        let buffer = emu.get_gpr16_value(&GPR::EDX);
+        for (i, byte) in data[..length].iter().enumerate() {
+            let address = emu.get_linear_address(&SREG::DS, buffer.wrapping_add(i as u16) as u32);
+            emu.set_memory8(address, *byte as u32).map_err(|_| ERROR_ACCESS_DENIED)?;
+        }
+        Ok(length as u16)
+    }
+
+    fn write(&mut self, emu: &mut Emulator) -> DosResult {
+        let handle = emu.get_gpr16_value(&GPR::EBX);
+        let (buffer, count) = (emu.get_gpr16_value(&GPR::EDX), emu.get_gpr16_value(&GPR::ECX));
+        let data = (0..count)
+            .map(|i| emu.get_memory8(emu.get_linear_address(&SREG::DS, buffer.wrapping_add(i) as u32)))
+            .collect::<Result<Vec<u8>, EmuError>>()
+            .map_err(|_| ERROR_ACCESS_DENIED)?;
+        match handle {
+            0 => return Err(ERROR_ACCESS_DENIED),
+            1 | 3 | 4 => stdout().write_all(&data).and_then(|_| stdout().flush()),
+            2 => stderr().write_all(&data),
+            _ => self.files.get_mut(&handle).ok_or(ERROR_INVALID_HANDLE)?.write_all(&data),
+        }.map_err(|_| ERROR_ACCESS_DENIED)?;
+        Ok(count)
+    }
+}
+
+impl InterruptHandler for Dos {
+    fn interrupt(&mut self, emu: &mut Emulator, vector: u8) -> Result<bool, EmuError> {
+        match vector {
+            TERMINATE_VECTOR => Err(EmuError::Exit(0)),
+            SERVICE_VECTOR => self.service(emu).map(|_| true),
+            _ => Ok(false),
+        }
+    }
+}
+
+// an ASCIIZ path at DS:offset
+fn read_asciiz(emu: &Emulator, offset: u16) -> Result<String, u16> {
+    let mut bytes = Vec::new();
+    while bytes.len() < MAX_PATH {
+        let address = emu.get_linear_address(&SREG::DS, offset.wrapping_add(bytes.len() as u16) as u32);
+        match emu.get_memory8(address).map_err(|_| ERROR_PATH_NOT_FOUND)? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            byte => bytes.push(byte),
+        }
+    }
+    Err(ERROR_PATH_NOT_FOUND)
+}
+
+// the end of the input reads as ^Z
+fn getchar() -> io::Result<u8> {
+    let mut input = [0u8; 1];
+    match stdin().read(&mut input)? {
+        0 => Ok(0x1a),
+        _ => Ok(input[0]),
+    }
+}
+
+fn putchar(char: u8) -> io::Result<()> {
+    let mut stdout = stdout();
+    stdout.write_all(&[char])?;
+    stdout.flush()
+}
+
+// console failures stop the emulation
+fn io_error(err: io::Error) -> EmuError {
+    EmuError::Console(err.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Step;
+    use crate::instruction::InstructionVector;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn dos_test() {
+        let root = env::temp_dir().join(format!("rpx86-dos-{}", process::id()));
+        fs::create_dir_all(root.join("Sub")).unwrap();
+        fs::write(root.join("Sub").join("in.txt"), "data").unwrap();
+
+        let mut emu = Emulator::new(0x100000, 0x0, 0x0);
+        emu.set_real_mode();
+        let image = [
+            0xb4, 0x30, 0xcd, 0x21,                 // mov ah, 0x30; int 0x21
+            0xba, 0x40, 0x01, 0xb4, 0x3d, 0xb0, 0x00, 0xcd, 0x21,   // mov dx, name; mov ah, 0x3d; mov al, 0; int 0x21
+            0x89, 0xc3,                             // mov bx, ax
+            0xba, 0x60, 0x01, 0xb9, 0x10, 0x00, 0xb4, 0x3f, 0xcd, 0x21, // mov dx, buffer; mov cx, 0x10; mov ah, 0x3f; int 0x21
+            0xb4, 0x3e, 0xcd, 0x21,                 // mov ah, 0x3e; int 0x21
+            0xb4, 0x3e, 0xcd, 0x21,                 // mov ah, 0x3e; int 0x21
+            0xb8, 0x07, 0x4c, 0xcd, 0x21,           // mov ax, 0x4c07; int 0x21
+        ];
+        let mut image = image.to_vec();
+        image.resize(0x40, 0x90);
+        image.extend_from_slice(b"C:\\SUB\\IN.TXT\0");
+        let dos = Dos::new(&mut emu, &image, " /q", root.clone()).unwrap();
+        emu.attach_interrupt_handler(TERMINATE_VECTOR..=SERVICE_VECTOR, Box::new(dos));
+
+        // PSP and registers
+        assert_eq!(emu.get_memory16(0x10000), Ok(0x20cd));
+        assert_eq!(emu.get_memory8(0x10080), Ok(3));
+        assert_eq!(emu.get_memory32(0x10081), Ok(0x0d712f20));
+        assert_eq!(emu.get_linear_eip(), 0x10100);
+        assert_eq!(emu.get_sp(), 0xfffc);
+
+        let instructions = InstructionVector::new(0x100);
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0x0005);
+        assert_eq!(emu.run_until(&instructions, 9), Step::Continue);
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 4);
+        assert!(!emu.is_carry());
+        assert_eq!(emu.get_memory32(0x10160), Ok(u32::from_le_bytes(*b"data")));
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), 0);
+        assert_eq!(emu.run_until(&instructions, 2), Step::Continue);
+        assert_eq!(emu.get_gpr16_value(&GPR::EAX), ERROR_INVALID_HANDLE);
+        assert!(emu.is_carry());
+        assert_eq!(emu.run(&instructions), Ok(Step::Exit(7)));
+
+        // paths are no longer than MAX_PATH
+        for i in 0..MAX_PATH as u32 {
+            emu.set_memory8(0x10200 + i, b'A' as u32).unwrap();
+        }
+        assert_eq!(read_asciiz(&emu, 0x200), Err(ERROR_PATH_NOT_FOUND));
+        emu.set_memory8(0x1027f, 0x0).unwrap();
+        assert_eq!(read_asciiz(&emu, 0x200).map(|path| path.len()), Ok(MAX_PATH - 1));
+
+        // the sandbox cannot be left
+        let dos = Dos { root: root.clone(), files: BTreeMap::new() };
+        std::os::unix::fs::symlink(env::temp_dir(), root.join("out")).unwrap();
+        assert_eq!(dos.resolve("..\\secret"), None);
+        assert_eq!(dos.resolve("out\\secret"), None);
+        assert_eq!(dos.resolve("sub\\NEW.TXT"), Some(root.canonicalize().unwrap().join("Sub").join("NEW.TXT")));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
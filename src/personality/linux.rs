@@ -0,0 +1,434 @@
+//
+// Linux i386 system calls
+//
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, stderr, stdin, stdout, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::emulator::{Emulator, GPR};
+use crate::emulator::elf::Elf;
+use crate::emulator::error::EmuError;
+use crate::emulator::interrupt::InterruptHandler;
+
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+const PAGE_SIZE: u32 = 0x1000;
+const STACK_TOP: u32 = 0xc0000000;
+const STACK_SIZE: u32 = 0x100000;
+const MMAP_BASE: u32 = 0x40000000;
+// anonymous mappings are placed between MMAP_BASE and the stack
+const MMAP_END: u32 = STACK_TOP - STACK_SIZE;
+// a read returns at most this many bytes, the guest sees a short read for larger counts
+const READ_CHUNK: u32 = 0x10000;
+
+const SYS_EXIT: u32 = 1;
+const SYS_READ: u32 = 3;
+const SYS_WRITE: u32 = 4;
+const SYS_OPEN: u32 = 5;
+const SYS_CLOSE: u32 = 6;
+const SYS_TIME: u32 = 13;
+const SYS_GETPID: u32 = 20;
+const SYS_BRK: u32 = 45;
+const SYS_IOCTL: u32 = 54;
+const SYS_MMAP: u32 = 90;
+const SYS_MUNMAP: u32 = 91;
+const SYS_WRITEV: u32 = 146;
+const SYS_MMAP2: u32 = 192;
+const SYS_EXIT_GROUP: u32 = 252;
+
+const EBADF: u32 = 9;
+const ENOMEM: u32 = 12;
+const EFAULT: u32 = 14;
+const ENODEV: u32 = 19;
+const EINVAL: u32 = 22;
+const ENOTTY: u32 = 25;
+const ENOSYS: u32 = 38;
+const EIO: u32 = 5;
+
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_CREAT: u32 = 0x40;
+const O_EXCL: u32 = 0x80;
+const O_TRUNC: u32 = 0x200;
+const O_APPEND: u32 = 0x400;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const AT_NULL: u32 = 0;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_RANDOM: u32 = 25;
+
+// a failed system call returns -errno in EAX
+type SysResult = Result<u32, u32>;
+
+// user-mode personality: int 0x80 is serviced by the host and the process sees a Linux-like stack
+pub struct Linux {
+    brk: u32,
+    brk_mapped: u32,
+    mmap_next: u32,
+    files: BTreeMap<u32, File>,
+}
+
+impl Linux {
+    // map the stack and lay out argc, argv, envp and auxv on it the way the kernel does for execve
+    pub fn new(emu: &mut Emulator, elf: &Elf, args: &[String], env: &[String]) -> Result<Linux, EmuError> {
+        emu.map_ram(STACK_TOP - STACK_SIZE, STACK_SIZE);
+        let mut sp = STACK_TOP;
+        let mut copy = |emu: &mut Emulator, bytes: &[u8]| -> Result<u32, EmuError> {
+            sp -= bytes.len() as u32;
+            write_bytes(emu, sp, bytes)?;
+            Ok(sp)
+        };
+
+        let mut string_pointers = |emu: &mut Emulator, strings: &[String]| {
+            strings.iter()
+                .map(|string| copy(emu, format!("{}\0", string).as_bytes()))
+                .collect::<Result<Vec<u32>, EmuError>>()
+        };
+        let envp = string_pointers(emu, env)?;
+        let argv = string_pointers(emu, args)?;
+        let random = copy(emu, b"rpx86 not random")?;
+
+        let auxv = [(AT_PAGESZ, PAGE_SIZE), (AT_ENTRY, elf.get_entry()), (AT_RANDOM, random), (AT_NULL, 0)];
+        // argc ends up 16-byte aligned
+        let words = 1 + argv.len() + 1 + envp.len() + 1 + auxv.len() * 2;
+        emu.set_gpr(&GPR::ESP, sp & !0xf);
+        for _ in 0..(4 - words % 4) % 4 {
+            emu.push32(0)?;
+        }
+        for (key, value) in auxv.iter().rev() {
+            emu.push32(*value)?;
+            emu.push32(*key)?;
+        }
+        for pointers in [envp, argv.clone()] {
+            emu.push32(0)?;
+            for pointer in pointers.iter().rev() {
+                emu.push32(*pointer)?;
+            }
+        }
+        emu.push32(argv.len() as u32)?;
+        for reg in [GPR::EAX, GPR::EBX, GPR::ECX, GPR::EDX, GPR::ESI, GPR::EDI, GPR::EBP] {
+            emu.set_gpr(&reg, 0);
+        }
+
+        // the heap starts at the page following the highest segment
+        let end = elf.get_segments().iter()
+            .map(|segment| segment.get_address() + segment.get_memory_size())
+            .max()
+            .unwrap_or(0);
+        let brk = page_align(end).ok_or(EmuError::MemoryOutOfRange(end))?;
+        Ok(Linux { brk, brk_mapped: brk, mmap_next: MMAP_BASE, files: BTreeMap::new() })
+    }
+
+    fn syscall(&mut self, emu: &mut Emulator) -> Result<SysResult, EmuError> {
+        let arg = |reg: GPR| emu.get_gpr_value(&reg);
+        let (number, a1, a2, a3) = (arg(GPR::EAX), arg(GPR::EBX), arg(GPR::ECX), arg(GPR::EDX));
+        let a4 = arg(GPR::ESI);
+
+        let result = match number {
+            SYS_EXIT | SYS_EXIT_GROUP => return Err(EmuError::Exit(a1 as i32)),
+            SYS_READ => self.read(emu, a1, a2, a3),
+            SYS_WRITE => self.write(emu, a1, a2, a3),
+            SYS_OPEN => self.open(emu, a1, a2, a3),
+            SYS_CLOSE => self.close(a1),
+            SYS_TIME => time(emu, a1),
+            SYS_GETPID => Ok(process::id()),
+            SYS_BRK => Ok(self.set_brk(emu, a1)),
+            SYS_IOCTL => Err(ENOTTY),
+            SYS_MMAP => {
+                // old mmap takes its arguments in memory, with the offset in bytes
+                let args = (0..6).map(|i| emu.get_memory32(a1.wrapping_add(i * 4))).collect::<Result<Vec<u32>, EmuError>>();
+                match args {
+                    Ok(args) => self.mmap(emu, args[0], args[1], args[3]),
+                    Err(_) => Err(EFAULT),
+                }
+            },
+            SYS_MMAP2 => self.mmap(emu, a1, a2, a4),
+            SYS_MUNMAP => self.munmap(emu, a1, a2),
+            SYS_WRITEV => self.writev(emu, a1, a2, a3),
+            _ => Err(ENOSYS),
+        };
+        Ok(result)
+    }
+
+    fn read(&mut self, emu: &mut Emulator, fd: u32, buffer: u32, count: u32) -> SysResult {
+        let mut data = vec![0; count.min(READ_CHUNK) as usize];
+        let length = match fd {
+            0 => stdin().read(&mut data),
+            1 | 2 => return Err(EBADF),
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?.read(&mut data),
+        }.map_err(errno)?;
+        write_bytes(emu, buffer, &data[..length]).map_err(|_| EFAULT)?;
+        Ok(length as u32)
+    }
+
+    fn write(&mut self, emu: &mut Emulator, fd: u32, buffer: u32, count: u32) -> SysResult {
+        let data = read_bytes(emu, buffer, count).map_err(|_| EFAULT)?;
+        match fd {
+            0 => return Err(EBADF),
+            1 => stdout().write_all(&data).and_then(|_| stdout().flush()),
+            2 => stderr().write_all(&data),
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?.write_all(&data),
+        }.map_err(errno)?;
+        Ok(count)
+    }
+
+    fn writev(&mut self, emu: &mut Emulator, fd: u32, iov: u32, count: u32) -> SysResult {
+        let mut total = 0;
+        for i in 0..count {
+            let entry = iov.wrapping_add(i.wrapping_mul(8));
+            let base = emu.get_memory32(entry).map_err(|_| EFAULT)?;
+            let length = emu.get_memory32(entry.wrapping_add(4)).map_err(|_| EFAULT)?;
+            total += self.write(emu, fd, base, length)?;
+        }
+        Ok(total)
+    }
+
+    fn open(&mut self, emu: &mut Emulator, path: u32, flags: u32, mode: u32) -> SysResult {
+        let path = read_string(emu, path).map_err(|_| EFAULT)?;
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            options.create(true).create_new(flags & O_EXCL != 0);
+        }
+        let file = options.mode(mode).open(path).map_err(errno)?;
+
+        // the lowest free descriptor
+        let fd = (3..).find(|fd| !self.files.contains_key(fd)).ok_or(EINVAL)?;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn close(&mut self, fd: u32) -> SysResult {
+        match fd {
+            0..=2 => Ok(0),
+            _ => self.files.remove(&fd).map(|_| 0).ok_or(EBADF),
+        }
+    }
+
+    // brk never fails, it returns the current break when the request cannot be met.
+    // the heap may not wrap around or run into a mapping or the stack.
+    fn set_brk(&mut self, emu: &mut Emulator, brk: u32) -> u32 {
+        if brk < self.brk {
+            return self.brk;
+        }
+        let end = match page_align(brk) {
+            Some(end) => end,
+            None => return self.brk,
+        };
+        if end > self.brk_mapped {
+            let size = end - self.brk_mapped;
+            if !emu.find_regions(self.brk_mapped, size).is_empty() {
+                return self.brk;
+            }
+            emu.map_ram(self.brk_mapped, size);
+            self.brk_mapped = end;
+        }
+        self.brk = brk;
+        self.brk
+    }
+
+    // only anonymous mappings are supported
+    fn mmap(&mut self, emu: &mut Emulator, address: u32, length: u32, flags: u32) -> SysResult {
+        if flags & MAP_ANONYMOUS == 0 {
+            return Err(ENODEV);
+        }
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = page_align(length).ok_or(ENOMEM)?;
+        let address = if flags & MAP_FIXED != 0 {
+            if !address.is_multiple_of(PAGE_SIZE) || address.checked_add(length).is_none() {
+                return Err(EINVAL);
+            }
+            // mappings covered by the new one are replaced, only partly covering one is not supported
+            let regions = emu.find_regions(address, length);
+            let end = address as u64 + length as u64;
+            if regions.iter().any(|&(base, size)| base < address || base as u64 + size as u64 > end) {
+                return Err(EINVAL);
+            }
+            for (base, _) in regions {
+                emu.unmap(base);
+            }
+            address
+        } else {
+            let address = self.mmap_next;
+            let end = address.checked_add(length).filter(|&end| end <= MMAP_END).ok_or(ENOMEM)?;
+            if !emu.find_regions(address, length).is_empty() {
+                return Err(ENOMEM);
+            }
+            self.mmap_next = end;
+            address
+        };
+        emu.map_ram(address, length);
+        Ok(address)
+    }
+
+    // only whole mappings of the mmap area go away, the stack, the image and the heap stay
+    fn munmap(&mut self, emu: &mut Emulator, address: u32, length: u32) -> SysResult {
+        if !address.is_multiple_of(PAGE_SIZE) || length == 0 {
+            return Err(EINVAL);
+        }
+        let length = page_align(length).ok_or(EINVAL)?;
+        let end = address.checked_add(length).ok_or(EINVAL)?;
+        if address < MMAP_BASE || end > MMAP_END {
+            return Err(EINVAL);
+        }
+        let regions = emu.find_regions(address, length);
+        if regions.iter().any(|&(base, size)| base < address || base as u64 + size as u64 > end as u64) {
+            return Err(EINVAL);
+        }
+        for (base, _) in regions {
+            emu.unmap(base);
+        }
+        Ok(0)
+    }
+}
+
+impl InterruptHandler for Linux {
+    fn interrupt(&mut self, emu: &mut Emulator, vector: u8) -> Result<bool, EmuError> {
+        if vector != SYSCALL_VECTOR {
+            return Ok(false);
+        }
+        let result = match self.syscall(emu)? {
+            Ok(value) => value,
+            Err(errno) => errno.wrapping_neg(),
+        };
+        emu.set_gpr(&GPR::EAX, result);
+        Ok(true)
+    }
+}
+
+fn time(emu: &mut Emulator, pointer: u32) -> SysResult {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as u32);
+    if pointer != 0 {
+        emu.set_memory32(pointer, now).map_err(|_| EFAULT)?;
+    }
+    Ok(now)
+}
+
+fn errno(err: io::Error) -> u32 {
+    err.raw_os_error().map_or(EIO, |code| code as u32)
+}
+
+// None when rounding up runs past the end of the address space
+fn page_align(address: u32) -> Option<u32> {
+    address.checked_add(PAGE_SIZE - 1).map(|address| address & !(PAGE_SIZE - 1))
+}
+
+fn read_bytes(emu: &Emulator, address: u32, length: u32) -> Result<Vec<u8>, EmuError> {
+    (0..length).map(|i| emu.get_memory8(address.wrapping_add(i))).collect()
+}
+
+fn write_bytes(emu: &mut Emulator, address: u32, bytes: &[u8]) -> Result<(), EmuError> {
+    for (i, byte) in bytes.iter().enumerate() {
+        emu.set_memory8(address.wrapping_add(i as u32), *byte as u32)?;
+    }
+    Ok(())
+}
+
+fn read_string(emu: &Emulator, address: u32) -> Result<String, EmuError> {
+    let mut bytes = Vec::new();
+    loop {
+        match emu.get_memory8(address.wrapping_add(bytes.len() as u32))? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            byte => bytes.push(byte),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn syscall(emu: &mut Emulator, linux: &mut Linux, args: [u32; 4]) -> u32 {
+        for (reg, value) in [GPR::EAX, GPR::EBX, GPR::ECX, GPR::EDX].iter().zip(args) {
+            emu.set_gpr(reg, value);
+        }
+        assert_eq!(linux.interrupt(emu, SYSCALL_VECTOR), Ok(true));
+        emu.get_gpr_value(&GPR::EAX)
+    }
+
+    #[test]
+    fn linux_test() {
+        let mut emu = Emulator::new(0x10000, 0x0, 0x0);
+        let elf = emu.load_elf(&crate::emulator::elf::tests::build_elf(0x8048000, &[0x90], 0x10)).unwrap();
+        let args = ["prog".to_string(), "-v".to_string()];
+        let mut linux = Linux::new(&mut emu, &elf, &args, &["HOME=/".to_string()]).unwrap();
+
+        // argc, argv, envp and auxv
+        let sp = emu.get_gpr_value(&GPR::ESP);
+        assert_eq!(sp % 16, 0);
+        assert_eq!(emu.get_memory32(sp), Ok(2));
+        assert_eq!(read_string(&emu, emu.get_memory32(sp + 8).unwrap()), Ok("-v".to_string()));
+        assert_eq!(emu.get_memory32(sp + 12), Ok(0));
+        assert_eq!(read_string(&emu, emu.get_memory32(sp + 16).unwrap()), Ok("HOME=/".to_string()));
+        assert_eq!(emu.get_memory32(sp + 20), Ok(0));
+        assert_eq!(emu.get_memory32(sp + 24), Ok(AT_PAGESZ));
+        assert_eq!(emu.get_memory32(sp + 36), Ok(0x8048005));
+
+        // the heap grows from the page after .bss
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_BRK, 0, 0, 0]), 0x8049000);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_BRK, 0x804a800, 0, 0]), 0x804a800);
+        emu.set_memory32(0x804a7fc, 0x12345678).unwrap();
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_BRK, 0x8040000, 0, 0]), 0x804a800);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_BRK, 0xffffffff, 0, 0]), 0x804a800);
+
+        emu.set_gpr(&GPR::ESI, MAP_ANONYMOUS);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, 0, 0x1800, 3]), MMAP_BASE);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, 0, 0x1800, 3]), MMAP_BASE + 0x2000);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, 0, 0xffffffff, 3]), ENOMEM.wrapping_neg());
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, 0, MMAP_END - MMAP_BASE, 3]), ENOMEM.wrapping_neg());
+        // the heap does not grow into a mapping
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_BRK, MMAP_BASE + 0x800, 0, 0]), 0x804a800);
+        emu.set_gpr(&GPR::ESI, MAP_ANONYMOUS | MAP_FIXED);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, MMAP_BASE, 0x1000, 3]), EINVAL.wrapping_neg());
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, MMAP_BASE, 0x2000, 3]), MMAP_BASE);
+        assert_eq!(emu.find_regions(MMAP_BASE, 0x2000), vec![(MMAP_BASE, 0x2000)]);
+
+        // munmap takes whole mappings of the mmap area only
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MUNMAP, MMAP_BASE + 0x2001, 0x1000, 0]), EINVAL.wrapping_neg());
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MUNMAP, MMAP_BASE + 0x3000, 0x1000, 0]), EINVAL.wrapping_neg());
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MUNMAP, STACK_TOP - STACK_SIZE, STACK_SIZE, 0]), EINVAL.wrapping_neg());
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MUNMAP, 0x8049000, 0x2000, 0]), EINVAL.wrapping_neg());
+        assert!(emu.get_memory8(STACK_TOP - 1).is_ok());
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MUNMAP, MMAP_BASE + 0x2000, 0x1800, 0]), 0);
+        assert_eq!(emu.find_regions(MMAP_BASE + 0x2000, 0x2000), vec![]);
+        emu.set_gpr(&GPR::ESI, 0);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_MMAP2, 0, 0x1800, 3]), ENODEV.wrapping_neg());
+
+        // write a file and read it back
+        let path = env::temp_dir().join(format!("rpx86-linux-{}", process::id()));
+        write_bytes(&mut emu, MMAP_BASE, format!("{}\0", path.display()).as_bytes()).unwrap();
+        write_bytes(&mut emu, MMAP_BASE + 0x100, b"hello").unwrap();
+        let fd = syscall(&mut emu, &mut linux, [SYS_OPEN, MMAP_BASE, O_WRONLY | O_CREAT | O_TRUNC, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_WRITE, fd, MMAP_BASE + 0x100, 5]), 5);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_CLOSE, fd, 0, 0]), 0);
+        let fd = syscall(&mut emu, &mut linux, [SYS_OPEN, MMAP_BASE, 0, 0]);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_READ, fd, MMAP_BASE + 0x200, 0x10]), 5);
+        assert_eq!(read_bytes(&emu, MMAP_BASE + 0x200, 5), Ok(b"hello".to_vec()));
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_CLOSE, fd, 0, 0]), 0);
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_CLOSE, fd, 0, 0]), EBADF.wrapping_neg());
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(syscall(&mut emu, &mut linux, [SYS_GETPID, 0, 0, 0]), process::id());
+        assert_eq!(syscall(&mut emu, &mut linux, [0xffff, 0, 0, 0]), ENOSYS.wrapping_neg());
+        emu.set_gpr(&GPR::EAX, SYS_EXIT_GROUP);
+        emu.set_gpr(&GPR::EBX, 3);
+        assert_eq!(linux.interrupt(&mut emu, SYSCALL_VECTOR), Err(EmuError::Exit(3)));
+        assert_eq!(linux.interrupt(&mut emu, 0x21), Ok(false));
+    }
+}